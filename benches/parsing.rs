@@ -0,0 +1,85 @@
+//! Benchmarks for inbound message parsing over a batch of typical replies
+//! and bounces.
+//!
+//! Run with `cargo bench --bench parsing`.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use pushkind_hedwig::check_reply::parser::parse_email;
+use pushkind_hedwig::domain::CorrelationOrder;
+
+const DOMAIN: &str = "example.com";
+
+const PLAIN_REPLY: &str = "Subject: Re: Offer\r\nFrom: Alice <alice@example.org>\r\n\
+    In-Reply-To: <42.18c2f3a1b0@example.com>\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\n\
+    Thanks, I am interested!\r\n\r\nOn Mon, 1 Jan 2024 at 10:00, Shop <shop@example.com> wrote:\r\n\
+    > Hello Alice, your favourite colour is blue.\r\n> We have a new collection in blue.\r\n";
+
+const HTML_REPLY: &str = "Subject: Re: Offer\r\nFrom: Bob <bob@example.org>\r\n\
+    In-Reply-To: <43.18c2f3a1b0@example.com>\r\nMIME-Version: 1.0\r\n\
+    Content-Type: multipart/alternative; boundary=\"alt\"\r\n\r\n\
+    --alt\r\nContent-Type: text/html; charset=\"utf-8\"\r\n\r\n\
+    <html><body><div>Please call me tomorrow.</div><blockquote>\
+    <p>Hello Bob, your favourite colour is green.</p></blockquote></body></html>\r\n\
+    --alt--\r\n";
+
+const DSN_BOUNCE: &str = "Subject: Undelivered Mail Returned to Sender\r\n\
+    From: MAILER-DAEMON@mx.example.org\r\nMIME-Version: 1.0\r\n\
+    Content-Type: multipart/report; report-type=delivery-status; boundary=\"dsn\"\r\n\r\n\
+    --dsn\r\nContent-Type: text/plain\r\n\r\n\
+    I'm sorry to have to inform you that your message could not be delivered.\r\n\
+    --dsn\r\nContent-Type: message/delivery-status\r\n\r\n\
+    Reporting-MTA: dns; mx.example.org\r\n\r\n\
+    Final-Recipient: rfc822; carol@example.org\r\nAction: failed\r\nStatus: 5.1.1\r\n\
+    Diagnostic-Code: smtp; 550 5.1.1 User unknown\r\n\
+    --dsn\r\nContent-Type: text/rfc822-headers\r\n\r\n\
+    Message-ID: <44.18c2f3a1b0@example.com>\r\nTo: carol@example.org\r\n\
+    --dsn--\r\n";
+
+const FORWARDED_BOUNCE: &str = "Subject: Delivery failure\r\nFrom: postmaster@example.net\r\n\
+    Content-Type: text/plain\r\n\r\n\
+    Your message could not be delivered to one or more recipients.\r\n\r\n\
+    ----- Original message -----\r\n\
+    > From: Shop <shop@example.com>\r\n> To: dave@example.net\r\n> Subject: Offer\r\n";
+
+const DMARC_BOUNCE: &str = "Subject: Undeliverable\r\nFrom: MAILER-DAEMON@mx.example.org\r\n\
+    MIME-Version: 1.0\r\n\
+    Content-Type: multipart/report; report-type=delivery-status; boundary=\"dsn\"\r\n\r\n\
+    --dsn\r\nContent-Type: message/delivery-status\r\n\r\n\
+    Final-Recipient: rfc822; erin@example.org\r\nAction: failed\r\nStatus: 5.7.26\r\n\
+    Diagnostic-Code: smtp; 550 5.7.26 Unauthenticated email is rejected by DMARC policy\r\n\
+    --dsn--\r\n";
+
+const BATCH: [&str; 5] = [
+    PLAIN_REPLY,
+    HTML_REPLY,
+    DSN_BOUNCE,
+    FORWARDED_BOUNCE,
+    DMARC_BOUNCE,
+];
+
+fn bench_parse_batch(c: &mut Criterion) {
+    let bytes: usize = BATCH.iter().map(|raw| raw.len()).sum();
+    let mut group = c.benchmark_group("parse_email");
+    group.throughput(Throughput::Bytes(bytes as u64));
+    group.bench_function("batch", |b| {
+        b.iter(|| {
+            for raw in BATCH {
+                black_box(
+                    parse_email(
+                        black_box(raw.as_bytes()),
+                        DOMAIN,
+                        CorrelationOrder::default(),
+                        false,
+                    )
+                    .unwrap(),
+                );
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_batch);
+criterion_main!(benches);
@@ -0,0 +1,126 @@
+//! Benchmarks for the per-recipient rendering path.
+//!
+//! Run with `cargo bench --bench rendering`.
+
+use std::collections::BTreeMap;
+use std::hint::black_box;
+
+use chrono::Utc;
+use criterion::{Criterion, criterion_group, criterion_main};
+use pushkind_emailer::domain::email::{Email, EmailRecipient};
+use pushkind_emailer::domain::hub::Hub;
+use pushkind_hedwig::models::HubSettings;
+use pushkind_hedwig::send_email::message_builder::{
+    MessageOptions, build_message, fill_template, render_body,
+};
+
+const TEMPLATE: &str = "<html><body><p>Здравствуйте, {name}!</p>{message}\
+    <p>Ваш заказ от {date:%d.%m.%Y} на сумму {total:currency} готов.</p>\
+    <p>{unknown}</p><p><a href=\"{unsubscribe_url}\">Отписаться</a></p></body></html>";
+
+const MESSAGE: &str = "Hello {name}, your favourite colour is {color}. \
+    We have a new collection in {color} waiting for you, {name}!";
+
+fn fields() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("name".to_string(), "Alice".to_string()),
+        ("color".to_string(), "blue".to_string()),
+        ("date".to_string(), "2024-03-05".to_string()),
+        ("total".to_string(), "12345.6".to_string()),
+    ])
+}
+
+fn hub() -> Hub {
+    Hub::try_new(
+        1,
+        Some("shop@example.com".to_string()),
+        None,
+        Some("Shop".to_string()),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(TEMPLATE.to_string()),
+        0,
+    )
+    .unwrap()
+}
+
+fn email() -> Email {
+    Email::try_new(
+        1,
+        MESSAGE,
+        Utc::now().naive_utc(),
+        false,
+        Some("New collection".to_string()),
+        None,
+        None,
+        None,
+        0,
+        0,
+        0,
+        1,
+    )
+    .unwrap()
+}
+
+fn recipient() -> EmailRecipient {
+    EmailRecipient::try_new(
+        42,
+        1,
+        "alice@example.org",
+        false,
+        Utc::now().naive_utc(),
+        false,
+        None,
+        "Alice",
+        fields(),
+    )
+    .unwrap()
+}
+
+fn bench_fill_template(c: &mut Criterion) {
+    let vars = fields();
+    c.bench_function("fill_template", |b| {
+        b.iter(|| fill_template(black_box(TEMPLATE), black_box(&vars)))
+    });
+}
+
+fn bench_render_body(c: &mut Criterion) {
+    let (hub, email, recipient) = (hub(), email(), recipient());
+    c.bench_function("render_body", |b| {
+        b.iter(|| render_body(black_box(&hub), &email, &recipient, None))
+    });
+}
+
+fn bench_build_message(c: &mut Criterion) {
+    let (hub, email, recipient) = (hub(), email(), recipient());
+    let settings = HubSettings::default();
+    let options = MessageOptions::default();
+    c.bench_function("build_message", |b| {
+        b.iter(|| {
+            let message = build_message(
+                black_box(&hub),
+                &email,
+                &recipient,
+                "example.com",
+                &settings,
+                &options,
+            )
+            .unwrap();
+            let mut out = Vec::with_capacity(4096);
+            message.write_to(&mut out).unwrap();
+            out
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_fill_template,
+    bench_render_body,
+    bench_build_message
+);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+//! Normalization of email addresses before they are compared or stored.
+//!
+//! Unsubscribes, suppression lookups and the recipient deduplication of new
+//! emails all key addresses through an [`AddressNormalizer`], so one mailbox
+//! written two ways is treated the same everywhere. The workers use the
+//! normalizer described by the `suppression` configuration
+//! ([`SuppressionConfig`](crate::models::SuppressionConfig)).
+
+use crate::domain::normalize_address;
+
+/// Maps an address to the canonical form addresses are compared by.
+pub trait AddressNormalizer: Send + Sync {
+    /// Returns the canonical form of `address`; addresses with equal forms
+    /// are taken to reach the same mailbox.
+    fn normalize(&self, address: &str) -> String;
+}
+
+/// Trims surrounding whitespace and applies the enabled rules. The default
+/// only trims.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StandardNormalizer {
+    /// Treat `user+tag@domain` as `user@domain`.
+    pub strip_subaddress: bool,
+    /// Lower-case the whole address, local part included.
+    pub lowercase: bool,
+    /// Convert the domain to its lower-case ASCII (punycode) form; a domain
+    /// that is not a valid IDN is kept as is.
+    pub punycode_domains: bool,
+}
+
+impl AddressNormalizer for StandardNormalizer {
+    fn normalize(&self, address: &str) -> String {
+        let mut address = normalize_address(address, self.strip_subaddress);
+        if self.lowercase {
+            address = address.to_lowercase();
+        }
+        if !self.punycode_domains {
+            return address;
+        }
+        match address.rsplit_once('@') {
+            Some((local, domain)) => match idna::domain_to_ascii(domain) {
+                Ok(domain) => format!("{local}@{domain}"),
+                Err(_) => address,
+            },
+            None => address,
+        }
+    }
+}
+
+/// Applies Gmail's addressing rules after the inner normalizer: on
+/// `gmail.com` and `googlemail.com` the local part is case-insensitive, dots
+/// in it are ignored and a `+tag` is dropped, so `John.Doe+news@googlemail.com`
+/// becomes `johndoe@gmail.com`. Other addresses are left to the inner
+/// normalizer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GmailNormalizer<N>(pub N);
+
+impl<N: AddressNormalizer> AddressNormalizer for GmailNormalizer<N> {
+    fn normalize(&self, address: &str) -> String {
+        let address = self.0.normalize(address);
+        let Some((local, domain)) = address.rsplit_once('@') else {
+            return address;
+        };
+        if !domain.eq_ignore_ascii_case("gmail.com")
+            && !domain.eq_ignore_ascii_case("googlemail.com")
+        {
+            return address;
+        }
+        let local = local.split_once('+').map_or(local, |(base, _)| base);
+        let local: String = local
+            .chars()
+            .filter(|c| *c != '.')
+            .flat_map(char::to_lowercase)
+            .collect();
+        if local.is_empty() {
+            return address;
+        }
+        format!("{local}@gmail.com")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_default_only_trims() {
+        let normalizer = StandardNormalizer::default();
+        assert_eq!(
+            normalizer.normalize(" User+Promo@Example.com "),
+            "User+Promo@Example.com"
+        );
+    }
+
+    #[test]
+    fn standard_rules_combine() {
+        let normalizer = StandardNormalizer {
+            strip_subaddress: true,
+            lowercase: true,
+            punycode_domains: true,
+        };
+        assert_eq!(
+            normalizer.normalize("User+Promo@Пример.РФ"),
+            "user@xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(normalizer.normalize("no-domain"), "no-domain");
+    }
+
+    #[test]
+    fn gmail_ignores_dots_tags_and_case() {
+        let normalizer = GmailNormalizer(StandardNormalizer::default());
+        for address in [
+            "john.doe@gmail.com",
+            "John.Doe+news@gmail.com",
+            " j.o.h.n.d.o.e@GoogleMail.com",
+        ] {
+            assert_eq!(normalizer.normalize(address), "johndoe@gmail.com");
+        }
+    }
+
+    #[test]
+    fn gmail_leaves_other_domains_to_the_inner_normalizer() {
+        let normalizer = GmailNormalizer(StandardNormalizer {
+            lowercase: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            normalizer.normalize("John.Doe+news@Example.com"),
+            "john.doe+news@example.com"
+        );
+        assert_eq!(normalizer.normalize("+tag@gmail.com"), "+tag@gmail.com");
+    }
+
+    #[test]
+    fn custom_normalizers_plug_in() {
+        struct LocalPartOnly;
+
+        impl AddressNormalizer for LocalPartOnly {
+            fn normalize(&self, address: &str) -> String {
+                address.split('@').next().unwrap_or_default().to_string()
+            }
+        }
+
+        let normalizers: [&dyn AddressNormalizer; 2] =
+            [&LocalPartOnly, &GmailNormalizer(LocalPartOnly)];
+        for normalizer in normalizers {
+            assert_eq!(normalizer.normalize("user@example.com"), "user");
+        }
+    }
+}
@@ -3,15 +3,19 @@ use std::env;
 use config::Config;
 use dotenvy::dotenv;
 
-use pushkind_hedwig::{check_reply, models::ServerConfig};
+use pushkind_hedwig::{check_reply, logging, models::ServerConfig};
 
 /// Entry point for the reply-checking worker.
 #[tokio::main]
 async fn main() {
     // Load environment variables from `.env` in local development.
     dotenv().ok();
-    // Initialize logger with default level INFO if not provided.
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    // Initialize logger with default level INFO if not provided; the filter
+    // can be changed later with a `set_log_filter` control message.
+    if let Err(err) = logging::init("info") {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
 
     rustls::crypto::CryptoProvider::install_default(rustls::crypto::aws_lc_rs::default_provider())
         .expect("Could not install default crypto provider.");
@@ -44,13 +48,12 @@ async fn main() {
         }
     };
 
-    if let Err(e) = check_reply::run(
-        &server_config.database_url,
-        &server_config.domain,
-        &server_config.zmq_replier_pub,
-    )
-    .await
-    {
+    if let Err(err) = server_config.validate() {
+        log::error!("Invalid server config: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = check_reply::run(&server_config).await {
         log::error!("{e}");
         std::process::exit(1);
     }
@@ -44,13 +44,12 @@ async fn main() {
         }
     };
 
-    if let Err(e) = send_email::run(
-        &server_config.database_url,
-        &server_config.domain,
-        &server_config.zmq_emailer_sub,
-    )
-    .await
-    {
+    if let Err(err) = server_config.validate() {
+        log::error!("Invalid server config: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = send_email::run(&server_config).await {
         log::error!("{e}");
         std::process::exit(1);
     }
@@ -0,0 +1,263 @@
+//! Batched publishing of reply and unsubscribe notifications.
+//!
+//! While `monitor_hub` works through a backlog burst, notifications can be
+//! collected by a [`BatchingPublisher`] and published as one
+//! [`NotificationBatch`] instead of one ZeroMQ message each.
+
+use std::sync::{Mutex, PoisonError};
+
+use async_trait::async_trait;
+use pushkind_emailer::domain::types::HubId;
+use pushkind_emailer::models::zmq::{ZMQReplyMessage, ZMQUnsubscribeMessage};
+use serde::{Deserialize, Serialize};
+
+use crate::check_reply::service::{ReplyPublisher, publish_with_retry, spool_notification};
+use crate::check_reply::spool::Notification;
+use crate::errors::Error;
+use crate::models::ServerConfig;
+
+/// Several notifications of one hub published as a single message, e.g.
+/// `{"type": "notification_batch", "hub_id": 1, "notifications": [{"type":
+/// "reply", ...}, {"type": "unsubscribe", ...}]}`, in processing order.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "notification_batch")]
+pub struct NotificationBatch {
+    pub hub_id: i32,
+    pub notifications: Vec<Notification>,
+}
+
+/// Forwards notifications to `inner`, or collects them for [`flush`] when
+/// batching is enabled.
+///
+/// [`flush`]: BatchingPublisher::flush
+pub struct BatchingPublisher<'a, P: ReplyPublisher + ?Sized> {
+    inner: &'a P,
+    hub_id: HubId,
+    pending: Option<Mutex<Vec<Notification>>>,
+}
+
+impl<'a, P: ReplyPublisher + ?Sized> BatchingPublisher<'a, P> {
+    pub fn new(inner: &'a P, hub_id: HubId, batching: bool) -> Self {
+        Self {
+            inner,
+            hub_id,
+            pending: batching.then(|| Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns `true` when notifications are collected for [`flush`] rather
+    /// than published right away.
+    ///
+    /// [`flush`]: BatchingPublisher::flush
+    pub fn is_batching(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Publishes the collected notifications as one batch, retrying per
+    /// `publish_retry`. When every attempt fails, the notifications are
+    /// spooled one by one.
+    ///
+    /// Returns how many notifications the batch held.
+    pub async fn flush(self, config: &ServerConfig) -> usize {
+        let Some(pending) = self.pending else {
+            return 0;
+        };
+        let notifications = pending.into_inner().unwrap_or_else(PoisonError::into_inner);
+        if notifications.is_empty() {
+            return 0;
+        }
+
+        let count = notifications.len();
+        let batch = NotificationBatch {
+            hub_id: self.hub_id.get(),
+            notifications,
+        };
+        match publish_with_retry(&config.publish_retry, || self.inner.send_batch(&batch)).await {
+            Ok(()) => log::info!(
+                "ZMQ batch of {count} notification(s) sent in hub#{}",
+                self.hub_id
+            ),
+            Err(e) => {
+                log::error!(
+                    "Cannot send ZMQ batch of {count} notification(s) in hub#{} after {} attempt(s): {e}",
+                    self.hub_id,
+                    config.publish_retry.attempts.max(1)
+                );
+                for notification in batch.notifications {
+                    spool_notification(config.spool.as_ref(), notification).await;
+                }
+            }
+        }
+        count
+    }
+}
+
+#[async_trait]
+impl<P: ReplyPublisher + ?Sized> ReplyPublisher for BatchingPublisher<'_, P> {
+    async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error> {
+        let Some(pending) = &self.pending else {
+            return self.inner.send_reply(message).await;
+        };
+        pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Notification::Reply(ZMQReplyMessage {
+                hub_id: message.hub_id,
+                email: message.email.clone(),
+                message: message.message.clone(),
+                subject: message.subject.clone(),
+            }));
+        Ok(())
+    }
+
+    async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+        let Some(pending) = &self.pending else {
+            return self.inner.send_unsubscribe(message).await;
+        };
+        pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Notification::Unsubscribe(ZMQUnsubscribeMessage {
+                hub_id: message.hub_id,
+                email: message.email.clone(),
+                reason: message.reason.clone(),
+            }));
+        Ok(())
+    }
+
+    /// Confirmations are emails, not notifications, and are never batched.
+    async fn send_unsubscribe_confirmation(&self, hub_id: HubId, email: &str) -> Result<(), Error> {
+        self.inner
+            .send_unsubscribe_confirmation(hub_id, email)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        sent: Mutex<Vec<String>>,
+        batches: Mutex<Vec<serde_json::Value>>,
+    }
+
+    #[async_trait]
+    impl ReplyPublisher for Recorder {
+        async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error> {
+            self.sent
+                .lock()
+                .expect("lock poisoned")
+                .push(format!("reply:{}", message.email));
+            Ok(())
+        }
+
+        async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+            self.sent
+                .lock()
+                .expect("lock poisoned")
+                .push(format!("unsubscribe:{}", message.email));
+            Ok(())
+        }
+
+        async fn send_batch(&self, batch: &NotificationBatch) -> Result<(), Error> {
+            self.batches
+                .lock()
+                .expect("lock poisoned")
+                .push(serde_json::to_value(batch)?);
+            Ok(())
+        }
+    }
+
+    fn reply(email: &str) -> ZMQReplyMessage {
+        ZMQReplyMessage {
+            hub_id: 1,
+            email: email.into(),
+            message: "Thanks".into(),
+            subject: None,
+        }
+    }
+
+    fn unsubscribe(email: &str) -> ZMQUnsubscribeMessage {
+        ZMQUnsubscribeMessage {
+            hub_id: 1,
+            email: email.into(),
+            reason: Some("unsubscribe".into()),
+        }
+    }
+
+    fn hub_id() -> HubId {
+        HubId::try_from(1).unwrap()
+    }
+
+    #[tokio::test]
+    async fn batching_collects_notifications_into_one_message() {
+        let recorder = Recorder::default();
+        let publisher = BatchingPublisher::new(&recorder, hub_id(), true);
+        assert!(publisher.is_batching());
+        publisher.send_reply(&reply("a@example.org")).await.unwrap();
+        publisher
+            .send_unsubscribe(&unsubscribe("b@example.org"))
+            .await
+            .unwrap();
+        assert!(recorder.sent.lock().unwrap().is_empty());
+
+        assert_eq!(publisher.flush(&ServerConfig::default()).await, 2);
+        let batches = recorder.batches.into_inner().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0]["type"], "notification_batch");
+        assert_eq!(batches[0]["hub_id"], 1);
+        let notifications = batches[0]["notifications"].as_array().unwrap();
+        assert_eq!(notifications[0]["type"], "reply");
+        assert_eq!(notifications[0]["email"], "a@example.org");
+        assert_eq!(notifications[1]["type"], "unsubscribe");
+        assert_eq!(notifications[1]["reason"], "unsubscribe");
+    }
+
+    #[tokio::test]
+    async fn disabled_batching_publishes_each_notification() {
+        let recorder = Recorder::default();
+        let publisher = BatchingPublisher::new(&recorder, hub_id(), false);
+        publisher.send_reply(&reply("a@example.org")).await.unwrap();
+        publisher
+            .send_unsubscribe(&unsubscribe("b@example.org"))
+            .await
+            .unwrap();
+
+        assert!(!publisher.is_batching());
+        assert_eq!(publisher.flush(&ServerConfig::default()).await, 0);
+        assert_eq!(
+            recorder.sent.into_inner().unwrap(),
+            vec!["reply:a@example.org", "unsubscribe:b@example.org"]
+        );
+        assert!(recorder.batches.into_inner().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_batch_is_not_published() {
+        let recorder = Recorder::default();
+        let publisher = BatchingPublisher::new(&recorder, hub_id(), true);
+        assert_eq!(publisher.flush(&ServerConfig::default()).await, 0);
+        assert!(recorder.batches.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn batch_round_trips_through_json() {
+        let batch = NotificationBatch {
+            hub_id: 1,
+            notifications: vec![
+                Notification::Reply(reply("a@example.org")),
+                Notification::Unsubscribe(unsubscribe("b@example.org")),
+            ],
+        };
+        let json = serde_json::to_string(&batch).unwrap();
+        let decoded: NotificationBatch = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.hub_id, 1);
+        assert!(matches!(
+            decoded.notifications.as_slice(),
+            [Notification::Reply(r), Notification::Unsubscribe(u)]
+                if r.email == "a@example.org" && u.email == "b@example.org"
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Reply categorization for CRM routing.
+//!
+//! [`process_reply`](super::service::process_reply) passes every stored reply
+//! to a [`ReplyClassifier`] and saves the returned category next to the
+//! recipient. [`KeywordClassifier`] is the built-in implementation.
+
+use crate::models::KeywordRule;
+
+/// Assigns a category such as `interested` or `ooo` to a reply.
+pub trait ReplyClassifier: Send + Sync {
+    /// Returns the category of `reply`, or `None` when it fits none.
+    fn classify(&self, reply: &str) -> Option<String>;
+}
+
+fn rule(category: &str, keywords: &[&str]) -> KeywordRule {
+    KeywordRule {
+        category: category.to_string(),
+        keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+    }
+}
+
+/// Classifies replies by keyword; the first rule with a matching keyword wins.
+#[derive(Clone, Debug)]
+pub struct KeywordClassifier {
+    rules: Vec<KeywordRule>,
+}
+
+impl KeywordClassifier {
+    /// Builds a classifier from `rules`, falling back to the default rules
+    /// when none are given.
+    pub fn new(rules: Vec<KeywordRule>) -> Self {
+        if rules.is_empty() {
+            return Self::default();
+        }
+        let rules = rules
+            .into_iter()
+            .map(|rule| KeywordRule {
+                keywords: rule
+                    .keywords
+                    .iter()
+                    .map(|keyword| keyword.trim().to_lowercase())
+                    .filter(|keyword| !keyword.is_empty())
+                    .collect(),
+                ..rule
+            })
+            .collect();
+        Self { rules }
+    }
+}
+
+impl Default for KeywordClassifier {
+    /// Out-of-office, complaint, not-interested and interested rules in
+    /// English and Russian. Negative rules come first so "not interested"
+    /// is not read as "interested".
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                rule(
+                    "ooo",
+                    &[
+                        "out of office",
+                        "out of the office",
+                        "on vacation",
+                        "on leave",
+                        "automatic reply",
+                        "auto-reply",
+                        "в отпуске",
+                        "вне офиса",
+                        "автоответ",
+                    ],
+                ),
+                rule(
+                    "complaint",
+                    &[
+                        "spam",
+                        "stop emailing",
+                        "stop sending",
+                        "remove me",
+                        "complaint",
+                        "спам",
+                        "жалоба",
+                        "прекратите",
+                    ],
+                ),
+                rule(
+                    "not_interested",
+                    &[
+                        "not interested",
+                        "no thanks",
+                        "no, thanks",
+                        "не интересно",
+                        "не интересует",
+                        "нам не нужно",
+                    ],
+                ),
+                rule(
+                    "interested",
+                    &[
+                        "interested",
+                        "please send",
+                        "sounds good",
+                        "let's talk",
+                        "call me",
+                        "интересно",
+                        "интересует",
+                        "пришлите",
+                        "давайте",
+                    ],
+                ),
+            ],
+        }
+    }
+}
+
+impl ReplyClassifier for KeywordClassifier {
+    fn classify(&self, reply: &str) -> Option<String> {
+        let reply = reply.to_lowercase();
+        self.rules
+            .iter()
+            .find(|rule| rule.keywords.iter().any(|keyword| reply.contains(keyword)))
+            .map(|rule| rule.category.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(reply: &str) -> Option<String> {
+        KeywordClassifier::default().classify(reply)
+    }
+
+    #[test]
+    fn default_rules_categorize_sample_replies() {
+        assert_eq!(
+            classify("Yes, please send it over.").as_deref(),
+            Some("interested")
+        );
+        assert_eq!(
+            classify("Thanks, but we are NOT interested.").as_deref(),
+            Some("not_interested")
+        );
+        assert_eq!(
+            classify("Stop emailing me, this is spam.").as_deref(),
+            Some("complaint")
+        );
+        assert_eq!(
+            classify("I am out of office until Monday.").as_deref(),
+            Some("ooo")
+        );
+        assert_eq!(
+            classify("Добрый день! Пришлите, пожалуйста, прайс.").as_deref(),
+            Some("interested")
+        );
+        assert_eq!(classify("Who is this?"), None);
+    }
+
+    #[test]
+    fn custom_rules_replace_the_defaults() {
+        let classifier = KeywordClassifier::new(vec![rule("pricing", &[" Price "])]);
+        assert_eq!(
+            classifier.classify("What is the PRICE?").as_deref(),
+            Some("pricing")
+        );
+        assert_eq!(classifier.classify("Please send it."), None);
+
+        let classifier = KeywordClassifier::new(Vec::new());
+        assert_eq!(
+            classifier.classify("Please send it.").as_deref(),
+            Some("interested")
+        );
+    }
+}
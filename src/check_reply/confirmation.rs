@@ -0,0 +1,213 @@
+//! Automated confirmations of unsubscribe requests sent by reply.
+//!
+//! When `unsubscribe_confirmation.enabled` is set, `check_reply` answers an
+//! `unsubscribe` reply with a short email through the regular
+//! [`Mailer`]. Confirmations are limited per hub and per address, and
+//! messages marked `Auto-Submitted` are never confirmed, so a confirmation
+//! cannot start a mail loop.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use pushkind_emailer::domain::types::HubId;
+use tokio::time::Instant;
+
+use crate::errors::Error;
+use crate::models::UnsubscribeConfirmationConfig;
+use crate::repository::HubReader;
+use crate::send_email::message_builder::unsubscribe_confirmation;
+use crate::send_email::service::Mailer;
+
+/// Window the per-hub limit and the per-address deduplication apply to.
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Sends unsubscribe confirmations through `mailer`.
+pub struct UnsubscribeConfirmations<M, R> {
+    mailer: M,
+    repo: R,
+    config: UnsubscribeConfirmationConfig,
+    /// Confirmations sent within [`WINDOW`], oldest first.
+    sent: Mutex<VecDeque<(Instant, i32, String)>>,
+}
+
+impl<M: Mailer, R: HubReader + Send + Sync> UnsubscribeConfirmations<M, R> {
+    pub fn new(mailer: M, repo: R, config: UnsubscribeConfirmationConfig) -> Self {
+        Self {
+            mailer,
+            repo,
+            config,
+            sent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Emails `address` a confirmation of its unsubscribe from the hub.
+    ///
+    /// Returns `false` without sending when the address was already
+    /// confirmed within the last hour or the hub reached `max_per_hour`.
+    pub async fn confirm(&self, hub_id: HubId, address: &str) -> Result<bool, Error> {
+        if !self.admit(hub_id, address, Instant::now()) {
+            return Ok(false);
+        }
+        let hub = self
+            .repo
+            .get_hub_by_id(hub_id)?
+            .ok_or_else(|| Error::Config(format!("Hub#{hub_id} not found")))?;
+        let message =
+            unsubscribe_confirmation(&hub, address, &self.config.subject, &self.config.body)?;
+        self.mailer.send(&hub, message).await?;
+        Ok(true)
+    }
+
+    /// Records a confirmation to `address` at `now` unless the address or
+    /// the hub's hourly budget rules it out. A slot stays taken even if the
+    /// send then fails.
+    fn admit(&self, hub_id: HubId, address: &str, now: Instant) -> bool {
+        let mut sent = self.sent.lock().unwrap_or_else(PoisonError::into_inner);
+        while sent
+            .front()
+            .is_some_and(|(at, _, _)| now.duration_since(*at) >= WINDOW)
+        {
+            sent.pop_front();
+        }
+
+        let hub_id = hub_id.get();
+        let mut hub_count = 0;
+        for (_, hub, confirmed) in sent.iter() {
+            if *hub != hub_id {
+                continue;
+            }
+            if confirmed.eq_ignore_ascii_case(address) {
+                return false;
+            }
+            hub_count += 1;
+        }
+        if hub_count >= self.config.max_per_hour {
+            return false;
+        }
+        sent.push_back((now, hub_id, address.to_string()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use mail_send::mail_builder::MessageBuilder;
+    use pushkind_common::repository::errors::RepositoryResult;
+    use pushkind_emailer::domain::hub::Hub;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingMailer {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Mailer for CountingMailer {
+        async fn send(&self, _hub: &Hub, message: MessageBuilder<'_>) -> Result<(), Error> {
+            let mut raw = Vec::new();
+            message.write_to(&mut raw)?;
+            self.sent
+                .lock()
+                .expect("lock poisoned")
+                .push(String::from_utf8_lossy(&raw).into_owned());
+            Ok(())
+        }
+    }
+
+    struct OneHub;
+
+    impl HubReader for OneHub {
+        fn get_hub_by_id(&self, id: HubId) -> RepositoryResult<Option<Hub>> {
+            Ok(Some(
+                Hub::try_new(
+                    id.get(),
+                    Some("sender@example.com".to_string()),
+                    None,
+                    Some("Sender".to_string()),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    0,
+                )
+                .unwrap(),
+            ))
+        }
+
+        fn list_hubs(&self) -> RepositoryResult<Vec<Hub>> {
+            Ok(Vec::new())
+        }
+
+        fn get_uid_validity(&self, _hub_id: HubId) -> RepositoryResult<Option<u32>> {
+            Ok(None)
+        }
+
+        fn is_sending_paused(&self, _hub_id: HubId) -> RepositoryResult<bool> {
+            Ok(false)
+        }
+    }
+
+    fn confirmations(max_per_hour: usize) -> UnsubscribeConfirmations<CountingMailer, OneHub> {
+        UnsubscribeConfirmations::new(
+            CountingMailer::default(),
+            OneHub,
+            UnsubscribeConfirmationConfig {
+                enabled: true,
+                max_per_hour,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn hub(id: i32) -> HubId {
+        HubId::try_from(id).unwrap()
+    }
+
+    #[tokio::test]
+    async fn confirms_each_address_once() {
+        let confirmations = confirmations(10);
+        assert!(
+            confirmations
+                .confirm(hub(1), "user@example.org")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !confirmations
+                .confirm(hub(1), "USER@example.org")
+                .await
+                .unwrap()
+        );
+        assert!(
+            confirmations
+                .confirm(hub(2), "user@example.org")
+                .await
+                .unwrap()
+        );
+
+        let sent = confirmations.mailer.sent.into_inner().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].contains("Auto-Submitted: auto-replied"));
+        assert!(sent[0].contains("To: <user@example.org>"));
+    }
+
+    #[test]
+    fn hourly_budget_is_per_hub_and_expires() {
+        let confirmations = confirmations(2);
+        let start = Instant::now();
+        assert!(confirmations.admit(hub(1), "a@example.org", start));
+        assert!(confirmations.admit(hub(1), "b@example.org", start));
+        assert!(!confirmations.admit(hub(1), "c@example.org", start));
+        assert!(confirmations.admit(hub(2), "c@example.org", start));
+
+        let later = start + WINDOW;
+        assert!(confirmations.admit(hub(1), "c@example.org", later));
+        assert!(confirmations.admit(hub(1), "a@example.org", later));
+    }
+}
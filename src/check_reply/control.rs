@@ -0,0 +1,231 @@
+//! Control channel for the reply worker.
+//!
+//! Other services publish [`ControlMessage`]s over ZeroMQ to ask
+//! `check_reply` to act on a specific hub, e.g. reconnect after its IMAP
+//! credentials changed or reprocess a range of messages, or to change the
+//! worker's log filter.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::check_reply::service::UidRange;
+use crate::errors::Error;
+use crate::logging;
+
+/// Commands accepted on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Reload the hub configuration and reconnect its IMAP session.
+    ReloadHub { hub_id: i32 },
+    /// Run the messages with UIDs `from_uid..=to_uid` through reply
+    /// processing again, regardless of the hub's UID cursor.
+    ReprocessRange {
+        hub_id: i32,
+        from_uid: u32,
+        to_uid: u32,
+    },
+    /// Replace the worker's log filter (`RUST_LOG` syntax), e.g.
+    /// `info,pushkind_hedwig::check_reply::imap=debug`.
+    SetLogFilter { filter: String },
+}
+
+/// A validated request to reprocess a UID range of a hub's mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReprocessRequest {
+    pub hub_id: i32,
+    pub range: UidRange,
+}
+
+/// Decodes a JSON control message.
+pub fn parse_control_message(raw: &[u8]) -> Result<ControlMessage, serde_json::Error> {
+    serde_json::from_slice(raw)
+}
+
+/// Routes control messages to the hub monitor loops and the reprocessing
+/// queue.
+pub struct ControlSignals {
+    hubs: HashMap<i32, Arc<Notify>>,
+    reprocess: UnboundedSender<ReprocessRequest>,
+}
+
+impl ControlSignals {
+    /// Creates signals that queue reprocessing requests on `reprocess`.
+    pub fn new(reprocess: UnboundedSender<ReprocessRequest>) -> Self {
+        Self {
+            hubs: HashMap::new(),
+            reprocess,
+        }
+    }
+
+    /// Registers `hub_id` and returns the reload signal its monitor loop
+    /// waits on.
+    pub fn register(&mut self, hub_id: i32) -> Arc<Notify> {
+        Arc::clone(self.hubs.entry(hub_id).or_default())
+    }
+
+    /// Acts on `message` for the hub it targets.
+    ///
+    /// Fails when the hub is not monitored by this worker or the request is
+    /// invalid.
+    pub fn dispatch(&self, message: &ControlMessage) -> Result<(), Error> {
+        match *message {
+            ControlMessage::SetLogFilter { ref filter } => logging::set_filter(filter),
+            ControlMessage::ReloadHub { hub_id } => {
+                self.signal(hub_id)?.notify_waiters();
+                Ok(())
+            }
+            ControlMessage::ReprocessRange {
+                hub_id,
+                from_uid,
+                to_uid,
+            } => {
+                self.signal(hub_id)?;
+                let range = UidRange::new(from_uid, to_uid)?;
+                self.reprocess
+                    .send(ReprocessRequest { hub_id, range })
+                    .map_err(|_| Error::Config("Reprocessing queue is closed".to_string()))
+            }
+        }
+    }
+
+    fn signal(&self, hub_id: i32) -> Result<&Arc<Notify>, Error> {
+        self.hubs
+            .get(&hub_id)
+            .ok_or_else(|| Error::Config(format!("Hub#{hub_id} is not monitored")))
+    }
+}
+
+/// Consumes control messages from `zmq_address` until the socket fails.
+///
+/// Blocks the calling thread; run it via `tokio::task::spawn_blocking`.
+pub fn consume_control_messages(zmq_address: &str, signals: &ControlSignals) -> Result<(), Error> {
+    let context = zmq::Context::new();
+    let subscriber = context.socket(zmq::SUB)?;
+    subscriber.connect(zmq_address)?;
+    subscriber.set_subscribe(b"")?;
+
+    log::info!("Listening for control messages on {zmq_address}");
+
+    loop {
+        let raw = subscriber.recv_bytes(0)?;
+        match parse_control_message(&raw) {
+            Ok(message) => match signals.dispatch(&message) {
+                Ok(()) => log::info!("Dispatched control message {message:?}"),
+                Err(e) => log::warn!("Cannot dispatch control message {message:?}: {e}"),
+            },
+            Err(e) => log::error!("Invalid control message: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parses_reload_hub_message() {
+        let message = parse_control_message(br#"{"type":"reload_hub","hub_id":7}"#).unwrap();
+        assert_eq!(message, ControlMessage::ReloadHub { hub_id: 7 });
+    }
+
+    #[test]
+    fn parses_reprocess_range_message() {
+        let message = parse_control_message(
+            br#"{"type":"reprocess_range","hub_id":7,"from_uid":10,"to_uid":20}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            ControlMessage::ReprocessRange {
+                hub_id: 7,
+                from_uid: 10,
+                to_uid: 20
+            }
+        );
+    }
+
+    #[test]
+    fn parses_set_log_filter_message() {
+        let message = parse_control_message(
+            br#"{"type":"set_log_filter","filter":"info,pushkind_hedwig::check_reply::imap=debug"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            ControlMessage::SetLogFilter {
+                filter: "info,pushkind_hedwig::check_reply::imap=debug".into()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_control_message() {
+        assert!(parse_control_message(br#"{"type":"shutdown"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_wakes_only_the_targeted_hub() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut signals = ControlSignals::new(tx);
+        let first = signals.register(1);
+        let second = signals.register(2);
+
+        let first_waiter = tokio::spawn(async move { first.notified().await });
+        let second_wait = second.notified();
+        tokio::task::yield_now().await;
+
+        assert!(
+            signals
+                .dispatch(&ControlMessage::ReloadHub { hub_id: 1 })
+                .is_ok()
+        );
+        tokio::time::timeout(Duration::from_secs(1), first_waiter)
+            .await
+            .expect("hub#1 should be woken")
+            .unwrap();
+
+        tokio::pin!(second_wait);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), second_wait)
+                .await
+                .is_err()
+        );
+        assert!(
+            signals
+                .dispatch(&ControlMessage::ReloadHub { hub_id: 3 })
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reprocess_is_queued_for_monitored_hubs_only() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut signals = ControlSignals::new(tx);
+        signals.register(1);
+
+        let reprocess = |hub_id, from_uid, to_uid| ControlMessage::ReprocessRange {
+            hub_id,
+            from_uid,
+            to_uid,
+        };
+        assert!(signals.dispatch(&reprocess(1, 10, 20)).is_ok());
+        assert!(signals.dispatch(&reprocess(2, 10, 20)).is_err());
+        assert!(signals.dispatch(&reprocess(1, 20, 10)).is_err());
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ReprocessRequest {
+                hub_id: 1,
+                range: UidRange::new(10, 20).unwrap()
+            }
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
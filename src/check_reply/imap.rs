@@ -1,5 +1,9 @@
+use async_imap::imap_proto::types::{MessageSection, SectionPath};
 use async_imap::{Client, Session};
+use async_trait::async_trait;
 use futures::StreamExt;
+use mailparse::MailHeaderMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsConnector;
@@ -7,14 +11,23 @@ use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 
 use crate::errors::Error;
+use crate::models::{FetchItems, ImapFetchConfig};
+use crate::net::connect_tcp;
 
-/// Establish an IMAP session and select the INBOX.
+/// Establish an IMAP session and select the INBOX, connecting from
+/// `local_address` when set.
+///
+/// Returns the session together with the INBOX `UIDVALIDITY`, if the server
+/// reported one. An unreachable server fails with [`Error::Connection`], a
+/// failed TLS handshake with [`Error::TlsHandshake`] and rejected
+/// credentials with [`Error::Auth`].
 pub async fn init_session(
     imap_server: &str,
     imap_port: u16,
     username: &str,
     password: &str,
-) -> Result<Session<TlsStream<TcpStream>>, Error> {
+    local_address: Option<IpAddr>,
+) -> Result<(Session<TlsStream<TcpStream>>, Option<u32>), Error> {
     // Build a rustls connector with bundled webpki roots
     let root_store = RootCertStore {
         roots: webpki_roots::TLS_SERVER_ROOTS.into(),
@@ -28,11 +41,11 @@ pub async fn init_session(
     let tls_connector = TlsConnector::from(Arc::new(tls_config));
 
     // TCP connect
-    let tcp = TcpStream::connect((imap_server, imap_port))
+    let tcp = connect_tcp(imap_server, imap_port, local_address)
         .await
-        .map_err(|_| {
-            Error::Config(format!(
-                "Can't connect to the imap server: {imap_server}:{imap_port}"
+        .map_err(|e| {
+            Error::Connection(format!(
+                "Can't connect to the imap server {imap_server}:{imap_port}: {e}"
             ))
         })?;
     // SNI / server name for TLS
@@ -42,32 +55,66 @@ pub async fn init_session(
         .map_err(|_| Error::Config(format!("Invalid DNS name for SNI: {imap_server}")))?;
 
     // TLS handshake
-    let tls_stream = tls_connector
-        .connect(server_name, tcp)
-        .await
-        .map_err(|_| Error::Config("Can't connect to the imap server".to_string()))?;
+    let tls_stream = tls_connector.connect(server_name, tcp).await.map_err(|e| {
+        Error::TlsHandshake(format!(
+            "TLS handshake with the imap server {imap_server}:{imap_port} failed: {e}"
+        ))
+    })?;
 
     // Hand the TLS stream to async-imap
     let client = Client::new(tls_stream);
 
-    let mut session = client.login(username, password).await.map_err(|e| e.0)?;
+    let mut session = client
+        .login(username, password)
+        .await
+        .map_err(|(e, _)| login_error(e))?;
+
+    let mailbox = session.select("INBOX").await?;
+
+    Ok((session, mailbox.uid_validity))
+}
 
-    session.select("INBOX").await?;
+/// Maps a failed `LOGIN`: a `NO` or `BAD` answer means the credentials were
+/// rejected, anything else is an IMAP failure.
+fn login_error(err: async_imap::error::Error) -> Error {
+    match err {
+        async_imap::error::Error::No(msg) | async_imap::error::Error::Bad(msg) => {
+            Error::Auth(format!("IMAP login rejected: {msg}"))
+        }
+        other => Error::Imap(other),
+    }
+}
 
-    Ok(session)
+/// Returns the `UID FETCH` item list for `config`.
+///
+/// Sections are fetched with `BODY.PEEK` unless `peek` is off, so monitoring
+/// does not set the `\Seen` flag by default. The first part is requested
+/// together with its `MIME` header, which [`assemble_first_part`] needs for
+/// multipart messages.
+pub fn fetch_query(config: &ImapFetchConfig) -> String {
+    let item = if config.peek { "BODY.PEEK" } else { "BODY" };
+    match config.items {
+        FetchItems::Full => format!("{item}[]"),
+        FetchItems::HeaderAndFirstPart => {
+            format!("({item}[HEADER] {item}[1.MIME] {item}[1])")
+        }
+    }
 }
 
-/// Fetch the raw RFC822 message by UID.
+/// Fetch the message with `uid`, or the part of it selected by `config`.
+///
+/// A partial fetch is reassembled into a parseable message with
+/// [`assemble_first_part`].
 pub async fn fetch_message_rfc822(
     session: &mut Session<TlsStream<TcpStream>>,
     uid: u32,
+    config: &ImapFetchConfig,
 ) -> Option<Vec<u8>> {
-    // Fetch the whole message (headers + body) so downstream parsers can inspect
-    // MIME headers like `Content-Transfer-Encoding`.
-    let mut fetches = match session.uid_fetch(uid.to_string(), "BODY.PEEK[]").await {
+    let query = fetch_query(config);
+    let mut fetches = match session.uid_fetch(uid.to_string(), &query).await {
         Ok(f) => f,
         Err(e) => {
-            log::error!("Cannot fetch body for UID {uid}: {e}");
+            log::error!("Cannot fetch {query} for UID {uid}: {e}");
             return None;
         }
     };
@@ -75,14 +122,345 @@ pub async fn fetch_message_rfc822(
     let fetch = match fetches.next().await {
         Some(Ok(f)) => f,
         Some(Err(e)) => {
-            log::error!("Cannot fetch body for UID {uid}: {e}");
+            log::error!("Cannot fetch {query} for UID {uid}: {e}");
             return None;
         }
         None => return None,
     };
 
-    fetch
-        .body()
-        .or_else(|| fetch.text())
-        .map(|raw| raw.to_vec())
+    if let Some(raw) = fetch.body() {
+        return Some(raw.to_vec());
+    }
+    match (fetch.header(), fetch.section(&first_part(None))) {
+        (Some(header), Some(part)) => Some(assemble_first_part(
+            header,
+            fetch.section(&first_part(Some(MessageSection::Mime))),
+            part,
+        )),
+        (Some(header), None) => Some(assemble_first_part(header, None, b"")),
+        _ => fetch.text().map(|raw| raw.to_vec()),
+    }
+}
+
+fn first_part(section: Option<MessageSection>) -> SectionPath {
+    SectionPath::Part(vec![1], section)
+}
+
+/// Rebuilds a message from its `header`, the `MIME` header of its first
+/// part and the first `part` itself.
+///
+/// For a multipart message the top-level `Content-*` headers describe the
+/// whole multipart body, so they are replaced by the part's own MIME
+/// header. For a single-part message the first part is the body.
+pub fn assemble_first_part(header: &[u8], part_mime: Option<&[u8]>, part: &[u8]) -> Vec<u8> {
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    match (part_mime, mailparse::parse_headers(header)) {
+        (Some(mime), Ok((headers, _)))
+            if headers
+                .get_first_value("Content-Type")
+                .is_some_and(|value| {
+                    value
+                        .trim_start()
+                        .to_ascii_lowercase()
+                        .starts_with("multipart/")
+                }) =>
+        {
+            let mut kept = Vec::new();
+            for header in headers.iter().filter(|header| {
+                !header
+                    .get_key_ref()
+                    .to_ascii_lowercase()
+                    .starts_with("content-")
+            }) {
+                if !kept.is_empty() {
+                    kept.extend_from_slice(b"\r\n");
+                }
+                kept.extend_from_slice(header.get_key_ref().as_bytes());
+                kept.extend_from_slice(b": ");
+                kept.extend_from_slice(header.get_value_raw());
+            }
+            blocks.push(kept);
+            blocks.push(trim_line_ends(mime).to_vec());
+        }
+        _ => blocks.push(trim_line_ends(header).to_vec()),
+    }
+
+    let mut raw = blocks
+        .into_iter()
+        .filter(|block| !block.is_empty())
+        .collect::<Vec<_>>()
+        .join(&b"\r\n"[..]);
+    raw.extend_from_slice(b"\r\n\r\n");
+    raw.extend_from_slice(part);
+    raw
+}
+
+/// Strips the line breaks that end a header block.
+fn trim_line_ends(block: &[u8]) -> &[u8] {
+    let end = block
+        .iter()
+        .rposition(|byte| !matches!(byte, b'\r' | b'\n'))
+        .map_or(0, |pos| pos + 1);
+    &block[..end]
+}
+
+/// Largest message [`fetch_raw_message`] returns, in bytes.
+pub const MAX_RAW_MESSAGE_BYTES: u32 = 25 * 1024 * 1024;
+
+/// Read access to the messages of a selected mailbox.
+#[async_trait]
+pub trait MessageSource: Send {
+    /// Returns the `RFC822.SIZE` of the message with `uid`, or `None` when
+    /// no such message exists.
+    async fn message_size(&mut self, uid: u32) -> Result<Option<u32>, Error>;
+
+    /// Returns the raw RFC822 message with `uid`.
+    async fn message_rfc822(&mut self, uid: u32) -> Option<Vec<u8>>;
+}
+
+#[async_trait]
+impl MessageSource for Session<TlsStream<TcpStream>> {
+    async fn message_size(&mut self, uid: u32) -> Result<Option<u32>, Error> {
+        let mut fetches = self.uid_fetch(uid.to_string(), "RFC822.SIZE").await?;
+        match fetches.next().await {
+            Some(fetch) => Ok(fetch?.size),
+            None => Ok(None),
+        }
+    }
+
+    async fn message_rfc822(&mut self, uid: u32) -> Option<Vec<u8>> {
+        fetch_message_rfc822(self, uid, &ImapFetchConfig::default()).await
+    }
+}
+
+/// Fetch the full source of the message with `uid` for inspection.
+///
+/// The size is checked before downloading, so messages larger than
+/// `max_bytes` are rejected without transferring their content.
+pub async fn fetch_raw_message(
+    source: &mut (impl MessageSource + ?Sized),
+    uid: u32,
+    max_bytes: u32,
+) -> Result<Vec<u8>, Error> {
+    let not_found = || Error::NotFound(format!("Message UID {uid}"));
+    let too_large = |size: usize| {
+        Error::TooLarge(format!(
+            "Message UID {uid} is {size} bytes; limit is {max_bytes}"
+        ))
+    };
+
+    let size = source.message_size(uid).await?.ok_or_else(not_found)?;
+    if size > max_bytes {
+        return Err(too_large(size as usize));
+    }
+
+    let raw = source.message_rfc822(uid).await.ok_or_else(not_found)?;
+    if raw.len() > max_bytes as usize {
+        return Err(too_large(raw.len()));
+    }
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// Returns a local port nothing listens on.
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_is_a_connection_error() {
+        let port = closed_port().await;
+        let err = init_session("127.0.0.1", port, "user", "pass", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Connection(ref msg) if msg.contains(&port.to_string())));
+    }
+
+    #[tokio::test]
+    async fn plaintext_server_is_a_tls_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.write_all(b"* OK IMAP4rev1 ready\r\n").await;
+        });
+
+        let err = init_session("127.0.0.1", port, "user", "pass", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::TlsHandshake(_)));
+    }
+
+    #[tokio::test]
+    async fn session_connects_from_the_bound_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let (mut socket, peer) = listener.accept().await.unwrap();
+            let _ = socket.write_all(b"* OK IMAP4rev1 ready\r\n").await;
+            peer
+        });
+
+        let local = "127.0.0.1".parse().unwrap();
+        let err = init_session("127.0.0.1", port, "user", "pass", Some(local))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::TlsHandshake(_)));
+        assert_eq!(peer.await.unwrap().ip(), local);
+    }
+
+    #[tokio::test]
+    async fn unusable_bind_address_is_a_connection_error() {
+        let port = closed_port().await;
+        let local = "192.0.2.1".parse().unwrap();
+        let err = init_session("127.0.0.1", port, "user", "pass", Some(local))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Connection(_)));
+    }
+
+    #[test]
+    fn rejected_login_is_an_auth_error() {
+        let err = login_error(async_imap::error::Error::No(
+            "[AUTHENTICATIONFAILED] Invalid credentials".into(),
+        ));
+        assert!(matches!(err, Error::Auth(ref msg) if msg.contains("Invalid credentials")));
+
+        let err = login_error(async_imap::error::Error::ConnectionLost);
+        assert!(matches!(err, Error::Imap(_)));
+    }
+
+    /// Mailbox backed by a map of UID to raw message.
+    struct FakeMailbox {
+        messages: HashMap<u32, Vec<u8>>,
+        downloads: usize,
+    }
+
+    impl FakeMailbox {
+        fn with(uid: u32, raw: &[u8]) -> Self {
+            Self {
+                messages: HashMap::from([(uid, raw.to_vec())]),
+                downloads: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MessageSource for FakeMailbox {
+        async fn message_size(&mut self, uid: u32) -> Result<Option<u32>, Error> {
+            Ok(self.messages.get(&uid).map(|raw| raw.len() as u32))
+        }
+
+        async fn message_rfc822(&mut self, uid: u32) -> Option<Vec<u8>> {
+            self.downloads += 1;
+            self.messages.get(&uid).cloned()
+        }
+    }
+
+    const RAW: &[u8] = b"From: alice@example.com\r\nSubject: Re: hi\r\n\r\nThanks!\r\n";
+
+    #[tokio::test]
+    async fn returns_raw_message_bytes() {
+        let mut mailbox = FakeMailbox::with(42, RAW);
+        let raw = fetch_raw_message(&mut mailbox, 42, MAX_RAW_MESSAGE_BYTES)
+            .await
+            .unwrap();
+        assert_eq!(raw, RAW);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_message() {
+        let mut mailbox = FakeMailbox::with(42, RAW);
+        assert!(matches!(
+            fetch_raw_message(&mut mailbox, 7, MAX_RAW_MESSAGE_BYTES).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_message_without_downloading() {
+        let mut mailbox = FakeMailbox::with(42, RAW);
+        assert!(matches!(
+            fetch_raw_message(&mut mailbox, 42, 10).await,
+            Err(Error::TooLarge(_))
+        ));
+        assert_eq!(mailbox.downloads, 0);
+    }
+
+    fn fetch_config(items: FetchItems, peek: bool) -> ImapFetchConfig {
+        ImapFetchConfig { items, peek }
+    }
+
+    #[test]
+    fn fetch_query_peeks_at_the_selected_items() {
+        assert_eq!(fetch_query(&ImapFetchConfig::default()), "BODY.PEEK[]");
+        assert_eq!(
+            fetch_query(&fetch_config(FetchItems::HeaderAndFirstPart, true)),
+            "(BODY.PEEK[HEADER] BODY.PEEK[1.MIME] BODY.PEEK[1])"
+        );
+    }
+
+    #[test]
+    fn fetch_query_without_peek_marks_messages_seen() {
+        assert_eq!(
+            fetch_query(&fetch_config(FetchItems::Full, false)),
+            "BODY[]"
+        );
+        assert_eq!(
+            fetch_query(&fetch_config(FetchItems::HeaderAndFirstPart, false)),
+            "(BODY[HEADER] BODY[1.MIME] BODY[1])"
+        );
+    }
+
+    #[test]
+    fn fetch_items_are_configurable() {
+        let config: crate::models::ServerConfig =
+            serde_json::from_str(r#"{"imap_fetch": {"items": "header_and_first_part"}}"#).unwrap();
+        assert_eq!(config.imap_fetch.items, FetchItems::HeaderAndFirstPart);
+        assert!(config.imap_fetch.peek);
+        let config: crate::models::ServerConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.imap_fetch.items, FetchItems::Full);
+        assert!(config.imap_fetch.peek);
+    }
+
+    #[test]
+    fn single_part_message_keeps_its_header() {
+        let header = b"From: alice@example.com\r\nContent-Type: text/plain\r\n\r\n";
+        let raw = assemble_first_part(header, Some(b"\r\n"), b"Thanks!\r\n");
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        assert_eq!(
+            parsed.headers.get_first_value("From").unwrap(),
+            "alice@example.com"
+        );
+        assert_eq!(parsed.get_body().unwrap().trim(), "Thanks!");
+    }
+
+    #[test]
+    fn multipart_message_takes_the_first_part_content_headers() {
+        let header = b"From: alice@example.com\r\nSubject: Re: hi\r\n\
+            Content-Type: multipart/mixed;\r\n boundary=\"outer\"\r\n\r\n";
+        let mime = b"Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\r\n";
+        let raw = assemble_first_part(header, Some(mime), b"VGhhbmtzIQ==\r\n");
+
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        assert_eq!(parsed.headers.get_first_value("Subject").unwrap(), "Re: hi");
+        assert_eq!(parsed.ctype.mimetype, "text/plain");
+        assert!(parsed.subparts.is_empty());
+        assert_eq!(parsed.get_body().unwrap(), "Thanks!");
+    }
 }
@@ -0,0 +1,61 @@
+//! Language detection for stored replies.
+//!
+//! With `reply.detect_language` set, [`process_reply`](super::service::process_reply)
+//! saves the language of every stored reply next to the recipient, so replies
+//! can be routed to the right support team. [`detect_language`] tells the
+//! languages hubs correspond in, Russian and English, apart by script.
+
+/// Fewest letters a reply needs before its language is guessed.
+const MIN_LETTERS: usize = 3;
+
+/// Returns the ISO 639-1 code of `text`: `ru` when Cyrillic letters
+/// outnumber Latin ones and `en` when Latin letters do.
+///
+/// Returns `None` for text with fewer than three letters or as many
+/// Cyrillic as Latin letters.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let (mut cyrillic, mut latin) = (0usize, 0usize);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        if c.is_ascii_alphabetic() {
+            latin += 1;
+        } else if matches!(c, '\u{0400}'..='\u{04FF}') {
+            cyrillic += 1;
+        }
+    }
+    if cyrillic + latin < MIN_LETTERS {
+        return None;
+    }
+    match cyrillic.cmp(&latin) {
+        std::cmp::Ordering::Greater => Some("ru"),
+        std::cmp::Ordering::Less => Some("en"),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_russian_from_english() {
+        assert_eq!(
+            detect_language("Добрый день! Пришлите, пожалуйста, прайс."),
+            Some("ru")
+        );
+        assert_eq!(
+            detect_language("Yes, please send the price list."),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language("Спасибо, посмотрю PDF на сайте example.com"),
+            Some("ru")
+        );
+    }
+
+    #[test]
+    fn short_or_balanced_text_is_undetected() {
+        assert_eq!(detect_language("ok"), None);
+        assert_eq!(detect_language("+1 !!! 42"), None);
+        assert_eq!(detect_language("abc где"), None);
+    }
+}
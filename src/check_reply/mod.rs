@@ -1,37 +1,357 @@
+pub mod batch;
+pub mod classifier;
+pub mod confirmation;
+pub mod control;
 pub mod imap;
+pub mod language;
+pub mod pacing;
 pub mod parser;
 pub mod service;
+pub mod spool;
 
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use pushkind_common::db::establish_connection_pool;
-use pushkind_common::zmq::{ZmqSender, ZmqSenderOptions};
-use tokio::task::JoinSet;
+use async_trait::async_trait;
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use pushkind_common::zmq::{ZmqSender, ZmqSenderExt, ZmqSenderOptions};
+use pushkind_emailer::domain::hub::Hub;
+use pushkind_emailer::domain::types::HubId;
+use pushkind_emailer::models::zmq::{ZMQReplyMessage, ZMQUnsubscribeMessage};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
 
-use crate::check_reply::service::monitor_hub;
+use crate::check_reply::batch::NotificationBatch;
+use crate::check_reply::classifier::KeywordClassifier;
+use crate::check_reply::confirmation::UnsubscribeConfirmations;
+use crate::check_reply::control::{ControlSignals, consume_control_messages};
+use crate::check_reply::service::{ReplyPublisher, monitor_hub, reprocess_range};
+use crate::check_reply::spool::Spool;
+use crate::db::{ensure_schema, establish_pool};
 use crate::errors::Error;
+use crate::models::{HubRefreshConfig, ServerConfig, ZmqTopicsConfig};
 use crate::repository::{DieselRepository, HubReader};
+use crate::send_email::SmtpMailer;
+
+/// Publishes notifications on the replier socket under their configured
+/// topic prefixes, and emails unsubscribe confirmations when they are
+/// enabled.
+struct ZmqPublisher {
+    sender: ZmqSender,
+    topics: ZmqTopicsConfig,
+    confirmations: Option<Arc<UnsubscribeConfirmations<SmtpMailer, DieselRepository>>>,
+}
+
+impl ZmqPublisher {
+    async fn publish(&self, topic: &str, message: &impl Serialize) -> Result<(), Error> {
+        if topic.is_empty() {
+            return Ok(self.sender.send_json(message).await?);
+        }
+        Ok(self.sender.send_bytes(topic_frame(topic, message)?).await?)
+    }
+}
+
+#[async_trait]
+impl ReplyPublisher for ZmqPublisher {
+    async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error> {
+        self.publish(&self.topics.reply, message).await
+    }
+
+    async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+        self.publish(&self.topics.unsubscribe, message).await
+    }
+
+    async fn send_batch(&self, batch: &NotificationBatch) -> Result<(), Error> {
+        self.publish(&self.topics.batch, batch).await
+    }
+
+    /// Sends the confirmation from a separate task, so a slow SMTP server
+    /// does not hold up the hub's mailbox loop; failures are logged there.
+    async fn send_unsubscribe_confirmation(&self, hub_id: HubId, email: &str) -> Result<(), Error> {
+        let Some(confirmations) = &self.confirmations else {
+            return Ok(());
+        };
+        let confirmations = Arc::clone(confirmations);
+        let email = email.to_string();
+        tokio::spawn(async move {
+            match confirmations.confirm(hub_id, &email).await {
+                Ok(true) => log::info!("Unsubscribe confirmation sent to {email} in hub#{hub_id}"),
+                Ok(false) => log::info!(
+                    "Rate limit skipped unsubscribe confirmation to {email} in hub#{hub_id}"
+                ),
+                Err(e) => log::error!(
+                    "Cannot send unsubscribe confirmation to {email} in hub#{hub_id}: {e}"
+                ),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Encodes `message` as JSON prefixed with `topic`, the single-frame form
+/// ZeroMQ subscribers match their subscriptions against.
+fn topic_frame(topic: &str, message: &impl Serialize) -> Result<Vec<u8>, Error> {
+    let mut frame = topic.as_bytes().to_vec();
+    serde_json::to_writer(&mut frame, message)?;
+    Ok(frame)
+}
+
+/// Checks IMAP settings, e.g. before a hub is saved: connects (from
+/// `local_address` when set), logs in, selects the INBOX and logs out.
+///
+/// Fails with [`Error::Connection`] when the server is unreachable,
+/// [`Error::TlsHandshake`] when TLS cannot be established and
+/// [`Error::Auth`] when the credentials are rejected.
+pub async fn test_imap_connection(
+    server: &str,
+    port: u16,
+    user: &str,
+    pass: &str,
+    local_address: Option<IpAddr>,
+) -> Result<(), Error> {
+    let (mut session, _) = imap::init_session(server, port, user, pass, local_address).await?;
+    session.logout().await?;
+    Ok(())
+}
+
+/// Hub fields the IMAP monitor connects with. A periodic refresh restarts the
+/// monitor only when these change; the UID cursor moves on its own.
+#[derive(Debug, PartialEq)]
+struct ImapSettings {
+    server: Option<String>,
+    port: Option<i64>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+impl ImapSettings {
+    fn of(hub: &Hub) -> Self {
+        Self {
+            server: hub
+                .imap_server
+                .as_ref()
+                .map(|server| server.as_str().to_string()),
+            port: hub.imap_port.map(|port| i64::from(port.get())),
+            login: hub.login.as_ref().map(|login| login.as_str().to_string()),
+            password: hub
+                .password
+                .as_ref()
+                .map(|password| password.as_str().to_string()),
+        }
+    }
+}
+
+/// Refetches the hub and reports whether its IMAP settings differ from
+/// `current`. A missing hub counts as changed; a failed fetch does not.
+fn imap_settings_changed(repo: &DieselRepository, hub_id: HubId, current: &ImapSettings) -> bool {
+    match repo.get_hub_by_id(hub_id) {
+        Ok(Some(hub)) => ImapSettings::of(&hub) != *current,
+        Ok(None) => true,
+        Err(e) => {
+            log::error!("Failed to refresh hub#{} config: {}", hub_id, e);
+            false
+        }
+    }
+}
+
+/// Picks the next refresh deadline with a fresh random jitter.
+fn next_refresh(refresh: &HubRefreshConfig) -> Option<Instant> {
+    let mut bytes = [0_u8; 8];
+    let sample = match SystemRandom::new().fill(&mut bytes) {
+        Ok(()) => u64::from_le_bytes(bytes) as f64 / u64::MAX as f64,
+        Err(_) => 0.5,
+    };
+    refresh.delay(sample).map(|delay| Instant::now() + delay)
+}
+
+/// Sleeps until `deadline`, or forever when refreshing is disabled.
+async fn wait_for_refresh(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                return;
+            }
+            Err(e) => log::error!("Cannot listen for SIGTERM: {e}"),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Cannot listen for Ctrl-C: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+fn log_monitor_exit(result: Result<(), JoinError>) {
+    match result {
+        Ok(()) => {
+            log::info!("A monitor task exited cleanly");
+        }
+        Err(e) if e.is_cancelled() => {
+            log::warn!("A monitor task was aborted");
+        }
+        Err(e) => {
+            log::error!("A monitor task join error: {e:?}");
+        }
+    }
+}
+
+/// Waits up to `timeout` for the monitor tasks to finish, then aborts the
+/// ones still running.
+///
+/// Returns the number of aborted tasks.
+async fn drain_monitors(join_set: &mut JoinSet<()>, timeout: Duration) -> usize {
+    let drained = tokio::time::timeout(timeout, async {
+        while let Some(result) = join_set.join_next().await {
+            log_monitor_exit(result);
+        }
+    })
+    .await;
+    if drained.is_ok() {
+        return 0;
+    }
+
+    let lingering = join_set.len();
+    log::warn!("Aborting {lingering} monitor task(s) still running after {timeout:?}");
+    join_set.shutdown().await;
+    lingering
+}
 
 /// Run the reply monitoring worker.
-pub async fn run(database_url: &str, domain: &str, zmq_address: &str) -> Result<(), Error> {
-    let db_pool = establish_connection_pool(database_url)?;
-    let repo = DieselRepository::new(db_pool);
+///
+/// Returns once every monitor task has exited, or after SIGTERM/Ctrl-C
+/// once the tasks have stopped or `shutdown.drain_timeout_secs` elapsed.
+pub async fn run(config: &ServerConfig) -> Result<(), Error> {
+    let db_pool = establish_pool(&config.database_url, &config.db_pool)?;
+    ensure_schema(&db_pool)?;
+    let repo = DieselRepository::new(db_pool).with_normalizer(config.suppression.clone());
 
-    let zmq_sender = ZmqSender::start(ZmqSenderOptions::pub_default(zmq_address))?;
+    let config = Arc::new(config.clone());
+    let zmq_sender = ZmqPublisher {
+        sender: ZmqSender::start(ZmqSenderOptions::pub_default(&config.zmq_replier_pub))?,
+        topics: config.zmq_topics.clone(),
+        confirmations: config.unsubscribe_confirmation.enabled.then(|| {
+            Arc::new(UnsubscribeConfirmations::new(
+                SmtpMailer::new(Arc::clone(&config)),
+                repo.clone(),
+                config.unsubscribe_confirmation.clone(),
+            ))
+        }),
+    };
     let zmq_sender = Arc::new(zmq_sender);
 
-    let domain = Arc::new(domain.to_owned());
+    let classifier = Arc::new(KeywordClassifier::new(config.reply.categories.clone()));
     let hubs = repo.list_hubs()?;
     let mut join_set = JoinSet::new();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let (reprocess_tx, mut reprocess_rx) = mpsc::unbounded_channel();
+    let mut signals = ControlSignals::new(reprocess_tx);
+    let reloads: Vec<_> = hubs
+        .iter()
+        .map(|hub| signals.register(hub.id.get()))
+        .collect();
+
+    if let Some(control_address) = config.zmq_control_sub.clone() {
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = consume_control_messages(&control_address, &signals) {
+                log::error!("Control channel stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(spool_config) = config.spool.clone() {
+        let zmq_sender = zmq_sender.clone();
+        tokio::spawn(async move {
+            let spool = Spool::new(&spool_config.dir);
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(spool_config.flush_interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                match spool.flush(zmq_sender.as_ref()).await {
+                    Ok(0) => {}
+                    Ok(count) => log::info!("Flushed {count} spooled notification(s)"),
+                    Err(e) => log::error!("Cannot flush notification spool: {e}"),
+                }
+            }
+        });
+    }
+
+    {
+        let repo = repo.clone();
+        let config = Arc::clone(&config);
+        let zmq_sender = zmq_sender.clone();
+        let classifier = Arc::clone(&classifier);
+        tokio::spawn(async move {
+            while let Some(request) = reprocess_rx.recv().await {
+                let hub = match HubId::try_from(request.hub_id)
+                    .map_err(|e| Error::Config(e.to_string()))
+                    .and_then(|hub_id| Ok(repo.get_hub_by_id(hub_id)?))
+                {
+                    Ok(Some(hub)) => hub,
+                    Ok(None) => {
+                        log::warn!("Hub#{} not found for reprocessing", request.hub_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to fetch hub#{} config: {}", request.hub_id, e);
+                        continue;
+                    }
+                };
+
+                match reprocess_range(
+                    &repo,
+                    &hub,
+                    request.range,
+                    &config,
+                    zmq_sender.as_ref(),
+                    classifier.as_ref(),
+                )
+                .await
+                {
+                    Ok(count) => log::info!(
+                        "Reprocessed {} message(s) in UID range {} for hub#{}",
+                        count,
+                        request.range,
+                        hub.id
+                    ),
+                    Err(e) => log::error!(
+                        "Reprocessing UID range {} failed for hub#{}: {}",
+                        request.range,
+                        hub.id,
+                        e
+                    ),
+                }
+            }
+        });
+    }
 
     log::info!("Starting email checking worker");
 
-    for hub in hubs {
+    for (hub, reload) in hubs.into_iter().zip(reloads) {
         let repo = repo.clone();
-        let domain = Arc::clone(&domain);
+        let config = Arc::clone(&config);
         let zmq_sender = zmq_sender.clone();
+        let classifier = Arc::clone(&classifier);
         let hub_id = hub.id;
+        let mut shutdown = shutdown_rx.clone();
         join_set.spawn(async move {
             log::info!("Starting monitor loop for hub#{}", hub_id);
             loop {
@@ -51,15 +371,57 @@ pub async fn run(database_url: &str, domain: &str, zmq_address: &str) -> Result<
                     continue;
                 };
 
+                let imap = ImapSettings::of(&hub);
+
                 // Run hub monitor in a child task to catch panics via JoinError
                 let repo_for_task = repo.clone();
-                let domain_for_task = domain.to_string();
+                let config_for_task = Arc::clone(&config);
                 let zmq_for_task = zmq_sender.clone();
-                let handle = tokio::spawn(async move {
-                    monitor_hub(repo_for_task, hub, domain_for_task, &zmq_for_task).await
+                let classifier_for_task = Arc::clone(&classifier);
+                let mut handle = tokio::spawn(async move {
+                    monitor_hub(
+                        repo_for_task,
+                        hub,
+                        config_for_task,
+                        zmq_for_task.as_ref(),
+                        classifier_for_task.as_ref(),
+                    )
+                    .await
                 });
 
-                match handle.await {
+                let mut refresh_at = next_refresh(&config.hub_refresh);
+                let mut stopping = false;
+                let outcome = loop {
+                    tokio::select! {
+                        outcome = &mut handle => break Some(outcome),
+                        _ = reload.notified() => {
+                            log::info!("Reloading hub#{} config on request", hub_id);
+                            break None;
+                        }
+                        _ = shutdown.changed() => {
+                            log::info!("Stopping monitor loop for hub#{}", hub_id);
+                            stopping = true;
+                            break None;
+                        }
+                        _ = wait_for_refresh(refresh_at) => {
+                            refresh_at = next_refresh(&config.hub_refresh);
+                            if imap_settings_changed(&repo, hub_id, &imap) {
+                                log::info!("Hub#{} IMAP settings changed; reconnecting", hub_id);
+                                break None;
+                            }
+                        }
+                    }
+                };
+                let Some(outcome) = outcome else {
+                    handle.abort();
+                    let _ = handle.await;
+                    if stopping {
+                        break;
+                    }
+                    continue;
+                };
+
+                match outcome {
                     Ok(Ok(())) => {
                         log::info!("monitor_hub completed for hub#{}", hub_id);
                         break;
@@ -86,16 +448,112 @@ pub async fn run(database_url: &str, domain: &str, zmq_address: &str) -> Result<
     }
 
     // Drain join handles; monitor tasks self-restart on failure
-    while let Some(result) = join_set.join_next().await {
-        match result {
-            Ok(()) => {
-                log::info!("A monitor task exited cleanly");
-            }
-            Err(e) => {
-                log::error!("A monitor task join error: {e:?}");
-            }
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        tokio::select! {
+            result = join_set.join_next() => match result {
+                Some(result) => log_monitor_exit(result),
+                None => return Ok(()),
+            },
+            _ = &mut signal => break,
         }
     }
 
+    log::info!("Shutdown requested; stopping monitor tasks");
+    let _ = shutdown_tx.send(true);
+    let timeout = Duration::from_secs(config.shutdown.drain_timeout_secs);
+    drain_monitors(&mut join_set, timeout).await;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply() -> ZMQReplyMessage {
+        ZMQReplyMessage {
+            hub_id: 1,
+            email: "user@example.org".into(),
+            message: "Thanks".into(),
+            subject: None,
+        }
+    }
+
+    #[test]
+    fn topic_frame_prefixes_the_json_payload() {
+        let frame = topic_frame("hedwig.reply ", &reply()).unwrap();
+        let payload = frame.strip_prefix(b"hedwig.reply ").unwrap();
+        assert_eq!(payload, serde_json::to_vec(&reply()).unwrap().as_slice());
+    }
+
+    fn hub(server: &str, last_uid: i32) -> Hub {
+        Hub::try_new(
+            1,
+            Some("inbox@example.com".to_string()),
+            Some("secret".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(server.to_string()),
+            Some(993),
+            None,
+            last_uid,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn imap_settings_ignore_the_uid_cursor() {
+        let current = ImapSettings::of(&hub("imap.example.com", 10));
+        assert_eq!(ImapSettings::of(&hub("imap.example.com", 42)), current);
+        assert_ne!(ImapSettings::of(&hub("imap2.example.com", 10)), current);
+    }
+
+    #[tokio::test]
+    async fn refresh_waits_for_the_jittered_interval() {
+        let refresh = HubRefreshConfig {
+            interval_secs: 60,
+            jitter_secs: 10,
+        };
+        let started = Instant::now();
+        let delay = next_refresh(&refresh).unwrap() - started;
+        assert!(delay >= Duration::from_secs(50) && delay <= Duration::from_secs(71));
+        assert_eq!(next_refresh(&HubRefreshConfig::default()), None);
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        wait_for_refresh(Some(deadline)).await;
+        assert!(Instant::now() >= deadline);
+
+        let disabled = tokio::time::timeout(Duration::from_millis(50), wait_for_refresh(None));
+        assert!(disabled.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_short_lived_tasks() {
+        let mut join_set = JoinSet::new();
+        for delay in [10, 20, 30] {
+            join_set.spawn(tokio::time::sleep(Duration::from_millis(delay)));
+        }
+
+        let started = tokio::time::Instant::now();
+        let aborted = drain_monitors(&mut join_set, Duration::from_secs(5)).await;
+        assert_eq!(aborted, 0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(join_set.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_aborts_tasks_still_running_at_timeout() {
+        let mut join_set = JoinSet::new();
+        join_set.spawn(tokio::time::sleep(Duration::from_millis(10)));
+        join_set.spawn(std::future::pending::<()>());
+
+        let aborted = drain_monitors(&mut join_set, Duration::from_millis(100)).await;
+        assert_eq!(aborted, 1);
+        assert!(join_set.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+//! Pacing of IMAP commands for providers that throttle busy clients.
+//!
+//! With `hubs.<id>.imap_ops_per_minute` set, `monitor_hub` spaces its INBOX
+//! searches and message fetches evenly through an [`ImapPacer`], so working
+//! through a large backlog does not get the account throttled or banned.
+
+use tokio::time::{Duration, Instant, sleep};
+
+/// Spaces IMAP operations at least `60 / ops_per_minute` seconds apart.
+#[derive(Debug)]
+pub struct ImapPacer {
+    interval: Option<Duration>,
+    /// Earliest start of the next operation.
+    next: Option<Instant>,
+}
+
+impl ImapPacer {
+    /// Builds a pacer allowing `ops_per_minute` operations; unset or `0`
+    /// leaves operations unpaced.
+    pub fn new(ops_per_minute: Option<u32>) -> Self {
+        let interval = ops_per_minute
+            .filter(|ops| *ops > 0)
+            .map(|ops| Duration::from_secs(60) / ops);
+        Self {
+            interval,
+            next: None,
+        }
+    }
+
+    /// Waits until the next operation may start.
+    pub async fn pace(&mut self) {
+        let delay = self.reserve(Instant::now());
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+    }
+
+    /// Reserves the next slot for an operation requested at `now` and
+    /// returns how long to wait for it.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        let Some(interval) = self.interval else {
+            return Duration::ZERO;
+        };
+        let start = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(start + interval);
+        start - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_are_spaced_by_the_rate() {
+        let mut pacer = ImapPacer::new(Some(30));
+        let start = Instant::now();
+        let delays: Vec<_> = (0..4).map(|_| pacer.reserve(start)).collect();
+        assert_eq!(
+            delays,
+            [0, 2, 4, 6].map(Duration::from_secs).to_vec(),
+            "a burst is spread at two-second intervals"
+        );
+    }
+
+    #[test]
+    fn idle_time_is_not_saved_up() {
+        let mut pacer = ImapPacer::new(Some(60));
+        let start = Instant::now();
+        assert_eq!(pacer.reserve(start), Duration::ZERO);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(pacer.reserve(later), Duration::ZERO);
+        assert_eq!(pacer.reserve(later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unset_or_zero_rate_is_unpaced() {
+        let start = Instant::now();
+        for ops_per_minute in [None, Some(0)] {
+            let mut pacer = ImapPacer::new(ops_per_minute);
+            for _ in 0..3 {
+                assert_eq!(pacer.reserve(start), Duration::ZERO);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pace_sleeps_until_the_next_slot() {
+        let mut pacer = ImapPacer::new(Some(6000));
+        let start = Instant::now();
+        for _ in 0..5 {
+            pacer.pace().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
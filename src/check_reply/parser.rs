@@ -1,70 +1,275 @@
+use std::cell::OnceCell;
+
 use html2text;
-use mailparse::{self, MailAddr, MailAddrList, MailHeaderMap, ParsedMail};
-use once_cell::sync::Lazy;
+use mailparse::{self, MailAddr, MailAddrList, MailHeaderMap, ParsedMail, SingleInfo};
+
+use crate::domain::{CorrelationOrder, recipient_id_from_envelope, recipient_id_from_message_id};
 use regex::Regex;
 
+use crate::regexes::{AUTH_FAILURE, EMAIL, ORIGINAL_MESSAGE, UNICODE_EMAIL};
+
 /// Parsed data extracted from an email message relevant for reply handling.
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct ParsedEmail {
     pub subject: Option<String>,
+    /// The message's own `Message-ID`, without angle brackets.
+    pub message_id: Option<String>,
     pub sender_email: Option<String>,
     pub recipient_id: Option<i32>,
     pub reply: Option<String>,
     pub bounce_recipient: Option<String>,
+    /// The delivery report blames SPF, DKIM or DMARC rather than the mailbox.
+    pub bounce_auth_failure: bool,
+    /// Identifier from the `List-Id` header, without angle brackets.
+    pub list_id: Option<String>,
+    /// An `Auto-Submitted` header other than `no` marks the message as
+    /// automated (RFC 3834), e.g. an auto-reply to a confirmation.
+    pub auto_submitted: bool,
+    /// RFC 3834 automatic response such as a vacation notice:
+    /// `Auto-Submitted: auto-replied`, or an `auto-generated` message whose
+    /// subject carries the `Auto:` prefix of section 3.1.5. Implies
+    /// `auto_submitted`; such messages are not counted as replies.
+    pub auto_reply: bool,
+    /// `Feedback-ID` of the original message returned in a bounce or
+    /// complaint report, attributing it to a campaign.
+    pub feedback_id: Option<String>,
+    /// ARF complaint report (RFC 5965) found in a `message/feedback-report`
+    /// part.
+    pub complaint: Option<Complaint>,
+}
+
+/// A mailbox provider's feedback report about a message the hub sent.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Complaint {
+    /// Lower-cased `Feedback-Type`, e.g. `abuse`.
+    pub feedback_type: String,
+    /// Complained-about address from `Original-Rcpt-To`, else from the `To`
+    /// header of the returned message.
+    pub recipient: Option<String>,
+    /// Recipient ID from the returned message's Message-ID, for providers
+    /// that redact the address.
+    pub recipient_id: Option<i32>,
+}
+
+impl Complaint {
+    /// Returns `true` for reports of unwanted mail. `not-spam` reports and
+    /// `auth-failure` (DMARC/DKIM) reports do not describe the recipient's
+    /// wishes.
+    pub fn is_unwanted(&self) -> bool {
+        !matches!(self.feedback_type.as_str(), "not-spam" | "auth-failure")
+    }
 }
 
 /// Parse an RFC822 email message using `mailparse` and expose the relevant fields.
-pub fn parse_email(raw: &[u8], domain: &str) -> Result<ParsedEmail, mailparse::MailParseError> {
+///
+/// The recipient ID is taken from `In-Reply-To` or from a plus-addressed
+/// `Delivered-To`/`To` mailbox on `domain`, trying them in `order`.
+///
+/// Bounced addresses are matched with ASCII domains only unless
+/// `unicode_domains` is set, which also accepts internationalized domains.
+///
+/// Header fields are read first and body parts are decoded only when an
+/// extractor asks for them, so attachments are never decoded apart from
+/// `message/delivery-status` reports.
+pub fn parse_email(
+    raw: &[u8],
+    domain: &str,
+    order: CorrelationOrder,
+    unicode_domains: bool,
+) -> Result<ParsedEmail, mailparse::MailParseError> {
     let parsed = mailparse::parse_mail(raw)?;
     let subject = parsed.headers.get_first_value("Subject");
+    let message_id = extract_message_id(&parsed);
     let sender_email = extract_sender_email(&parsed);
-    let recipient_id = extract_recipient_id(&parsed, domain);
-    let bounce_recipient = find_bounce_recipient(&parsed);
-    let reply = find_reply(&parsed);
+    let recipient_id = match order {
+        CorrelationOrder::InReplyToFirst => extract_recipient_id(&parsed, domain)
+            .or_else(|| extract_envelope_recipient_id(&parsed, domain)),
+        CorrelationOrder::EnvelopeFirst => extract_envelope_recipient_id(&parsed, domain)
+            .or_else(|| extract_recipient_id(&parsed, domain)),
+    };
+    let list_id = extract_list_id(&parsed);
+    let auto_submitted = is_auto_submitted(&parsed);
+    let auto_reply = is_auto_reply(&parsed, subject.as_deref());
+    let feedback_id = returned_header(&parsed, "Feedback-ID");
+    let parts = text_parts(&parsed);
+    let email_re = if unicode_domains {
+        &*UNICODE_EMAIL
+    } else {
+        &*EMAIL
+    };
+    let bounce_recipient = find_bounce_recipient(&parts, email_re);
+    let bounce_auth_failure = has_auth_failure(&parts);
+    let reply = find_reply(&parts);
+    let complaint = extract_complaint(&parsed, domain, email_re);
 
     Ok(ParsedEmail {
         subject,
+        message_id,
         sender_email,
         recipient_id,
         reply,
         bounce_recipient,
+        bounce_auth_failure,
+        list_id,
+        auto_submitted,
+        auto_reply,
+        feedback_id,
+        complaint,
     })
 }
 
+/// Returns the keyword of the `Auto-Submitted` header, lower-cased and
+/// without parameters.
+fn auto_submitted_keyword(parsed: &ParsedMail) -> Option<String> {
+    let value = parsed.headers.get_first_value("Auto-Submitted")?;
+    let keyword = value.split(';').next().unwrap_or_default().trim();
+    (!keyword.is_empty()).then(|| keyword.to_ascii_lowercase())
+}
+
+/// Returns `true` when the `Auto-Submitted` header is present with a value
+/// other than `no`.
+fn is_auto_submitted(parsed: &ParsedMail) -> bool {
+    auto_submitted_keyword(parsed).is_some_and(|keyword| keyword != "no")
+}
+
+/// Returns `true` for an RFC 3834 automatic response: `auto-replied`, or
+/// `auto-generated` with a subject starting with `Auto:`.
+fn is_auto_reply(parsed: &ParsedMail, subject: Option<&str>) -> bool {
+    match auto_submitted_keyword(parsed).as_deref() {
+        Some("auto-replied") => true,
+        Some("auto-generated") => subject.is_some_and(|subject| {
+            subject
+                .trim_start()
+                .get(..5)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case("auto:"))
+        }),
+        _ => false,
+    }
+}
+
+/// Returns the `Message-ID` header value without angle brackets.
+fn extract_message_id(parsed: &ParsedMail) -> Option<String> {
+    let header = parsed.headers.get_first_value("Message-ID")?;
+    let id = header
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Returns the identifier of a `List-Id` header such as
+/// `"News" <news.example.com>`.
+fn extract_list_id(parsed: &ParsedMail) -> Option<String> {
+    let header = parsed.headers.get_first_value("List-Id")?;
+    let id = match header.rsplit_once('<') {
+        Some((_, rest)) => rest.split('>').next().unwrap_or_default(),
+        None => header.as_str(),
+    };
+    let id = id.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Returns the first leaf of the MIME tree, depth first, for which `find`
+/// returns a value.
+fn find_leaf<'a, T>(
+    part: &'a ParsedMail<'a>,
+    find: &impl Fn(&'a ParsedMail<'a>) -> Option<T>,
+) -> Option<T> {
+    if part.subparts.is_empty() {
+        return find(part);
+    }
+    part.subparts.iter().find_map(|sub| find_leaf(sub, find))
+}
+
+/// Returns the header `name` of the original message returned in a report,
+/// i.e. a `message/rfc822` or `text/rfc822-headers` part.
+fn returned_header(parsed: &ParsedMail, name: &str) -> Option<String> {
+    find_leaf(parsed, &|part| {
+        let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+        if mimetype != "message/rfc822" && mimetype != "text/rfc822-headers" {
+            return None;
+        }
+        let body = part.get_body_raw().ok()?;
+        let (headers, _) = mailparse::parse_headers(&body).ok()?;
+        let value = headers.get_first_value(name)?;
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// Reads the ARF `message/feedback-report` part, if any.
+fn extract_complaint(parsed: &ParsedMail, domain: &str, email_re: &Regex) -> Option<Complaint> {
+    let report = find_leaf(parsed, &|part| {
+        if !part
+            .ctype
+            .mimetype
+            .eq_ignore_ascii_case("message/feedback-report")
+        {
+            return None;
+        }
+        part.get_body_raw().ok()
+    })?;
+    let (fields, _) = mailparse::parse_headers(&report).ok()?;
+    let feedback_type = fields
+        .get_first_value("Feedback-Type")?
+        .trim()
+        .to_ascii_lowercase();
+    let recipient = fields
+        .get_first_value("Original-Rcpt-To")
+        .and_then(|rcpt| extract_email_address(&rcpt, email_re))
+        .or_else(|| {
+            returned_header(parsed, "To").and_then(|to| extract_email_address(&to, email_re))
+        });
+    let recipient_id = returned_header(parsed, "Message-ID")
+        .and_then(|message_id| recipient_id_in_message_ids(&message_id, domain));
+    Some(Complaint {
+        feedback_type,
+        recipient,
+        recipient_id,
+    })
+}
+
+/// Returns the first mailbox of the `Sender` header, else of `From`.
+///
+/// Group syntax (RFC 5322 section 3.4, allowed in `From` by RFC 6854) is
+/// expanded, so `Team: a@x, b@x;` yields `a@x`. An empty group such as
+/// `undisclosed-recipients:;` holds no mailbox and the next header is tried.
 fn extract_sender_email(parsed: &ParsedMail) -> Option<String> {
     for header in ["Sender", "From"] {
         if let Some(mail_header) = parsed.headers.get_first_header(header)
             && let Ok(addresses) = mailparse::addrparse_header(mail_header)
-            && let Some(email) = first_mailbox(&addresses)
+            && let Some(mailbox) = mailboxes(&addresses).next()
         {
-            return Some(email);
+            return Some(mailbox.addr.clone());
         }
     }
     None
 }
 
-fn first_mailbox(addresses: &MailAddrList) -> Option<String> {
-    for addr in addresses.iter() {
-        match addr {
-            MailAddr::Single(single) => return Some(single.addr.clone()),
-            MailAddr::Group(group) => {
-                if let Some(member) = group.addrs.first() {
-                    return Some(member.addr.clone());
-                }
-            }
-        }
-    }
-    None
+/// Mailboxes of an address list in header order, with the members of each
+/// group in place of the group. Empty groups contribute nothing.
+fn mailboxes(addresses: &MailAddrList) -> impl Iterator<Item = &SingleInfo> {
+    addresses.iter().flat_map(|addr| match addr {
+        MailAddr::Single(single) => std::slice::from_ref(single),
+        MailAddr::Group(group) => group.addrs.as_slice(),
+    })
 }
 
 fn extract_recipient_id(parsed: &ParsedMail, domain: &str) -> Option<i32> {
     let header = parsed.headers.get_first_value("In-Reply-To")?;
+    recipient_id_in_message_ids(&header, domain)
+}
+
+/// Returns the recipient ID of the first `<id@domain>` Message-ID in
+/// `header` that was generated for `domain`.
+fn recipient_id_in_message_ids(header: &str, domain: &str) -> Option<i32> {
     for segment in header.split('<').skip(1) {
         if let Some(candidate) = segment.split('>').next() {
             let mut parts = candidate.split('@');
             match (parts.next(), parts.next()) {
                 (Some(id), Some(message_domain)) if message_domain == domain => {
-                    if let Ok(value) = id.parse() {
+                    if let Some(value) = recipient_id_from_message_id(id) {
                         return Some(value);
                     }
                 }
@@ -75,40 +280,130 @@ fn extract_recipient_id(parsed: &ParsedMail, domain: &str) -> Option<i32> {
     None
 }
 
-fn find_reply(parsed: &ParsedMail) -> Option<String> {
-    if let Some(body) = find_first_body(parsed, "text/plain") {
-        let cleaned = extract_reply_text(&body);
-        if !cleaned.is_empty() {
-            return Some(cleaned);
-        }
-    }
+/// Looks for a recipient ID tag in the addresses of the `Delivered-To` and
+/// `To` headers, in that order. Only addresses on `domain` count, so a
+/// message to a tagged address elsewhere cannot claim one of our recipients.
+fn extract_envelope_recipient_id(parsed: &ParsedMail, domain: &str) -> Option<i32> {
+    ["Delivered-To", "To"]
+        .into_iter()
+        .flat_map(|name| parsed.headers.get_all_headers(name))
+        .filter_map(|header| mailparse::addrparse_header(header).ok())
+        .find_map(|addresses| {
+            mailboxes(&addresses)
+                .filter(|mailbox| {
+                    mailbox
+                        .addr
+                        .trim()
+                        .rsplit_once('@')
+                        .is_some_and(|(_, address_domain)| {
+                            address_domain.eq_ignore_ascii_case(domain)
+                        })
+                })
+                .find_map(|mailbox| recipient_id_from_envelope(&mailbox.addr))
+        })
+}
 
-    if let Some(body) = find_first_body(parsed, "text/html") {
-        let text = strip_html_tags(&body);
-        let cleaned = extract_reply_text(&text);
-        if !cleaned.is_empty() {
-            return Some(cleaned);
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextKind {
+    Plain,
+    Html,
+    DeliveryStatus,
+}
 
-    None
+/// A text leaf of the MIME tree whose body is decoded on first use.
+struct TextPart<'a> {
+    kind: TextKind,
+    attachment: bool,
+    part: &'a ParsedMail<'a>,
+    text: OnceCell<Option<String>>,
+}
+
+impl TextPart<'_> {
+    /// Decoded body; HTML is converted to plain text. `None` when the part
+    /// cannot be decoded.
+    fn text(&self) -> Option<&str> {
+        self.text
+            .get_or_init(|| {
+                let body = decode_body(self.part)?;
+                Some(match self.kind {
+                    TextKind::Html => strip_html_tags(&body),
+                    _ => body,
+                })
+            })
+            .as_deref()
+    }
 }
 
-fn find_first_body(parsed: &ParsedMail, mimetype: &str) -> Option<String> {
-    if parsed.subparts.is_empty() {
-        if !is_attachment(parsed) && parsed.ctype.mimetype.eq_ignore_ascii_case(mimetype) {
-            return parsed.get_body().ok();
+/// Decodes a text part to a string. UTF-8 bodies are validated explicitly
+/// and invalid bytes replaced with U+FFFD, so a partially broken reply is
+/// still usable; other charsets are left to `mailparse`.
+fn decode_body(part: &ParsedMail) -> Option<String> {
+    let charset = part.ctype.charset.trim();
+    if !charset.eq_ignore_ascii_case("utf-8") && !charset.eq_ignore_ascii_case("utf8") {
+        return part.get_body().ok();
+    }
+    let raw = part.get_body_raw().ok()?;
+    match String::from_utf8(raw) {
+        Ok(body) => Some(body),
+        Err(e) => {
+            log::warn!(
+                "Replaced invalid UTF-8 in a {} part (first bad byte at {})",
+                part.ctype.mimetype,
+                e.utf8_error().valid_up_to()
+            );
+            Some(String::from_utf8_lossy(e.as_bytes()).into_owned())
         }
-        return None;
     }
+}
 
-    for part in &parsed.subparts {
-        if let Some(body) = find_first_body(part, mimetype) {
-            return Some(body);
+/// Collects the `text/plain`, `text/html` and `message/delivery-status`
+/// leaves in document order from their headers alone, without decoding any
+/// body, so the extractors below share one walk over the MIME tree and
+/// decode each part at most once.
+fn text_parts<'a>(parsed: &'a ParsedMail<'a>) -> Vec<TextPart<'a>> {
+    fn collect<'a>(part: &'a ParsedMail<'a>, parts: &mut Vec<TextPart<'a>>) {
+        if !part.subparts.is_empty() {
+            for sub in &part.subparts {
+                collect(sub, parts);
+            }
+            return;
         }
+
+        let kind = match part.ctype.mimetype.to_ascii_lowercase().as_str() {
+            "text/plain" => TextKind::Plain,
+            "text/html" => TextKind::Html,
+            "message/delivery-status" => TextKind::DeliveryStatus,
+            // A blank or malformed Content-Type (no `type/subtype`) is read
+            // as text/plain, the RFC 2045 default for a missing header.
+            mimetype if !mimetype.contains('/') => TextKind::Plain,
+            _ => return,
+        };
+        parts.push(TextPart {
+            kind,
+            attachment: is_attachment(part),
+            part,
+            text: OnceCell::new(),
+        });
     }
 
-    None
+    let mut parts = Vec::new();
+    collect(parsed, &mut parts);
+    parts
+}
+
+/// Returns the reply from the first inline `text/plain` part, falling back
+/// to the first inline `text/html` part.
+fn find_reply(parts: &[TextPart]) -> Option<String> {
+    [TextKind::Plain, TextKind::Html]
+        .into_iter()
+        .filter_map(|kind| {
+            parts
+                .iter()
+                .find(|part| part.kind == kind && !part.attachment)
+        })
+        .filter_map(TextPart::text)
+        .map(extract_reply_text)
+        .find(|cleaned| !cleaned.is_empty())
 }
 
 fn is_attachment(part: &ParsedMail) -> bool {
@@ -118,45 +413,20 @@ fn is_attachment(part: &ParsedMail) -> bool {
         .unwrap_or(false)
 }
 
-fn find_bounce_recipient(parsed: &ParsedMail) -> Option<String> {
-    let mut stack = vec![parsed];
-    while let Some(part) = stack.pop() {
-        if let Some(email) = bounce_from_part(part) {
-            return Some(email);
-        }
-        for sub in &part.subparts {
-            stack.push(sub);
-        }
-    }
-    None
-}
-
-fn bounce_from_part(part: &ParsedMail) -> Option<String> {
-    let mimetype = part.ctype.mimetype.to_ascii_lowercase();
-    if mimetype == "message/delivery-status"
-        && let Ok(body) = part.get_body()
-        && let Some(email) = extract_bounce_from_status(&body)
-    {
-        return Some(email);
-    }
-
-    if (mimetype == "text/plain" || mimetype == "text/html")
-        && let Ok(body) = part.get_body()
-    {
-        let text = if mimetype == "text/html" {
-            strip_html_tags(&body)
-        } else {
-            body
-        };
-        if let Some(email) = extract_bounce_from_text(&text) {
-            return Some(email);
+/// Looks for the bounced address, checking parts from last to first so a
+/// `message/delivery-status` report wins over the human-readable part
+/// preceding it. Text attachments are skipped.
+fn find_bounce_recipient(parts: &[TextPart], email_re: &Regex) -> Option<String> {
+    parts.iter().rev().find_map(|part| match part.kind {
+        TextKind::DeliveryStatus => extract_bounce_from_status(part.text()?, email_re),
+        TextKind::Plain | TextKind::Html if !part.attachment => {
+            extract_bounce_from_text(part.text()?, email_re)
         }
-    }
-
-    None
+        TextKind::Plain | TextKind::Html => None,
+    })
 }
 
-fn extract_bounce_from_status(input: &str) -> Option<String> {
+fn extract_bounce_from_status(input: &str, email_re: &Regex) -> Option<String> {
     for line in input.lines() {
         let line = line.trim();
         if line.is_empty() {
@@ -165,10 +435,10 @@ fn extract_bounce_from_status(input: &str) -> Option<String> {
         let lower = line.to_ascii_lowercase();
         if lower.starts_with("final-recipient") || lower.starts_with("original-recipient") {
             if let Some((_, rest)) = line.split_once(';') {
-                if let Some(email) = extract_email_address(rest.trim()) {
+                if let Some(email) = extract_email_address(rest.trim(), email_re) {
                     return Some(email);
                 }
-            } else if let Some(email) = extract_email_address(line) {
+            } else if let Some(email) = extract_email_address(line, email_re) {
                 return Some(email);
             }
         }
@@ -176,15 +446,27 @@ fn extract_bounce_from_status(input: &str) -> Option<String> {
     None
 }
 
-fn extract_bounce_from_text(input: &str) -> Option<String> {
+/// Extracts the bounced address from a human-readable bounce.
+///
+/// Lines naming the recipient explicitly win. Otherwise the `To:` header of
+/// the original message quoted after a marker such as `Original message`
+/// is used, for providers that forward bounces without a
+/// `message/delivery-status` part. The first other address is the last
+/// resort.
+fn extract_bounce_from_text(input: &str, email_re: &Regex) -> Option<String> {
     let mut fallback = None;
+    let mut in_original = false;
+    let mut original_to = None;
     for line in input.lines() {
         let line = line.trim();
         if line.is_empty() {
             continue;
         }
+        if ORIGINAL_MESSAGE.is_match(line) {
+            in_original = true;
+        }
 
-        if let Some(email) = extract_email_address(line) {
+        if let Some(email) = extract_email_address(line, email_re) {
             let lower = line.to_ascii_lowercase();
             if lower.contains("final-recipient")
                 || lower.contains("original-recipient")
@@ -195,21 +477,50 @@ fn extract_bounce_from_text(input: &str) -> Option<String> {
                 return Some(email);
             }
 
+            let header = lower.trim_start_matches(['>', ' ']);
+            if in_original && original_to.is_none() && header.starts_with("to:") {
+                original_to = Some(email);
+                continue;
+            }
+
             if fallback.is_none() && !lower.contains("mailer-daemon") {
                 fallback = Some(email);
             }
         }
     }
 
-    fallback
+    original_to.or(fallback)
 }
 
-static EMAIL_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}").expect("Email regex should compile")
-});
+fn is_auth_failure_diagnostic(line: &str) -> bool {
+    AUTH_FAILURE.is_match(line)
+}
 
-fn extract_email_address(input: &str) -> Option<String> {
-    EMAIL_REGEX.find(input).map(|m| m.as_str().to_string())
+/// Returns `true` when a delivery report attributes the failure to sender
+/// authentication (SPF, DKIM or DMARC).
+///
+/// `message/delivery-status` parts are checked on their `Status` and
+/// `Diagnostic-Code` fields; human-readable text parts on every line.
+fn has_auth_failure(parts: &[TextPart]) -> bool {
+    parts.iter().any(|part| match part.kind {
+        TextKind::DeliveryStatus => part.text().is_some_and(|text| {
+            text.lines().any(|line| {
+                let lower = line.trim().to_ascii_lowercase();
+                (lower.starts_with("status") || lower.starts_with("diagnostic-code"))
+                    && is_auth_failure_diagnostic(&lower)
+            })
+        }),
+        TextKind::Plain | TextKind::Html => {
+            !part.attachment
+                && part
+                    .text()
+                    .is_some_and(|text| text.lines().any(is_auth_failure_diagnostic))
+        }
+    })
+}
+
+fn extract_email_address(input: &str, email_re: &Regex) -> Option<String> {
+    email_re.find(input).map(|m| m.as_str().to_string())
 }
 
 /// Remove HTML tags from the input and return plain text.
@@ -285,7 +596,8 @@ mod tests {
     const DOMAIN: &str = "example.com";
 
     fn parse(raw: &str) -> ParsedEmail {
-        parse_email(raw.as_bytes(), DOMAIN).expect("mail should parse")
+        parse_email(raw.as_bytes(), DOMAIN, CorrelationOrder::default(), false)
+            .expect("mail should parse")
     }
 
     #[test]
@@ -297,6 +609,114 @@ mod tests {
         assert_eq!(parsed.recipient_id, Some(42));
         assert_eq!(parsed.reply.as_deref(), Some("Thanks!"));
         assert!(parsed.bounce_recipient.is_none());
+        assert!(parsed.message_id.is_none());
+    }
+
+    #[test]
+    fn extracts_own_message_id() {
+        let raw = "Subject: Re: Hello\r\nFrom: sender@example.org\r\nMessage-ID:  <CAF=abc.123@mail.example.org> \r\nIn-Reply-To: <42@example.com>\r\nContent-Type: text/plain\r\n\r\nThanks!\r\n";
+        assert_eq!(
+            parse(raw).message_id.as_deref(),
+            Some("CAF=abc.123@mail.example.org")
+        );
+
+        let raw = "Subject: Re: Hello\r\nFrom: sender@example.org\r\nMessage-ID: <>\r\nContent-Type: text/plain\r\n\r\nThanks!\r\n";
+        assert!(parse(raw).message_id.is_none());
+    }
+
+    #[test]
+    fn invalid_utf8_body_still_yields_a_reply() {
+        let mut raw = b"Subject: Re: Hello\r\nFrom: sender@example.com\r\nIn-Reply-To: <42@example.com>\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n".to_vec();
+        raw.extend_from_slice(
+            b"Thanks \xff\xfe for the \xd0\x9f\xd1\x80\xd0\xb0\xd0\xb9\xd1\x81!\r\n",
+        );
+        let parsed = parse_email(&raw, DOMAIN, CorrelationOrder::default(), false).unwrap();
+        assert_eq!(
+            parsed.reply.as_deref(),
+            Some("Thanks \u{FFFD}\u{FFFD} for the Прайс!")
+        );
+        assert_eq!(parsed.recipient_id, Some(42));
+    }
+
+    #[test]
+    fn detects_rfc3834_vacation_responses() {
+        let raw = "Subject: Auto: Re: Offer\r\nFrom: user@example.org\r\nIn-Reply-To: <42@example.com>\r\nAuto-Submitted: auto-replied\r\nContent-Type: text/plain\r\n\r\nI am on vacation until Monday.\r\n";
+        let parsed = parse(raw);
+        assert!(parsed.auto_reply);
+        assert!(parsed.auto_submitted);
+        assert_eq!(parsed.recipient_id, Some(42));
+
+        let raw = "Subject: AUTO: Out of office\r\nFrom: user@example.org\r\nAuto-Submitted: Auto-Generated\r\nContent-Type: text/plain\r\n\r\nAway\r\n";
+        assert!(parse(raw).auto_reply);
+    }
+
+    #[test]
+    fn other_automated_mail_is_not_an_auto_reply() {
+        for raw in [
+            // Generated, but not a response.
+            "Subject: Weekly digest\r\nFrom: robot@example.org\r\nAuto-Submitted: auto-generated\r\nContent-Type: text/plain\r\n\r\nNews\r\n",
+            "Subject: Auto: Re: Offer\r\nFrom: user@example.org\r\nAuto-Submitted: no\r\nContent-Type: text/plain\r\n\r\nThanks\r\n",
+            "Subject: Auto: Re: Offer\r\nFrom: user@example.org\r\nContent-Type: text/plain\r\n\r\nThanks\r\n",
+        ] {
+            assert!(!parse(raw).auto_reply, "{raw}");
+        }
+    }
+
+    #[test]
+    fn detects_auto_submitted_messages() {
+        let raw = "Subject: Re: You have been unsubscribed\r\nFrom: user@example.org\r\nAuto-Submitted: auto-replied; owner-email=\"user@example.org\"\r\nContent-Type: text/plain\r\n\r\nI am away\r\n";
+        assert!(parse(raw).auto_submitted);
+
+        let raw = "Subject: unsubscribe\r\nFrom: user@example.org\r\nAuto-Submitted: No\r\nContent-Type: text/plain\r\n\r\nplease\r\n";
+        assert!(!parse(raw).auto_submitted);
+
+        let raw = "Subject: unsubscribe\r\nFrom: user@example.org\r\nContent-Type: text/plain\r\n\r\nplease\r\n";
+        assert!(!parse(raw).auto_submitted);
+    }
+
+    #[test]
+    fn extracts_feedback_id_of_the_returned_message() {
+        let raw = "Subject: Undelivered Mail Returned to Sender\r\nFrom: MAILER-DAEMON@example.net\r\nContent-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\r\n--b\r\nContent-Type: text/plain\r\n\r\nDelivery failed.\r\n--b\r\nContent-Type: message/delivery-status\r\n\r\nFinal-Recipient: rfc822; user@example.org\r\nAction: failed\r\n--b\r\nContent-Type: text/rfc822-headers\r\n\r\nFrom: sender@example.com\r\nTo: user@example.org\r\nFeedback-ID: 7:1:vip:acme\r\n--b--\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.bounce_recipient.as_deref(), Some("user@example.org"));
+        assert_eq!(parsed.feedback_id.as_deref(), Some("7:1:vip:acme"));
+
+        let raw = "Subject: Hi\r\nFrom: user@example.org\r\nFeedback-ID: 7:1:vip:acme\r\nContent-Type: text/plain\r\n\r\nHello\r\n";
+        assert_eq!(parse(raw).feedback_id, None);
+    }
+
+    const ARF_REPORT: &str = "Subject: Abuse report\r\nFrom: feedback@provider.example\r\nContent-Type: multipart/report; report-type=feedback-report; boundary=\"arf\"\r\n\r\n--arf\r\nContent-Type: text/plain\r\n\r\nThis is an email abuse report.\r\n--arf\r\nContent-Type: message/feedback-report\r\n\r\nFeedback-Type: Abuse\r\nUser-Agent: ProviderFBL/1.0\r\nVersion: 1\r\nOriginal-Rcpt-To: <user@example.org>\r\n--arf\r\nContent-Type: message/rfc822\r\n\r\nFrom: sender@example.com\r\nTo: redacted@provider.example\r\nMessage-ID: <42.abc@example.com>\r\nFeedback-ID: 7:1:acme\r\nSubject: Offer\r\n\r\nBuy now\r\n--arf--\r\n";
+
+    #[test]
+    fn parses_arf_complaint_report() {
+        let parsed = parse(ARF_REPORT);
+        assert_eq!(
+            parsed.complaint,
+            Some(Complaint {
+                feedback_type: "abuse".into(),
+                recipient: Some("user@example.org".into()),
+                recipient_id: Some(42),
+            })
+        );
+        assert!(parsed.complaint.unwrap().is_unwanted());
+        assert_eq!(parsed.feedback_id.as_deref(), Some("7:1:acme"));
+        assert_eq!(parsed.recipient_id, None);
+    }
+
+    #[test]
+    fn arf_recipient_falls_back_to_returned_message() {
+        let raw = ARF_REPORT
+            .replace("Original-Rcpt-To: <user@example.org>\r\n", "")
+            .replace("Feedback-Type: Abuse", "Feedback-Type: not-spam");
+        let complaint = parse(&raw).complaint.unwrap();
+        assert_eq!(complaint.feedback_type, "not-spam");
+        assert_eq!(
+            complaint.recipient.as_deref(),
+            Some("redacted@provider.example")
+        );
+        assert!(!complaint.is_unwanted());
+
+        assert_eq!(parse("Subject: Hi\r\n\r\nHello\r\n").complaint, None);
     }
 
     #[test]
@@ -306,6 +726,44 @@ mod tests {
         assert_eq!(parsed.sender_email.as_deref(), Some("sender@example.com"));
     }
 
+    #[test]
+    fn expands_group_addresses_for_the_sender() {
+        let raw = "Subject: Hi\r\nFrom: Sales Team: anna@example.org, boris@example.org;\r\nContent-Type: text/plain\r\n\r\nHello\r\n";
+        assert_eq!(parse(raw).sender_email.as_deref(), Some("anna@example.org"));
+    }
+
+    #[test]
+    fn empty_groups_are_skipped() {
+        let raw = "Subject: Hi\r\nSender: undisclosed-recipients:;\r\nFrom: Empty:;, Anna <anna@example.org>\r\nContent-Type: text/plain\r\n\r\nHello\r\n";
+        assert_eq!(parse(raw).sender_email.as_deref(), Some("anna@example.org"));
+
+        let raw = "Subject: Hi\r\nFrom: undisclosed-recipients:;\r\nTo: undisclosed-recipients:;\r\nContent-Type: text/plain\r\n\r\nHello\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.sender_email, None);
+        assert_eq!(parsed.recipient_id, None);
+        assert_eq!(parsed.reply.as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn extracts_recipient_id_from_group_member() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nTo: undisclosed-recipients:;, Replies: info@example.com, replies+rcpt31@example.com;\r\nContent-Type: text/plain\r\n\r\nHi\r\n";
+        assert_eq!(parse(raw).recipient_id, Some(31));
+    }
+
+    #[test]
+    fn reads_reply_without_content_type() {
+        let raw = "Subject: Re: Hello\r\nFrom: sender@example.com\r\nIn-Reply-To: <42@example.com>\r\n\r\nThanks!\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.reply.as_deref(), Some("Thanks!"));
+        assert_eq!(parsed.recipient_id, Some(42));
+
+        let raw = "Subject: Re: Hello\r\nFrom: sender@example.com\r\nContent-Type: ; charset=utf-8\r\n\r\nThanks!\r\n";
+        assert_eq!(parse(raw).reply.as_deref(), Some("Thanks!"));
+
+        let raw = "Subject: Re: Hello\r\nFrom: sender@example.com\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\r\nThanks!\r\n--b--\r\n";
+        assert_eq!(parse(raw).reply.as_deref(), Some("Thanks!"));
+    }
+
     #[test]
     fn decodes_base64_html_reply() {
         let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nContent-Type: text/html; charset=\"utf-8\"\r\nContent-Transfer-Encoding: base64\r\n\r\nPGRpdj5UaGFua3MhPC9kaXY+";
@@ -330,12 +788,155 @@ mod tests {
         );
     }
 
+    fn idn_bounce(address: &str) -> String {
+        format!(
+            "Subject: Undelivered\r\nFrom: Mailer <mailer@example.com>\r\nContent-Type: multipart/report; boundary=\"BOUNDARY\"\r\n\r\n--BOUNDARY\r\nContent-Type: message/delivery-status; charset=utf-8\r\n\r\nFinal-Recipient: rfc822; {address}\r\n--BOUNDARY--\r\n"
+        )
+    }
+
+    fn bounce_with(raw: &str, unicode_domains: bool) -> Option<String> {
+        parse_email(
+            raw.as_bytes(),
+            DOMAIN,
+            CorrelationOrder::default(),
+            unicode_domains,
+        )
+        .unwrap()
+        .bounce_recipient
+    }
+
+    #[test]
+    fn unicode_domains_are_matched_only_when_allowed() {
+        let punycode = idn_bounce("user@xn--e1afmkfd.xn--p1ai");
+        for unicode_domains in [false, true] {
+            assert_eq!(
+                bounce_with(&punycode, unicode_domains).as_deref(),
+                Some("user@xn--e1afmkfd.xn--p1ai")
+            );
+        }
+
+        let unicode = idn_bounce("user@пример.рф");
+        assert_eq!(bounce_with(&unicode, false), None);
+        assert_eq!(
+            bounce_with(&unicode, true).as_deref(),
+            Some("user@пример.рф")
+        );
+    }
+
+    #[test]
+    fn extracts_original_recipient_from_forwarded_bounce() {
+        let raw = "Subject: Undelivered Mail Returned to Sender\r\nFrom: Postmaster <postmaster@provider.example>\r\nContent-Type: text/plain\r\n\r\nYour message could not be delivered.\r\n\r\n---------- Forwarded message ----------\r\nFrom: Sender <sender@example.com>\r\nTo: Alice <gone@example.org>\r\nSubject: Offer\r\n\r\nHello\r\n";
+        assert_eq!(
+            parse(raw).bounce_recipient.as_deref(),
+            Some("gone@example.org")
+        );
+
+        let quoted = "Subject: Undelivered\r\nFrom: Postmaster <postmaster@provider.example>\r\nContent-Type: text/plain\r\n\r\nDelivery failed.\r\n\r\n> ----- Original message -----\r\n> From: sender@example.com\r\n> To: quoted@example.org\r\n";
+        assert_eq!(
+            parse(quoted).bounce_recipient.as_deref(),
+            Some("quoted@example.org")
+        );
+    }
+
+    #[test]
+    fn detects_dmarc_rejection_in_delivery_status() {
+        let raw = "Subject: Undelivered\r\nFrom: Mailer <mailer@example.com>\r\nContent-Type: multipart/report; boundary=\"BOUNDARY\"\r\n\r\n--BOUNDARY\r\nContent-Type: message/delivery-status\r\n\r\nFinal-Recipient: rfc822; user@example.org\r\nAction: failed\r\nStatus: 5.7.26\r\nDiagnostic-Code: smtp; 550 5.7.26 Unauthenticated email is not accepted due to domain's DMARC policy\r\n--BOUNDARY--\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.bounce_recipient.as_deref(), Some("user@example.org"));
+        assert!(parsed.bounce_auth_failure);
+    }
+
+    #[test]
+    fn detects_spf_failure_in_text_report() {
+        let raw = "Subject: Undelivered\r\nFrom: Mailer <mailer@example.com>\r\nContent-Type: text/plain\r\n\r\nDelivery to user@example.org failed.\r\n550 5.7.1 Message rejected: SPF check failed for example.com\r\n";
+        assert!(parse(raw).bounce_auth_failure);
+    }
+
+    #[test]
+    fn mailbox_bounce_is_not_an_auth_failure() {
+        let raw = "Subject: Undelivered\r\nFrom: Mailer <mailer@example.com>\r\nContent-Type: multipart/report; boundary=\"BOUNDARY\"\r\n\r\n--BOUNDARY\r\nContent-Type: message/delivery-status\r\n\r\nFinal-Recipient: rfc822; user@example.org\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 User unknown\r\n--BOUNDARY--\r\n";
+        assert!(!parse(raw).bounce_auth_failure);
+    }
+
     #[test]
     fn extracts_recipient_id_from_in_reply_to() {
         let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nIn-Reply-To: <24@example.com>\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nHi\r\n";
         let parsed = parse(raw);
         assert_eq!(parsed.recipient_id, Some(24));
     }
+
+    #[test]
+    fn extracts_list_id() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nList-Id: \"Weekly News\" <news.example.com>\r\nContent-Type: text/plain\r\n\r\nHi\r\n";
+        assert_eq!(parse(raw).list_id.as_deref(), Some("news.example.com"));
+
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nContent-Type: text/plain\r\n\r\nHi\r\n";
+        assert_eq!(parse(raw).list_id, None);
+    }
+
+    #[test]
+    fn extracts_recipient_id_from_delivered_to() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nDelivered-To: replies+rcpt24@example.com\r\nTo: Replies <replies@example.com>\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nHi\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.recipient_id, Some(24));
+    }
+
+    #[test]
+    fn ignores_recipient_tags_on_other_domains() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nDelivered-To: replies+rcpt24@other.example\r\nTo: Replies <replies+rcpt25@EXAMPLE.com>\r\nContent-Type: text/plain\r\n\r\nHi\r\n";
+        assert_eq!(parse(raw).recipient_id, Some(25));
+
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nTo: replies+rcpt24@other.example\r\nContent-Type: text/plain\r\n\r\nHi\r\n";
+        assert_eq!(parse(raw).recipient_id, None);
+    }
+
+    #[test]
+    fn correlation_order_decides_between_sources() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nIn-Reply-To: <24@example.com>\r\nTo: replies+rcpt7@example.com\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nHi\r\n";
+        let parse_with = |order| {
+            parse_email(raw.as_bytes(), DOMAIN, order, false)
+                .unwrap()
+                .recipient_id
+        };
+        assert_eq!(parse_with(CorrelationOrder::InReplyToFirst), Some(24));
+        assert_eq!(parse_with(CorrelationOrder::EnvelopeFirst), Some(7));
+    }
+
+    #[test]
+    fn extracts_recipient_id_from_tokenized_message_id() {
+        let raw = "Subject: Hi\r\nFrom: Sender <sender@example.com>\r\nIn-Reply-To: <24.18c4f0a1b2c30001@example.com>\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nHi\r\n";
+        let parsed = parse(raw);
+        assert_eq!(parsed.recipient_id, Some(24));
+    }
+
+    fn with_attachments(body: &str) -> String {
+        let attachment = "QUJDREVGR0g=\r\n".repeat(2048);
+        format!(
+            "Subject: Re: Offer\r\nFrom: Sender <sender@example.com>\r\nIn-Reply-To: <42@example.com>\r\nContent-Type: multipart/mixed; boundary=\"MIXED\"\r\n\r\n--MIXED\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\n{body}\r\n--MIXED\r\nContent-Type: text/plain; name=\"log.txt\"\r\nContent-Disposition: attachment; filename=\"log.txt\"\r\nContent-Transfer-Encoding: base64\r\n\r\n{attachment}--MIXED\r\nContent-Type: application/pdf; name=\"offer.pdf\"\r\nContent-Disposition: attachment; filename=\"offer.pdf\"\r\nContent-Transfer-Encoding: base64\r\n\r\n{attachment}--MIXED--\r\n"
+        )
+    }
+
+    #[test]
+    fn attachments_do_not_change_the_result() {
+        let plain = "Subject: Re: Offer\r\nFrom: Sender <sender@example.com>\r\nIn-Reply-To: <42@example.com>\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nThanks, call me!\r\n";
+        assert_eq!(parse(&with_attachments("Thanks, call me!")), parse(plain));
+    }
+
+    #[test]
+    fn attachments_are_not_decoded() {
+        let raw = with_attachments("Thanks, call me!");
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let parts = text_parts(&parsed);
+        assert_eq!(parts.len(), 2);
+
+        assert!(find_reply(&parts).is_some());
+        assert!(find_bounce_recipient(&parts, &EMAIL).is_none());
+        assert!(!has_auth_failure(&parts));
+
+        assert!(parts[0].text.get().is_some());
+        assert!(parts[1].attachment);
+        assert!(parts[1].text.get().is_none());
+    }
 }
 
 #[cfg(test)]
@@ -1,31 +1,192 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use async_imap::Session;
-use pushkind_common::zmq::{ZmqSender, ZmqSenderExt};
+use async_trait::async_trait;
+use chrono::Utc;
 use pushkind_emailer::domain::email::EmailRecipient;
 use pushkind_emailer::domain::hub::Hub;
 use pushkind_emailer::domain::types::{EmailRecipientId, EmailRecipientReply, HubId, ImapUid};
 use pushkind_emailer::models::zmq::{ZMQReplyMessage, ZMQUnsubscribeMessage};
 use tokio::net::TcpStream;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep};
 use tokio_rustls::client::TlsStream;
 
-use crate::domain::UpdateEmailRecipient;
+use crate::address::AddressNormalizer;
+use crate::credentials::{SystemSecrets, resolve_credentials};
+use crate::domain::{DeliveryEventKind, UnsubscribeSource, UpdateEmailRecipient};
 use crate::errors::Error;
-use crate::repository::{DieselRepository, EmailReader, EmailWriter, HubWriter};
+use crate::models::{
+    BacklogConfig, BacklogOrder, BounceBreakerConfig, EmptyReplyAction, ImapSearchConfig,
+    PublishRetryConfig, ReplyConfig, ServerConfig, SpoolConfig, UidPersistConfig,
+};
+use crate::repository::{
+    DeliveryReader, DeliveryWriter, DieselRepository, EmailReader, EmailWriter, HubReader,
+    HubWriter, SuppressionReader,
+};
 
-use super::imap::{fetch_message_rfc822, init_session};
-use super::parser::parse_email;
+use super::batch::{BatchingPublisher, NotificationBatch};
+use super::classifier::ReplyClassifier;
+use super::imap::{MAX_RAW_MESSAGE_BYTES, fetch_message_rfc822, fetch_raw_message, init_session};
+use super::language::detect_language;
+use super::pacing::ImapPacer;
+use super::parser::{Complaint, parse_email};
+use super::spool::{Notification, Spool};
+
+/// Abstraction over publishing reply and unsubscribe notifications.
+#[async_trait]
+pub trait ReplyPublisher: Send + Sync {
+    /// Publishes a recipient reply.
+    async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error>;
+
+    /// Publishes an unsubscribe or bounce of a recipient address.
+    async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error>;
+
+    /// Emails `email` a confirmation of its unsubscribe request sent by
+    /// reply. The default sends nothing.
+    async fn send_unsubscribe_confirmation(
+        &self,
+        _hub_id: HubId,
+        _email: &str,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Publishes several notifications as one message. The default publishes
+    /// them one by one, stopping at the first failure.
+    async fn send_batch(&self, batch: &NotificationBatch) -> Result<(), Error> {
+        for notification in &batch.notifications {
+            notification.publish(self).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `publish` until it succeeds or `retry.attempts` are used up, sleeping
+/// with exponential backoff between attempts.
+///
+/// Returns the last error once every attempt has failed.
+pub(crate) async fn publish_with_retry<F, Fut>(
+    retry: &PublishRetryConfig,
+    mut publish: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let attempts = retry.attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match publish().await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < attempts => {
+                let delay = retry.backoff(attempt);
+                log::warn!(
+                    "ZMQ publish attempt {attempt}/{attempts} failed: {err}; retrying in {delay:?}"
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Stores a notification that could not be published in the configured
+/// spool; without a spool it is dropped.
+pub(crate) async fn spool_notification(spool: Option<&SpoolConfig>, notification: Notification) {
+    let Some(spool) = spool else {
+        log::error!("Dropping unpublished notification, no spool configured: {notification:?}");
+        return;
+    };
+
+    match Spool::new(&spool.dir).push(&notification).await {
+        Ok(()) => log::info!(
+            "Spooled unpublished notification in {}",
+            spool.dir.display()
+        ),
+        Err(e) => log::error!("Dropping unpublished notification {notification:?}: {e}"),
+    }
+}
+
+/// Longest unsubscribe reason, in characters, taken from a reply body.
+const MAX_UNSUBSCRIBE_REASON_CHARS: usize = 500;
+
+/// Reason recorded for an unsubscribe request: what the sender wrote above
+/// the quoted text, truncated, or the subject when they wrote nothing.
+fn unsubscribe_reason(reply: Option<&str>, subject: &str) -> String {
+    match reply.map(str::trim).filter(|reply| !reply.is_empty()) {
+        Some(reply) => reply.chars().take(MAX_UNSUBSCRIBE_REASON_CHARS).collect(),
+        None => subject.to_string(),
+    }
+}
+
+/// Returns `true` when the hub already processed the reply with this
+/// Message-ID. A failed lookup lets the reply through, and so does a zero
+/// `dedup_capacity`.
+fn is_processed_reply(
+    repo: &(impl DeliveryReader + ?Sized),
+    hub_id: HubId,
+    message_id: &str,
+    config: &ReplyConfig,
+) -> bool {
+    if config.dedup_capacity == 0 {
+        return false;
+    }
+    match repo.is_reply_recorded(hub_id, message_id) {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            log::error!("Cannot look up reply Message-ID <{message_id}> in hub#{hub_id}: {e}");
+            false
+        }
+    }
+}
+
+/// Records the Message-ID of a processed reply, so later passes skip it.
+fn remember_reply(
+    repo: &(impl DeliveryWriter + ?Sized),
+    hub_id: HubId,
+    message_id: &str,
+    config: &ReplyConfig,
+) {
+    if config.dedup_capacity == 0 {
+        return;
+    }
+    if let Err(e) = repo.record_reply_once(hub_id, message_id, config.dedup_capacity) {
+        log::error!("Cannot record reply Message-ID <{message_id}> in hub#{hub_id}: {e}");
+    }
+}
+
+/// Returns `true` when `email` is not suppressed in the hub yet, so a
+/// repeated or reprocessed unsubscribe request is confirmed only once. A
+/// failed lookup counts as suppressed.
+fn is_new_unsubscribe(
+    repo: &(impl SuppressionReader + ?Sized),
+    hub_id: HubId,
+    email: &str,
+) -> bool {
+    match repo.is_suppressed(email, hub_id) {
+        Ok(suppressed) => !suppressed,
+        Err(e) => {
+            log::error!("Cannot check suppression of {email} in hub#{hub_id}: {e}");
+            false
+        }
+    }
+}
 
 async fn send_unsubscribe_message(
     repo: &(impl EmailWriter + ?Sized),
-    zmq_sender: &ZmqSender,
+    publisher: &(impl ReplyPublisher + ?Sized),
     hub_id: HubId,
     email: String,
+    source: UnsubscribeSource,
     reason: Option<String>,
+    config: &ServerConfig,
 ) {
-    match repo.unsubscribe_recipient(&email, hub_id, reason.as_deref()) {
+    let email = config.suppression.normalize(&email);
+    match repo.unsubscribe_recipient(&email, hub_id, source, reason.as_deref()) {
         Ok(_) => log::info!("Persisted unsubscribe for {email} in hub#{hub_id}"),
         Err(err) => {
             log::error!("Cannot persist unsubscribe for {email} in hub#{hub_id}: {err}");
@@ -38,20 +199,29 @@ async fn send_unsubscribe_message(
         reason,
     };
 
-    match zmq_sender.send_json(&message).await {
+    match publish_with_retry(&config.publish_retry, || {
+        publisher.send_unsubscribe(&message)
+    })
+    .await
+    {
         Ok(_) => log::info!("ZMQ unsubscribe message sent for {email} in hub#{hub_id}"),
         Err(err) => {
-            log::error!("Cannot send ZMQ unsubscribe message for {email} in hub#{hub_id}: {err}")
+            log::error!(
+                "Cannot send ZMQ unsubscribe message for {email} in hub#{hub_id} after {} attempt(s): {err}",
+                config.publish_retry.attempts.max(1)
+            );
+            spool_notification(config.spool.as_ref(), Notification::Unsubscribe(message)).await;
         }
     }
 }
 
 async fn send_reply_message(
-    zmq_sender: &ZmqSender,
+    publisher: &(impl ReplyPublisher + ?Sized),
     hub_id: HubId,
     email: &str,
     reply: Option<&str>,
     subject: Option<&str>,
+    config: &ServerConfig,
 ) {
     let message = ZMQReplyMessage {
         hub_id: hub_id.get(),
@@ -60,21 +230,110 @@ async fn send_reply_message(
         subject: subject.map(str::to_string),
     };
 
-    match zmq_sender.send_json(&message).await {
+    match publish_with_retry(&config.publish_retry, || publisher.send_reply(&message)).await {
         Ok(_) => {
             log::info!("ZMQ message sent for {email} in hub#{hub_id}");
         }
         Err(e) => {
-            log::error!("Cannot send ZMQ message for {email} in hub#{hub_id}: {e}");
+            log::error!(
+                "Cannot send ZMQ message for {email} in hub#{hub_id} after {} attempt(s): {e}",
+                config.publish_retry.attempts.max(1)
+            );
+            spool_notification(config.spool.as_ref(), Notification::Reply(message)).await;
+        }
+    }
+}
+
+/// Records the bounce notification with IMAP `uid` and raises an alert when
+/// the bounce rate trips the circuit breaker that pauses `send_email` for the
+/// hub.
+///
+/// A notification already recorded (e.g. when a UID range is reprocessed) is
+/// not counted again and returns `false`.
+fn record_bounce(
+    repo: &(impl DeliveryReader + DeliveryWriter + ?Sized),
+    hub_id: HubId,
+    uid: u32,
+    breaker: &BounceBreakerConfig,
+) -> bool {
+    match repo.record_bounce_once(hub_id, uid) {
+        Ok(true) => {}
+        Ok(false) => {
+            log::debug!("Bounce UID {uid} in hub#{hub_id} already recorded");
+            return false;
+        }
+        Err(err) => {
+            log::error!("Cannot record bounce in hub#{hub_id}: {err}");
+            return false;
+        }
+    }
+
+    match repo.bounce_stats(hub_id, breaker.window_start(Utc::now().naive_utc())) {
+        Ok(stats) if breaker.is_tripped(&stats) => {
+            log::error!(
+                "ALERT: bounce rate for hub#{} is {:.1}% ({} of {}), above {:.1}%; sending is paused",
+                hub_id,
+                stats.rate() * 100.0,
+                stats.bounced,
+                stats.sent,
+                breaker.threshold * 100.0
+            );
+            true
+        }
+        Ok(_) => false,
+        Err(err) => {
+            log::error!("Cannot load bounce stats for hub#{hub_id}: {err}");
+            false
         }
     }
 }
 
+/// Marks `recipient` as opened and stores `reply`, recording the first
+/// open and reply as delivery events.
+///
+/// Replies shorter than `config.min_length` characters after trimming (e.g.
+/// `k` auto-acks) are logged and dropped, so they do not count as replies.
+/// A missing or blank reply is handled per `config.empty_reply`. Stored
+/// replies are categorized by `classifier` and the category is saved with
+/// the recipient, as is their language with `config.detect_language`.
+///
+/// Returns `false` when the recipient could not be updated, so the reply
+/// is left for a later pass.
 pub async fn process_reply(
-    repo: &(impl EmailWriter + ?Sized),
+    repo: &(impl EmailWriter + DeliveryWriter + ?Sized),
+    hub_id: HubId,
     recipient: &EmailRecipient,
     reply: Option<String>,
-) {
+    config: &ReplyConfig,
+    classifier: &(impl ReplyClassifier + ?Sized),
+) -> bool {
+    let min_length = config.min_length;
+    let (reply, placeholder) = match reply.filter(|reply| !reply.trim().is_empty()) {
+        Some(reply) => {
+            let length = reply.trim().chars().count();
+            if length < min_length {
+                log::info!(
+                    "Not counting {length}-character reply from recipient {} as a reply (minimum {min_length})",
+                    recipient.id
+                );
+            }
+            ((length >= min_length).then_some(reply), false)
+        }
+        None => match config.empty_reply {
+            EmptyReplyAction::MarkOpened => (None, false),
+            EmptyReplyAction::CountAsReplied => {
+                log::info!(
+                    "Counting empty reply from recipient {} as a reply",
+                    recipient.id
+                );
+                (Some(config.empty_reply_text.clone()), true)
+            }
+            EmptyReplyAction::Skip => {
+                log::info!("Ignoring empty reply from recipient {}", recipient.id);
+                return true;
+            }
+        },
+    };
     let reply = reply.and_then(|reply| match EmailRecipientReply::try_from(reply) {
         Ok(reply) => Some(reply),
         Err(err) => {
@@ -96,25 +355,174 @@ pub async fn process_reply(
         },
     ) {
         log::error!("Cannot set email recipient replied status: {e}");
-    } else {
-        log::info!("Email recipient replied status set for {}", recipient.id);
+        return false;
+    }
+    log::info!("Email recipient replied status set for {}", recipient.id);
+
+    // The placeholder of an empty reply says nothing about the recipient.
+    let text = reply.as_ref().filter(|_| !placeholder);
+    if let Some(category) = text.and_then(|reply| classifier.classify(reply.as_str())) {
+        match repo.set_reply_category(recipient.id, &category) {
+            Ok(()) => log::info!(
+                "Reply of recipient {} categorized as {category}",
+                recipient.id
+            ),
+            Err(e) => log::error!(
+                "Cannot store reply category for recipient {}: {e}",
+                recipient.id
+            ),
+        }
+    }
+
+    if config.detect_language
+        && let Some(language) = text.and_then(|reply| detect_language(reply.as_str()))
+    {
+        match repo.set_reply_language(recipient.id, language) {
+            Ok(()) => log::info!("Reply of recipient {} is in {language}", recipient.id),
+            Err(e) => log::error!(
+                "Cannot store reply language for recipient {}: {e}",
+                recipient.id
+            ),
+        }
+    }
+
+    // Only first transitions are counted so reprocessed replies do not
+    // inflate the daily statistics.
+    let mut events = Vec::new();
+    if !recipient.opened {
+        events.push(DeliveryEventKind::Opened);
     }
+    if recipient.reply.is_none() && reply.is_some() {
+        events.push(DeliveryEventKind::Replied);
+    }
+    for kind in events {
+        if let Err(e) = repo.record_delivery_event(hub_id, kind) {
+            log::error!("Cannot record {} event in hub#{hub_id}: {e}", kind.as_str());
+        }
+    }
+    true
 }
 
-pub async fn process_new_message(
+/// Unsubscribes the recipient an ARF report complains about, with source
+/// [`UnsubscribeSource::Complaint`] and reason `complaint: {feedback_type}`.
+///
+/// Reports are unauthenticated, so only a returned Message-ID that names one
+/// of the hub's recipients triggers an unsubscribe, and the address stored
+/// for that recipient is used rather than the report's `Original-Rcpt-To`.
+/// `not-spam` and `auth-failure` reports are only logged.
+async fn handle_complaint(
     repo: &(impl EmailReader + EmailWriter + ?Sized),
+    publisher: &(impl ReplyPublisher + ?Sized),
+    hub_id: HubId,
+    uid: u32,
+    complaint: &Complaint,
+    feedback_id: Option<&str>,
+    config: &ServerConfig,
+) {
+    let feedback_type = &complaint.feedback_type;
+    let feedback_id = feedback_id.unwrap_or("none");
+    if !complaint.is_unwanted() {
+        log::info!(
+            "Ignoring {feedback_type} feedback report UID {uid} in hub#{hub_id} (Feedback-ID {feedback_id})"
+        );
+        return;
+    }
+
+    let reported = complaint
+        .recipient_id
+        .and_then(|id| EmailRecipientId::try_from(id).ok())
+        .and_then(|id| match repo.get_email_recipient_by_id(id, hub_id) {
+            Ok(recipient) => recipient.map(|recipient| recipient.address.as_str().to_string()),
+            Err(e) => {
+                log::error!(
+                    "Failed to load recipient id {} in hub#{hub_id}: {e}",
+                    id.get()
+                );
+                None
+            }
+        });
+    let Some(email) = reported else {
+        log::warn!(
+            "Complaint UID {uid} in hub#{hub_id} does not reference a message of this hub; ignoring report about {} (Feedback-ID {feedback_id})",
+            complaint.recipient.as_deref().unwrap_or("unknown")
+        );
+        return;
+    };
+    if let Some(rcpt_to) = complaint.recipient.as_deref()
+        && config.suppression.normalize(rcpt_to) != config.suppression.normalize(&email)
+    {
+        log::warn!(
+            "Complaint UID {uid} in hub#{hub_id} names {rcpt_to}, but the message was sent to {email}; unsubscribing {email}"
+        );
+    }
+
+    log::info!(
+        "Complaint ({feedback_type}) about {email} in hub#{hub_id} (Feedback-ID {feedback_id})"
+    );
+    send_unsubscribe_message(
+        repo,
+        publisher,
+        hub_id,
+        email,
+        UnsubscribeSource::Complaint,
+        Some(format!("complaint: {feedback_type}")),
+        config,
+    )
+    .await;
+}
+
+pub async fn process_new_message(
+    repo: &(
+         impl EmailReader + EmailWriter + DeliveryReader + DeliveryWriter + SuppressionReader + ?Sized
+     ),
     session: &mut Session<TlsStream<TcpStream>>,
     uid: u32,
-    domain: &str,
+    config: &ServerConfig,
     hub_id: HubId,
-    zmq_sender: &ZmqSender,
+    publisher: &(impl ReplyPublisher + ?Sized),
+    classifier: &(impl ReplyClassifier + ?Sized),
 ) {
-    let raw_message = match fetch_message_rfc822(session, uid).await {
+    let raw_message = match fetch_message_rfc822(session, uid, &config.imap_fetch).await {
         Some(raw) => raw,
         None => return,
     };
 
-    let parsed = match parse_email(&raw_message, domain) {
+    handle_message(
+        repo,
+        &raw_message,
+        uid,
+        config,
+        hub_id,
+        publisher,
+        classifier,
+    )
+    .await;
+}
+
+/// Handles a fetched message: unsubscribe requests and bounces suppress the
+/// address, anything else is treated as a reply.
+///
+/// Messages carrying the hub's own `List-Id` are campaign mail looping back
+/// into the inbox and are ignored.
+async fn handle_message(
+    repo: &(
+         impl EmailReader + EmailWriter + DeliveryReader + DeliveryWriter + SuppressionReader + ?Sized
+     ),
+    raw_message: &[u8],
+    uid: u32,
+    config: &ServerConfig,
+    hub_id: HubId,
+    publisher: &(impl ReplyPublisher + ?Sized),
+    classifier: &(impl ReplyClassifier + ?Sized),
+) {
+    let settings = config.hub_settings(hub_id);
+    let domain = settings.domain_or(&config.domain);
+    let parsed = match parse_email(
+        raw_message,
+        domain,
+        config.correlation.order,
+        config.suppression.allow_unicode_domains,
+    ) {
         Ok(parsed) => parsed,
         Err(err) => {
             log::error!("Cannot parse email UID {} in hub#{}: {}", uid, hub_id, err);
@@ -122,18 +530,45 @@ pub async fn process_new_message(
         }
     };
 
+    if let Some(list_id) = parsed.list_id.as_deref()
+        && let Some(own) = settings.list_id.as_ref()
+        && own.matches(list_id)
+    {
+        log::info!("Ignoring email UID {uid} in hub#{hub_id}: own list traffic ({list_id})");
+        return;
+    }
+
+    if let Some(complaint) = parsed.complaint.as_ref() {
+        let feedback_id = parsed.feedback_id.as_deref();
+        handle_complaint(repo, publisher, hub_id, uid, complaint, feedback_id, config).await;
+        return;
+    }
+
     if let Some(subject) = parsed.subject.as_ref() {
         if subject.eq_ignore_ascii_case("unsubscribe") {
             match parsed.sender_email.clone() {
                 Some(email) => {
+                    let confirm =
+                        !parsed.auto_submitted && is_new_unsubscribe(repo, hub_id, &email);
                     send_unsubscribe_message(
                         repo,
-                        zmq_sender,
+                        publisher,
                         hub_id,
-                        email,
-                        Some(subject.clone()),
+                        email.clone(),
+                        UnsubscribeSource::Request,
+                        Some(unsubscribe_reason(parsed.reply.as_deref(), subject)),
+                        config,
                     )
                     .await;
+                    if confirm
+                        && let Err(e) = publisher
+                            .send_unsubscribe_confirmation(hub_id, &email)
+                            .await
+                    {
+                        log::error!(
+                            "Cannot send unsubscribe confirmation to {email} in hub#{hub_id}: {e}"
+                        );
+                    }
                     return;
                 }
                 None => log::warn!(
@@ -142,9 +577,30 @@ pub async fn process_new_message(
                 ),
             }
         } else if subject.eq_ignore_ascii_case("Undelivered Mail Returned to Sender") {
+            if parsed.bounce_auth_failure {
+                log::error!(
+                    "Bounce UID {} in hub#{} was rejected for SPF/DKIM/DMARC; check the sending domain's authentication setup. Recipient {} is kept subscribed",
+                    uid,
+                    hub_id,
+                    parsed.bounce_recipient.as_deref().unwrap_or("unknown")
+                );
+                return;
+            }
             if let Some(email) = parsed.bounce_recipient.clone() {
-                send_unsubscribe_message(repo, zmq_sender, hub_id, email, Some(subject.clone()))
-                    .await;
+                if let Some(feedback_id) = parsed.feedback_id.as_deref() {
+                    log::info!("Bounce UID {uid} in hub#{hub_id} has Feedback-ID {feedback_id}");
+                }
+                record_bounce(repo, hub_id, uid, &config.bounce_breaker);
+                send_unsubscribe_message(
+                    repo,
+                    publisher,
+                    hub_id,
+                    email,
+                    UnsubscribeSource::Bounce,
+                    Some(subject.clone()),
+                    config,
+                )
+                .await;
                 return;
             } else {
                 log::warn!(
@@ -155,6 +611,28 @@ pub async fn process_new_message(
         }
     }
 
+    if parsed.auto_reply {
+        log::info!(
+            "Ignoring automatic reply UID {uid} in hub#{hub_id} from {} (recipient id {}, subject {:?})",
+            parsed.sender_email.as_deref().unwrap_or("unknown"),
+            parsed
+                .recipient_id
+                .map_or_else(|| "unknown".to_string(), |id| id.to_string()),
+            parsed.subject.as_deref().unwrap_or_default()
+        );
+        return;
+    }
+
+    if let Some(message_id) = parsed.message_id.as_deref()
+        && is_processed_reply(repo, hub_id, message_id, &config.reply)
+    {
+        log::info!(
+            "Skipping reply UID {uid} in hub#{hub_id}: Message-ID <{message_id}> already processed"
+        );
+        return;
+    }
+
+    let mut processed = true;
     if let Some(recipient_id) = parsed.recipient_id {
         let reply = parsed.reply.clone();
         let recipient_id = match EmailRecipientId::try_from(recipient_id) {
@@ -170,34 +648,53 @@ pub async fn process_new_message(
             }
         };
 
-        match repo.get_email_recipient_by_id(recipient_id, hub_id) {
+        processed = match repo.get_email_recipient_by_id(recipient_id, hub_id) {
             Ok(Some(recipient)) => {
-                process_reply(repo, &recipient, reply).await;
+                process_reply(repo, hub_id, &recipient, reply, &config.reply, classifier).await
             }
-            Ok(None) => log::warn!(
-                "Recipient not found for id {} in hub#{}",
-                recipient_id.get(),
-                hub_id,
-            ),
-            Err(e) => log::error!(
-                "Failed to load recipient id {} in hub#{}: {}",
-                recipient_id.get(),
-                hub_id,
-                e,
-            ),
-        }
+            // Nothing later passes could update, so the reply is published
+            // and remembered like one without a recipient tag.
+            Ok(None) => {
+                log::warn!(
+                    "Recipient not found for id {} in hub#{}",
+                    recipient_id.get(),
+                    hub_id,
+                );
+                true
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to load recipient id {} in hub#{}: {}",
+                    recipient_id.get(),
+                    hub_id,
+                    e,
+                );
+                false
+            }
+        };
+    }
+
+    // Neither published nor remembered, so recovery or `reprocess_range`
+    // handles the reply again without publishing it twice.
+    if !processed {
+        log::warn!("Leaving reply UID {uid} in hub#{hub_id} for a later pass");
+        return;
     }
 
     let reply = parsed.reply.as_deref();
     let subject = parsed.subject.as_deref();
     if let Some(email) = parsed.sender_email.as_deref() {
-        send_reply_message(zmq_sender, hub_id, email, reply, subject).await;
+        send_reply_message(publisher, hub_id, email, reply, subject, config).await;
     } else {
         log::warn!(
             "Cannot send ZMQ reply message in hub#{}: missing sender email",
             hub_id
         );
     }
+
+    if let Some(message_id) = parsed.message_id.as_deref() {
+        remember_reply(repo, hub_id, message_id, &config.reply);
+    }
 }
 
 fn persist_last_processed_uid(
@@ -250,145 +747,792 @@ fn persist_last_processed_uid(
     }
 }
 
-fn ordered_uids(uids: impl IntoIterator<Item = u32>) -> Vec<u32> {
-    let mut ordered: Vec<u32> = uids.into_iter().collect();
-    ordered.sort_unstable();
-    ordered
+/// Batches IMAP UID persistence so a backlog does not write once per message.
+///
+/// The newest processed UID is written after every `batch_size` messages or
+/// once `interval` has elapsed since the last write, and always on
+/// [`UidCheckpoint::flush`]. UIDs processed after the last write are fetched
+/// again after a crash, so message handling must tolerate reprocessing.
+struct UidCheckpoint {
+    batch_size: usize,
+    interval: Duration,
+    persisted: ImapUid,
+    pending: Option<u32>,
+    pending_count: usize,
+    last_write: Instant,
 }
 
-pub async fn monitor_hub(
-    repo: DieselRepository,
-    hub: Hub,
-    domain: String,
-    zmq_sender: &ZmqSender,
-) -> Result<(), Error> {
-    let (imap_server, imap_port, username, password) =
-        match (&hub.imap_server, hub.imap_port, &hub.login, &hub.password) {
-            (Some(server), Some(port), Some(username), Some(password)) => (
-                server.as_str(),
-                port.get(),
-                username.as_str(),
-                password.as_str(),
-            ),
-            _ => {
-                return Err(Error::Config(format!(
-                    "Cannot get imap server and port for the hub#{}",
-                    hub.id
-                )));
-            }
-        };
+impl UidCheckpoint {
+    fn new(persisted: ImapUid, config: &UidPersistConfig, now: Instant) -> Self {
+        Self {
+            batch_size: config.batch_size.max(1),
+            interval: Duration::from_secs(config.interval_secs),
+            persisted,
+            pending: None,
+            pending_count: 0,
+            last_write: now,
+        }
+    }
 
-    let mut session = init_session(imap_server, imap_port, username, password).await?;
+    /// Records `uid` as processed, persisting it if the batch is due.
+    fn record(&mut self, repo: &(impl HubWriter + ?Sized), hub_id: HubId, uid: u32, now: Instant) {
+        self.pending = Some(uid);
+        self.pending_count += 1;
 
-    let mut last_uid: u32 = hub.imap_last_uid.get() as u32;
-    let mut persisted_uid = hub.imap_last_uid;
+        let interval_elapsed =
+            !self.interval.is_zero() && now.duration_since(self.last_write) >= self.interval;
+        if self.pending_count >= self.batch_size || interval_elapsed {
+            self.flush(repo, hub_id, now);
+        }
+    }
 
-    let initial_search = format!("UID {}:*", last_uid.saturating_add(1));
-    let initial_uids = match session.uid_search(&initial_search).await {
-        Ok(uids) => uids,
-        Err(e) => {
-            log::error!("Cannot fetch initial IMAP backlog in hub#{}: {e}", hub.id);
-            HashSet::<u32>::new()
+    /// Persists the newest recorded UID, if any.
+    fn flush(&mut self, repo: &(impl HubWriter + ?Sized), hub_id: HubId, now: Instant) {
+        if let Some(uid) = self.pending.take() {
+            persist_last_processed_uid(repo, hub_id, &mut self.persisted, uid);
         }
+        self.pending_count = 0;
+        self.last_write = now;
+    }
+}
+
+/// Reconciles the stored UID cursor with the mailbox `UIDVALIDITY`.
+///
+/// UIDs are only meaningful within one `UIDVALIDITY`; when the server reports
+/// a different value than the one stored (e.g. the mailbox was recreated),
+/// the cursor is reset to zero so the mailbox is scanned from the start, and
+/// recorded bounces are detached from their UIDs so new notifications that
+/// reuse them are still counted. Returns the cursor to resume from.
+fn reconcile_uid_validity(
+    repo: &(impl HubWriter + DeliveryWriter + ?Sized),
+    hub_id: HubId,
+    stored: Option<u32>,
+    current: Option<u32>,
+    last_uid: ImapUid,
+) -> ImapUid {
+    let Some(current) = current else {
+        return last_uid;
     };
 
-    let cutoff_uid = last_uid;
-    for uid in ordered_uids(initial_uids.into_iter())
-        .into_iter()
-        .filter(|&uid| uid != cutoff_uid)
+    let mut resume_from = last_uid;
+    if let Some(stored) = stored
+        && stored != current
+    {
+        log::warn!(
+            "UIDVALIDITY changed from {stored} to {current} in hub#{hub_id}; resetting IMAP cursor from {}",
+            last_uid.get()
+        );
+        match ImapUid::try_from(0) {
+            Ok(reset) => match repo.set_imap_last_uid(hub_id, reset) {
+                Ok(()) => resume_from = reset,
+                Err(err) => log::error!("Cannot reset IMAP last UID for hub#{hub_id}: {err}"),
+            },
+            Err(err) => log::error!("Cannot reset IMAP last UID for hub#{hub_id}: {err}"),
+        }
+        if let Err(err) = repo.forget_bounce_uids(hub_id) {
+            log::error!("Cannot detach recorded bounces from old UIDs in hub#{hub_id}: {err}");
+        }
+    }
+
+    if stored != Some(current)
+        && let Err(err) = repo.set_uid_validity(hub_id, current)
     {
-        process_new_message(&repo, &mut session, uid, &domain, hub.id, zmq_sender).await;
-        last_uid = uid;
-        persist_last_processed_uid(&repo, hub.id, &mut persisted_uid, uid);
+        log::error!("Cannot persist UIDVALIDITY {current} for hub#{hub_id}: {err}");
     }
 
-    log::info!("Starting a monitoring loop for hub#{}", hub.id);
-    loop {
-        let mut idle = session.idle();
-        if let Err(e) = idle.init().await {
-            log::error!("Idle start error in hub#{}: {e}", hub.id);
-            let _ = idle.done().await; // attempt to recover
-            return Err(e.into());
-        }
-        let (wait, stop) = idle.wait();
-        let keepalive = tokio::spawn(async move {
-            sleep(Duration::from_secs(60 * 29)).await;
-            drop(stop);
-        });
+    resume_from
+}
 
-        if let Err(e) = wait.await {
-            if let async_imap::error::Error::Io(ref io_err) = e {
-                if io_err.kind() == std::io::ErrorKind::TimedOut {
-                    // keepalive triggered; not a fatal error
-                } else {
-                    log::error!("Idle error in hub#{}: {e}", hub.id);
-                    let _ = idle.done().await;
-                    return Err(e.into());
-                }
-            } else {
-                log::error!("Idle error in hub#{}: {e}", hub.id);
-                let _ = idle.done().await;
-                return Err(e.into());
-            }
+fn ordered_uids(uids: impl IntoIterator<Item = u32>) -> Vec<u32> {
+    let mut ordered: Vec<u32> = uids.into_iter().collect();
+    ordered.sort_unstable();
+    ordered
+}
+
+/// The messages [`monitor_hub`] processes in one pass.
+#[derive(Debug, PartialEq, Eq)]
+struct BacklogBatch {
+    /// UIDs to process now, in the configured order.
+    uids: Vec<u32>,
+    /// Messages left for later passes.
+    remaining: usize,
+}
+
+/// Tracks progress through the messages after the persisted UID cursor.
+///
+/// The cursor only moves past a UID once every older message the last
+/// search found has been processed, so it stays monotonic and never skips
+/// older mail when newer messages are handled first. UIDs processed above
+/// the cursor are remembered and not handed out again.
+struct UidCursor {
+    last: u32,
+    /// Found but not yet processed UIDs above `last`.
+    outstanding: BTreeSet<u32>,
+    /// Processed UIDs above `last`.
+    done: BTreeSet<u32>,
+}
+
+impl UidCursor {
+    fn new(last: u32) -> Self {
+        Self {
+            last,
+            outstanding: BTreeSet::new(),
+            done: BTreeSet::new(),
         }
+    }
 
-        keepalive.abort();
-        let _ = keepalive.await;
-        session = match idle.done().await {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("Idle done error in hub#{}: {e}", hub.id);
-                return Err(e.into());
-            }
-        };
+    /// Highest UID below which every message has been processed.
+    fn last(&self) -> u32 {
+        self.last
+    }
 
-        let search_query = format!("UID {}:*", last_uid.saturating_add(1));
-        let new_uids = match session.uid_search(&search_query).await {
-            Ok(uids) => uids,
-            Err(e) => {
-                log::error!("Cannot search new emails in hub#{}: {e}", hub.id);
-                continue;
+    /// Picks the next pass from the UIDs a `UID {last + 1}:*` search found.
+    ///
+    /// UIDs at or below the cursor are dropped: servers answer that search
+    /// with the newest message even when it is older than the cursor. At most
+    /// `backlog.batch_size` UIDs are taken, oldest or newest first per
+    /// `backlog.order`.
+    fn next_batch(
+        &mut self,
+        found: impl IntoIterator<Item = u32>,
+        backlog: &BacklogConfig,
+    ) -> BacklogBatch {
+        self.outstanding = found
+            .into_iter()
+            .filter(|uid| *uid > self.last && !self.done.contains(uid))
+            .collect();
+
+        let limit = backlog.batch_size.map_or(usize::MAX, |size| size.max(1));
+        let uids: Vec<u32> = match backlog.order {
+            BacklogOrder::OldestFirst => self.outstanding.iter().copied().take(limit).collect(),
+            BacklogOrder::NewestFirst => {
+                self.outstanding.iter().rev().copied().take(limit).collect()
             }
         };
+        BacklogBatch {
+            remaining: self.outstanding.len() - uids.len(),
+            uids,
+        }
+    }
 
-        for uid in ordered_uids(new_uids.into_iter()) {
-            process_new_message(&repo, &mut session, uid, &domain, hub.id, zmq_sender).await;
-            last_uid = uid;
-            persist_last_processed_uid(&repo, hub.id, &mut persisted_uid, uid);
+    /// Marks `uid` as processed and returns the new cursor when it advanced.
+    fn complete(&mut self, uid: u32) -> Option<u32> {
+        self.outstanding.remove(&uid);
+        self.done.insert(uid);
+
+        let before = self.last;
+        while let Some(&next) = self.done.first() {
+            if self.outstanding.first().is_some_and(|&older| older < next) {
+                break;
+            }
+            self.done.pop_first();
+            self.last = next;
         }
+        (self.last != before).then_some(self.last)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pushkind_common::repository::errors::RepositoryResult;
-    use pushkind_emailer::domain::types::{HubId, ImapUid};
-    use std::sync::{Arc, Mutex};
+/// Inclusive range of IMAP UIDs requested for reprocessing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidRange {
+    from: u32,
+    to: u32,
+}
 
-    #[derive(Clone, Default)]
-    struct RecordingHubWriter {
-        calls: Arc<Mutex<Vec<(HubId, ImapUid)>>>,
+impl UidRange {
+    /// Creates a range, rejecting `0` and reversed bounds.
+    pub fn new(from: u32, to: u32) -> Result<Self, Error> {
+        if from == 0 || from > to {
+            return Err(Error::Config(format!("Invalid UID range {from}:{to}")));
+        }
+        Ok(Self { from, to })
     }
 
-    impl HubWriter for RecordingHubWriter {
-        fn set_imap_last_uid(&self, hub_id: HubId, uid: ImapUid) -> RepositoryResult<()> {
-            self.calls
-                .lock()
-                .expect("lock poisoned")
-                .push((hub_id, uid));
-            Ok(())
-        }
+    fn search_query(&self) -> String {
+        format!("UID {}:{}", self.from, self.to)
     }
 
-    fn persist_uids_in_order(
-        repo: &(impl HubWriter + ?Sized),
-        hub_id: HubId,
-        last_uid: &mut u32,
-        persisted_uid: &mut ImapUid,
-        uids: impl IntoIterator<Item = u32>,
-    ) {
-        for uid in ordered_uids(uids) {
+    /// Orders the UIDs returned by the server and drops those outside the
+    /// range; servers may answer `UID n:m` with the newest message even when
+    /// it lies below `n`.
+    fn plan(&self, found: impl IntoIterator<Item = u32>) -> Vec<u32> {
+        ordered_uids(
+            found
+                .into_iter()
+                .filter(|uid| (self.from..=self.to).contains(uid)),
+        )
+    }
+}
+
+impl std::fmt::Display for UidRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.from, self.to)
+    }
+}
+
+/// Capability advertised by servers supporting Gmail's IMAP extensions,
+/// including `X-GM-RAW` search.
+const GMAIL_EXTENSION: &str = "X-GM-EXT-1";
+
+/// Returns the configured `X-GM-RAW` query for a hub, or `None` when the
+/// server lacks Gmail's extensions or no query is configured.
+fn gmail_raw_query(config: &ImapSearchConfig, gmail: bool, domain: &str) -> Option<String> {
+    config
+        .gmail_raw
+        .as_deref()
+        .map(str::trim)
+        .filter(|query| gmail && !query.is_empty())
+        .map(|query| query.replace("{domain}", domain))
+}
+
+/// Returns the `UID SEARCH` query for messages after `last_uid`, narrowed
+/// by `gmail_raw` when set.
+fn new_mail_query(last_uid: u32, gmail_raw: Option<&str>) -> String {
+    let range = format!("UID {}:*", last_uid.saturating_add(1));
+    match gmail_raw {
+        Some(query) => format!(
+            "{range} X-GM-RAW \"{}\"",
+            query.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => range,
+    }
+}
+
+/// Returns whether the server advertises Gmail's IMAP extensions. A failed
+/// `CAPABILITY` is logged and treated as a non-Gmail server.
+async fn supports_gmail_search(session: &mut Session<TlsStream<TcpStream>>, hub: &Hub) -> bool {
+    match session.capabilities().await {
+        Ok(capabilities) => capabilities.has_str(GMAIL_EXTENSION),
+        Err(e) => {
+            log::warn!("Cannot read IMAP capabilities of hub#{}: {e}", hub.id);
+            false
+        }
+    }
+}
+
+async fn connect_hub(
+    hub: &Hub,
+    config: &ServerConfig,
+) -> Result<(Session<TlsStream<TcpStream>>, Option<u32>), Error> {
+    let (imap_server, imap_port) = match (&hub.imap_server, hub.imap_port) {
+        (Some(server), Some(port)) => (server.as_str(), port.get()),
+        _ => {
+            return Err(Error::Config(format!(
+                "Cannot get imap server and port for the hub#{}",
+                hub.id
+            )));
+        }
+    };
+    let key = config.password_key()?;
+    let settings = config.hub_settings(hub.id);
+    let credentials = resolve_credentials(hub, &settings, &SystemSecrets, key.as_ref()).await?;
+
+    init_session(
+        imap_server,
+        imap_port,
+        &credentials.login,
+        &credentials.password,
+        settings.bind_address,
+    )
+    .await
+}
+
+/// Fetches the full RFC822 source of the message with `uid` in the hub's
+/// INBOX so an operator can inspect the original reply.
+///
+/// Opens a dedicated IMAP session and leaves the message unread. Messages
+/// over [`MAX_RAW_MESSAGE_BYTES`] are rejected.
+pub async fn fetch_reply_source(
+    hub: &Hub,
+    uid: u32,
+    config: &ServerConfig,
+) -> Result<Vec<u8>, Error> {
+    let (mut session, _) = connect_hub(hub, config).await?;
+    let raw = fetch_raw_message(&mut session, uid, MAX_RAW_MESSAGE_BYTES).await;
+
+    if let Err(e) = session.logout().await {
+        log::warn!("IMAP logout failed for hub#{}: {e}", hub.id);
+    }
+    raw
+}
+
+/// Runs the messages in `range` through [`process_new_message`] again.
+///
+/// Used to recover replies missed because of a bug. The hub's UID cursor is
+/// neither consulted nor updated. Bounces are recorded once per message, and
+/// replies and unsubscribes are idempotent in the database; the ZeroMQ
+/// events for the range are published again. Returns the number of messages
+/// processed.
+pub async fn reprocess_range(
+    repo: &DieselRepository,
+    hub: &Hub,
+    range: UidRange,
+    config: &ServerConfig,
+    publisher: &(impl ReplyPublisher + ?Sized),
+    classifier: &(impl ReplyClassifier + ?Sized),
+) -> Result<usize, Error> {
+    let (mut session, _) = connect_hub(hub, config).await?;
+
+    let found = session.uid_search(&range.search_query()).await?;
+    let uids = range.plan(found);
+    log::info!(
+        "Reprocessing {} message(s) in UID range {} for hub#{}",
+        uids.len(),
+        range,
+        hub.id
+    );
+
+    for &uid in &uids {
+        process_new_message(
+            repo,
+            &mut session,
+            uid,
+            config,
+            hub.id,
+            publisher,
+            classifier,
+        )
+        .await;
+    }
+
+    if let Err(e) = session.logout().await {
+        log::warn!("IMAP logout failed for hub#{}: {e}", hub.id);
+    }
+
+    Ok(uids.len())
+}
+
+/// How long an IDLE command runs before it is restarted, below the 30
+/// minutes after which RFC 2177 lets servers drop an idle client.
+const IDLE_KEEPALIVE: Duration = Duration::from_secs(60 * 29);
+
+/// Returns `true` when an IDLE wait error comes from our own keepalive
+/// stopping the IDLE rather than from a broken connection.
+///
+/// Once the keepalive has fired, any error is expected: depending on the TLS
+/// and `async-imap` versions the interrupted read surfaces as a timeout, a
+/// reset or something else. A timeout is also accepted on its own, in case
+/// the error wins the race with the keepalive flag.
+fn is_keepalive_idle_end(err: &async_imap::error::Error, keepalive_fired: bool) -> bool {
+    keepalive_fired
+        || matches!(
+            err,
+            async_imap::error::Error::Io(io_err) if io_err.kind() == std::io::ErrorKind::TimedOut
+        )
+}
+
+/// Decides when a quiet IMAP connection needs a NOOP under
+/// `imap_keepalive.noop_interval_secs`.
+struct NoopSchedule {
+    interval: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl NoopSchedule {
+    fn new(interval: Option<Duration>, now: Instant) -> Self {
+        Self {
+            interval,
+            last_activity: now,
+        }
+    }
+
+    /// Notes that a command was sent to the server at `now`.
+    fn touch(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Returns `true` once the connection has been quiet for the interval.
+    fn is_due(&self, now: Instant) -> bool {
+        self.interval
+            .is_some_and(|interval| now.duration_since(self.last_activity) >= interval)
+    }
+
+    /// How long an IDLE may run before it is restarted.
+    fn idle_timeout(&self) -> Duration {
+        self.interval
+            .map_or(IDLE_KEEPALIVE, |interval| interval.min(IDLE_KEEPALIVE))
+    }
+}
+
+/// Sends a NOOP when `schedule` says the connection has been quiet too long.
+async fn noop_if_due(
+    session: &mut Session<TlsStream<TcpStream>>,
+    schedule: &mut NoopSchedule,
+    hub: &Hub,
+) -> Result<(), Error> {
+    if !schedule.is_due(Instant::now()) {
+        return Ok(());
+    }
+    session.noop().await.map_err(|e| {
+        log::error!("NOOP keepalive failed in hub#{}: {e}", hub.id);
+        Error::from(e)
+    })?;
+    log::debug!("Sent NOOP keepalive in hub#{}", hub.id);
+    schedule.touch(Instant::now());
+    Ok(())
+}
+
+pub async fn monitor_hub(
+    repo: DieselRepository,
+    hub: Hub,
+    config: Arc<ServerConfig>,
+    publisher: &(impl ReplyPublisher + ?Sized),
+    classifier: &(impl ReplyClassifier + ?Sized),
+) -> Result<(), Error> {
+    let (mut session, uid_validity) = connect_hub(&hub, &config).await?;
+
+    let stored_validity = match repo.get_uid_validity(hub.id) {
+        Ok(stored) => stored,
+        Err(err) => {
+            log::error!("Cannot load UIDVALIDITY for hub#{}: {err}", hub.id);
+            None
+        }
+    };
+    let start_uid = reconcile_uid_validity(
+        &repo,
+        hub.id,
+        stored_validity,
+        uid_validity,
+        hub.imap_last_uid,
+    );
+
+    let settings = config.hub_settings(hub.id);
+    let gmail_raw = match &config.imap_search.gmail_raw {
+        Some(_) => gmail_raw_query(
+            &config.imap_search,
+            supports_gmail_search(&mut session, &hub).await,
+            settings.domain_or(&config.domain),
+        ),
+        None => None,
+    };
+    if let Some(query) = &gmail_raw {
+        log::info!("Searching hub#{} with X-GM-RAW {query:?}", hub.id);
+    }
+
+    let mut cursor = UidCursor::new(start_uid.get() as u32);
+    let mut checkpoint = UidCheckpoint::new(start_uid, &config.uid_persist, Instant::now());
+    let mut noop = NoopSchedule::new(config.imap_keepalive.noop_interval(), Instant::now());
+    let mut pacer = ImapPacer::new(settings.imap_ops_per_minute);
+
+    // The backlog is worked through in batches before the first IDLE; each
+    // batch is followed by a fresh search, so the cursor is persisted per
+    // batch and newly arrived mail joins the queue.
+    let mut backlog_pending = true;
+
+    log::info!("Starting a monitoring loop for hub#{}", hub.id);
+    loop {
+        if !backlog_pending {
+            noop.touch(Instant::now());
+            session = idle_until_change(session, &hub, noop.idle_timeout()).await?;
+            // An IDLE that ran the whole interval was ended by the timeout.
+            noop_if_due(&mut session, &mut noop, &hub).await?;
+        }
+
+        let search_query = new_mail_query(cursor.last(), gmail_raw.as_deref());
+        pacer.pace().await;
+        let found = match session.uid_search(&search_query).await {
+            Ok(uids) => uids,
+            Err(e) => {
+                log::error!("Cannot search new emails in hub#{}: {e}", hub.id);
+                backlog_pending = false;
+                continue;
+            }
+        };
+
+        let batch = cursor.next_batch(found, &config.backlog);
+        // A single new message is live traffic and is published right away.
+        let batched = BatchingPublisher::new(
+            publisher,
+            hub.id,
+            config.backlog.batch_notifications && batch.uids.len() > 1,
+        );
+        // Batched notifications exist only in memory until the flush, so the
+        // cursor may not be persisted past them before it.
+        let mut unflushed = None;
+        noop.touch(Instant::now());
+        for uid in batch.uids {
+            // Slow processing can leave the connection quiet between fetches.
+            noop_if_due(&mut session, &mut noop, &hub).await?;
+            pacer.pace().await;
+            noop.touch(Instant::now());
+            process_new_message(
+                &repo,
+                &mut session,
+                uid,
+                &config,
+                hub.id,
+                &batched,
+                classifier,
+            )
+            .await;
+            if let Some(last) = cursor.complete(uid) {
+                if batched.is_batching() {
+                    unflushed = Some(last);
+                } else {
+                    checkpoint.record(&repo, hub.id, last, Instant::now());
+                }
+            }
+        }
+        batched.flush(&config).await;
+        if let Some(last) = unflushed {
+            checkpoint.record(&repo, hub.id, last, Instant::now());
+        }
+        checkpoint.flush(&repo, hub.id, Instant::now());
+
+        backlog_pending = batch.remaining > 0;
+        if backlog_pending {
+            log::info!(
+                "{} backlog message(s) left in hub#{}; continuing with the next batch",
+                batch.remaining,
+                hub.id
+            );
+        }
+    }
+}
+
+/// Runs one IDLE command until the server reports a change or `timeout`
+/// restarts it, and returns the session ready for further commands.
+async fn idle_until_change(
+    session: Session<TlsStream<TcpStream>>,
+    hub: &Hub,
+    timeout: Duration,
+) -> Result<Session<TlsStream<TcpStream>>, Error> {
+    let mut idle = session.idle();
+    if let Err(e) = idle.init().await {
+        log::error!("Idle start error in hub#{}: {e}", hub.id);
+        let _ = idle.done().await; // attempt to recover
+        return Err(e.into());
+    }
+    let (wait, stop) = idle.wait();
+    let keepalive_fired = Arc::new(AtomicBool::new(false));
+    let keepalive = tokio::spawn({
+        let keepalive_fired = Arc::clone(&keepalive_fired);
+        async move {
+            sleep(timeout).await;
+            keepalive_fired.store(true, Ordering::SeqCst);
+            drop(stop);
+        }
+    });
+
+    if let Err(e) = wait.await {
+        if is_keepalive_idle_end(&e, keepalive_fired.load(Ordering::SeqCst)) {
+            log::debug!("IDLE keepalive ended the wait in hub#{}: {e}", hub.id);
+        } else {
+            log::error!("Idle error in hub#{}: {e}", hub.id);
+            let _ = idle.done().await;
+            return Err(e.into());
+        }
+    }
+
+    keepalive.abort();
+    let _ = keepalive.await;
+    idle.done().await.map_err(|e| {
+        log::error!("Idle done error in hub#{}: {e}", hub.id);
+        Error::from(e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_reply::classifier::KeywordClassifier;
+    use crate::domain::{BounceStats, HubDailyStats, Pagination};
+    use pushkind_common::repository::errors::RepositoryResult;
+    use pushkind_emailer::domain::email::{EmailWithRecipients, NewEmail, NewEmailRecipient};
+    use pushkind_emailer::domain::types::{
+        EmailBody, HubId, ImapUid, RecipientEmail, RecipientName,
+    };
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    struct InMemoryDeliveries {
+        sent: i64,
+        bounced: Mutex<i64>,
+        bounce_uids: Mutex<HashSet<u32>>,
+    }
+
+    impl InMemoryDeliveries {
+        fn new(sent: i64) -> Self {
+            Self {
+                sent,
+                bounced: Mutex::new(0),
+                bounce_uids: Mutex::new(HashSet::new()),
+            }
+        }
+    }
+
+    impl DeliveryReader for InMemoryDeliveries {
+        fn bounce_stats(
+            &self,
+            _hub_id: HubId,
+            _since: chrono::NaiveDateTime,
+        ) -> RepositoryResult<BounceStats> {
+            Ok(BounceStats {
+                sent: self.sent,
+                bounced: *self.bounced.lock().expect("lock poisoned"),
+            })
+        }
+
+        fn count_delivery_events(
+            &self,
+            hub_id: HubId,
+            kind: DeliveryEventKind,
+            since: chrono::NaiveDateTime,
+        ) -> RepositoryResult<i64> {
+            let stats = self.bounce_stats(hub_id, since)?;
+            Ok(match kind {
+                DeliveryEventKind::Sent => stats.sent,
+                DeliveryEventKind::Bounce => stats.bounced,
+                DeliveryEventKind::Opened | DeliveryEventKind::Replied => 0,
+            })
+        }
+
+        fn hub_daily_stats(
+            &self,
+            _hub_id: HubId,
+            _from: chrono::NaiveDate,
+            _to: chrono::NaiveDate,
+        ) -> RepositoryResult<Vec<HubDailyStats>> {
+            Ok(Vec::new())
+        }
+
+        fn is_reply_recorded(&self, _hub_id: HubId, _message_id: &str) -> RepositoryResult<bool> {
+            Ok(false)
+        }
+    }
+
+    impl DeliveryWriter for InMemoryDeliveries {
+        fn record_delivery_event(
+            &self,
+            _hub_id: HubId,
+            kind: DeliveryEventKind,
+        ) -> RepositoryResult<()> {
+            if kind == DeliveryEventKind::Bounce {
+                *self.bounced.lock().expect("lock poisoned") += 1;
+            }
+            Ok(())
+        }
+
+        fn record_bounce_once(&self, hub_id: HubId, uid: u32) -> RepositoryResult<bool> {
+            if !self.bounce_uids.lock().expect("lock poisoned").insert(uid) {
+                return Ok(false);
+            }
+            self.record_delivery_event(hub_id, DeliveryEventKind::Bounce)?;
+            Ok(true)
+        }
+
+        fn reserve_delivery_event(
+            &self,
+            hub_id: HubId,
+            kind: DeliveryEventKind,
+            _since: chrono::NaiveDateTime,
+            _cap: i64,
+        ) -> RepositoryResult<Option<i32>> {
+            self.record_delivery_event(hub_id, kind)?;
+            Ok(Some(0))
+        }
+
+        fn delete_delivery_event(&self, _event_id: i32) -> RepositoryResult<()> {
+            Ok(())
+        }
+
+        fn forget_bounce_uids(&self, _hub_id: HubId) -> RepositoryResult<usize> {
+            let mut uids = self.bounce_uids.lock().expect("lock poisoned");
+            let detached = uids.len();
+            uids.clear();
+            Ok(detached)
+        }
+
+        fn record_reply_once(
+            &self,
+            _hub_id: HubId,
+            _message_id: &str,
+            _keep: usize,
+        ) -> RepositoryResult<bool> {
+            Ok(true)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingHubWriter {
+        calls: Arc<Mutex<Vec<(HubId, ImapUid)>>>,
+        validities: Arc<Mutex<Vec<u32>>>,
+        /// Number of `forget_bounce_uids` calls.
+        bounce_resets: Arc<Mutex<usize>>,
+    }
+
+    impl HubWriter for RecordingHubWriter {
+        fn set_imap_last_uid(&self, hub_id: HubId, uid: ImapUid) -> RepositoryResult<()> {
+            self.calls
+                .lock()
+                .expect("lock poisoned")
+                .push((hub_id, uid));
+            Ok(())
+        }
+
+        fn set_uid_validity(&self, _hub_id: HubId, uid_validity: u32) -> RepositoryResult<()> {
+            self.validities
+                .lock()
+                .expect("lock poisoned")
+                .push(uid_validity);
+            Ok(())
+        }
+
+        fn set_sending_paused(&self, _hub_id: HubId, _paused: bool) -> RepositoryResult<()> {
+            Ok(())
+        }
+    }
+
+    impl DeliveryWriter for RecordingHubWriter {
+        fn record_delivery_event(
+            &self,
+            _hub_id: HubId,
+            _kind: DeliveryEventKind,
+        ) -> RepositoryResult<()> {
+            Ok(())
+        }
+
+        fn record_bounce_once(&self, _hub_id: HubId, _uid: u32) -> RepositoryResult<bool> {
+            Ok(true)
+        }
+
+        fn reserve_delivery_event(
+            &self,
+            _hub_id: HubId,
+            _kind: DeliveryEventKind,
+            _since: chrono::NaiveDateTime,
+            _cap: i64,
+        ) -> RepositoryResult<Option<i32>> {
+            Ok(Some(0))
+        }
+
+        fn delete_delivery_event(&self, _event_id: i32) -> RepositoryResult<()> {
+            Ok(())
+        }
+
+        fn forget_bounce_uids(&self, _hub_id: HubId) -> RepositoryResult<usize> {
+            *self.bounce_resets.lock().expect("lock poisoned") += 1;
+            Ok(0)
+        }
+
+        fn record_reply_once(
+            &self,
+            _hub_id: HubId,
+            _message_id: &str,
+            _keep: usize,
+        ) -> RepositoryResult<bool> {
+            Ok(true)
+        }
+    }
+
+    fn persist_uids_in_order(
+        repo: &(impl HubWriter + ?Sized),
+        hub_id: HubId,
+        last_uid: &mut u32,
+        persisted_uid: &mut ImapUid,
+        uids: impl IntoIterator<Item = u32>,
+    ) {
+        for uid in ordered_uids(uids) {
             *last_uid = uid;
             persist_last_processed_uid(repo, hub_id, persisted_uid, uid);
         }
@@ -440,4 +1584,1256 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn bounce_trips_breaker_once_threshold_is_reached() {
+        let repo = InMemoryDeliveries::new(20);
+        let breaker = BounceBreakerConfig {
+            threshold: 0.1,
+            window_hours: 24,
+            min_sent: 20,
+        };
+        let hub_id = HubId::try_from(1).unwrap();
+
+        let tripped: Vec<bool> = (1..=3)
+            .map(|uid| record_bounce(&repo, hub_id, uid, &breaker))
+            .collect();
+
+        assert_eq!(tripped, vec![false, true, true]);
+        assert_eq!(*repo.bounced.lock().expect("lock poisoned"), 3);
+    }
+
+    #[test]
+    fn reprocessed_bounce_is_not_counted_twice() {
+        let repo = InMemoryDeliveries::new(100);
+        let breaker = BounceBreakerConfig::default();
+        let hub_id = HubId::try_from(1).unwrap();
+
+        record_bounce(&repo, hub_id, 10, &breaker);
+        record_bounce(&repo, hub_id, 10, &breaker);
+
+        assert_eq!(*repo.bounced.lock().expect("lock poisoned"), 1);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Published {
+        Reply(String),
+        Unsubscribe(String),
+    }
+
+    #[derive(Default)]
+    struct RecordingPublisher {
+        published: Mutex<Vec<Published>>,
+        /// JSON payloads of published replies.
+        replies: Mutex<Vec<serde_json::Value>>,
+        /// Reasons of published unsubscribes.
+        unsubscribe_reasons: Mutex<Vec<Option<String>>>,
+        /// Addresses sent an unsubscribe confirmation.
+        confirmations: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ReplyPublisher for RecordingPublisher {
+        async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error> {
+            self.published
+                .lock()
+                .expect("lock poisoned")
+                .push(Published::Reply(message.email.clone()));
+            self.replies
+                .lock()
+                .expect("lock poisoned")
+                .push(serde_json::to_value(message)?);
+            Ok(())
+        }
+
+        async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+            self.published
+                .lock()
+                .expect("lock poisoned")
+                .push(Published::Unsubscribe(message.email.clone()));
+            self.unsubscribe_reasons
+                .lock()
+                .expect("lock poisoned")
+                .push(message.reason.clone());
+            Ok(())
+        }
+
+        async fn send_unsubscribe_confirmation(
+            &self,
+            _hub_id: HubId,
+            email: &str,
+        ) -> Result<(), Error> {
+            self.confirmations
+                .lock()
+                .expect("lock poisoned")
+                .push(email.to_string());
+            Ok(())
+        }
+    }
+
+    /// Fails the first `failures` publishes, then succeeds.
+    struct FlakyPublisher {
+        failures: u32,
+        calls: Mutex<u32>,
+    }
+
+    impl FlakyPublisher {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures,
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn attempt(&self) -> Result<(), Error> {
+            let mut calls = self.calls.lock().expect("lock poisoned");
+            *calls += 1;
+            if *calls <= self.failures {
+                Err(Error::Config("socket busy".into()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ReplyPublisher for FlakyPublisher {
+        async fn send_reply(&self, _message: &ZMQReplyMessage) -> Result<(), Error> {
+            self.attempt()
+        }
+
+        async fn send_unsubscribe(&self, _message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+            self.attempt()
+        }
+    }
+
+    fn retry_config(attempts: u32) -> PublishRetryConfig {
+        PublishRetryConfig {
+            attempts,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_retries_until_success() {
+        let publisher = FlakyPublisher::new(2);
+        let message = ZMQReplyMessage {
+            hub_id: 1,
+            email: "user@example.org".into(),
+            message: "Thanks".into(),
+            subject: None,
+        };
+
+        let result = publish_with_retry(&retry_config(3), || publisher.send_reply(&message)).await;
+
+        assert!(result.is_ok());
+        assert_eq!(*publisher.calls.lock().expect("lock poisoned"), 3);
+    }
+
+    #[tokio::test]
+    async fn publish_gives_up_after_configured_attempts() {
+        let publisher = FlakyPublisher::new(5);
+        let message = ZMQUnsubscribeMessage {
+            hub_id: 1,
+            email: "user@example.org".into(),
+            reason: None,
+        };
+
+        let result =
+            publish_with_retry(&retry_config(3), || publisher.send_unsubscribe(&message)).await;
+
+        assert!(result.is_err());
+        assert_eq!(*publisher.calls.lock().expect("lock poisoned"), 3);
+    }
+
+    #[tokio::test]
+    async fn undeliverable_reply_is_spooled_and_flushed_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            publish_retry: retry_config(2),
+            spool: Some(SpoolConfig {
+                dir: dir.path().to_path_buf(),
+                flush_interval_secs: 1,
+            }),
+            ..ServerConfig::default()
+        };
+        let hub_id = HubId::try_from(1).unwrap();
+
+        let down = FlakyPublisher::new(u32::MAX);
+        send_reply_message(&down, hub_id, "user@example.org", Some("Hi"), None, &config).await;
+        assert_eq!(*down.calls.lock().expect("lock poisoned"), 2);
+
+        let recovered = RecordingPublisher::default();
+        let flushed = Spool::new(dir.path()).flush(&recovered).await.unwrap();
+        assert_eq!(flushed, 1);
+        assert_eq!(
+            recovered.published.into_inner().unwrap(),
+            vec![Published::Reply("user@example.org".into())]
+        );
+    }
+
+    fn setup_repo() -> (tempfile::TempDir, DieselRepository) {
+        let (dir, pool) = setup_pool();
+        (dir, DieselRepository::new(pool))
+    }
+
+    /// Hub 1's email to `addresses`.
+    fn new_email(addresses: &[&str]) -> NewEmail {
+        NewEmail {
+            message: EmailBody::new("Hello").unwrap(),
+            subject: None,
+            attachment: None,
+            attachment_name: None,
+            attachment_mime: None,
+            hub_id: HubId::try_from(1).unwrap(),
+            recipients: addresses
+                .iter()
+                .map(|address| NewEmailRecipient {
+                    address: RecipientEmail::try_from(*address).unwrap(),
+                    name: RecipientName::new("Alice").unwrap(),
+                    fields: Default::default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Stores hub 1's email to `addresses`.
+    fn email_with_recipients(repo: &DieselRepository, addresses: &[&str]) -> EmailWithRecipients {
+        repo.create_email(&new_email(addresses)).unwrap()
+    }
+
+    fn setup_pool() -> (tempfile::TempDir, pushkind_common::db::DbPool) {
+        use diesel::connection::SimpleConnection;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let pool =
+            pushkind_common::db::establish_connection_pool(db_path.to_str().unwrap()).unwrap();
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE emails (id INTEGER PRIMARY KEY, message TEXT NOT NULL, created_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, subject TEXT, attachment BLOB, attachment_name TEXT, attachment_mime TEXT, num_sent INTEGER NOT NULL DEFAULT 0, num_opened INTEGER NOT NULL DEFAULT 0, num_replied INTEGER NOT NULL DEFAULT 0, hub_id INTEGER NOT NULL);\n\
+                 CREATE TABLE email_recipients (id INTEGER PRIMARY KEY, email_id INTEGER NOT NULL REFERENCES emails(id), address TEXT NOT NULL, opened BOOL NOT NULL, updated_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, reply TEXT, name TEXT, fields TEXT);\n\
+                 CREATE TABLE unsubscribes (email TEXT NOT NULL, hub_id INTEGER NOT NULL, reason TEXT, created_at TIMESTAMP, PRIMARY KEY (email, hub_id));",
+            )
+            .unwrap();
+        crate::db::ensure_schema(&pool).unwrap();
+        (dir, pool)
+    }
+
+    fn handle_config() -> ServerConfig {
+        ServerConfig {
+            domain: "example.com".into(),
+            ..ServerConfig::default()
+        }
+    }
+
+    async fn handle(raw: &str) -> (Vec<Published>, i64) {
+        handle_with(raw, &handle_config()).await
+    }
+
+    async fn handle_with(raw: &str, config: &ServerConfig) -> (Vec<Published>, i64) {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+
+        handle_message(
+            &repo,
+            raw.as_bytes(),
+            1,
+            config,
+            hub_id,
+            &publisher,
+            &KeywordClassifier::default(),
+        )
+        .await;
+
+        let since = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let bounced = repo.bounce_stats(hub_id, since).unwrap().bounced;
+        (publisher.published.into_inner().unwrap(), bounced)
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_request_publishes_unsubscribe() {
+        let (published, bounced) = handle(
+            "Subject: unsubscribe\r\nFrom: User <user@example.org>\r\nContent-Type: text/plain\r\n\r\nplease\r\n",
+        )
+        .await;
+
+        assert_eq!(
+            published,
+            vec![Published::Unsubscribe("user@example.org".into())]
+        );
+        assert_eq!(bounced, 0);
+    }
+
+    fn complaint_report(feedback_type: &str, rcpt_to: &str, message_id: Option<&str>) -> String {
+        let message_id = message_id
+            .map(|id| format!("Message-ID: <{id}>\r\n"))
+            .unwrap_or_default();
+        format!(
+            "Subject: Abuse report\r\nFrom: feedback@provider.example\r\nContent-Type: multipart/report; report-type=feedback-report; boundary=\"arf\"\r\n\r\n--arf\r\nContent-Type: text/plain\r\n\r\nAbuse report\r\n--arf\r\nContent-Type: message/feedback-report\r\n\r\nFeedback-Type: {feedback_type}\r\nVersion: 1\r\nOriginal-Rcpt-To: {rcpt_to}\r\n--arf\r\nContent-Type: text/rfc822-headers\r\n\r\nFrom: sender@example.com\r\nTo: {rcpt_to}\r\n{message_id}Subject: Offer\r\n--arf--\r\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn complaint_report_unsubscribes_the_recipient() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let stored = email_with_recipients(&repo, &["user@example.org"]);
+        let message_id = format!("{}.token@example.com", stored.recipients[0].id.get());
+
+        // The report names a redacted address; the recipient the returned
+        // Message-ID maps to is unsubscribed instead.
+        for feedback_type in ["abuse", "not-spam"] {
+            let raw = complaint_report(feedback_type, "redacted@example.org", Some(&message_id));
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                1,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        assert_eq!(
+            publisher.published.into_inner().unwrap(),
+            vec![Published::Unsubscribe("user@example.org".into())]
+        );
+        assert_eq!(
+            publisher.unsubscribe_reasons.into_inner().unwrap(),
+            vec![Some("complaint: abuse".to_string())]
+        );
+        assert!(!repo.is_suppressed("redacted@example.org", hub_id).unwrap());
+        let entries = repo
+            .list_unsubscribes(
+                hub_id,
+                Pagination {
+                    offset: 0,
+                    limit: 10,
+                },
+            )
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].email, "user@example.org");
+        assert_eq!(entries[0].source, Some(UnsubscribeSource::Complaint));
+    }
+
+    #[tokio::test]
+    async fn complaint_report_without_known_message_is_ignored() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        for message_id in [None, Some("999.token@example.com")] {
+            let raw = complaint_report("abuse", "victim@example.org", message_id);
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                1,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        assert!(publisher.published.into_inner().unwrap().is_empty());
+        assert!(!repo.is_suppressed("victim@example.org", hub_id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn repeated_message_id_is_processed_once() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let reply = |message_id: &str| {
+            format!(
+                "Subject: Re: Offer\r\nFrom: <user@example.org>\r\nMessage-ID: <{message_id}>\r\nContent-Type: text/plain\r\n\r\nInterested!\r\n"
+            )
+        };
+        for (uid, message_id) in [
+            (1, "a@mail.example.org"),
+            (2, "a@mail.example.org"),
+            (3, "b@mail.example.org"),
+        ] {
+            handle_message(
+                &repo,
+                reply(message_id).as_bytes(),
+                uid,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        assert_eq!(
+            publisher.published.into_inner().unwrap(),
+            vec![
+                Published::Reply("user@example.org".into()),
+                Published::Reply("user@example.org".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_to_unknown_recipient_is_published_once() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let raw = "Subject: Re: Offer\r\nFrom: <user@example.org>\r\nMessage-ID: <a@mail.example.org>\r\nIn-Reply-To: <999.token@example.com>\r\nContent-Type: text/plain\r\n\r\nInterested!\r\n";
+        for uid in [1, 2] {
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                uid,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        assert!(
+            repo.is_reply_recorded(hub_id, "a@mail.example.org")
+                .unwrap()
+        );
+        assert_eq!(
+            publisher.published.into_inner().unwrap(),
+            vec![Published::Reply("user@example.org".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn vacation_auto_reply_is_not_counted_as_a_reply() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let raw = "Subject: Auto: Re: Offer\r\nFrom: <user@example.org>\r\nIn-Reply-To: <1@example.com>\r\nAuto-Submitted: auto-replied\r\nContent-Type: text/plain\r\n\r\nI am on vacation.\r\n";
+        handle_message(
+            &repo,
+            raw.as_bytes(),
+            1,
+            &handle_config(),
+            hub_id,
+            &publisher,
+            &KeywordClassifier::default(),
+        )
+        .await;
+
+        assert!(publisher.published.into_inner().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_is_confirmed_once_and_never_for_auto_replies() {
+        let (_dir, repo) = setup_repo();
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let messages = [
+            "Subject: unsubscribe\r\nFrom: <user@example.org>\r\nContent-Type: text/plain\r\n\r\nplease\r\n",
+            "Subject: Unsubscribe\r\nFrom: <user@example.org>\r\nContent-Type: text/plain\r\n\r\nagain\r\n",
+            "Subject: unsubscribe\r\nFrom: <robot@example.org>\r\nAuto-Submitted: auto-replied\r\nContent-Type: text/plain\r\n\r\naway\r\n",
+        ];
+        for raw in messages {
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                1,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        assert_eq!(
+            publisher.confirmations.into_inner().unwrap(),
+            vec!["user@example.org"]
+        );
+        assert_eq!(publisher.published.into_inner().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_reply_body_is_captured_as_reason() {
+        use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+        use pushkind_emailer::schema::unsubscribes;
+
+        let (_dir, pool) = setup_pool();
+        let repo = DieselRepository::new(pool.clone());
+        let publisher = RecordingPublisher::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let long = "x".repeat(MAX_UNSUBSCRIBE_REASON_CHARS + 50);
+        let messages = [
+            (
+                "user@example.org",
+                "Too many emails, sorry.\r\n\r\n> Offer".to_string(),
+            ),
+            ("long@example.org", long),
+            ("empty@example.org", String::new()),
+        ];
+        for (from, body) in &messages {
+            let raw = format!(
+                "Subject: Unsubscribe\r\nFrom: <{from}>\r\nContent-Type: text/plain\r\n\r\n{body}\r\n"
+            );
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                1,
+                &handle_config(),
+                hub_id,
+                &publisher,
+                &KeywordClassifier::default(),
+            )
+            .await;
+        }
+
+        let reasons = publisher.unsubscribe_reasons.into_inner().unwrap();
+        assert_eq!(reasons[0].as_deref(), Some("Too many emails, sorry."));
+        assert_eq!(
+            reasons[1].as_ref().map(|reason| reason.chars().count()),
+            Some(MAX_UNSUBSCRIBE_REASON_CHARS)
+        );
+        assert_eq!(reasons[2].as_deref(), Some("Unsubscribe"));
+
+        let stored: Option<String> = unsubscribes::table
+            .filter(unsubscribes::email.eq("user@example.org"))
+            .select(unsubscribes::reason)
+            .first(&mut pool.get().unwrap())
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some("Too many emails, sorry."));
+    }
+
+    #[tokio::test]
+    async fn bounce_publishes_unsubscribe_and_records_bounce() {
+        let (published, bounced) = handle(
+            "Subject: Undelivered Mail Returned to Sender\r\nFrom: Mailer <mailer@example.org>\r\nContent-Type: multipart/report; boundary=\"BOUNDARY\"\r\n\r\n--BOUNDARY\r\nContent-Type: message/delivery-status\r\n\r\nFinal-Recipient: rfc822; bounced@example.org\r\n--BOUNDARY--\r\n",
+        )
+        .await;
+
+        assert_eq!(
+            published,
+            vec![Published::Unsubscribe("bounced@example.org".into())]
+        );
+        assert_eq!(bounced, 1);
+    }
+
+    #[tokio::test]
+    async fn auth_failure_bounce_keeps_recipient_subscribed() {
+        let (published, bounced) = handle(
+            "Subject: Undelivered Mail Returned to Sender\r\nFrom: Mailer <mailer@example.org>\r\nContent-Type: multipart/report; boundary=\"BOUNDARY\"\r\n\r\n--BOUNDARY\r\nContent-Type: message/delivery-status\r\n\r\nFinal-Recipient: rfc822; bounced@example.org\r\nStatus: 5.7.26\r\nDiagnostic-Code: smtp; 550 5.7.26 DMARC policy violation\r\n--BOUNDARY--\r\n",
+        )
+        .await;
+
+        assert!(published.is_empty());
+        assert_eq!(bounced, 0);
+    }
+
+    #[tokio::test]
+    async fn ordinary_message_publishes_reply() {
+        let (published, bounced) = handle(
+            "Subject: Re: Hello\r\nFrom: User <user@example.org>\r\nContent-Type: text/plain\r\n\r\nThanks!\r\n",
+        )
+        .await;
+
+        assert_eq!(published, vec![Published::Reply("user@example.org".into())]);
+        assert_eq!(bounced, 0);
+    }
+
+    #[tokio::test]
+    async fn own_list_traffic_is_ignored() {
+        use crate::models::{HubSettings, ListIdConfig};
+
+        let raw = "Subject: Hello\r\nFrom: Sender <sender@example.com>\r\nList-Id: <News.Example.com>\r\nContent-Type: text/plain\r\n\r\nCampaign\r\n";
+        let mut config = handle_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                list_id: Some(ListIdConfig {
+                    id: "news.example.com".into(),
+                    name: None,
+                }),
+                ..Default::default()
+            },
+        );
+
+        let (published, _) = handle_with(raw, &config).await;
+        assert!(published.is_empty());
+
+        // Other lists are still handled as replies.
+        let (published, _) = handle(raw).await;
+        assert_eq!(
+            published,
+            vec![Published::Reply("sender@example.com".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_reply_is_not_counted_below_minimum_length() {
+        let (_dir, repo) = setup_repo();
+        let hub_id = HubId::try_from(1).unwrap();
+        let stored = email_with_recipients(&repo, &["to@example.com"]);
+        let recipient = &stored.recipients[0];
+        let classifier = KeywordClassifier::default();
+        let config = ReplyConfig {
+            min_length: 2,
+            ..ReplyConfig::default()
+        };
+
+        process_reply(
+            &repo,
+            hub_id,
+            recipient,
+            Some(" k ".into()),
+            &config,
+            &classifier,
+        )
+        .await;
+        let updated = repo
+            .get_email_recipient_by_id(recipient.id, hub_id)
+            .unwrap()
+            .unwrap();
+        assert!(updated.opened);
+        assert!(updated.reply.is_none());
+
+        process_reply(
+            &repo,
+            hub_id,
+            &updated,
+            Some("ok".into()),
+            &config,
+            &classifier,
+        )
+        .await;
+        let updated = repo
+            .get_email_recipient_by_id(recipient.id, hub_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            updated.reply.as_ref().map(|reply| reply.as_str()),
+            Some("ok")
+        );
+
+        let since = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let replied = repo
+            .count_delivery_events(hub_id, DeliveryEventKind::Replied, since)
+            .unwrap();
+        assert_eq!(replied, 1);
+        assert_eq!(repo.get_reply_category(recipient.id).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reply_category_is_stored_and_replaced() {
+        let (_dir, repo) = setup_repo();
+        let hub_id = HubId::try_from(1).unwrap();
+        let stored = email_with_recipients(&repo, &["to@example.com"]);
+        let recipient = &stored.recipients[0];
+        let classifier = KeywordClassifier::default();
+        let config = ReplyConfig::default();
+
+        let reply = Some("Thanks, but we are not interested.".to_string());
+        process_reply(&repo, hub_id, recipient, reply, &config, &classifier).await;
+        assert_eq!(
+            repo.get_reply_category(recipient.id).unwrap().as_deref(),
+            Some("not_interested")
+        );
+
+        let reply = Some("Actually, please send the offer.".to_string());
+        process_reply(&repo, hub_id, recipient, reply, &config, &classifier).await;
+        assert_eq!(
+            repo.get_reply_category(recipient.id).unwrap().as_deref(),
+            Some("interested")
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_language_is_stored_when_enabled() {
+        let (_dir, repo) = setup_repo();
+        let hub_id = HubId::try_from(1).unwrap();
+        let stored = email_with_recipients(
+            &repo,
+            &["ru@example.com", "en@example.com", "off@example.com"],
+        );
+        let [ru, en, off] = &stored.recipients[..] else {
+            panic!("three recipients expected");
+        };
+        let classifier = KeywordClassifier::default();
+        let config = ReplyConfig {
+            detect_language: true,
+            ..ReplyConfig::default()
+        };
+
+        let reply = Some("Добрый день! Пришлите прайс, пожалуйста.".to_string());
+        process_reply(&repo, hub_id, ru, reply, &config, &classifier).await;
+        let reply = Some("Thanks, please send the price list.".to_string());
+        process_reply(&repo, hub_id, en, reply, &config, &classifier).await;
+        let reply = Some("Thanks, please send the price list.".to_string());
+        process_reply(
+            &repo,
+            hub_id,
+            off,
+            reply,
+            &ReplyConfig::default(),
+            &classifier,
+        )
+        .await;
+
+        assert_eq!(
+            repo.get_reply_language(ru.id).unwrap().as_deref(),
+            Some("ru")
+        );
+        assert_eq!(
+            repo.get_reply_language(en.id).unwrap().as_deref(),
+            Some("en")
+        );
+        assert_eq!(repo.get_reply_language(off.id).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn empty_reply_handling_is_configurable() {
+        async fn handle_empty(action: EmptyReplyAction) -> EmailRecipient {
+            let (_dir, repo) = setup_repo();
+            let hub_id = HubId::try_from(1).unwrap();
+            let stored = email_with_recipients(&repo, &["to@example.com"]);
+            let recipient_id = stored.recipients[0].id;
+            let raw = format!(
+                "Subject: Re: Offer\r\nFrom: <to@example.com>\r\nIn-Reply-To: <{}.abc@example.com>\r\nContent-Type: application/pdf\r\nContent-Transfer-Encoding: base64\r\n\r\nJVBERi0xLjQ=\r\n",
+                recipient_id.get()
+            );
+            let mut config = handle_config();
+            config.reply.empty_reply = action;
+            handle_message(
+                &repo,
+                raw.as_bytes(),
+                1,
+                &config,
+                hub_id,
+                &RecordingPublisher::default(),
+                &KeywordClassifier::default(),
+            )
+            .await;
+            repo.get_email_recipient_by_id(recipient_id, hub_id)
+                .unwrap()
+                .unwrap()
+        }
+
+        let recipient = handle_empty(EmptyReplyAction::MarkOpened).await;
+        assert!(recipient.opened);
+        assert!(recipient.reply.is_none());
+
+        let recipient = handle_empty(EmptyReplyAction::CountAsReplied).await;
+        assert!(recipient.opened);
+        assert_eq!(
+            recipient.reply.as_ref().map(|reply| reply.as_str()),
+            Some("(empty reply)")
+        );
+
+        let recipient = handle_empty(EmptyReplyAction::Skip).await;
+        assert!(!recipient.opened);
+        assert!(recipient.reply.is_none());
+    }
+
+    /// Keeps the rendered bytes of every message it is asked to send.
+    #[derive(Default)]
+    struct CapturingMailer {
+        sent: Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl crate::send_email::service::Mailer for CapturingMailer {
+        async fn send(
+            &self,
+            _hub: &Hub,
+            message: mail_send::mail_builder::MessageBuilder<'_>,
+        ) -> Result<(), Error> {
+            let mut raw = Vec::new();
+            message.write_to(&mut raw)?;
+            self.sent.lock().expect("lock poisoned").push(raw);
+            Ok(())
+        }
+    }
+
+    fn header_value<'a>(raw: &'a str, name: &str) -> Option<&'a str> {
+        raw.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header.eq_ignore_ascii_case(name).then_some(value.trim())
+        })
+    }
+
+    /// Outcome of [`send_and_reply`].
+    struct RoundTrip {
+        message_id: String,
+        recipient: EmailRecipient,
+        publisher: RecordingPublisher,
+    }
+
+    /// Sends a one-recipient email through `send_email` with `send_config`,
+    /// then handles a reply to the captured Message-ID with `reply_config`.
+    async fn send_and_reply(send_config: &ServerConfig, reply_config: &ServerConfig) -> RoundTrip {
+        use diesel::connection::SimpleConnection;
+        use pushkind_emailer::models::zmq::ZMQSendEmailMessage;
+
+        let (_dir, pool) = setup_pool();
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE hubs (id INTEGER PRIMARY KEY, login TEXT, password TEXT, sender TEXT, smtp_server TEXT, smtp_port INTEGER, created_at TIMESTAMP, updated_at TIMESTAMP, imap_server TEXT, imap_port INTEGER, email_template TEXT, imap_last_uid INTEGER NOT NULL DEFAULT 0);\n\
+                 INSERT INTO hubs (id, login, password, sender, email_template) VALUES (1, 'sender@example.com', 'pass', 'sender@example.com', '{message}');",
+            )
+            .unwrap();
+        let repo = DieselRepository::new(pool);
+        let hub_id = HubId::try_from(1).unwrap();
+        let stored = repo
+            .create_email(&NewEmail {
+                message: EmailBody::new("Would you like a quote?").unwrap(),
+                subject: Some("Offer".try_into().unwrap()),
+                ..new_email(&["alice@example.org"])
+            })
+            .unwrap();
+        let recipient_id = stored.recipients[0].id;
+
+        let mailer = CapturingMailer::default();
+        let job = ZMQSendEmailMessage::RetryEmail((stored.email.id.get(), hub_id.get()));
+        crate::send_email::service::send_email(job, &repo, send_config, &mailer)
+            .await
+            .unwrap();
+
+        let sent = mailer.sent.into_inner().unwrap();
+        assert_eq!(sent.len(), 1);
+        let sent = String::from_utf8_lossy(&sent[0]);
+        let message_id = header_value(&sent, "Message-ID")
+            .expect("sent mail has a Message-ID")
+            .to_string();
+
+        let reply = format!(
+            "Subject: Re: Offer\r\nFrom: Alice <alice@example.org>\r\nTo: sender@example.com\r\nIn-Reply-To: {message_id}\r\nReferences: {message_id}\r\nContent-Type: text/plain; charset=\"utf-8\"\r\n\r\nYes, please send it.\r\n\r\nOn Mon, 1 Jan 2024, sender@example.com wrote:\r\n> Would you like a quote?\r\n"
+        );
+        let publisher = RecordingPublisher::default();
+        handle_message(
+            &repo,
+            reply.as_bytes(),
+            7,
+            reply_config,
+            hub_id,
+            &publisher,
+            &KeywordClassifier::default(),
+        )
+        .await;
+
+        let recipient = repo
+            .get_email_recipient_by_id(recipient_id, hub_id)
+            .unwrap()
+            .unwrap();
+        RoundTrip {
+            message_id,
+            recipient,
+            publisher,
+        }
+    }
+
+    #[tokio::test]
+    async fn sent_message_id_correlates_the_reply() {
+        let config = handle_config();
+        let RoundTrip {
+            recipient,
+            publisher,
+            ..
+        } = send_and_reply(&config, &config).await;
+
+        assert!(recipient.opened);
+        assert_eq!(
+            recipient.reply.as_ref().map(|reply| reply.as_str()),
+            Some("Yes, please send it.")
+        );
+
+        assert_eq!(
+            publisher.published.into_inner().unwrap(),
+            vec![Published::Reply("alice@example.org".into())]
+        );
+        let payload = &publisher.replies.into_inner().unwrap()[0];
+        assert_eq!(payload["hub_id"], 1);
+        assert_eq!(payload["email"], "alice@example.org");
+        assert_eq!(payload["message"], "Yes, please send it.");
+        assert_eq!(payload["subject"], "Re: Offer");
+    }
+
+    fn tenant_config() -> ServerConfig {
+        use crate::models::HubSettings;
+
+        let mut config = handle_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                domain: Some("tenant.example".into()),
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    #[tokio::test]
+    async fn correlation_uses_the_hub_domain() {
+        let config = tenant_config();
+        let round_trip = send_and_reply(&config, &config).await;
+
+        assert!(round_trip.message_id.ends_with("@tenant.example>"));
+        assert!(round_trip.recipient.opened);
+        assert!(round_trip.recipient.reply.is_some());
+    }
+
+    #[tokio::test]
+    async fn other_domains_do_not_correlate() {
+        let round_trip = send_and_reply(&tenant_config(), &handle_config()).await;
+
+        assert!(!round_trip.recipient.opened);
+        assert!(round_trip.recipient.reply.is_none());
+    }
+
+    fn io_error(kind: std::io::ErrorKind) -> async_imap::error::Error {
+        async_imap::error::Error::Io(std::io::Error::new(kind, "idle interrupted"))
+    }
+
+    #[test]
+    fn errors_after_the_keepalive_fired_are_expected() {
+        for kind in [
+            std::io::ErrorKind::TimedOut,
+            std::io::ErrorKind::ConnectionReset,
+            std::io::ErrorKind::UnexpectedEof,
+        ] {
+            assert!(is_keepalive_idle_end(&io_error(kind), true));
+        }
+        assert!(is_keepalive_idle_end(
+            &async_imap::error::Error::ConnectionLost,
+            true
+        ));
+    }
+
+    #[test]
+    fn errors_without_the_keepalive_are_fatal_except_timeouts() {
+        assert!(is_keepalive_idle_end(
+            &io_error(std::io::ErrorKind::TimedOut),
+            false
+        ));
+        assert!(!is_keepalive_idle_end(
+            &io_error(std::io::ErrorKind::ConnectionReset),
+            false
+        ));
+        assert!(!is_keepalive_idle_end(
+            &async_imap::error::Error::ConnectionLost,
+            false
+        ));
+    }
+
+    #[test]
+    fn noop_is_due_after_a_quiet_interval() {
+        let start = Instant::now();
+        let mut schedule = NoopSchedule::new(Some(Duration::from_secs(240)), start);
+        assert!(!schedule.is_due(start + Duration::from_secs(239)));
+        assert!(schedule.is_due(start + Duration::from_secs(240)));
+
+        schedule.touch(start + Duration::from_secs(200));
+        assert!(!schedule.is_due(start + Duration::from_secs(300)));
+        assert!(schedule.is_due(start + Duration::from_secs(440)));
+    }
+
+    #[test]
+    fn noop_interval_shortens_idle() {
+        let start = Instant::now();
+        let schedule = NoopSchedule::new(Some(Duration::from_secs(240)), start);
+        assert_eq!(schedule.idle_timeout(), Duration::from_secs(240));
+
+        let schedule = NoopSchedule::new(Some(Duration::from_secs(60 * 60)), start);
+        assert_eq!(schedule.idle_timeout(), IDLE_KEEPALIVE);
+    }
+
+    #[test]
+    fn disabled_noop_is_never_due() {
+        use crate::models::ImapKeepaliveConfig;
+
+        let start = Instant::now();
+        let schedule = NoopSchedule::new(
+            ImapKeepaliveConfig {
+                noop_interval_secs: Some(0),
+            }
+            .noop_interval(),
+            start,
+        );
+        assert!(!schedule.is_due(start + Duration::from_secs(60 * 60 * 24)));
+        assert_eq!(schedule.idle_timeout(), IDLE_KEEPALIVE);
+        assert!(ImapKeepaliveConfig::default().noop_interval().is_none());
+    }
+
+    fn backlog(batch_size: Option<usize>, order: BacklogOrder) -> BacklogConfig {
+        BacklogConfig {
+            batch_size,
+            order,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backlog_batch_skips_uids_at_or_below_the_cursor() {
+        let mut cursor = UidCursor::new(10);
+        let batch = cursor.next_batch([7, 12, 10, 11], &backlog(None, BacklogOrder::OldestFirst));
+        assert_eq!(
+            batch,
+            BacklogBatch {
+                uids: vec![11, 12],
+                remaining: 0
+            }
+        );
+    }
+
+    /// Processes passes until the backlog is empty, returning the processing
+    /// order and every cursor value handed to the checkpoint.
+    fn drain(
+        cursor: &mut UidCursor,
+        found: &[u32],
+        backlog: &BacklogConfig,
+    ) -> (Vec<u32>, Vec<u32>) {
+        let (mut processed, mut persisted) = (Vec::new(), Vec::new());
+        loop {
+            let batch = cursor.next_batch(found.iter().copied(), backlog);
+            for uid in batch.uids {
+                processed.push(uid);
+                persisted.extend(cursor.complete(uid));
+            }
+            if batch.remaining == 0 {
+                return (processed, persisted);
+            }
+        }
+    }
+
+    #[test]
+    fn backlog_batch_caps_each_pass_oldest_first() {
+        let found = [25, 21, 24, 22, 23];
+        let backlog = backlog(Some(2), BacklogOrder::OldestFirst);
+        let mut cursor = UidCursor::new(20);
+
+        let batch = cursor.next_batch(found, &backlog);
+        assert_eq!(batch.uids, vec![21, 22]);
+        assert_eq!(batch.remaining, 3);
+
+        let mut cursor = UidCursor::new(20);
+        let (processed, persisted) = drain(&mut cursor, &found, &backlog);
+        assert_eq!(processed, vec![21, 22, 23, 24, 25]);
+        assert_eq!(persisted, vec![21, 22, 23, 24, 25]);
+        assert_eq!(cursor.last(), 25);
+    }
+
+    #[test]
+    fn newest_first_processes_recent_mail_without_skipping_older() {
+        let found = [21, 22, 23, 24, 25];
+        let backlog = backlog(Some(2), BacklogOrder::NewestFirst);
+        let mut cursor = UidCursor::new(20);
+
+        let (processed, persisted) = drain(&mut cursor, &found, &backlog);
+        assert_eq!(processed, vec![25, 24, 23, 22, 21]);
+        // The cursor stays put until the oldest message is done, then jumps.
+        assert_eq!(persisted, vec![25]);
+        assert_eq!(cursor.last(), 25);
+    }
+
+    #[test]
+    fn cursor_only_moves_past_contiguous_processed_uids() {
+        let mut cursor = UidCursor::new(10);
+        let batch = cursor.next_batch([11, 12, 13], &backlog(Some(1), BacklogOrder::NewestFirst));
+        assert_eq!(batch.uids, vec![13]);
+
+        assert_eq!(cursor.complete(13), None);
+        assert_eq!(cursor.last(), 10);
+
+        // A processed UID is not handed out again by later searches.
+        let batch = cursor.next_batch([11, 12, 13], &backlog(None, BacklogOrder::OldestFirst));
+        assert_eq!(batch.uids, vec![11, 12]);
+        assert_eq!(cursor.complete(12), None);
+        assert_eq!(cursor.complete(11), Some(13));
+    }
+
+    #[test]
+    fn zero_batch_size_still_makes_progress() {
+        let mut cursor = UidCursor::new(0);
+        let batch = cursor.next_batch([3, 4], &backlog(Some(0), BacklogOrder::OldestFirst));
+        assert_eq!(batch.uids, vec![3]);
+        assert_eq!(batch.remaining, 1);
+    }
+
+    #[test]
+    fn uid_range_rejects_invalid_bounds() {
+        assert!(UidRange::new(0, 5).is_err());
+        assert!(UidRange::new(6, 5).is_err());
+        assert!(UidRange::new(5, 5).is_ok());
+    }
+
+    #[test]
+    fn uid_range_plan_ignores_cursor_and_out_of_range_uids() {
+        let range = UidRange::new(10, 20).unwrap();
+
+        assert_eq!(range.search_query(), "UID 10:20");
+        // UIDs below a stored cursor are still processed; the server's
+        // fallback answer (here 3) and UIDs past the range are dropped.
+        assert_eq!(range.plan([15, 3, 10, 20, 21, 12]), vec![10, 12, 15, 20]);
+        assert!(range.plan([]).is_empty());
+    }
+
+    #[test]
+    fn gmail_raw_search_needs_the_capability_and_a_query() {
+        let config = ImapSearchConfig {
+            gmail_raw: Some("from:mailer-daemon OR {domain}".into()),
+        };
+        assert_eq!(
+            gmail_raw_query(&config, true, "example.com").as_deref(),
+            Some("from:mailer-daemon OR example.com")
+        );
+        assert_eq!(gmail_raw_query(&config, false, "example.com"), None);
+        assert_eq!(
+            gmail_raw_query(&ImapSearchConfig::default(), true, "example.com"),
+            None
+        );
+        let blank = ImapSearchConfig {
+            gmail_raw: Some("  ".into()),
+        };
+        assert_eq!(gmail_raw_query(&blank, true, "example.com"), None);
+    }
+
+    #[test]
+    fn new_mail_query_falls_back_to_a_uid_search() {
+        assert_eq!(new_mail_query(41, None), "UID 42:*");
+        assert_eq!(
+            new_mail_query(41, Some(r#"subject:"re: offer" \ x"#)),
+            r#"UID 42:* X-GM-RAW "subject:\"re: offer\" \\ x""#
+        );
+    }
+
+    fn uid_config(batch_size: usize, interval_secs: u64) -> UidPersistConfig {
+        UidPersistConfig {
+            batch_size,
+            interval_secs,
+        }
+    }
+
+    fn persisted_uids(repo: &RecordingHubWriter) -> Vec<i32> {
+        repo.calls
+            .lock()
+            .expect("lock poisoned")
+            .iter()
+            .map(|(_, uid)| uid.get())
+            .collect()
+    }
+
+    #[test]
+    fn checkpoint_persists_every_batch_and_on_flush() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let now = Instant::now();
+        let mut checkpoint =
+            UidCheckpoint::new(ImapUid::try_from(0).unwrap(), &uid_config(3, 0), now);
+
+        for uid in 1..=7 {
+            checkpoint.record(&repo, hub_id, uid, now);
+        }
+        assert_eq!(persisted_uids(&repo), vec![3, 6]);
+
+        checkpoint.flush(&repo, hub_id, now);
+        assert_eq!(persisted_uids(&repo), vec![3, 6, 7]);
+
+        // Nothing pending: flushing again does not write.
+        checkpoint.flush(&repo, hub_id, now);
+        assert_eq!(persisted_uids(&repo), vec![3, 6, 7]);
+    }
+
+    #[test]
+    fn checkpoint_persists_when_interval_elapses() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let start = Instant::now();
+        let mut checkpoint =
+            UidCheckpoint::new(ImapUid::try_from(0).unwrap(), &uid_config(100, 10), start);
+
+        checkpoint.record(&repo, hub_id, 1, start + Duration::from_secs(1));
+        assert!(persisted_uids(&repo).is_empty());
+
+        checkpoint.record(&repo, hub_id, 2, start + Duration::from_secs(11));
+        assert_eq!(persisted_uids(&repo), vec![2]);
+    }
+
+    #[test]
+    fn checkpoint_default_persists_each_uid() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+        let now = Instant::now();
+        let mut checkpoint = UidCheckpoint::new(
+            ImapUid::try_from(0).unwrap(),
+            &UidPersistConfig::default(),
+            now,
+        );
+
+        checkpoint.record(&repo, hub_id, 1, now);
+        checkpoint.record(&repo, hub_id, 2, now);
+        assert_eq!(persisted_uids(&repo), vec![1, 2]);
+    }
+
+    #[test]
+    fn uid_validity_change_resets_cursor() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+
+        let resume = reconcile_uid_validity(
+            &repo,
+            hub_id,
+            Some(100),
+            Some(200),
+            ImapUid::try_from(42).unwrap(),
+        );
+
+        assert_eq!(resume.get(), 0);
+        assert_eq!(persisted_uids(&repo), vec![0]);
+        assert_eq!(*repo.validities.lock().expect("lock poisoned"), vec![200]);
+        assert_eq!(*repo.bounce_resets.lock().expect("lock poisoned"), 1);
+    }
+
+    #[test]
+    fn unchanged_uid_validity_keeps_cursor() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+
+        let resume = reconcile_uid_validity(
+            &repo,
+            hub_id,
+            Some(100),
+            Some(100),
+            ImapUid::try_from(42).unwrap(),
+        );
+
+        assert_eq!(resume.get(), 42);
+        assert!(persisted_uids(&repo).is_empty());
+        assert!(repo.validities.lock().expect("lock poisoned").is_empty());
+        assert_eq!(*repo.bounce_resets.lock().expect("lock poisoned"), 0);
+    }
+
+    #[test]
+    fn first_seen_uid_validity_is_stored_without_reset() {
+        let repo = RecordingHubWriter::default();
+        let hub_id = HubId::try_from(1).unwrap();
+
+        let resume = reconcile_uid_validity(
+            &repo,
+            hub_id,
+            None,
+            Some(100),
+            ImapUid::try_from(42).unwrap(),
+        );
+
+        assert_eq!(resume.get(), 42);
+        assert!(persisted_uids(&repo).is_empty());
+        assert_eq!(*repo.validities.lock().expect("lock poisoned"), vec![100]);
+    }
 }
@@ -0,0 +1,198 @@
+//! Durable spool for reply and unsubscribe notifications.
+//!
+//! Notifications that cannot be published after all retries are written to a
+//! directory, one JSON file each, and re-sent in order by a background flusher
+//! once the ZeroMQ peer is reachable again. File access goes through
+//! `tokio::fs`, so a slow disk does not block the runtime.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use pushkind_emailer::models::zmq::{ZMQReplyMessage, ZMQUnsubscribeMessage};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::check_reply::service::ReplyPublisher;
+use crate::errors::Error;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A notification waiting to be published.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    Reply(ZMQReplyMessage),
+    Unsubscribe(ZMQUnsubscribeMessage),
+}
+
+impl Notification {
+    /// Publishes the notification with the matching `publisher` method.
+    pub async fn publish(&self, publisher: &(impl ReplyPublisher + ?Sized)) -> Result<(), Error> {
+        match self {
+            Notification::Reply(message) => publisher.send_reply(message).await,
+            Notification::Unsubscribe(message) => publisher.send_unsubscribe(message).await,
+        }
+    }
+}
+
+/// Directory-backed queue of unpublished notifications.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Persists `notification` at the end of the queue.
+    pub async fn push(&self, notification: &Notification) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let name = format!(
+            "{:020}-{:010}",
+            Utc::now().timestamp_micros(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        fs::write(&tmp, serde_json::to_vec(notification)?).await?;
+        fs::rename(&tmp, self.dir.join(format!("{name}.json"))).await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Re-sends spooled notifications oldest first, removing each one once it
+    /// is published.
+    ///
+    /// Stops at the first publish failure so ordering is kept; unreadable
+    /// files are logged and moved aside. Returns how many were published.
+    pub async fn flush(&self, publisher: &(impl ReplyPublisher + ?Sized)) -> Result<usize, Error> {
+        let mut published = 0;
+        for path in self.pending().await? {
+            let notification = match read_notification(&path).await {
+                Ok(notification) => notification,
+                Err(e) => {
+                    log::error!("Cannot read spooled notification {}: {e}", path.display());
+                    fs::rename(&path, path.with_extension("bad")).await?;
+                    continue;
+                }
+            };
+
+            if let Err(e) = notification.publish(publisher).await {
+                log::warn!("Spooled notifications still undeliverable: {e}");
+                break;
+            }
+            fs::remove_file(&path).await?;
+            published += 1;
+        }
+        Ok(published)
+    }
+}
+
+async fn read_notification(path: &Path) -> Result<Notification, Error> {
+    Ok(serde_json::from_slice(&fs::read(path).await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Publisher {
+        fail: bool,
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ReplyPublisher for Publisher {
+        async fn send_reply(&self, message: &ZMQReplyMessage) -> Result<(), Error> {
+            if self.fail {
+                return Err(Error::Config("peer down".into()));
+            }
+            self.sent
+                .lock()
+                .expect("lock poisoned")
+                .push(format!("reply:{}", message.email));
+            Ok(())
+        }
+
+        async fn send_unsubscribe(&self, message: &ZMQUnsubscribeMessage) -> Result<(), Error> {
+            if self.fail {
+                return Err(Error::Config("peer down".into()));
+            }
+            self.sent
+                .lock()
+                .expect("lock poisoned")
+                .push(format!("unsubscribe:{}", message.email));
+            Ok(())
+        }
+    }
+
+    fn reply(email: &str) -> Notification {
+        Notification::Reply(ZMQReplyMessage {
+            hub_id: 1,
+            email: email.into(),
+            message: "Thanks".into(),
+            subject: None,
+        })
+    }
+
+    fn unsubscribe(email: &str) -> Notification {
+        Notification::Unsubscribe(ZMQUnsubscribeMessage {
+            hub_id: 1,
+            email: email.into(),
+            reason: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn keeps_notifications_until_publisher_recovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::new(dir.path().join("spool"));
+        spool.push(&reply("a@example.org")).await.unwrap();
+        spool.push(&unsubscribe("b@example.org")).await.unwrap();
+
+        let down = Publisher {
+            fail: true,
+            ..Publisher::default()
+        };
+        assert_eq!(spool.flush(&down).await.unwrap(), 0);
+        assert_eq!(spool.pending().await.unwrap().len(), 2);
+
+        let up = Publisher::default();
+        assert_eq!(spool.flush(&up).await.unwrap(), 2);
+        assert_eq!(
+            *up.sent.lock().expect("lock poisoned"),
+            vec!["reply:a@example.org", "unsubscribe:b@example.org"]
+        );
+        assert!(spool.pending().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn flushing_a_missing_spool_is_a_no_op() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::new(dir.path().join("absent"));
+        assert_eq!(spool.flush(&Publisher::default()).await.unwrap(), 0);
+    }
+}
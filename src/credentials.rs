@@ -0,0 +1,368 @@
+//! Resolution of hub SMTP/IMAP credentials.
+//!
+//! Hubs store `login`/`password` in the shared database. A hub can instead
+//! reference secrets kept outside the database via `hubs.<id>.credentials`
+//! in the configuration; each reference is resolved through a
+//! [`SecretResolver`] and falls back to the database value when unset.
+//!
+//! Stored passwords may be encrypted with AES-256-GCM under the configured
+//! `password_key`; see [`encrypt_password`].
+
+use async_trait::async_trait;
+use aws_lc_rs::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use pushkind_emailer::domain::hub::Hub;
+
+use crate::errors::Error;
+use crate::models::{HubSettings, SecretRef};
+
+/// Login and password used to authenticate against the hub's mail servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubCredentials {
+    pub login: String,
+    pub password: String,
+}
+
+/// Prefix marking an encrypted `password` column value.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+/// 256-bit key used to encrypt stored hub passwords.
+pub struct PasswordKey(LessSafeKey);
+
+impl PasswordKey {
+    /// Decodes a base64-encoded 32-byte key.
+    pub fn from_base64(encoded: &str) -> Result<Self, Error> {
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| Error::Config(format!("password_key is not valid base64: {e}")))?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes)
+            .map_err(|_| Error::Config("password_key must be 32 bytes".into()))?;
+        Ok(Self(LessSafeKey::new(key)))
+    }
+}
+
+/// Encrypts `password` for storage in the hub `password` column.
+///
+/// The result is `enc:v1:` followed by base64 of a random nonce and the
+/// AES-256-GCM ciphertext with its tag.
+pub fn encrypt_password(password: &str, key: &PasswordKey) -> Result<String, Error> {
+    let mut nonce = [0_u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Error::Config("Cannot generate a nonce".into()))?;
+
+    let mut sealed = password.as_bytes().to_vec();
+    key.0
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .map_err(|_| Error::Config("Cannot encrypt password".into()))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&sealed);
+    Ok(format!("{ENCRYPTED_PREFIX}{}", STANDARD.encode(payload)))
+}
+
+/// Decrypts a value produced by [`encrypt_password`].
+///
+/// Fails when the value is not encrypted, was encrypted under another key,
+/// or has been tampered with.
+pub fn decrypt_password(encrypted: &str, key: &PasswordKey) -> Result<String, Error> {
+    let invalid = || Error::Config("Cannot decrypt password".into());
+
+    let encoded = encrypted
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .ok_or_else(invalid)?;
+    let mut payload = STANDARD.decode(encoded).map_err(|_| invalid())?;
+    if payload.len() < NONCE_LEN {
+        return Err(invalid());
+    }
+    let mut sealed = payload.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&payload).map_err(|_| invalid())?;
+
+    let plain = key
+        .0
+        .open_in_place(nonce, Aad::empty(), &mut sealed)
+        .map_err(|_| invalid())?;
+    String::from_utf8(plain.to_vec()).map_err(|_| invalid())
+}
+
+/// Returns the plaintext of a stored password, decrypting it when it
+/// carries the encryption prefix.
+fn stored_password(stored: &str, key: Option<&PasswordKey>) -> Result<String, Error> {
+    if !stored.starts_with(ENCRYPTED_PREFIX) {
+        return Ok(stored.to_string());
+    }
+    match key {
+        Some(key) => decrypt_password(stored, key),
+        None => Err(Error::Config(
+            "Hub password is encrypted but no password_key is configured".into(),
+        )),
+    }
+}
+
+/// Looks up the value behind a [`SecretRef`].
+#[async_trait]
+pub trait SecretResolver: Send + Sync {
+    async fn resolve(&self, secret: &SecretRef) -> Result<String, Error>;
+}
+
+/// Resolves secrets from process environment variables and files.
+///
+/// Files are read with `tokio::fs`, so resolving credentials on the send and
+/// IMAP-connect paths does not block the runtime.
+pub struct SystemSecrets;
+
+#[async_trait]
+impl SecretResolver for SystemSecrets {
+    async fn resolve(&self, secret: &SecretRef) -> Result<String, Error> {
+        match secret {
+            SecretRef::Env(name) => std::env::var(name)
+                .map_err(|e| Error::Config(format!("Cannot read secret from ${name}: {e}"))),
+            SecretRef::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| {
+                    Error::Config(format!("Cannot read secret from {}: {e}", path.display()))
+                }),
+        }
+    }
+}
+
+/// Returns one credential field: the configured secret when set, otherwise
+/// the value stored in the database.
+async fn credential_field(
+    hub: &Hub,
+    name: &str,
+    secret: Option<&SecretRef>,
+    stored: Option<&str>,
+    resolver: &(impl SecretResolver + ?Sized),
+) -> Result<String, Error> {
+    match (secret, stored) {
+        (Some(secret), _) => resolver.resolve(secret).await,
+        (None, Some(value)) => Ok(value.to_string()),
+        (None, None) => Err(Error::Config(format!(
+            "Hub#{} has no {name} configured",
+            hub.id
+        ))),
+    }
+}
+
+/// Resolves the hub credentials, preferring configured secret references
+/// over the values stored in the database.
+///
+/// Passwords carrying the `enc:v1:` prefix are decrypted with `key`.
+pub async fn resolve_credentials(
+    hub: &Hub,
+    settings: &HubSettings,
+    resolver: &(impl SecretResolver + ?Sized),
+    key: Option<&PasswordKey>,
+) -> Result<HubCredentials, Error> {
+    let configured = settings.credentials.as_ref();
+    let login = credential_field(
+        hub,
+        "login",
+        configured.and_then(|c| c.login.as_ref()),
+        hub.login.as_ref().map(|login| login.as_str()),
+        resolver,
+    )
+    .await?;
+    let password = credential_field(
+        hub,
+        "password",
+        configured.and_then(|c| c.password.as_ref()),
+        hub.password.as_ref().map(|password| password.as_str()),
+        resolver,
+    )
+    .await?;
+
+    Ok(HubCredentials {
+        login,
+        password: stored_password(&password, key)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CredentialsConfig;
+    use std::collections::HashMap;
+
+    struct MapSecrets(HashMap<String, String>);
+
+    #[async_trait]
+    impl SecretResolver for MapSecrets {
+        async fn resolve(&self, secret: &SecretRef) -> Result<String, Error> {
+            let key = match secret {
+                SecretRef::Env(name) => name.clone(),
+                SecretRef::File(path) => path.display().to_string(),
+            };
+            self.0
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| Error::Config(format!("missing {key}")))
+        }
+    }
+
+    fn hub(login: Option<&str>, password: Option<&str>) -> Hub {
+        Hub::try_new(
+            1,
+            login.map(str::to_string),
+            password.map(str::to_string),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+        )
+        .unwrap()
+    }
+
+    fn secrets(entries: &[(&str, &str)]) -> MapSecrets {
+        MapSecrets(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_database_credentials() {
+        let credentials = resolve_credentials(
+            &hub(Some("user@example.com"), Some("db-secret")),
+            &HubSettings::default(),
+            &secrets(&[]),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(credentials.login, "user@example.com");
+        assert_eq!(credentials.password, "db-secret");
+    }
+
+    #[tokio::test]
+    async fn configured_secret_overrides_database_value() {
+        let settings = HubSettings {
+            credentials: Some(CredentialsConfig {
+                login: None,
+                password: Some(SecretRef::Env("HUB1_PASSWORD".into())),
+            }),
+            ..HubSettings::default()
+        };
+
+        let credentials = resolve_credentials(
+            &hub(Some("user@example.com"), None),
+            &settings,
+            &secrets(&[("HUB1_PASSWORD", "vault-secret")]),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(credentials.login, "user@example.com");
+        assert_eq!(credentials.password, "vault-secret");
+    }
+
+    #[tokio::test]
+    async fn unresolvable_secret_is_an_error() {
+        let settings = HubSettings {
+            credentials: Some(CredentialsConfig {
+                login: None,
+                password: Some(SecretRef::Env("MISSING".into())),
+            }),
+            ..HubSettings::default()
+        };
+
+        let result = resolve_credentials(
+            &hub(Some("user@example.com"), Some("db-secret")),
+            &settings,
+            &secrets(&[]),
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(
+            resolve_credentials(
+                &hub(None, None),
+                &HubSettings::default(),
+                &secrets(&[]),
+                None
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    fn key(byte: u8) -> PasswordKey {
+        PasswordKey::from_base64(&STANDARD.encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn password_encryption_round_trips() {
+        let key = key(7);
+        let encrypted = encrypt_password("smtp-secret", &key).unwrap();
+
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+        assert!(!encrypted.contains("smtp-secret"));
+        assert_ne!(encrypted, encrypt_password("smtp-secret", &key).unwrap());
+        assert_eq!(decrypt_password(&encrypted, &key).unwrap(), "smtp-secret");
+    }
+
+    #[test]
+    fn decryption_rejects_wrong_key_and_tampering() {
+        let encrypted = encrypt_password("smtp-secret", &key(7)).unwrap();
+        assert!(decrypt_password(&encrypted, &key(8)).is_err());
+
+        let mut tampered = encrypted.into_bytes();
+        let last = tampered.len() - 3;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(decrypt_password(&tampered, &key(7)).is_err());
+    }
+
+    #[test]
+    fn rejects_keys_of_wrong_length() {
+        assert!(PasswordKey::from_base64(&STANDARD.encode([1_u8; 16])).is_err());
+        assert!(PasswordKey::from_base64("not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn resolving_credentials_decrypts_stored_password() {
+        let key = key(3);
+        let encrypted = encrypt_password("db-secret", &key).unwrap();
+        let hub = hub(Some("user@example.com"), Some(&encrypted));
+
+        let credentials =
+            resolve_credentials(&hub, &HubSettings::default(), &secrets(&[]), Some(&key))
+                .await
+                .unwrap();
+        assert_eq!(credentials.password, "db-secret");
+
+        // Encrypted values are never passed through as the password.
+        assert!(
+            resolve_credentials(&hub, &HubSettings::default(), &secrets(&[]), None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn system_secrets_read_files_without_trailing_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "file-secret\n").unwrap();
+
+        let value = SystemSecrets.resolve(&SecretRef::File(path)).await.unwrap();
+        assert_eq!(value, "file-secret");
+    }
+}
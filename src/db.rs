@@ -0,0 +1,256 @@
+//! Database pool construction for the workers.
+//!
+//! Wraps the r2d2 builder so pool limits come from [`DbPoolConfig`] instead
+//! of the r2d2 defaults used by `pushkind_common::db`, and configures every
+//! SQLite connection for concurrent access by both workers. [`ensure_schema`]
+//! creates the tables Hedwig owns, which the `pushkind-emailer` migrations
+//! do not.
+
+use std::time::Duration;
+
+use diesel::connection::SimpleConnection;
+use diesel::dsl::sql;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::sql_types::Bool;
+use diesel::sqlite::SqliteConnection;
+use diesel::{RunQueryDsl, select};
+use pushkind_common::db::{DbConnection, DbPool};
+use pushkind_common::repository::errors::RepositoryError;
+
+use crate::errors::Error;
+use crate::models::DbPoolConfig;
+
+/// Applies SQLite pragmas to each new pooled connection.
+///
+/// WAL journaling and a busy timeout avoid "database is locked" errors when
+/// both workers write concurrently; `foreign_keys` enforces the schema's
+/// `REFERENCES` clauses, which SQLite ignores by default.
+#[derive(Debug, Clone, Copy)]
+pub struct SqlitePragmas {
+    pub busy_timeout_ms: u64,
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "PRAGMA journal_mode = WAL; PRAGMA busy_timeout = {}; PRAGMA foreign_keys = ON;",
+            self.busy_timeout_ms
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// DDL of the tables Hedwig owns, as they are created on a new database.
+const HEDWIG_TABLES: &str = "\
+    CREATE TABLE IF NOT EXISTS delivery_events (id INTEGER PRIMARY KEY, hub_id INTEGER NOT NULL, kind TEXT NOT NULL, created_at TIMESTAMP NOT NULL, imap_uid BIGINT);
+    CREATE TABLE IF NOT EXISTS hub_state (hub_id INTEGER PRIMARY KEY, uid_validity BIGINT, sending_paused BOOLEAN NOT NULL DEFAULT 0);
+    CREATE TABLE IF NOT EXISTS global_suppressions (email TEXT PRIMARY KEY, reason TEXT, created_at TIMESTAMP NOT NULL);
+    CREATE TABLE IF NOT EXISTS reply_categories (recipient_id INTEGER PRIMARY KEY, category TEXT NOT NULL, updated_at TIMESTAMP NOT NULL);
+    CREATE TABLE IF NOT EXISTS reply_languages (recipient_id INTEGER PRIMARY KEY, language TEXT NOT NULL, updated_at TIMESTAMP NOT NULL);
+    CREATE TABLE IF NOT EXISTS rejected_recipients (recipient_id INTEGER PRIMARY KEY, reason TEXT NOT NULL, created_at TIMESTAMP NOT NULL);
+    CREATE TABLE IF NOT EXISTS email_deadlines (email_id INTEGER PRIMARY KEY, expires_at TIMESTAMP NOT NULL);
+    CREATE TABLE IF NOT EXISTS processed_replies (id INTEGER PRIMARY KEY, hub_id INTEGER NOT NULL, message_id TEXT NOT NULL, created_at TIMESTAMP NOT NULL, UNIQUE (hub_id, message_id));
+    CREATE TABLE IF NOT EXISTS unsubscribe_sources (hub_id INTEGER NOT NULL, email TEXT NOT NULL, source TEXT NOT NULL, PRIMARY KEY (hub_id, email));";
+
+/// Columns added to Hedwig tables after they first shipped, as
+/// `(table, column, definition)`; older databases get them on startup.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("delivery_events", "imap_uid", "BIGINT"),
+    ("hub_state", "sending_paused", "BOOLEAN NOT NULL DEFAULT 0"),
+];
+
+/// Indexes on Hedwig tables; created after [`ADDED_COLUMNS`] since they may
+/// cover added columns.
+const HEDWIG_INDEXES: &str = "\
+    CREATE INDEX IF NOT EXISTS delivery_events_hub_created ON delivery_events (hub_id, created_at);
+    CREATE UNIQUE INDEX IF NOT EXISTS delivery_events_hub_kind_uid ON delivery_events (hub_id, kind, imap_uid);";
+
+/// Creates or upgrades the tables Hedwig owns and checks the columns it
+/// needs in the shared `pushkind-emailer` tables.
+///
+/// Idempotent, so both workers run it on startup. Fails with
+/// [`Error::Config`] when `unsubscribes` lacks `created_at`, which every
+/// unsubscribe insert writes but which Hedwig does not add to a table it
+/// does not own.
+pub fn ensure_schema(pool: &DbPool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    conn.batch_execute(HEDWIG_TABLES)
+        .map_err(RepositoryError::from)?;
+    for (table, column, definition) in ADDED_COLUMNS {
+        if !has_column(&mut conn, table, column)? {
+            log::info!("Adding column {table}.{column}");
+            conn.batch_execute(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+            ))
+            .map_err(RepositoryError::from)?;
+        }
+    }
+    conn.batch_execute(HEDWIG_INDEXES)
+        .map_err(RepositoryError::from)?;
+
+    if !has_column(&mut conn, "unsubscribes", "created_at")? {
+        return Err(Error::Config(
+            "unsubscribes.created_at is missing; add it with \
+             `ALTER TABLE unsubscribes ADD COLUMN created_at TIMESTAMP;`"
+                .into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns whether `table` has `column`; `false` when the table is missing.
+///
+/// Only called with the constant names above, so formatting them into the
+/// query is safe.
+fn has_column(conn: &mut DbConnection, table: &str, column: &str) -> Result<bool, Error> {
+    let exists = select(sql::<Bool>(&format!(
+        "EXISTS (SELECT 1 FROM pragma_table_info('{table}') WHERE name = '{column}')"
+    )))
+    .get_result(conn)
+    .map_err(RepositoryError::from)?;
+    Ok(exists)
+}
+
+/// Builds a SQLite connection pool for `database_url` using `config` limits.
+///
+/// Fails with [`Error::Pool`] if the initial connections cannot be opened
+/// within `config.connection_timeout_secs`.
+pub fn establish_pool(database_url: &str, config: &DbPoolConfig) -> Result<DbPool, Error> {
+    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+    let pool = Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(Duration::from_secs(config.connection_timeout_secs))
+        .connection_customizer(Box::new(SqlitePragmas {
+            busy_timeout_ms: config.busy_timeout_ms,
+        }))
+        .build(manager)?;
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_pool_with_configured_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("pool.db");
+        let config = DbPoolConfig {
+            max_size: 3,
+            min_idle: Some(1),
+            connection_timeout_secs: 2,
+            ..Default::default()
+        };
+
+        let pool = establish_pool(db_path.to_str().unwrap(), &config).unwrap();
+
+        assert_eq!(pool.max_size(), 3);
+        assert_eq!(pool.min_idle(), Some(1));
+        assert_eq!(pool.connection_timeout(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn connections_enforce_foreign_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("pragmas.db");
+        let pool = establish_pool(db_path.to_str().unwrap(), &DbPoolConfig::default()).unwrap();
+        let mut conn = pool.get().unwrap();
+
+        conn.batch_execute(
+            "CREATE TABLE hubs (id INTEGER PRIMARY KEY);\n\
+             CREATE TABLE emails (id INTEGER PRIMARY KEY, hub_id INTEGER NOT NULL REFERENCES hubs(id));",
+        )
+        .unwrap();
+
+        assert!(
+            conn.batch_execute("INSERT INTO emails (id, hub_id) VALUES (1, 99);")
+                .is_err()
+        );
+        conn.batch_execute(
+            "INSERT INTO hubs (id) VALUES (99); INSERT INTO emails (id, hub_id) VALUES (1, 99);",
+        )
+        .unwrap();
+    }
+
+    /// Creates the shared `unsubscribes` table `ensure_schema` checks.
+    fn create_unsubscribes(pool: &DbPool, created_at: bool) {
+        let column = if created_at {
+            ", created_at TIMESTAMP"
+        } else {
+            ""
+        };
+        pool.get()
+            .unwrap()
+            .batch_execute(&format!(
+                "CREATE TABLE unsubscribes (email TEXT NOT NULL, hub_id INTEGER NOT NULL, reason TEXT{column}, PRIMARY KEY (email, hub_id));"
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn ensure_schema_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("schema.db");
+        let pool = establish_pool(db_path.to_str().unwrap(), &DbPoolConfig::default()).unwrap();
+        create_unsubscribes(&pool, true);
+
+        ensure_schema(&pool).unwrap();
+        ensure_schema(&pool).unwrap();
+
+        let mut conn = pool.get().unwrap();
+        for (table, column) in [
+            ("delivery_events", "imap_uid"),
+            ("hub_state", "sending_paused"),
+            ("global_suppressions", "email"),
+            ("reply_categories", "category"),
+            ("reply_languages", "language"),
+            ("rejected_recipients", "reason"),
+            ("email_deadlines", "expires_at"),
+            ("processed_replies", "message_id"),
+            ("unsubscribe_sources", "source"),
+        ] {
+            assert!(
+                has_column(&mut conn, table, column).unwrap(),
+                "{table}.{column}"
+            );
+        }
+    }
+
+    #[test]
+    fn ensure_schema_adds_missing_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("upgrade.db");
+        let pool = establish_pool(db_path.to_str().unwrap(), &DbPoolConfig::default()).unwrap();
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE delivery_events (id INTEGER PRIMARY KEY, hub_id INTEGER NOT NULL, kind TEXT NOT NULL, created_at TIMESTAMP NOT NULL);\n\
+                 CREATE TABLE hub_state (hub_id INTEGER PRIMARY KEY, uid_validity BIGINT);\n\
+                 INSERT INTO hub_state (hub_id, uid_validity) VALUES (1, 7);",
+            )
+            .unwrap();
+        create_unsubscribes(&pool, true);
+
+        ensure_schema(&pool).unwrap();
+
+        let mut conn = pool.get().unwrap();
+        assert!(has_column(&mut conn, "delivery_events", "imap_uid").unwrap());
+        let paused: bool = select(sql::<Bool>(
+            "(SELECT sending_paused FROM hub_state WHERE hub_id = 1)",
+        ))
+        .get_result(&mut conn)
+        .unwrap();
+        assert!(!paused);
+    }
+
+    #[test]
+    fn ensure_schema_requires_unsubscribes_created_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("shared.db");
+        let pool = establish_pool(db_path.to_str().unwrap(), &DbPoolConfig::default()).unwrap();
+        create_unsubscribes(&pool, false);
+
+        assert!(matches!(ensure_schema(&pool), Err(Error::Config(_))));
+    }
+}
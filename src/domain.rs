@@ -1,4 +1,35 @@
-use pushkind_emailer::domain::types::EmailRecipientReply;
+use std::collections::BTreeMap;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use pushkind_emailer::domain::types::{EmailRecipientId, EmailRecipientReply};
+use pushkind_emailer::models::zmq::ZMQSendEmailMessage;
+use serde::{Deserialize, Serialize};
+
+/// Recipient field holding base64-encoded personal attachment content.
+pub const ATTACHMENT_FIELD: &str = "attachment";
+/// Recipient field holding the personal attachment file name.
+pub const ATTACHMENT_NAME_FIELD: &str = "attachment_name";
+/// Recipient field holding the personal attachment MIME type.
+pub const ATTACHMENT_MIME_FIELD: &str = "attachment_mime";
+
+/// Recipient field holding the Message-ID a follow-up replies to.
+pub const IN_REPLY_TO_FIELD: &str = "in_reply_to";
+/// Recipient field holding earlier thread Message-IDs, whitespace-separated.
+pub const REFERENCES_FIELD: &str = "references";
+/// Recipient field opting the recipient out of open tracking.
+pub const NO_TRACKING_FIELD: &str = "no_tracking";
+
+/// Recipient fields read by Hedwig itself; they are never template variables.
+pub const RESERVED_FIELDS: [&str; 6] = [
+    ATTACHMENT_FIELD,
+    ATTACHMENT_NAME_FIELD,
+    ATTACHMENT_MIME_FIELD,
+    IN_REPLY_TO_FIELD,
+    REFERENCES_FIELD,
+    NO_TRACKING_FIELD,
+];
 
 /// Updates to apply to an email recipient record.
 pub struct UpdateEmailRecipient<'a> {
@@ -6,3 +37,635 @@ pub struct UpdateEmailRecipient<'a> {
     pub opened: Option<bool>,
     pub reply: Option<&'a EmailRecipientReply>,
 }
+
+/// From mailbox replacing the hub default for a single send job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FromOverride {
+    pub address: String,
+    /// Display name; the hub display name is used when unset.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Options of a raw send, which delivers the stored message as the complete
+/// body: no hub template, placeholders, link stripping or tracking pixel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawSend {
+    /// Keeps the `List-Unsubscribe` headers, which transactional mail
+    /// usually leaves out.
+    #[serde(default)]
+    pub list_unsubscribe: bool,
+}
+
+/// Ad-hoc recipient of a "send test to myself" preview.
+///
+/// Never stored in `email_recipients`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewRecipient {
+    pub address: String,
+    pub name: String,
+    /// Template fields, as on a stored recipient.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A send job as received over ZeroMQ.
+///
+/// Wraps the shared [`ZMQSendEmailMessage`] with Hedwig-specific options.
+/// The options are optional keys next to the message's variant tag, e.g.
+/// `{"RetryEmail": [5, 1], "from_override": {"address": "..."}}`, so plain
+/// `ZMQSendEmailMessage` payloads decode with default options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendEmailRequest {
+    #[serde(flatten)]
+    pub message: ZMQSendEmailMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_override: Option<FromOverride>,
+    /// Explicit `Date` header, e.g. the intended time of a scheduled send;
+    /// `mail-builder` stamps the current time when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+    /// Sends the message pre-rendered, e.g. `"raw": {}`; see [`RawSend`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<RawSend>,
+    /// Deadline after which the email is no longer sent, e.g. the start of
+    /// the event it announces; recipients still unsent then are counted as
+    /// expired. Stored with the email, so later jobs without it keep the
+    /// deadline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl SendEmailRequest {
+    /// ID of the hub the job sends through.
+    pub fn hub_id(&self) -> i32 {
+        match &self.message {
+            ZMQSendEmailMessage::RetryEmail((_, hub_id)) => *hub_id,
+            ZMQSendEmailMessage::NewEmail(boxed) => boxed.1.hub_id.get(),
+        }
+    }
+}
+
+/// Per-recipient outcome of a send job.
+///
+/// Every recipient of the email is counted exactly once, so the five counts
+/// add up to the number of recipients.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendSummary {
+    /// Recipients accepted by the SMTP server in this job.
+    pub sent: usize,
+    /// Recipients whose send was attempted and failed; see `errors`.
+    pub failed: usize,
+    /// Recipients left unsent without an attempt: already sent earlier,
+    /// held back by a warm-up cap, an operator pause, the bounce breaker or
+    /// the strict spam check, or whose suppression lookup failed.
+    pub skipped: usize,
+    /// Recipients unsubscribed from the hub or globally suppressed.
+    pub suppressed: usize,
+    /// Recipients not attempted because the job's `expires_at` had passed.
+    pub expired: usize,
+    /// Why each failed recipient was not sent, in completion order.
+    pub errors: Vec<(EmailRecipientId, String)>,
+    /// Set when a warm-up cap deferred recipients: when and what to retry.
+    pub retry: Option<DeferredRetry>,
+}
+
+/// Retry of the recipients a warm-up cap deferred, as
+/// `RetryEmail((email_id, hub_id))` once the hub's cap resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeferredRetry {
+    pub email_id: i32,
+    pub hub_id: i32,
+    /// The next UTC midnight after the job.
+    pub at: DateTime<Utc>,
+}
+
+impl From<ZMQSendEmailMessage> for SendEmailRequest {
+    fn from(message: ZMQSendEmailMessage) -> Self {
+        Self {
+            message,
+            from_override: None,
+            date: None,
+            raw: None,
+            expires_at: None,
+        }
+    }
+}
+
+/// Builds the Message-ID (without angle brackets) for one send attempt.
+///
+/// The local part is `{recipient_id}.{token}`. A fresh `token` per attempt
+/// keeps retries from reusing an ID, while [`recipient_id_from_message_id`]
+/// still recovers the recipient.
+pub fn message_id(recipient_id: i32, token: &str, domain: &str) -> String {
+    format!("{recipient_id}.{token}@{domain}")
+}
+
+/// Local-part prefix of preview Message-IDs.
+pub const PREVIEW_MESSAGE_ID_PREFIX: &str = "preview";
+
+/// Builds the Message-ID for a preview send.
+///
+/// The local part is `preview.{token}`, which
+/// [`recipient_id_from_message_id`] never maps to a recipient, so replies to
+/// a preview are not correlated with a stored recipient.
+pub fn preview_message_id(token: &str, domain: &str) -> String {
+    format!("{PREVIEW_MESSAGE_ID_PREFIX}.{token}@{domain}")
+}
+
+/// Recovers the recipient ID from a Message-ID local part.
+///
+/// Accepts both `{recipient_id}.{token}` and the legacy `{recipient_id}`
+/// form used before send tokens were added.
+pub fn recipient_id_from_message_id(local_part: &str) -> Option<i32> {
+    let id = local_part.split_once('.').map_or(local_part, |(id, _)| id);
+    id.parse().ok()
+}
+
+/// Builds the RFC 8058 one-click unsubscribe URL for a recipient token.
+///
+/// The link is served by the same host as the tracking pixel; the token is
+/// handed back to [`crate::unsubscribe::process_one_click_unsubscribe`].
+pub fn one_click_unsubscribe_url(token: &str, domain: &str) -> String {
+    format!("https://mail.{domain}/unsubscribe/{token}")
+}
+
+/// Prefix of the plus-address tag carrying a recipient ID, as in
+/// `replies+rcpt42@example.com`.
+pub const ENVELOPE_TAG_PREFIX: &str = "rcpt";
+
+/// Recovers the recipient ID from a plus-addressed (VERP) mailbox such as
+/// `replies+rcpt42@example.com`.
+pub fn recipient_id_from_envelope(address: &str) -> Option<i32> {
+    let (local, _) = address.trim().rsplit_once('@')?;
+    let (_, tag) = local.split_once('+')?;
+    tag.strip_prefix(ENVELOPE_TAG_PREFIX)?.parse().ok()
+}
+
+/// Which correlation source wins when an incoming message carries both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorrelationOrder {
+    /// `In-Reply-To`, then the plus-addressed `Delivered-To`/`To` headers.
+    #[default]
+    InReplyToFirst,
+    /// The plus-addressed `Delivered-To`/`To` headers, then `In-Reply-To`.
+    EnvelopeFirst,
+}
+
+/// Normalizes an email address for suppression matching.
+///
+/// Surrounding whitespace is trimmed. With `strip_subaddress`, a `+tag`
+/// suffix in the local part is removed so `user+promo@example.com` and
+/// `user@example.com` refer to the same mailbox.
+pub fn normalize_address(address: &str, strip_subaddress: bool) -> String {
+    let address = address.trim();
+    if !strip_subaddress {
+        return address.to_string();
+    }
+
+    match address.rsplit_once('@') {
+        Some((local, domain)) => {
+            let local = local.split_once('+').map_or(local, |(base, _)| base);
+            if local.is_empty() {
+                address.to_string()
+            } else {
+                format!("{local}@{domain}")
+            }
+        }
+        None => address.to_string(),
+    }
+}
+
+/// Delivery outcome recorded for deliverability tracking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryEventKind {
+    /// A message was accepted by the SMTP server.
+    Sent,
+    /// A bounce notification was received for a message.
+    Bounce,
+    /// A recipient was first marked as having opened a message.
+    Opened,
+    /// A recipient replied to a message for the first time.
+    Replied,
+}
+
+impl DeliveryEventKind {
+    /// Returns the value stored in the `kind` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryEventKind::Sent => "sent",
+            DeliveryEventKind::Bounce => "bounce",
+            DeliveryEventKind::Opened => "opened",
+            DeliveryEventKind::Replied => "replied",
+        }
+    }
+
+    /// Parses a `kind` column value.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sent" => Some(DeliveryEventKind::Sent),
+            "bounce" => Some(DeliveryEventKind::Bounce),
+            "opened" => Some(DeliveryEventKind::Opened),
+            "replied" => Some(DeliveryEventKind::Replied),
+            _ => None,
+        }
+    }
+}
+
+/// Delivery event counts for a hub on one UTC day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HubDailyStats {
+    pub date: NaiveDate,
+    pub sent: i64,
+    pub opened: i64,
+    pub replied: i64,
+    pub bounced: i64,
+}
+
+impl HubDailyStats {
+    /// Empty bucket for `date`.
+    pub fn empty(date: NaiveDate) -> Self {
+        Self {
+            date,
+            sent: 0,
+            opened: 0,
+            replied: 0,
+            bounced: 0,
+        }
+    }
+
+    /// Counts one event of `kind`.
+    pub fn add(&mut self, kind: DeliveryEventKind) {
+        match kind {
+            DeliveryEventKind::Sent => self.sent += 1,
+            DeliveryEventKind::Opened => self.opened += 1,
+            DeliveryEventKind::Replied => self.replied += 1,
+            DeliveryEventKind::Bounce => self.bounced += 1,
+        }
+    }
+}
+
+/// Sent and bounced counts for a hub over a rolling window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BounceStats {
+    pub sent: i64,
+    pub bounced: i64,
+}
+
+impl BounceStats {
+    /// Fraction of sent messages that bounced, or `0.0` when nothing was sent.
+    pub fn rate(&self) -> f64 {
+        if self.sent <= 0 {
+            return 0.0;
+        }
+        self.bounced as f64 / self.sent as f64
+    }
+}
+
+/// A page of a listing: at most `limit` rows after skipping `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// What an unsubscribe was recorded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsubscribeSource {
+    /// The recipient asked by email.
+    Request,
+    /// A bounce notification named the address.
+    Bounce,
+    /// A mailbox provider forwarded an ARF complaint about a message.
+    Complaint,
+    /// The recipient followed the one-click `List-Unsubscribe` link.
+    OneClick,
+}
+
+impl UnsubscribeSource {
+    /// Returns the value stored in the `source` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnsubscribeSource::Request => "request",
+            UnsubscribeSource::Bounce => "bounce",
+            UnsubscribeSource::Complaint => "complaint",
+            UnsubscribeSource::OneClick => "one_click",
+        }
+    }
+
+    /// Parses a `source` column value.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "request" => Some(UnsubscribeSource::Request),
+            "bounce" => Some(UnsubscribeSource::Bounce),
+            "complaint" => Some(UnsubscribeSource::Complaint),
+            "one_click" => Some(UnsubscribeSource::OneClick),
+            _ => None,
+        }
+    }
+}
+
+/// An address unsubscribed from a hub, as listed for admins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsubscribeEntry {
+    pub email: String,
+    /// What recorded the unsubscribe; `None` for rows recorded before
+    /// sources were tracked.
+    pub source: Option<UnsubscribeSource>,
+    /// Why the address was unsubscribed, e.g. the reply text, a bounce
+    /// subject, `complaint: abuse` or `one-click unsubscribe`.
+    pub reason: Option<String>,
+    /// When the address was first unsubscribed; `None` for rows recorded
+    /// before the column existed.
+    pub created_at: Option<NaiveDateTime>,
+}
+
+/// Attachment addressed to a single recipient.
+///
+/// Recipients are shared `pushkind-emailer` types, so the attachment travels in
+/// the recipient `fields` map under the reserved `attachment*` keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientAttachment {
+    pub content: Vec<u8>,
+    pub name: String,
+    pub mime: String,
+}
+
+impl RecipientAttachment {
+    /// Extracts the attachment from recipient fields.
+    ///
+    /// Returns `None` when any part is missing or empty, or when the content
+    /// is not valid base64.
+    pub fn from_fields(fields: &BTreeMap<String, String>) -> Option<Self> {
+        let encoded = fields.get(ATTACHMENT_FIELD)?;
+        let name = fields.get(ATTACHMENT_NAME_FIELD)?;
+        let mime = fields.get(ATTACHMENT_MIME_FIELD)?;
+        if encoded.is_empty() || name.is_empty() || mime.is_empty() {
+            return None;
+        }
+
+        let content = match STANDARD.decode(encoded.trim()) {
+            Ok(content) if !content.is_empty() => content,
+            Ok(_) => return None,
+            Err(e) => {
+                log::warn!("Ignoring recipient attachment {name}: invalid base64: {e}");
+                return None;
+            }
+        };
+
+        Some(Self {
+            content,
+            name: name.clone(),
+            mime: mime.clone(),
+        })
+    }
+
+    /// Stores the attachment in recipient fields under the reserved keys.
+    pub fn write_to_fields(&self, fields: &mut BTreeMap<String, String>) {
+        fields.insert(ATTACHMENT_FIELD.into(), STANDARD.encode(&self.content));
+        fields.insert(ATTACHMENT_NAME_FIELD.into(), self.name.clone());
+        fields.insert(ATTACHMENT_MIME_FIELD.into(), self.mime.clone());
+    }
+}
+
+/// Threading headers placing a follow-up in the recipient's conversation.
+///
+/// Like [`RecipientAttachment`], they travel in recipient `fields`, under
+/// the reserved `in_reply_to` and `references` keys. Message-IDs are stored
+/// without angle brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyThread {
+    pub in_reply_to: String,
+    /// Full `References` chain, ending with `in_reply_to`.
+    pub references: Vec<String>,
+}
+
+impl ReplyThread {
+    /// Extracts the thread from recipient fields.
+    ///
+    /// Returns `None` without an `in_reply_to` Message-ID. Angle brackets
+    /// around IDs are accepted and stripped.
+    pub fn from_fields(fields: &BTreeMap<String, String>) -> Option<Self> {
+        let in_reply_to = strip_angle_brackets(fields.get(IN_REPLY_TO_FIELD)?)?;
+        let mut references: Vec<String> = fields
+            .get(REFERENCES_FIELD)
+            .map(|ids| {
+                ids.split_whitespace()
+                    .filter_map(strip_angle_brackets)
+                    .collect()
+            })
+            .unwrap_or_default();
+        if references.last() != Some(&in_reply_to) {
+            references.push(in_reply_to.clone());
+        }
+
+        Some(Self {
+            in_reply_to,
+            references,
+        })
+    }
+}
+
+/// Returns the recipient `fields` usable as template variables, leaving out
+/// the [`RESERVED_FIELDS`] so an attachment payload is never rendered.
+pub fn template_fields(fields: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    fields
+        .iter()
+        .filter(|(key, _)| !RESERVED_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Returns `true` when the recipient `fields` opt out of open tracking:
+/// `no_tracking` is `true`, `yes` or `1`, ignoring case and surrounding
+/// whitespace.
+pub fn tracking_opted_out(fields: &BTreeMap<String, String>) -> bool {
+    fields.get(NO_TRACKING_FIELD).is_some_and(|value| {
+        let value = value.trim();
+        ["true", "yes", "1"]
+            .iter()
+            .any(|truthy| value.eq_ignore_ascii_case(truthy))
+    })
+}
+
+/// Trims a Message-ID and removes surrounding `<`/`>`; `None` when empty.
+fn strip_angle_brackets(id: &str) -> Option<String> {
+    let id = id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_subaddress_when_enabled() {
+        assert_eq!(
+            normalize_address(" user+promo@example.com ", true),
+            "user@example.com"
+        );
+        assert_eq!(
+            normalize_address("user@example.com", true),
+            "user@example.com"
+        );
+        assert_eq!(
+            normalize_address("user+promo@example.com", false),
+            "user+promo@example.com"
+        );
+        assert_eq!(
+            normalize_address("+only@example.com", true),
+            "+only@example.com"
+        );
+    }
+
+    #[test]
+    fn send_request_decodes_plain_and_extended_payloads() {
+        let plain: SendEmailRequest = serde_json::from_str(r#"{"RetryEmail":[5,1]}"#).unwrap();
+        assert!(matches!(
+            plain.message,
+            ZMQSendEmailMessage::RetryEmail((5, 1))
+        ));
+        assert_eq!(plain.from_override, None);
+
+        let extended: SendEmailRequest = serde_json::from_str(
+            r#"{"RetryEmail":[5,1],"from_override":{"address":"billing@example.com"}}"#,
+        )
+        .unwrap();
+        assert_eq!(extended.hub_id(), 1);
+        assert_eq!(
+            extended.from_override,
+            Some(FromOverride {
+                address: "billing@example.com".into(),
+                name: None,
+            })
+        );
+        assert_eq!(extended.date, None);
+
+        let dated: SendEmailRequest =
+            serde_json::from_str(r#"{"RetryEmail":[5,1],"date":"2024-01-15T09:30:00Z"}"#).unwrap();
+        assert_eq!(dated.date.map(|date| date.timestamp()), Some(1_705_311_000));
+        assert_eq!(dated.raw, None);
+
+        let raw: SendEmailRequest =
+            serde_json::from_str(r#"{"RetryEmail":[5,1],"raw":{}}"#).unwrap();
+        assert_eq!(raw.raw, Some(RawSend::default()));
+    }
+
+    #[test]
+    fn recipient_id_round_trips_through_message_id() {
+        let id = message_id(42, "18c4f0a1b2c30001", "example.com");
+        assert_eq!(id, "42.18c4f0a1b2c30001@example.com");
+
+        let (local, _) = id.split_once('@').unwrap();
+        assert_eq!(recipient_id_from_message_id(local), Some(42));
+        assert_eq!(recipient_id_from_message_id("42"), Some(42));
+        assert_eq!(recipient_id_from_message_id("abc.123"), None);
+    }
+
+    #[test]
+    fn preview_message_id_has_no_recipient() {
+        let id = preview_message_id("18c4f0a1b2c30001", "example.com");
+        let (local, domain) = id.split_once('@').unwrap();
+        assert_eq!(local, "preview.18c4f0a1b2c30001");
+        assert_eq!(domain, "example.com");
+        assert_eq!(recipient_id_from_message_id(local), None);
+    }
+
+    #[test]
+    fn recipient_id_from_envelope_requires_tag() {
+        assert_eq!(
+            recipient_id_from_envelope("replies+rcpt42@example.com"),
+            Some(42)
+        );
+        assert_eq!(
+            recipient_id_from_envelope("replies+promo@example.com"),
+            None
+        );
+        assert_eq!(recipient_id_from_envelope("replies@example.com"), None);
+    }
+
+    #[test]
+    fn bounce_rate_is_zero_without_sends() {
+        let stats = BounceStats {
+            sent: 0,
+            bounced: 3,
+        };
+        assert_eq!(stats.rate(), 0.0);
+    }
+
+    #[test]
+    fn bounce_rate_is_fraction_of_sent() {
+        let stats = BounceStats {
+            sent: 40,
+            bounced: 10,
+        };
+        assert_eq!(stats.rate(), 0.25);
+    }
+
+    #[test]
+    fn recipient_attachment_round_trips_through_fields() {
+        let attachment = RecipientAttachment {
+            content: b"invoice".to_vec(),
+            name: "invoice.pdf".into(),
+            mime: "application/pdf".into(),
+        };
+        let mut fields = BTreeMap::new();
+        attachment.write_to_fields(&mut fields);
+
+        assert_eq!(RecipientAttachment::from_fields(&fields), Some(attachment));
+    }
+
+    #[test]
+    fn recipient_attachment_requires_valid_content() {
+        let mut fields = BTreeMap::new();
+        fields.insert(ATTACHMENT_FIELD.to_string(), "not base64!".to_string());
+        fields.insert(ATTACHMENT_NAME_FIELD.to_string(), "a.txt".to_string());
+        fields.insert(ATTACHMENT_MIME_FIELD.to_string(), "text/plain".to_string());
+
+        assert_eq!(RecipientAttachment::from_fields(&fields), None);
+    }
+
+    #[test]
+    fn no_tracking_field_opts_out() {
+        let mut fields = BTreeMap::new();
+        assert!(!tracking_opted_out(&fields));
+        for value in ["true", " TRUE ", "yes", "1"] {
+            fields.insert(NO_TRACKING_FIELD.to_string(), value.to_string());
+            assert!(tracking_opted_out(&fields), "{value:?}");
+        }
+        for value in ["false", "0", ""] {
+            fields.insert(NO_TRACKING_FIELD.to_string(), value.to_string());
+            assert!(!tracking_opted_out(&fields), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn reply_thread_appends_parent_to_references() {
+        let mut fields = BTreeMap::new();
+        assert_eq!(ReplyThread::from_fields(&fields), None);
+
+        fields.insert(
+            IN_REPLY_TO_FIELD.to_string(),
+            " <reply@mail.example> ".to_string(),
+        );
+        fields.insert(
+            REFERENCES_FIELD.to_string(),
+            "<1.abc@example.com> <reply@mail.example>".to_string(),
+        );
+        let thread = ReplyThread::from_fields(&fields).unwrap();
+        assert_eq!(thread.in_reply_to, "reply@mail.example");
+        assert_eq!(
+            thread.references,
+            vec!["1.abc@example.com", "reply@mail.example"]
+        );
+
+        fields.remove(REFERENCES_FIELD);
+        let thread = ReplyThread::from_fields(&fields).unwrap();
+        assert_eq!(thread.references, vec!["reply@mail.example"]);
+    }
+}
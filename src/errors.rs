@@ -36,10 +36,38 @@ pub enum Error {
     #[error("database pool error: {0}")]
     Pool(#[from] diesel::r2d2::PoolError),
 
+    /// Filesystem failures, e.g. in the notification spool.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// JSON encoding or decoding failures.
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The server could not be reached (name resolution or TCP connect).
+    #[error("connection error: {0}")]
+    Connection(String),
+
+    /// The TLS handshake with the server failed.
+    #[error("tls handshake error: {0}")]
+    TlsHandshake(String),
+
+    /// The server rejected the login credentials.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+
     /// Problems with environment or configuration.
     #[error("configuration error: {0}")]
     Config(String),
 
+    /// A requested item, e.g. an IMAP message, does not exist.
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    /// A message is over a size limit.
+    #[error("too large: {0}")]
+    TooLarge(String),
+
     ///Problems with ZmqSender
     #[error("zmq sender error: {0}")]
     ZmqSender(#[from] pushkind_common::zmq::ZmqSenderError),
@@ -1,6 +1,14 @@
+pub mod address;
 pub mod check_reply;
+pub mod credentials;
+pub mod db;
 pub mod domain;
 pub mod errors;
+pub mod logging;
 pub mod models;
+mod net;
+mod regexes;
 pub mod repository;
+pub mod schema;
 pub mod send_email;
+pub mod unsubscribe;
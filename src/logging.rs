@@ -0,0 +1,130 @@
+//! Runtime-reconfigurable logging.
+//!
+//! Wraps an [`env_logger::Logger`] so its filter (`RUST_LOG` syntax, e.g.
+//! `info,pushkind_hedwig::check_reply::imap=debug`) can be replaced while
+//! the worker runs, for instance from a control message.
+
+use std::sync::{PoisonError, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+
+use crate::errors::Error;
+
+/// Logger whose `env_logger` filter can be swapped at runtime.
+pub struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+fn build_logger(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+impl ReloadableLogger {
+    /// Creates a logger applying `filter`.
+    pub fn new(filter: &str) -> Self {
+        Self {
+            inner: RwLock::new(build_logger(filter)),
+        }
+    }
+
+    /// Most verbose level any directive of the current filter enables.
+    pub fn filter(&self) -> LevelFilter {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .filter()
+    }
+
+    /// Replaces the filter and returns its most verbose level.
+    ///
+    /// Fails with [`Error::Config`] on an empty filter.
+    pub fn set_filter(&self, filter: &str) -> Result<LevelFilter, Error> {
+        if filter.trim().is_empty() {
+            return Err(Error::Config("Log filter must not be empty".into()));
+        }
+        let logger = build_logger(filter);
+        let level = logger.filter();
+        *self.inner.write().unwrap_or_else(PoisonError::into_inner) = logger;
+        Ok(level)
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .log(record);
+    }
+
+    fn flush(&self) {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush();
+    }
+}
+
+static LOGGER: OnceCell<ReloadableLogger> = OnceCell::new();
+
+/// Installs the global logger with the filter from `RUST_LOG`, falling back
+/// to `default_filter`.
+pub fn init(default_filter: &str) -> Result<(), Error> {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string());
+    let logger = LOGGER.get_or_init(|| ReloadableLogger::new(&filter));
+    log::set_logger(logger).map_err(|e| Error::Config(format!("Cannot install logger: {e}")))?;
+    log::set_max_level(logger.filter());
+    Ok(())
+}
+
+/// Replaces the filter of the logger installed by [`init`].
+pub fn set_filter(filter: &str) -> Result<(), Error> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| Error::Config("Runtime log filter is not installed".into()))?;
+    log::set_max_level(logger.set_filter(filter)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(logger: &ReloadableLogger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn set_filter_enables_debug_for_one_module() {
+        let logger = ReloadableLogger::new("info");
+        let parser = "pushkind_hedwig::check_reply::parser";
+        assert!(!enabled(&logger, parser, Level::Debug));
+        assert_eq!(logger.filter(), LevelFilter::Info);
+
+        let level = logger.set_filter(&format!("info,{parser}=debug")).unwrap();
+        assert_eq!(level, LevelFilter::Debug);
+        assert!(enabled(&logger, parser, Level::Debug));
+        assert!(!enabled(
+            &logger,
+            "pushkind_hedwig::check_reply::imap",
+            Level::Debug
+        ));
+        assert!(enabled(&logger, "pushkind_hedwig::send_email", Level::Info));
+    }
+
+    #[test]
+    fn rejects_empty_filter() {
+        let logger = ReloadableLogger::new("warn");
+        assert!(matches!(logger.set_filter("  "), Err(Error::Config(_))));
+        assert_eq!(logger.filter(), LevelFilter::Warn);
+    }
+}
@@ -1,21 +1,1161 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use chrono::{NaiveDate, NaiveDateTime};
 use diesel::prelude::*;
+use pushkind_emailer::domain::types::HubId;
 use serde::Deserialize;
 
+use crate::address::{AddressNormalizer, GmailNormalizer, StandardNormalizer};
+use crate::credentials::PasswordKey;
+use crate::domain::{BounceStats, CorrelationOrder};
+use crate::errors::Error;
+use crate::send_email::template::check_partials;
+use crate::unsubscribe::UnsubscribeKey;
+
 #[derive(Insertable)]
-#[diesel(table_name = pushkind_emailer::schema::unsubscribes)]
+#[diesel(table_name = crate::schema::unsubscribes)]
 pub struct Unsubscribe<'a> {
     pub email: &'a str,
     pub hub_id: i32,
     pub reason: Option<&'a str>,
+    pub created_at: NaiveDateTime,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::delivery_events)]
+pub struct NewDeliveryEvent<'a> {
+    pub hub_id: i32,
+    pub kind: &'a str,
+    pub created_at: NaiveDateTime,
+    /// IMAP UID of the bounce notification; `None` for sends.
+    pub imap_uid: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::processed_replies)]
+pub struct NewProcessedReply<'a> {
+    pub hub_id: i32,
+    pub message_id: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::rejected_recipients)]
+pub struct NewRejectedRecipient<'a> {
+    pub recipient_id: i32,
+    pub reason: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::email_deadlines)]
+pub struct NewEmailDeadline {
+    pub email_id: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::unsubscribe_sources)]
+pub struct NewUnsubscribeSource<'a> {
+    pub hub_id: i32,
+    pub email: &'a str,
+    pub source: &'a str,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::global_suppressions)]
+pub struct NewGlobalSuppression<'a> {
+    pub email: &'a str,
+    pub reason: Option<&'a str>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::reply_categories)]
+pub struct NewReplyCategory<'a> {
+    pub recipient_id: i32,
+    pub category: &'a str,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::reply_languages)]
+pub struct NewReplyLanguage<'a> {
+    pub recipient_id: i32,
+    pub language: &'a str,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 /// Basic configuration shared across handlers.
 pub struct ServerConfig {
+    #[serde(default)]
     pub domain: String,
+    /// Required; checked by [`ServerConfig::validate`].
+    #[serde(default)]
     pub database_url: String,
+    #[serde(default = "ServerConfig::default_zmq_emailer_pub")]
     pub zmq_emailer_pub: String,
+    #[serde(default = "ServerConfig::default_zmq_emailer_sub")]
     pub zmq_emailer_sub: String,
+    #[serde(default = "ServerConfig::default_zmq_replier_pub")]
     pub zmq_replier_pub: String,
+    #[serde(default = "ServerConfig::default_zmq_replier_sub")]
     pub zmq_replier_sub: String,
+    /// Optional endpoint `check_reply` subscribes to for control messages.
+    #[serde(default)]
+    pub zmq_control_sub: Option<String>,
+    #[serde(default)]
+    pub zmq_topics: ZmqTopicsConfig,
+    #[serde(default)]
+    pub bounce_breaker: BounceBreakerConfig,
+    #[serde(default)]
+    pub db_pool: DbPoolConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub spam_check: SpamCheckConfig,
+    #[serde(default)]
+    pub sender_auth: DeclaredSenderAuthGuardConfig,
+    #[serde(default)]
+    pub uid_persist: UidPersistConfig,
+    #[serde(default)]
+    pub backlog: BacklogConfig,
+    #[serde(default)]
+    pub imap_fetch: ImapFetchConfig,
+    #[serde(default)]
+    pub imap_search: ImapSearchConfig,
+    #[serde(default)]
+    pub imap_keepalive: ImapKeepaliveConfig,
+    #[serde(default)]
+    pub hub_refresh: HubRefreshConfig,
+    #[serde(default)]
+    pub suppression: SuppressionConfig,
+    #[serde(default)]
+    pub correlation: CorrelationConfig,
+    #[serde(default)]
+    pub message_id: MessageIdConfig,
+    #[serde(default)]
+    pub reply: ReplyConfig,
+    #[serde(default)]
+    pub unsubscribe_confirmation: UnsubscribeConfirmationConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub publish_retry: PublishRetryConfig,
+    /// Base64-encoded 32-byte key for encrypted hub passwords.
+    #[serde(default)]
+    pub password_key: Option<String>,
+    /// HMAC secret signing one-click unsubscribe tokens.
+    #[serde(default)]
+    pub unsubscribe_secret: Option<String>,
+    /// Directory of file-based hub templates named `{hub_id}.html`.
+    #[serde(default)]
+    pub template_dir: Option<PathBuf>,
+    /// Shared template blocks, e.g. a header or footer, that hub templates
+    /// include with `{>name}`.
+    #[serde(default)]
+    pub template_partials: HashMap<String, String>,
+    /// On-disk spool for notifications that could not be published.
+    #[serde(default)]
+    pub spool: Option<SpoolConfig>,
+    /// Per-hub overrides keyed by hub ID.
+    #[serde(default)]
+    pub hubs: HashMap<i32, HubSettings>,
+}
+
+impl ServerConfig {
+    fn default_zmq_emailer_pub() -> String {
+        "tcp://127.0.0.1:5557".into()
+    }
+
+    fn default_zmq_emailer_sub() -> String {
+        "tcp://127.0.0.1:5558".into()
+    }
+
+    fn default_zmq_replier_pub() -> String {
+        "tcp://127.0.0.1:5559".into()
+    }
+
+    fn default_zmq_replier_sub() -> String {
+        "tcp://127.0.0.1:5560".into()
+    }
+
+    /// Returns the settings for `hub_id`, falling back to defaults.
+    pub fn hub_settings(&self, hub_id: HubId) -> HubSettings {
+        self.hubs.get(&hub_id.get()).cloned().unwrap_or_default()
+    }
+
+    /// Decodes `password_key`, if configured.
+    pub fn password_key(&self) -> Result<Option<PasswordKey>, Error> {
+        self.password_key
+            .as_deref()
+            .map(PasswordKey::from_base64)
+            .transpose()
+    }
+
+    /// Builds the unsubscribe token key from `unsubscribe_secret`, if set.
+    pub fn unsubscribe_key(&self) -> Result<Option<UnsubscribeKey>, Error> {
+        self.unsubscribe_secret
+            .as_deref()
+            .map(UnsubscribeKey::new)
+            .transpose()
+    }
+
+    /// Checks settings that deserialization cannot, naming the offending
+    /// field in the error.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.database_url.trim().is_empty() {
+            return Err(Error::Config("database_url must be set".into()));
+        }
+        self.password_key()?;
+        self.unsubscribe_key()?;
+        check_partials(&self.template_partials)?;
+
+        let endpoints = [
+            ("zmq_emailer_pub", Some(&self.zmq_emailer_pub)),
+            ("zmq_emailer_sub", Some(&self.zmq_emailer_sub)),
+            ("zmq_replier_pub", Some(&self.zmq_replier_pub)),
+            ("zmq_replier_sub", Some(&self.zmq_replier_sub)),
+            ("zmq_control_sub", self.zmq_control_sub.as_ref()),
+        ];
+        for (field, address) in endpoints {
+            if let Some(address) = address
+                && !is_tcp_endpoint(address)
+            {
+                return Err(Error::Config(format!(
+                    "{field} must be a tcp://host:port address, got {address:?}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns `true` for `tcp://host:port` with a non-empty host and numeric port.
+fn is_tcp_endpoint(address: &str) -> bool {
+    let Some(rest) = address.strip_prefix("tcp://") else {
+        return false;
+    };
+    match rest.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Settings that apply to a single hub.
+pub struct HubSettings {
+    /// Daily send caps applied while a new sending domain warms up.
+    pub warmup: Option<WarmupConfig>,
+    /// Hostname announced in SMTP EHLO/HELO instead of the local default.
+    pub helo_host: Option<String>,
+    /// Local source address of the hub's SMTP and IMAP connections; chosen
+    /// by the OS when unset.
+    pub bind_address: Option<IpAddr>,
+    /// Most IMAP searches and message fetches `check_reply` issues per
+    /// minute for the hub; unpaced when unset.
+    pub imap_ops_per_minute: Option<u32>,
+    /// Mailbox for the RFC 5322 `Sender` header when sending on behalf of
+    /// the `From` party; omitted when unset.
+    pub sender_header: Option<MailboxConfig>,
+    /// Secret references overriding the login/password stored in the database.
+    pub credentials: Option<CredentialsConfig>,
+    /// Recipients of one email sent in parallel, each over its own SMTP
+    /// connection; defaults to `1` (sequential).
+    pub send_concurrency: Option<usize>,
+    /// From addresses send jobs may use instead of the hub default
+    /// (case-insensitive). Overrides are rejected when empty.
+    pub allowed_from: Vec<String>,
+    /// Domains the hub's SMTP account may send as (case-insensitive), for
+    /// providers that reject a From outside the authenticated account's
+    /// domain. Any domain is accepted when empty.
+    pub allowed_sender_domains: Vec<String>,
+    /// Forced charset and transfer encoding for the message body; when
+    /// unset, `mail-builder` sends UTF-8 and picks the encoding itself.
+    pub body_encoding: Option<BodyEncodingConfig>,
+    /// Template file used instead of the database `email_template`.
+    pub template_path: Option<PathBuf>,
+    /// Subject used when an email has none; may contain `{name}` and
+    /// recipient field placeholders.
+    pub default_subject: Option<String>,
+    /// RFC 2919 `List-Id` stamped on the hub's campaign mail.
+    pub list_id: Option<ListIdConfig>,
+    /// Domain of the hub's Message-IDs and tracking links, and the one reply
+    /// correlation expects in `In-Reply-To`; the global `domain` when unset.
+    pub domain: Option<String>,
+    /// Sender authentication declared for the hub's sending domain; checked
+    /// by the `sender_auth` guard in strict mode without querying DNS.
+    pub sender_auth: Option<DeclaredSenderAuthConfig>,
+    /// Largest serialized message, attachments included, the hub's SMTP
+    /// provider accepts; unlimited when unset.
+    pub max_message_bytes: Option<usize>,
+    /// Query parameters removed from links in the rendered body, e.g.
+    /// `utm_source`; a trailing `*` matches a prefix (`utm_*`).
+    pub strip_link_params: Vec<String>,
+    /// Gmail Feedback Loop `Feedback-ID` stamped on the hub's campaign mail.
+    pub feedback_id: Option<FeedbackIdConfig>,
+}
+
+impl HubSettings {
+    /// Returns `true` when `address` is on the hub's From allowlist.
+    pub fn allows_from(&self, address: &str) -> bool {
+        let address = address.trim();
+        self.allowed_from
+            .iter()
+            .any(|allowed| allowed.trim().eq_ignore_ascii_case(address))
+    }
+
+    /// Returns `true` when the domain of the From `address` is one the
+    /// hub's SMTP account may send as, or no domains are configured.
+    pub fn allows_sender_domain(&self, address: &str) -> bool {
+        if self.allowed_sender_domains.is_empty() {
+            return true;
+        }
+        let Some((_, domain)) = address.trim().rsplit_once('@') else {
+            return false;
+        };
+        self.allowed_sender_domains
+            .iter()
+            .any(|allowed| allowed.trim().eq_ignore_ascii_case(domain))
+    }
+
+    /// Returns the hub's domain, or `default` when none is set.
+    pub fn domain_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.domain
+            .as_deref()
+            .map(str::trim)
+            .filter(|domain| !domain.is_empty())
+            .unwrap_or(default)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Fields of the `Feedback-ID: {campaign}:{hub}:{segment}:{sender}` header.
+/// The campaign is the email ID and the hub the hub ID.
+pub struct FeedbackIdConfig {
+    /// Stable sender identifier, the field Gmail aggregates complaints by.
+    pub sender_id: String,
+    /// Recipient field holding the segment, e.g. `segment`; the segment is
+    /// left out when unset or when the recipient lacks the field.
+    #[serde(default)]
+    pub segment_field: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// List identifier, e.g. `news.example.com`, with an optional description.
+pub struct ListIdConfig {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl ListIdConfig {
+    /// Returns `true` when `list_id` (without angle brackets) names this list.
+    pub fn matches(&self, list_id: &str) -> bool {
+        self.id.trim().eq_ignore_ascii_case(list_id.trim())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Charset and transfer encoding used for the text and HTML body parts.
+pub struct BodyEncodingConfig {
+    /// Content-Transfer-Encoding; chosen per body when unset.
+    pub transfer_encoding: Option<TransferEncoding>,
+    /// Charset label (e.g. `windows-1251`) the body is converted to;
+    /// defaults to UTF-8.
+    pub charset: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// Content-Transfer-Encoding that can be forced for body parts.
+pub enum TransferEncoding {
+    Base64,
+    QuotedPrintable,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Where to read hub credentials from instead of the database.
+pub struct CredentialsConfig {
+    pub login: Option<SecretRef>,
+    pub password: Option<SecretRef>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// Reference to a secret kept outside the database.
+pub enum SecretRef {
+    /// Name of an environment variable holding the value.
+    Env(String),
+    /// Path of a file holding the value; trailing newlines are ignored.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// A mailbox with an optional display name.
+pub struct MailboxConfig {
+    pub address: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Warm-up schedule for a hub's sending domain.
+pub struct WarmupConfig {
+    /// First day of the warm-up (UTC).
+    pub start_date: NaiveDate,
+    /// Daily send caps; entry `n` applies on day `n` after `start_date`.
+    pub daily_caps: Vec<i64>,
+}
+
+impl WarmupConfig {
+    /// Returns the send cap for `today`, or `None` once the schedule is over.
+    ///
+    /// Days before `start_date` use the first cap.
+    pub fn cap_for(&self, today: NaiveDate) -> Option<i64> {
+        let day = (today - self.start_date).num_days().max(0);
+        usize::try_from(day)
+            .ok()
+            .and_then(|day| self.daily_caps.get(day).copied())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Connection pool limits for the SQLite database.
+pub struct DbPoolConfig {
+    /// Maximum number of open connections.
+    pub max_size: u32,
+    /// Idle connections kept open; `None` keeps `max_size` connections.
+    pub min_idle: Option<u32>,
+    /// Seconds to wait for a connection before failing.
+    pub connection_timeout_secs: u64,
+    /// Milliseconds SQLite waits on a locked database before failing.
+    pub busy_timeout_ms: u64,
+}
+
+impl Default for DbPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout_secs: 30,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Retry policy for publishing reply and unsubscribe notifications.
+pub struct PublishRetryConfig {
+    /// Total publish attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry in milliseconds; doubled after each retry.
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between retries in milliseconds.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for PublishRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2000,
+        }
+    }
+}
+
+impl PublishRetryConfig {
+    /// Delay before retry number `retry` (starting at `1`).
+    pub fn backoff(&self, retry: u32) -> std::time::Duration {
+        let factor = 1_u64
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        std::time::Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How often `check_reply` re-reads each hub record while its monitor runs.
+pub struct HubRefreshConfig {
+    /// Seconds between refetches; `0` (the default) disables them.
+    pub interval_secs: u64,
+    /// Each interval is lengthened or shortened by a random amount up to
+    /// this many seconds, so hubs do not refetch in lockstep.
+    pub jitter_secs: u64,
+}
+
+impl HubRefreshConfig {
+    /// Delay before the next refetch for a random `sample` in `[0, 1]`, or
+    /// `None` when refetching is disabled. Never shorter than one second.
+    pub fn delay(&self, sample: f64) -> Option<std::time::Duration> {
+        if self.interval_secs == 0 {
+            return None;
+        }
+        let interval = self.interval_secs as f64;
+        let jitter = self.jitter_secs.min(self.interval_secs) as f64;
+        let secs = interval - jitter + 2.0 * jitter * sample.clamp(0.0, 1.0);
+        Some(std::time::Duration::from_secs_f64(secs.max(1.0)))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Location and flush cadence of the notification spool.
+pub struct SpoolConfig {
+    /// Directory holding spooled notifications; created if missing.
+    pub dir: PathBuf,
+    /// Seconds between attempts to re-send spooled notifications.
+    #[serde(default = "SpoolConfig::default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+}
+
+impl SpoolConfig {
+    fn default_flush_interval_secs() -> u64 {
+        30
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How addresses are normalized before unsubscribes are recorded or
+/// looked up and before the recipients of a new email are deduplicated.
+pub struct SuppressionConfig {
+    /// Treat `user+tag@domain` as `user@domain`.
+    pub strip_subaddress: bool,
+    /// Accept internationalized domains in bounce reports and compare
+    /// addresses by their punycode domain, so `user@пример.рф` and
+    /// `user@xn--e1afmkfd.xn--p1ai` match.
+    pub allow_unicode_domains: bool,
+    /// Compare addresses case-insensitively, local part included.
+    pub lowercase: bool,
+    /// Apply Gmail's rules to `gmail.com` and `googlemail.com` addresses;
+    /// see [`GmailNormalizer`].
+    pub gmail_canonical: bool,
+}
+
+impl SuppressionConfig {
+    fn standard(&self) -> StandardNormalizer {
+        StandardNormalizer {
+            strip_subaddress: self.strip_subaddress,
+            lowercase: self.lowercase,
+            punycode_domains: self.allow_unicode_domains,
+        }
+    }
+}
+
+impl AddressNormalizer for SuppressionConfig {
+    /// Normalizes with a [`StandardNormalizer`] built from the flags,
+    /// wrapped in a [`GmailNormalizer`] with `gmail_canonical`.
+    fn normalize(&self, address: &str) -> String {
+        if self.gmail_canonical {
+            GmailNormalizer(self.standard()).normalize(address)
+        } else {
+            self.standard().normalize(address)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How `check_reply` ties incoming messages to recipients.
+pub struct CorrelationConfig {
+    /// Which source is tried first when both are present.
+    pub order: CorrelationOrder,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How outbound Message-IDs stay unique across `send_email` processes.
+pub struct MessageIdConfig {
+    /// Identifier of this worker in Message-ID tokens, e.g. `mx1`. Letters,
+    /// digits and `-` are kept; a random identifier is drawn when unset.
+    pub node: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// What `check_reply` counts as a genuine reply.
+pub struct ReplyConfig {
+    /// Replies shorter than this many characters (after trimming) are
+    /// logged but not stored or counted; the recipient is still marked opened.
+    pub min_length: usize,
+    /// Keyword rules of the reply classifier, checked in order; the
+    /// built-in rules are used when empty.
+    pub categories: Vec<KeywordRule>,
+    /// Detect the language of stored replies and save it with the recipient.
+    pub detect_language: bool,
+    /// What a reply without extractable text does to the recipient.
+    pub empty_reply: EmptyReplyAction,
+    /// Reply stored for an empty reply with
+    /// [`EmptyReplyAction::CountAsReplied`].
+    pub empty_reply_text: String,
+    /// Message-IDs of processed replies remembered per hub, so a reply
+    /// fetched again after a UID reset or reprocessing is skipped; `0`
+    /// disables deduplication.
+    pub dedup_capacity: usize,
+}
+
+/// Handling of replies whose text is empty or cannot be extracted, e.g. an
+/// attachment-only message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyReplyAction {
+    /// Mark the recipient opened without storing or counting a reply.
+    #[default]
+    MarkOpened,
+    /// Store `empty_reply_text` as the reply, so the recipient counts as
+    /// replied.
+    CountAsReplied,
+    /// Leave the recipient untouched.
+    Skip,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+/// Reply category assigned when a reply contains any of `keywords`.
+pub struct KeywordRule {
+    pub category: String,
+    /// Matched case-insensitively anywhere in the reply.
+    pub keywords: Vec<String>,
+}
+
+impl Default for ReplyConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            categories: Vec::new(),
+            detect_language: false,
+            empty_reply: EmptyReplyAction::default(),
+            empty_reply_text: "(empty reply)".into(),
+            dedup_capacity: 10_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Automated email acknowledging an unsubscribe request sent by reply.
+pub struct UnsubscribeConfirmationConfig {
+    pub enabled: bool,
+    pub subject: String,
+    /// Plain-text body of the confirmation.
+    pub body: String,
+    /// Most confirmations a hub sends in any hour; further requests are
+    /// still unsubscribed but not confirmed.
+    pub max_per_hour: usize,
+}
+
+impl Default for UnsubscribeConfirmationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: "You have been unsubscribed".into(),
+            body: "You will no longer receive our emails.".into(),
+            max_per_hour: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Topic prefixes for notifications `check_reply` publishes on
+/// `zmq_replier_pub`, so subscribers can filter by message type.
+///
+/// An empty prefix publishes the bare JSON payload.
+pub struct ZmqTopicsConfig {
+    pub reply: String,
+    pub unsubscribe: String,
+    /// Prefix of batched notifications; see `backlog.batch_notifications`.
+    pub batch: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Bounded shutdown of the `check_reply` worker.
+pub struct ShutdownConfig {
+    /// Seconds monitor tasks get to stop after SIGTERM/Ctrl-C before they
+    /// are aborted.
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How `check_reply` works through messages that arrived while it was down.
+pub struct BacklogConfig {
+    /// Most messages processed per pass before the INBOX is searched again;
+    /// unset processes the whole backlog in one pass.
+    pub batch_size: Option<usize>,
+    /// Which end of the backlog is processed first.
+    pub order: BacklogOrder,
+    /// Publish the notifications of a multi-message pass as one
+    /// `notification_batch` message; single new messages are always
+    /// published on their own.
+    pub batch_notifications: bool,
+}
+
+/// Order in which `check_reply` processes backlog messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BacklogOrder {
+    /// Ascending UIDs.
+    #[default]
+    OldestFirst,
+    /// Descending UIDs, so recent replies are handled first after downtime.
+    NewestFirst,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// What `check_reply` downloads of each new message.
+pub struct ImapFetchConfig {
+    pub items: FetchItems,
+    /// Fetch with `BODY.PEEK` so monitoring leaves the `\Seen` flag alone;
+    /// `false` lets the server mark fetched messages as read.
+    pub peek: bool,
+}
+
+impl Default for ImapFetchConfig {
+    fn default() -> Self {
+        Self {
+            items: FetchItems::default(),
+            peek: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// How `check_reply` searches the INBOX for new messages.
+pub struct ImapSearchConfig {
+    /// Gmail search query narrowing the search through the `X-GM-RAW`
+    /// extension on servers advertising `X-GM-EXT-1`; `{domain}` is replaced
+    /// by the hub's domain. Unset, or on other servers, every new UID is
+    /// processed.
+    pub gmail_raw: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Keeps `check_reply`'s IMAP connections active on networks that drop quiet
+/// TCP connections well before the 29-minute IDLE keepalive.
+pub struct ImapKeepaliveConfig {
+    /// Send a NOOP once the connection has been quiet for this many seconds,
+    /// and restart IDLE at least this often; unset or `0` disables it.
+    pub noop_interval_secs: Option<u64>,
+}
+
+impl ImapKeepaliveConfig {
+    /// The NOOP interval, if enabled.
+    pub fn noop_interval(&self) -> Option<std::time::Duration> {
+        self.noop_interval_secs
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+/// Message data `check_reply` fetches for reply and bounce detection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchItems {
+    /// The whole message, attachments included.
+    #[default]
+    Full,
+    /// The message header and its first MIME part only, which skips
+    /// attachments of `multipart/mixed` mail.
+    HeaderAndFirstPart,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// How often `check_reply` persists the last processed IMAP UID.
+pub struct UidPersistConfig {
+    /// Persist after this many processed messages (`1` persists each one).
+    pub batch_size: usize,
+    /// Also persist once this many seconds pass since the last write; `0`
+    /// disables the time trigger.
+    pub interval_secs: u64,
+}
+
+impl Default for UidPersistConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 1,
+            interval_secs: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Limits for the cache of recently processed send jobs.
+pub struct DedupConfig {
+    /// Maximum number of remembered jobs; `0` disables de-duplication.
+    pub capacity: usize,
+    /// How long a processed job suppresses identical redeliveries, in seconds.
+    pub window_secs: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            window_secs: 300,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Sender authentication the operator declares for a hub's sending domain.
+/// The values are taken on trust; nothing checks them against DNS.
+pub struct DeclaredSenderAuthConfig {
+    /// An SPF record authorizes the hub's SMTP server.
+    pub spf: bool,
+    /// DKIM selector published for the sending domain.
+    pub dkim_selector: Option<String>,
+    /// A DMARC policy is published for the sending domain.
+    pub dmarc: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+/// Opt-in guard refusing sends from hubs whose configuration does not
+/// declare sender authentication.
+///
+/// This is a declared-config check only: it reads the hub's
+/// [`DeclaredSenderAuthConfig`] and performs no SPF, DKIM or DMARC lookup.
+pub struct DeclaredSenderAuthGuardConfig {
+    /// Fail send jobs for hubs that do not declare SPF and a DKIM selector.
+    pub strict: bool,
+    /// Also require a declared DMARC policy.
+    pub require_dmarc: bool,
+}
+
+impl DeclaredSenderAuthGuardConfig {
+    /// Returns the mechanisms `settings` does not declare, empty when the
+    /// hub may send.
+    pub fn missing_declarations(&self, settings: &HubSettings) -> Vec<&'static str> {
+        let auth = settings.sender_auth.clone().unwrap_or_default();
+        let mut missing = Vec::new();
+        if !auth.spf {
+            missing.push("SPF");
+        }
+        if auth
+            .dkim_selector
+            .as_deref()
+            .is_none_or(|selector| selector.trim().is_empty())
+        {
+            missing.push("DKIM");
+        }
+        if self.require_dmarc && !auth.dmarc {
+            missing.push("DMARC");
+        }
+        missing
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Content spam-score pre-check applied before sending an email.
+pub struct SpamCheckConfig {
+    /// Score at or above which a message is considered likely spam.
+    pub threshold: f64,
+    /// Block sends that reach the threshold instead of only logging them.
+    pub strict: bool,
+}
+
+impl Default for SpamCheckConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5.0,
+            strict: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+/// Thresholds for pausing a hub whose sends bounce too often.
+pub struct BounceBreakerConfig {
+    /// Bounce rate (0.0–1.0) at which sending is paused.
+    pub threshold: f64,
+    /// Length of the rolling window in hours.
+    pub window_hours: i64,
+    /// Minimum sends in the window before the rate is considered meaningful.
+    pub min_sent: i64,
+}
+
+impl Default for BounceBreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            window_hours: 24,
+            min_sent: 20,
+        }
+    }
+}
+
+impl BounceBreakerConfig {
+    /// Start of the rolling window relative to `now`.
+    pub fn window_start(&self, now: NaiveDateTime) -> NaiveDateTime {
+        now - chrono::Duration::hours(self.window_hours)
+    }
+
+    /// Returns `true` when `stats` should pause further sends.
+    pub fn is_tripped(&self, stats: &BounceStats) -> bool {
+        stats.sent >= self.min_sent && stats.rate() >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn warmup_cap_follows_schedule() {
+        let warmup = WarmupConfig {
+            start_date: date(2024, 1, 10),
+            daily_caps: vec![50, 100, 200],
+        };
+        assert_eq!(warmup.cap_for(date(2024, 1, 1)), Some(50));
+        assert_eq!(warmup.cap_for(date(2024, 1, 10)), Some(50));
+        assert_eq!(warmup.cap_for(date(2024, 1, 12)), Some(200));
+        assert_eq!(warmup.cap_for(date(2024, 1, 13)), None);
+    }
+
+    fn parse_config(json: &str) -> ServerConfig {
+        serde_json::from_str(json).expect("config should deserialize")
+    }
+
+    #[test]
+    fn missing_zmq_addresses_use_defaults() {
+        let config = parse_config(r#"{"database_url": "app.db"}"#);
+        assert_eq!(config.zmq_emailer_sub, "tcp://127.0.0.1:5558");
+        assert_eq!(config.zmq_replier_pub, "tcp://127.0.0.1:5559");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn sender_auth_guard_reports_missing_mechanisms() {
+        let guard = DeclaredSenderAuthGuardConfig {
+            strict: true,
+            require_dmarc: false,
+        };
+        assert_eq!(
+            guard.missing_declarations(&HubSettings::default()),
+            vec!["SPF", "DKIM"]
+        );
+
+        let mut settings = HubSettings {
+            sender_auth: Some(DeclaredSenderAuthConfig {
+                spf: true,
+                dkim_selector: Some("mail2024".into()),
+                dmarc: false,
+            }),
+            ..Default::default()
+        };
+        assert!(guard.missing_declarations(&settings).is_empty());
+
+        let guard = DeclaredSenderAuthGuardConfig {
+            require_dmarc: true,
+            ..guard
+        };
+        assert_eq!(guard.missing_declarations(&settings), vec!["DMARC"]);
+
+        settings.sender_auth = Some(DeclaredSenderAuthConfig {
+            spf: true,
+            dkim_selector: Some(" ".into()),
+            dmarc: true,
+        });
+        assert_eq!(guard.missing_declarations(&settings), vec!["DKIM"]);
+    }
+
+    #[test]
+    fn hub_refresh_delay_spreads_around_the_interval() {
+        use std::time::Duration;
+
+        assert_eq!(HubRefreshConfig::default().delay(0.5), None);
+
+        let refresh = HubRefreshConfig {
+            interval_secs: 300,
+            jitter_secs: 30,
+        };
+        assert_eq!(refresh.delay(0.0), Some(Duration::from_secs(270)));
+        assert_eq!(refresh.delay(0.5), Some(Duration::from_secs(300)));
+        assert_eq!(refresh.delay(1.0), Some(Duration::from_secs(330)));
+        assert_eq!(refresh.delay(7.0), Some(Duration::from_secs(330)));
+
+        let steady = HubRefreshConfig {
+            interval_secs: 60,
+            jitter_secs: 0,
+        };
+        assert_eq!(steady.delay(0.9), Some(Duration::from_secs(60)));
+
+        // Jitter is capped at the interval and the delay at one second.
+        let wide = HubRefreshConfig {
+            interval_secs: 10,
+            jitter_secs: 60,
+        };
+        assert_eq!(wide.delay(0.0), Some(Duration::from_secs(1)));
+        assert_eq!(wide.delay(1.0), Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn hub_domain_falls_back_to_the_global_domain() {
+        let settings = HubSettings {
+            domain: Some(" tenant.example ".into()),
+            ..Default::default()
+        };
+        assert_eq!(settings.domain_or("example.com"), "tenant.example");
+        assert_eq!(
+            HubSettings::default().domain_or("example.com"),
+            "example.com"
+        );
+        let blank = HubSettings {
+            domain: Some("  ".into()),
+            ..Default::default()
+        };
+        assert_eq!(blank.domain_or("example.com"), "example.com");
+    }
+
+    #[test]
+    fn unicode_domains_normalize_to_punycode() {
+        let ascii_only = SuppressionConfig::default();
+        assert_eq!(ascii_only.normalize(" user@пример.рф "), "user@пример.рф");
+
+        let idn = SuppressionConfig {
+            allow_unicode_domains: true,
+            strip_subaddress: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            idn.normalize("user+promo@Пример.рф"),
+            "user@xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(
+            idn.normalize("user@xn--e1afmkfd.xn--p1ai"),
+            "user@xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(idn.normalize("user@example.com"), "user@example.com");
+        assert_eq!(idn.normalize("no-domain"), "no-domain");
+    }
+
+    #[test]
+    fn suppression_flags_select_the_normalizer() {
+        let config =
+            parse_config(r#"{"suppression": {"lowercase": true, "gmail_canonical": true}}"#);
+        assert_eq!(
+            config.suppression.normalize("John.Doe+x@GoogleMail.com"),
+            "johndoe@gmail.com"
+        );
+        assert_eq!(
+            config.suppression.normalize("John.Doe+x@Example.com"),
+            "john.doe+x@example.com"
+        );
+    }
+
+    #[test]
+    fn zmq_topics_default_to_bare_payloads() {
+        let config = parse_config("{}");
+        assert!(config.zmq_topics.reply.is_empty());
+        assert!(config.zmq_topics.unsubscribe.is_empty());
+        assert!(config.zmq_topics.batch.is_empty());
+
+        let config = parse_config(r#"{"zmq_topics": {"unsubscribe": "unsub"}}"#);
+        assert!(config.zmq_topics.reply.is_empty());
+        assert_eq!(config.zmq_topics.unsubscribe, "unsub");
+    }
+
+    #[test]
+    fn validate_requires_database_url() {
+        let err = parse_config("{}").validate().unwrap_err();
+        assert!(err.to_string().contains("database_url"), "{err}");
+    }
+
+    #[test]
+    fn validate_names_malformed_zmq_address() {
+        let config =
+            parse_config(r#"{"database_url": "app.db", "zmq_replier_sub": "127.0.0.1:5560"}"#);
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("zmq_replier_sub"), "{err}");
+
+        let config = parse_config(
+            r#"{"database_url": "app.db", "zmq_control_sub": "tcp://localhost:port"}"#,
+        );
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("zmq_control_sub"), "{err}");
+    }
+
+    #[test]
+    fn validate_rejects_recursive_template_partials() {
+        let config = parse_config(
+            r#"{"database_url": "app.db", "template_partials": {"footer": "{>footer}"}}"#,
+        );
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("footer"), "{err}");
+    }
+
+    #[test]
+    fn publish_backoff_doubles_up_to_cap() {
+        let retry = PublishRetryConfig {
+            attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 300,
+        };
+        let delays: Vec<u128> = (1..=4).map(|n| retry.backoff(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 300, 300]);
+        assert_eq!(retry.backoff(100).as_millis(), 300);
+    }
+
+    #[test]
+    fn breaker_ignores_small_samples() {
+        let config = BounceBreakerConfig::default();
+        let stats = BounceStats {
+            sent: config.min_sent - 1,
+            bounced: config.min_sent - 1,
+        };
+        assert!(!config.is_tripped(&stats));
+    }
+
+    #[test]
+    fn breaker_trips_at_threshold() {
+        let config = BounceBreakerConfig {
+            threshold: 0.1,
+            window_hours: 24,
+            min_sent: 10,
+        };
+        assert!(!config.is_tripped(&BounceStats {
+            sent: 100,
+            bounced: 9
+        }));
+        assert!(config.is_tripped(&BounceStats {
+            sent: 100,
+            bounced: 10
+        }));
+    }
 }
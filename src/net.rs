@@ -0,0 +1,104 @@
+//! Outbound TCP connections for the SMTP and IMAP clients.
+//!
+//! Hosts with several IP addresses can pin a hub's connections to one of them
+//! with `hubs.<id>.bind_address`, so mail leaves from the address its DNS and
+//! PTR records name.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+
+/// Connects to `host:port`, from `local` when set.
+///
+/// With a local address only the server addresses of the same family are
+/// tried, in resolver order, and the first to accept wins. Fails with
+/// [`io::ErrorKind::AddrNotAvailable`] when the host has none.
+pub async fn connect_tcp(host: &str, port: u16, local: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(local) = local else {
+        return TcpStream::connect((host, port)).await;
+    };
+
+    let mut last_err = None;
+    for remote in lookup_host((host, port))
+        .await?
+        .filter(|remote| remote.is_ipv4() == local.is_ipv4())
+    {
+        match connect_from(local, remote).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{host} has no address reachable from {local}"),
+        )
+    }))
+}
+
+/// Binds a socket to `local` (any port) and connects it to `remote`.
+async fn connect_from(local: IpAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match local {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(local, 0))?;
+    socket.connect(remote).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_the_configured_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let local = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+        let stream = match connect_tcp("127.0.0.1", port, Some(local)).await {
+            Ok(stream) => stream,
+            // Loopback aliases beyond 127.0.0.1 are not available everywhere.
+            Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => return,
+            Err(e) => panic!("connect failed: {e}"),
+        };
+        assert_eq!(stream.local_addr().unwrap().ip(), local);
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), local);
+    }
+
+    #[tokio::test]
+    async fn unbound_connection_uses_the_default_route() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let stream = connect_tcp("127.0.0.1", port, None).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn address_family_mismatch_is_unavailable() {
+        let err = connect_tcp("127.0.0.1", 25, Some("::1".parse().unwrap()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn foreign_local_address_fails_to_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // TEST-NET-1 is never assigned to a local interface.
+        let local = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let err = connect_tcp("127.0.0.1", port, Some(local))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,49 @@
+//! Regular expressions shared across the crate.
+//!
+//! Each pattern is compiled once on first use. Keep new patterns here so no
+//! hot path (per recipient or per inbound message) builds a `Regex`.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// `{key}` or `{key:directive}` template placeholder.
+pub(crate) static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{([\p{L}\p{N}_]+?)(?::([^{}]+))?\}").expect("Placeholder regex should compile")
+});
+
+/// `{>name}` include of a template partial.
+pub(crate) static PARTIAL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{>([\p{L}\p{N}_-]+)\}").expect("Partial regex should compile"));
+
+/// An email address with an ASCII domain anywhere in a line of text;
+/// punycode (`xn--`) labels are matched, including the top-level domain.
+pub(crate) static EMAIL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)[a-z0-9._%+-]+@[a-z0-9.-]+\.(?:xn--[a-z0-9-]+|[a-z]{2,})")
+        .expect("Email regex should compile")
+});
+
+/// Like [`EMAIL`], but also matching internationalized addresses with
+/// Unicode letters in the local part and domain.
+pub(crate) static UNICODE_EMAIL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)[\p{L}\p{N}._%+-]+@[\p{L}\p{N}.-]+\.(?:xn--[a-z0-9-]+|\p{L}{2,})")
+        .expect("Unicode email regex should compile")
+});
+
+/// An `http(s)` link in HTML or plain text, up to the closing quote,
+/// bracket or whitespace.
+pub(crate) static LINK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)https?://[^\s"'<>]+"#).expect("Link regex should compile"));
+
+/// Start of the original message quoted in a forwarded or plain-text bounce.
+pub(crate) static ORIGINAL_MESSAGE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)(original message|forwarded message|copy of the message|message headers|undelivered message)")
+        .expect("Original message regex should compile")
+});
+
+/// A sender authentication failure in a delivery report line: an RFC 7372
+/// status code (`5.7.20`–`5.7.26`), or SPF/DKIM/DMARC named next to a
+/// failure verdict.
+pub(crate) static AUTH_FAILURE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b[45]\.7\.2[0-6]\b|\b(dmarc|dkim|spf)\b.*\b(fail|failed|failure|policy|reject|rejected)\b|\b(fail|failed|failure|policy|reject|rejected)\b.*\b(dmarc|dkim|spf)\b")
+        .expect("Auth failure regex should compile")
+});
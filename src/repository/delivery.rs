@@ -0,0 +1,251 @@
+//! Delivery event repository implementation backed by Diesel.
+//!
+//! Supplies the [`DeliveryReader`] and [`DeliveryWriter`] traits for
+//! [`DieselRepository`].
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use pushkind_common::repository::errors::RepositoryResult;
+use pushkind_emailer::domain::types::HubId;
+
+use crate::domain::{BounceStats, DeliveryEventKind, HubDailyStats};
+use crate::models::{NewDeliveryEvent, NewProcessedReply};
+use crate::repository::{DeliveryReader, DeliveryWriter, DieselRepository};
+
+fn count_events(
+    conn: &mut SqliteConnection,
+    hub_id: HubId,
+    kind: DeliveryEventKind,
+    since: NaiveDateTime,
+) -> QueryResult<i64> {
+    use crate::schema::delivery_events;
+
+    delivery_events::table
+        .filter(delivery_events::hub_id.eq(hub_id.get()))
+        .filter(delivery_events::kind.eq(kind.as_str()))
+        .filter(delivery_events::created_at.ge(since))
+        .count()
+        .get_result(conn)
+}
+
+impl DeliveryReader for DieselRepository {
+    fn bounce_stats(&self, hub_id: HubId, since: NaiveDateTime) -> RepositoryResult<BounceStats> {
+        let mut conn = self.conn()?;
+
+        Ok(BounceStats {
+            sent: count_events(&mut conn, hub_id, DeliveryEventKind::Sent, since)?,
+            bounced: count_events(&mut conn, hub_id, DeliveryEventKind::Bounce, since)?,
+        })
+    }
+
+    fn count_delivery_events(
+        &self,
+        hub_id: HubId,
+        kind: DeliveryEventKind,
+        since: NaiveDateTime,
+    ) -> RepositoryResult<i64> {
+        let mut conn = self.conn()?;
+        Ok(count_events(&mut conn, hub_id, kind, since)?)
+    }
+
+    fn hub_daily_stats(
+        &self,
+        hub_id: HubId,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> RepositoryResult<Vec<HubDailyStats>> {
+        use crate::schema::delivery_events;
+
+        let mut days: Vec<HubDailyStats> = from
+            .iter_days()
+            .take_while(|day| *day <= to)
+            .map(HubDailyStats::empty)
+            .collect();
+        let Some(end) = to.succ_opt().filter(|_| !days.is_empty()) else {
+            return Ok(days);
+        };
+
+        let mut conn = self.conn()?;
+        // Bucketed here rather than with SQL date functions so the query
+        // stays backend-neutral.
+        let events: Vec<(NaiveDateTime, String)> = delivery_events::table
+            .filter(delivery_events::hub_id.eq(hub_id.get()))
+            .filter(delivery_events::created_at.ge(from.and_time(NaiveTime::MIN)))
+            .filter(delivery_events::created_at.lt(end.and_time(NaiveTime::MIN)))
+            .select((delivery_events::created_at, delivery_events::kind))
+            .load(&mut *conn)?;
+
+        for (created_at, kind) in events {
+            let Some(kind) = DeliveryEventKind::parse(&kind) else {
+                continue;
+            };
+            let offset = (created_at.date() - from).num_days();
+            if let Some(day) = usize::try_from(offset).ok().and_then(|i| days.get_mut(i)) {
+                day.add(kind);
+            }
+        }
+
+        Ok(days)
+    }
+
+    fn is_reply_recorded(&self, hub_id: HubId, message_id: &str) -> RepositoryResult<bool> {
+        use crate::schema::processed_replies;
+        let mut conn = self.conn()?;
+
+        let recorded = diesel::select(exists(
+            processed_replies::table
+                .filter(processed_replies::hub_id.eq(hub_id.get()))
+                .filter(processed_replies::message_id.eq(message_id)),
+        ))
+        .get_result::<bool>(&mut *conn)?;
+        Ok(recorded)
+    }
+}
+
+impl DeliveryWriter for DieselRepository {
+    fn record_delivery_event(
+        &self,
+        hub_id: HubId,
+        kind: DeliveryEventKind,
+    ) -> RepositoryResult<()> {
+        use crate::schema::delivery_events;
+        let mut conn = self.conn()?;
+
+        diesel::insert_into(delivery_events::table)
+            .values(&NewDeliveryEvent {
+                hub_id: hub_id.get(),
+                kind: kind.as_str(),
+                created_at: Utc::now().naive_utc(),
+                imap_uid: None,
+            })
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn reserve_delivery_event(
+        &self,
+        hub_id: HubId,
+        kind: DeliveryEventKind,
+        since: NaiveDateTime,
+        cap: i64,
+    ) -> RepositoryResult<Option<i32>> {
+        use crate::schema::delivery_events;
+        let mut conn = self.conn()?;
+
+        let reserve = |conn: &mut SqliteConnection| -> QueryResult<Option<i32>> {
+            if count_events(conn, hub_id, kind, since)? >= cap {
+                return Ok(None);
+            }
+            diesel::insert_into(delivery_events::table)
+                .values(&NewDeliveryEvent {
+                    hub_id: hub_id.get(),
+                    kind: kind.as_str(),
+                    created_at: Utc::now().naive_utc(),
+                    imap_uid: None,
+                })
+                .returning(delivery_events::id)
+                .get_result(conn)
+                .map(Some)
+        };
+        // `BEGIN IMMEDIATE` takes the write lock before counting, so no
+        // other connection can reserve in between. Inside
+        // [`DieselRepository::transaction`] the outer transaction is used.
+        let reserved = match self.pinned {
+            Some(_) => reserve(&mut *conn)?,
+            None => conn.immediate_transaction(reserve)?,
+        };
+        Ok(reserved)
+    }
+
+    fn delete_delivery_event(&self, event_id: i32) -> RepositoryResult<()> {
+        use crate::schema::delivery_events;
+        let mut conn = self.conn()?;
+
+        diesel::delete(delivery_events::table.filter(delivery_events::id.eq(event_id)))
+            .execute(&mut *conn)?;
+        Ok(())
+    }
+
+    fn record_bounce_once(&self, hub_id: HubId, uid: u32) -> RepositoryResult<bool> {
+        use crate::schema::delivery_events;
+        let mut conn = self.conn()?;
+
+        let inserted = diesel::insert_into(delivery_events::table)
+            .values(&NewDeliveryEvent {
+                hub_id: hub_id.get(),
+                kind: DeliveryEventKind::Bounce.as_str(),
+                created_at: Utc::now().naive_utc(),
+                imap_uid: Some(i64::from(uid)),
+            })
+            .on_conflict((
+                delivery_events::hub_id,
+                delivery_events::kind,
+                delivery_events::imap_uid,
+            ))
+            .do_nothing()
+            .execute(&mut *conn)?;
+
+        Ok(inserted > 0)
+    }
+
+    fn forget_bounce_uids(&self, hub_id: HubId) -> RepositoryResult<usize> {
+        use crate::schema::delivery_events;
+        let mut conn = self.conn()?;
+
+        let detached = diesel::update(
+            delivery_events::table
+                .filter(delivery_events::hub_id.eq(hub_id.get()))
+                .filter(delivery_events::imap_uid.is_not_null()),
+        )
+        .set(delivery_events::imap_uid.eq(None::<i64>))
+        .execute(&mut *conn)?;
+
+        Ok(detached)
+    }
+
+    fn record_reply_once(
+        &self,
+        hub_id: HubId,
+        message_id: &str,
+        keep: usize,
+    ) -> RepositoryResult<bool> {
+        use crate::schema::processed_replies;
+        let mut conn = self.conn()?;
+
+        let inserted = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let inserted = diesel::insert_into(processed_replies::table)
+                .values(&NewProcessedReply {
+                    hub_id: hub_id.get(),
+                    message_id,
+                    created_at: Utc::now().naive_utc(),
+                })
+                .on_conflict((processed_replies::hub_id, processed_replies::message_id))
+                .do_nothing()
+                .execute(conn)?;
+            if inserted == 0 {
+                return Ok(false);
+            }
+
+            let oldest_dropped = processed_replies::table
+                .filter(processed_replies::hub_id.eq(hub_id.get()))
+                .order(processed_replies::id.desc())
+                .offset(i64::try_from(keep).unwrap_or(i64::MAX))
+                .select(processed_replies::id)
+                .first::<i32>(conn)
+                .optional()?;
+            if let Some(id) = oldest_dropped {
+                diesel::delete(
+                    processed_replies::table
+                        .filter(processed_replies::hub_id.eq(hub_id.get()))
+                        .filter(processed_replies::id.le(id)),
+                )
+                .execute(conn)?;
+            }
+            Ok(true)
+        })?;
+
+        Ok(inserted)
+    }
+}
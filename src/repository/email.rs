@@ -3,7 +3,10 @@
 //! Provides [`EmailReader`] and [`EmailWriter`] trait implementations for
 //! [`DieselRepository`].
 
-use chrono::Utc;
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::dsl::exists;
 use diesel::prelude::*;
 use pushkind_common::repository::errors::{RepositoryError, RepositoryResult};
 use pushkind_emailer::domain::email::{
@@ -17,8 +20,11 @@ use pushkind_emailer::models::email::{
 };
 use pushkind_emailer::schema::email_recipients;
 
-use crate::domain::UpdateEmailRecipient;
-use crate::models::Unsubscribe;
+use crate::domain::{UnsubscribeSource, UpdateEmailRecipient};
+use crate::models::{
+    NewEmailDeadline, NewRejectedRecipient, NewReplyCategory, NewReplyLanguage,
+    NewUnsubscribeSource, Unsubscribe,
+};
 use crate::repository::{DieselRepository, EmailReader, EmailWriter};
 
 #[derive(AsChangeset)]
@@ -47,7 +53,7 @@ impl EmailReader for DieselRepository {
             .inner_join(emails::table)
             .filter(emails::hub_id.eq(hub_id.get()))
             .select(DbEmailRecipient::as_select())
-            .load::<DbEmailRecipient>(&mut conn)?;
+            .load::<DbEmailRecipient>(&mut *conn)?;
 
         recipients
             .into_iter()
@@ -68,7 +74,7 @@ impl EmailReader for DieselRepository {
             .inner_join(emails::table)
             .filter(emails::hub_id.eq(hub_id.get()))
             .select(DbEmailRecipient::as_select())
-            .first::<DbEmailRecipient>(&mut conn)
+            .first::<DbEmailRecipient>(&mut *conn)
             .optional()?;
 
         recipient
@@ -88,14 +94,14 @@ impl EmailReader for DieselRepository {
             .filter(emails::id.eq(id.get()))
             .filter(emails::hub_id.eq(hub_id.get()))
             .select(DbEmail::as_select())
-            .first::<DbEmail>(&mut conn)
+            .first::<DbEmail>(&mut *conn)
             .optional()?;
 
         if let Some(email) = email {
             let recipients = email_recipients::table
                 .filter(email_recipients::email_id.eq(email.id))
                 .select(DbEmailRecipient::as_select())
-                .load::<DbEmailRecipient>(&mut conn)?;
+                .load::<DbEmailRecipient>(&mut *conn)?;
 
             let email: DomainEmail = email.try_into().map_err(constraint_err)?;
             let recipients = recipients
@@ -108,6 +114,58 @@ impl EmailReader for DieselRepository {
             Ok(None)
         }
     }
+
+    fn get_reply_category(
+        &self,
+        recipient_id: EmailRecipientId,
+    ) -> RepositoryResult<Option<String>> {
+        use crate::schema::reply_categories;
+        let mut conn = self.conn()?;
+
+        Ok(reply_categories::table
+            .filter(reply_categories::recipient_id.eq(recipient_id.get()))
+            .select(reply_categories::category)
+            .first::<String>(&mut *conn)
+            .optional()?)
+    }
+
+    fn get_reply_language(
+        &self,
+        recipient_id: EmailRecipientId,
+    ) -> RepositoryResult<Option<String>> {
+        use crate::schema::reply_languages;
+        let mut conn = self.conn()?;
+
+        Ok(reply_languages::table
+            .filter(reply_languages::recipient_id.eq(recipient_id.get()))
+            .select(reply_languages::language)
+            .first::<String>(&mut *conn)
+            .optional()?)
+    }
+
+    fn is_recipient_rejected(&self, recipient_id: EmailRecipientId) -> RepositoryResult<bool> {
+        use crate::schema::rejected_recipients;
+        let mut conn = self.conn()?;
+
+        let rejected = diesel::select(exists(
+            rejected_recipients::table
+                .filter(rejected_recipients::recipient_id.eq(recipient_id.get())),
+        ))
+        .get_result::<bool>(&mut *conn)?;
+        Ok(rejected)
+    }
+
+    fn get_email_deadline(&self, email_id: EmailId) -> RepositoryResult<Option<DateTime<Utc>>> {
+        use crate::schema::email_deadlines;
+        let mut conn = self.conn()?;
+
+        let expires_at = email_deadlines::table
+            .filter(email_deadlines::email_id.eq(email_id.get()))
+            .select(email_deadlines::expires_at)
+            .first::<NaiveDateTime>(&mut *conn)
+            .optional()?;
+        Ok(expires_at.map(|at| at.and_utc()))
+    }
 }
 
 impl EmailWriter for DieselRepository {
@@ -122,7 +180,19 @@ impl EmailWriter for DieselRepository {
                 .values(&new_email)
                 .get_result(conn)?;
 
+            // Checked here rather than by a unique index, which the shared
+            // `email_recipients` table does not have; addresses that
+            // normalize alike reach one mailbox.
+            let mut seen = HashSet::new();
             for item in &email.recipients {
+                if !seen.insert(self.normalizer.normalize(item.address.as_str())) {
+                    log::warn!(
+                        "Skipping duplicate recipient {} for email_id {}",
+                        item.address.as_str(),
+                        inserted.id
+                    );
+                    continue;
+                }
                 let fields = serde_json::to_string(&item.fields).map_err(|e| {
                     RepositoryError::ValidationError(format!("Invalid fields JSON: {e}"))
                 })?;
@@ -214,22 +284,189 @@ impl EmailWriter for DieselRepository {
         &self,
         email: &str,
         hub_id: HubId,
+        source: UnsubscribeSource,
         reason: Option<&str>,
     ) -> RepositoryResult<()> {
-        use pushkind_emailer::schema::unsubscribes;
+        use crate::schema::{unsubscribe_sources, unsubscribes};
+
+        let email = self.normalizer.normalize(email);
+        let mut conn = self.conn()?;
+
+        conn.transaction(|conn| {
+            let inserted = diesel::insert_into(unsubscribes::table)
+                .values(Unsubscribe {
+                    email: &email,
+                    hub_id: hub_id.get(),
+                    reason,
+                    created_at: Utc::now().naive_utc(),
+                })
+                .on_conflict((unsubscribes::email, unsubscribes::hub_id))
+                .do_nothing()
+                .execute(conn)?;
+
+            // The source describes the row above, so a repeated unsubscribe
+            // leaves the original one in place.
+            if inserted > 0 {
+                let row = NewUnsubscribeSource {
+                    hub_id: hub_id.get(),
+                    email: &email,
+                    source: source.as_str(),
+                };
+                diesel::insert_into(unsubscribe_sources::table)
+                    .values(&row)
+                    .on_conflict((unsubscribe_sources::hub_id, unsubscribe_sources::email))
+                    .do_update()
+                    .set(unsubscribe_sources::source.eq(row.source))
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn set_reply_category(
+        &self,
+        recipient_id: EmailRecipientId,
+        category: &str,
+    ) -> RepositoryResult<()> {
+        use crate::schema::reply_categories;
 
         let mut conn = self.conn()?;
+        let row = NewReplyCategory {
+            recipient_id: recipient_id.get(),
+            category,
+            updated_at: Utc::now().naive_utc(),
+        };
+
+        diesel::insert_into(reply_categories::table)
+            .values(&row)
+            .on_conflict(reply_categories::recipient_id)
+            .do_update()
+            .set(&row)
+            .execute(&mut *conn)?;
 
-        diesel::insert_into(unsubscribes::table)
-            .values(Unsubscribe {
-                email,
-                hub_id: hub_id.get(),
+        Ok(())
+    }
+
+    fn set_reply_language(
+        &self,
+        recipient_id: EmailRecipientId,
+        language: &str,
+    ) -> RepositoryResult<()> {
+        use crate::schema::reply_languages;
+
+        let mut conn = self.conn()?;
+        let row = NewReplyLanguage {
+            recipient_id: recipient_id.get(),
+            language,
+            updated_at: Utc::now().naive_utc(),
+        };
+
+        diesel::insert_into(reply_languages::table)
+            .values(&row)
+            .on_conflict(reply_languages::recipient_id)
+            .do_update()
+            .set(&row)
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn reject_recipient(
+        &self,
+        recipient_id: EmailRecipientId,
+        reason: &str,
+    ) -> RepositoryResult<()> {
+        use crate::schema::rejected_recipients;
+
+        let mut conn = self.conn()?;
+        diesel::insert_into(rejected_recipients::table)
+            .values(NewRejectedRecipient {
+                recipient_id: recipient_id.get(),
                 reason,
+                created_at: Utc::now().naive_utc(),
             })
-            .on_conflict((unsubscribes::email, unsubscribes::hub_id))
+            .on_conflict(rejected_recipients::recipient_id)
             .do_nothing()
-            .execute(&mut conn)?;
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn set_email_deadline(
+        &self,
+        email_id: EmailId,
+        expires_at: DateTime<Utc>,
+    ) -> RepositoryResult<()> {
+        use crate::schema::email_deadlines;
+
+        let row = NewEmailDeadline {
+            email_id: email_id.get(),
+            expires_at: expires_at.naive_utc(),
+        };
+        let mut conn = self.conn()?;
+        diesel::insert_into(email_deadlines::table)
+            .values(&row)
+            .on_conflict(email_deadlines::email_id)
+            .do_update()
+            .set(&row)
+            .execute(&mut *conn)?;
 
         Ok(())
     }
+
+    fn purge_emails_older_than(
+        &self,
+        cutoff: NaiveDateTime,
+        hub_id: HubId,
+    ) -> RepositoryResult<usize> {
+        use crate::schema::{
+            email_deadlines, rejected_recipients, reply_categories, reply_languages,
+        };
+        use pushkind_emailer::schema::{email_recipients, emails};
+
+        let mut conn = self.conn()?;
+        conn.transaction(|conn| {
+            let expired = || {
+                emails::table
+                    .filter(emails::hub_id.eq(hub_id.get()))
+                    .filter(emails::created_at.lt(cutoff))
+                    .select(emails::id)
+            };
+
+            let expired_recipients = || {
+                email_recipients::table
+                    .filter(email_recipients::email_id.eq_any(expired()))
+                    .select(email_recipients::id)
+            };
+
+            diesel::delete(
+                reply_categories::table
+                    .filter(reply_categories::recipient_id.eq_any(expired_recipients())),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                reply_languages::table
+                    .filter(reply_languages::recipient_id.eq_any(expired_recipients())),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                rejected_recipients::table
+                    .filter(rejected_recipients::recipient_id.eq_any(expired_recipients())),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                email_deadlines::table.filter(email_deadlines::email_id.eq_any(expired())),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                email_recipients::table.filter(email_recipients::email_id.eq_any(expired())),
+            )
+            .execute(conn)?;
+            let purged =
+                diesel::delete(emails::table.filter(emails::id.eq_any(expired()))).execute(conn)?;
+
+            Ok(purged)
+        })
+    }
 }
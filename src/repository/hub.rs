@@ -1,6 +1,6 @@
 //! Hub repository implementation backed by Diesel.
 //!
-//! Supplies the [`HubReader`] trait for [`DieselRepository`].
+//! Supplies the [`HubReader`] and [`HubWriter`] traits for [`DieselRepository`].
 
 use diesel::prelude::*;
 use pushkind_common::repository::errors::RepositoryResult;
@@ -22,7 +22,7 @@ impl HubReader for DieselRepository {
         let mut conn = self.conn()?;
         let result = hubs::table
             .filter(hubs::id.eq(id.get()))
-            .first::<DbHub>(&mut conn)
+            .first::<DbHub>(&mut *conn)
             .optional()?;
         result
             .map(|hub| hub.try_into().map_err(constraint_err))
@@ -32,12 +32,37 @@ impl HubReader for DieselRepository {
     fn list_hubs(&self) -> RepositoryResult<Vec<DomainHub>> {
         use pushkind_emailer::schema::hubs;
         let mut conn = self.conn()?;
-        let result = hubs::table.load::<DbHub>(&mut conn)?;
+        let result = hubs::table.load::<DbHub>(&mut *conn)?;
         result
             .into_iter()
             .map(|hub| hub.try_into().map_err(constraint_err))
             .collect()
     }
+
+    fn get_uid_validity(&self, hub_id: HubId) -> RepositoryResult<Option<u32>> {
+        use crate::schema::hub_state;
+        let mut conn = self.conn()?;
+        let uid_validity = hub_state::table
+            .filter(hub_state::hub_id.eq(hub_id.get()))
+            .select(hub_state::uid_validity)
+            .first::<Option<i64>>(&mut *conn)
+            .optional()?
+            .flatten();
+        uid_validity
+            .map(|value| u32::try_from(value).map_err(constraint_err))
+            .transpose()
+    }
+
+    fn is_sending_paused(&self, hub_id: HubId) -> RepositoryResult<bool> {
+        use crate::schema::hub_state;
+        let mut conn = self.conn()?;
+        let paused = hub_state::table
+            .filter(hub_state::hub_id.eq(hub_id.get()))
+            .select(hub_state::sending_paused)
+            .first::<bool>(&mut *conn)
+            .optional()?;
+        Ok(paused.unwrap_or(false))
+    }
 }
 
 impl HubWriter for DieselRepository {
@@ -47,7 +72,41 @@ impl HubWriter for DieselRepository {
         let mut conn = self.conn()?;
         diesel::update(hubs::table.filter(hubs::id.eq(hub_id.get())))
             .set(hubs::imap_last_uid.eq(uid.get()))
-            .execute(&mut conn)?;
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn set_uid_validity(&self, hub_id: HubId, uid_validity: u32) -> RepositoryResult<()> {
+        use crate::schema::hub_state;
+
+        let mut conn = self.conn()?;
+        diesel::insert_into(hub_state::table)
+            .values((
+                hub_state::hub_id.eq(hub_id.get()),
+                hub_state::uid_validity.eq(Some(i64::from(uid_validity))),
+            ))
+            .on_conflict(hub_state::hub_id)
+            .do_update()
+            .set(hub_state::uid_validity.eq(Some(i64::from(uid_validity))))
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn set_sending_paused(&self, hub_id: HubId, paused: bool) -> RepositoryResult<()> {
+        use crate::schema::hub_state;
+
+        let mut conn = self.conn()?;
+        diesel::insert_into(hub_state::table)
+            .values((
+                hub_state::hub_id.eq(hub_id.get()),
+                hub_state::sending_paused.eq(paused),
+            ))
+            .on_conflict(hub_state::hub_id)
+            .do_update()
+            .set(hub_state::sending_paused.eq(paused))
+            .execute(&mut *conn)?;
 
         Ok(())
     }
@@ -4,34 +4,172 @@
 //! alongside [`DieselRepository`], a small wrapper around a Diesel
 //! connection pool.
 
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use diesel::connection::{Connection, TransactionManager};
+use diesel::sqlite::SqliteConnection;
 use pushkind_common::db::{DbConnection, DbPool};
 use pushkind_common::repository::errors::RepositoryResult;
 use pushkind_emailer::domain::email::{EmailRecipient, EmailWithRecipients, NewEmail};
 use pushkind_emailer::domain::hub::Hub;
 use pushkind_emailer::domain::types::{EmailId, EmailRecipientId, HubId, ImapUid};
 
-use crate::domain::UpdateEmailRecipient;
+use crate::address::{AddressNormalizer, StandardNormalizer};
+use crate::domain::{
+    BounceStats, DeliveryEventKind, HubDailyStats, Pagination, UnsubscribeEntry,
+    UpdateEmailRecipient,
+};
 
+pub mod delivery;
 pub mod email;
 pub mod hub;
+pub mod suppression;
 
 /// Concrete repository backed by a Diesel connection pool.
+///
+/// Inside [`DieselRepository::transaction`] the repository is pinned to a
+/// single connection so that every operation joins the open transaction.
+///
+/// Addresses are keyed through the repository's [`AddressNormalizer`] when
+/// unsubscribes are stored or looked up and when the recipients of a new
+/// email are deduplicated.
 #[derive(Clone)]
 pub struct DieselRepository {
     pool: DbPool, // r2d2::Pool is cheap to clone
+    pinned: Option<Arc<Mutex<DbConnection>>>,
+    normalizer: Arc<dyn AddressNormalizer>,
+}
+
+/// Connection handed out by [`DieselRepository`]: either a fresh pooled
+/// connection or the one pinned for the current transaction.
+enum RepoConnection<'a> {
+    Pooled(DbConnection),
+    Pinned(MutexGuard<'a, DbConnection>),
+}
+
+impl Deref for RepoConnection<'_> {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            RepoConnection::Pooled(conn) => conn,
+            RepoConnection::Pinned(conn) => conn,
+        }
+    }
+}
+
+impl DerefMut for RepoConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            RepoConnection::Pooled(conn) => conn,
+            RepoConnection::Pinned(conn) => conn,
+        }
+    }
 }
 
 impl DieselRepository {
-    /// Creates a new [`DieselRepository`] from the given pool.
+    /// Creates a new [`DieselRepository`] from the given pool, normalizing
+    /// addresses with the default [`StandardNormalizer`].
     pub fn new(pool: DbPool) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            pinned: None,
+            normalizer: Arc::new(StandardNormalizer::default()),
+        }
+    }
+
+    /// Uses `normalizer` for addresses instead of the default.
+    pub fn with_normalizer(mut self, normalizer: impl AddressNormalizer + 'static) -> Self {
+        self.normalizer = Arc::new(normalizer);
+        self
     }
 
-    fn conn(&self) -> RepositoryResult<DbConnection> {
-        Ok(self.pool.get()?)
+    /// Returns the forms an address is matched by: as given (trimmed) and
+    /// normalized. Rows stored before a normalization rule was enabled keep
+    /// the old form, so lookups must match both.
+    fn address_forms(&self, address: &str) -> Vec<String> {
+        let trimmed = address.trim();
+        let normalized = self.normalizer.normalize(trimmed);
+        if normalized == trimmed {
+            vec![normalized]
+        } else {
+            vec![trimmed.to_string(), normalized]
+        }
+    }
+
+    fn conn(&self) -> RepositoryResult<RepoConnection<'_>> {
+        match &self.pinned {
+            Some(conn) => Ok(RepoConnection::Pinned(
+                conn.lock().unwrap_or_else(PoisonError::into_inner),
+            )),
+            None => Ok(RepoConnection::Pooled(self.pool.get()?)),
+        }
+    }
+
+    /// Runs `f` inside a database transaction.
+    ///
+    /// The repository passed to `f` shares one connection, so all operations
+    /// performed through it commit together when `f` returns `Ok` and roll
+    /// back together when it returns `Err`. Calling `transaction` on an
+    /// already transactional repository reuses the outer transaction.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use pushkind_emailer::domain::types::{EmailRecipientId, HubId, ImapUid};
+    /// use pushkind_hedwig::domain::UpdateEmailRecipient;
+    /// use pushkind_hedwig::repository::{DieselRepository, EmailWriter, HubWriter};
+    /// # fn demo(repo: &DieselRepository) {
+    /// let _ = repo.transaction(|tx| {
+    ///     tx.set_imap_last_uid(HubId::try_from(1).unwrap(), ImapUid::try_from(10).unwrap())?;
+    ///     tx.update_recipient(EmailRecipientId::try_from(1).unwrap(), &UpdateEmailRecipient {
+    ///         sent: None,
+    ///         opened: Some(true),
+    ///         reply: None,
+    ///     })?;
+    ///     Ok(())
+    /// });
+    /// # }
+    /// ```
+    pub fn transaction<T, F>(&self, f: F) -> RepositoryResult<T>
+    where
+        F: FnOnce(&DieselRepository) -> RepositoryResult<T>,
+    {
+        if self.pinned.is_some() {
+            return f(self);
+        }
+
+        let mut conn = self.pool.get()?;
+        TransactionManagerOf::begin_transaction(&mut *conn)?;
+        let pinned = Arc::new(Mutex::new(conn));
+        let tx = DieselRepository {
+            pool: self.pool.clone(),
+            pinned: Some(Arc::clone(&pinned)),
+            normalizer: Arc::clone(&self.normalizer),
+        };
+
+        let result = f(&tx);
+        drop(tx);
+
+        let mut conn = pinned.lock().unwrap_or_else(PoisonError::into_inner);
+        match result {
+            Ok(value) => {
+                TransactionManagerOf::commit_transaction(&mut **conn)?;
+                Ok(value)
+            }
+            Err(err) => {
+                if let Err(rollback_err) = TransactionManagerOf::rollback_transaction(&mut **conn) {
+                    log::error!("Cannot roll back repository transaction: {rollback_err}");
+                }
+                Err(err)
+            }
+        }
     }
 }
 
+type TransactionManagerOf = <SqliteConnection as Connection>::TransactionManager;
+
 /// Read-only operations for email entities.
 pub trait EmailReader {
     /// Fetches an email with its recipients by ID constrained by `hub_id`.
@@ -53,15 +191,39 @@ pub trait EmailReader {
         id: EmailRecipientId,
         hub_id: HubId,
     ) -> RepositoryResult<Option<EmailRecipient>>;
+
+    /// Returns the category stored with the recipient's reply, if any.
+    fn get_reply_category(
+        &self,
+        recipient_id: EmailRecipientId,
+    ) -> RepositoryResult<Option<String>>;
+
+    /// Returns the language code stored with the recipient's reply, if any.
+    fn get_reply_language(
+        &self,
+        recipient_id: EmailRecipientId,
+    ) -> RepositoryResult<Option<String>>;
+
+    /// Returns `true` when the recipient was rejected for good and must not
+    /// be attempted again.
+    fn is_recipient_rejected(&self, recipient_id: EmailRecipientId) -> RepositoryResult<bool>;
+
+    /// Returns the deadline stored with the email, after which it is no
+    /// longer sent.
+    fn get_email_deadline(&self, email_id: EmailId) -> RepositoryResult<Option<DateTime<Utc>>>;
 }
 
 /// Write operations for email entities.
 pub trait EmailWriter {
-    /// Persists a new email and its recipients.
+    /// Persists a new email and its recipients, skipping recipients whose
+    /// normalized address repeats an earlier one.
     fn create_email(&self, email: &NewEmail) -> RepositoryResult<EmailWithRecipients>;
 
     /// Updates a single recipient and returns the refreshed email state.
     ///
+    /// The recipient update and the recalculation of the email counters run
+    /// in one transaction: if either fails, neither is applied.
+    ///
     /// # Example
     /// ```no_run
     /// use pushkind_hedwig::domain::UpdateEmailRecipient;
@@ -80,12 +242,58 @@ pub trait EmailWriter {
         recipient_id: EmailRecipientId,
         updates: &UpdateEmailRecipient,
     ) -> RepositoryResult<EmailWithRecipients>;
+    /// Unsubscribes the normalized `email` from the hub, recording what
+    /// the unsubscribe came from. An existing unsubscribe keeps its reason
+    /// and source.
     fn unsubscribe_recipient(
         &self,
         email: &str,
         hub_id: HubId,
+        source: UnsubscribeSource,
         reason: Option<&str>,
     ) -> RepositoryResult<()>;
+
+    /// Stores the category of the recipient's reply, replacing an earlier one.
+    fn set_reply_category(
+        &self,
+        recipient_id: EmailRecipientId,
+        category: &str,
+    ) -> RepositoryResult<()>;
+
+    /// Stores the language of the recipient's reply, replacing an earlier one.
+    fn set_reply_language(
+        &self,
+        recipient_id: EmailRecipientId,
+        language: &str,
+    ) -> RepositoryResult<()>;
+
+    /// Marks the recipient as rejected for good, e.g. because its message
+    /// can never be sent; later send jobs skip it. Rejecting a recipient
+    /// twice keeps the first reason.
+    fn reject_recipient(
+        &self,
+        recipient_id: EmailRecipientId,
+        reason: &str,
+    ) -> RepositoryResult<()>;
+
+    /// Stores the email's deadline, replacing an earlier one.
+    fn set_email_deadline(
+        &self,
+        email_id: EmailId,
+        expires_at: DateTime<Utc>,
+    ) -> RepositoryResult<()>;
+
+    /// Deletes the hub's emails created before `cutoff` together with their
+    /// recipients and returns how many emails were removed.
+    ///
+    /// Recipients and their reply categories, languages and rejections, and
+    /// the emails' deadlines, are deleted first and all deletes share one
+    /// transaction.
+    fn purge_emails_older_than(
+        &self,
+        cutoff: NaiveDateTime,
+        hub_id: HubId,
+    ) -> RepositoryResult<usize>;
 }
 
 /// Read-only operations for hubs.
@@ -95,10 +303,126 @@ pub trait HubReader {
 
     /// Lists all hubs stored in the repository.
     fn list_hubs(&self) -> RepositoryResult<Vec<Hub>>;
+
+    /// Returns the IMAP `UIDVALIDITY` recorded with the hub's UID cursor.
+    fn get_uid_validity(&self, hub_id: HubId) -> RepositoryResult<Option<u32>>;
+
+    /// Returns `true` when an operator paused sending for the hub.
+    fn is_sending_paused(&self, hub_id: HubId) -> RepositoryResult<bool>;
 }
 
 /// Write operations for hub entities.
 pub trait HubWriter {
     /// Persists the latest seen IMAP UID for the hub.
     fn set_imap_last_uid(&self, hub_id: HubId, uid: ImapUid) -> RepositoryResult<()>;
+
+    /// Records the IMAP `UIDVALIDITY` the hub's UID cursor belongs to.
+    fn set_uid_validity(&self, hub_id: HubId, uid_validity: u32) -> RepositoryResult<()>;
+
+    /// Pauses or resumes sending for the hub.
+    fn set_sending_paused(&self, hub_id: HubId, paused: bool) -> RepositoryResult<()>;
+}
+
+/// Read-only access to delivery outcomes.
+pub trait DeliveryReader {
+    /// Counts sends and bounces recorded for the hub since `since`.
+    fn bounce_stats(&self, hub_id: HubId, since: NaiveDateTime) -> RepositoryResult<BounceStats>;
+
+    /// Counts events of `kind` recorded for the hub since `since`.
+    fn count_delivery_events(
+        &self,
+        hub_id: HubId,
+        kind: DeliveryEventKind,
+        since: NaiveDateTime,
+    ) -> RepositoryResult<i64>;
+
+    /// Returns per-day event counts for the hub from `from` to `to`
+    /// inclusive (UTC), one entry per day including days without events.
+    fn hub_daily_stats(
+        &self,
+        hub_id: HubId,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> RepositoryResult<Vec<HubDailyStats>>;
+
+    /// Returns `true` when the hub recorded the reply Message-ID with
+    /// [`DeliveryWriter::record_reply_once`].
+    fn is_reply_recorded(&self, hub_id: HubId, message_id: &str) -> RepositoryResult<bool>;
+}
+
+/// Records delivery outcomes used by the bounce-rate breaker and warm-up caps.
+pub trait DeliveryWriter {
+    /// Stores a delivery event for the hub stamped with the current time.
+    fn record_delivery_event(&self, hub_id: HubId, kind: DeliveryEventKind)
+    -> RepositoryResult<()>;
+
+    /// Stores a `kind` event for the hub unless `cap` events of that kind
+    /// were already recorded since `since`, and returns the new event's ID.
+    ///
+    /// The count and the insert are atomic, so concurrent jobs, even in
+    /// other processes, cannot overshoot the cap. Returns `None` when the
+    /// cap is reached.
+    fn reserve_delivery_event(
+        &self,
+        hub_id: HubId,
+        kind: DeliveryEventKind,
+        since: NaiveDateTime,
+        cap: i64,
+    ) -> RepositoryResult<Option<i32>>;
+
+    /// Deletes an event stored by
+    /// [`reserve_delivery_event`](Self::reserve_delivery_event), returning
+    /// its slot.
+    fn delete_delivery_event(&self, event_id: i32) -> RepositoryResult<()>;
+
+    /// Stores a bounce for the notification with IMAP `uid`.
+    ///
+    /// Returns `false` without writing when that notification was already
+    /// recorded, so reprocessing a mailbox does not inflate the bounce rate.
+    fn record_bounce_once(&self, hub_id: HubId, uid: u32) -> RepositoryResult<bool>;
+
+    /// Detaches the hub's recorded bounces from their IMAP UIDs, e.g. after
+    /// a `UIDVALIDITY` change lets the server reuse them. The bounces still
+    /// count towards the bounce rate. Returns how many were detached.
+    fn forget_bounce_uids(&self, hub_id: HubId) -> RepositoryResult<usize>;
+
+    /// Stores the Message-ID of a processed reply.
+    ///
+    /// Returns `false` without writing when the hub already recorded it, so
+    /// a reply fetched again is not counted twice. Only the hub's `keep`
+    /// most recent IDs are retained.
+    fn record_reply_once(
+        &self,
+        hub_id: HubId,
+        message_id: &str,
+        keep: usize,
+    ) -> RepositoryResult<bool>;
+}
+
+/// Read access to the addresses that must not be mailed.
+pub trait SuppressionReader {
+    /// Returns `true` when `email` unsubscribed from the hub or is on the
+    /// global suppression list, matching the address both as given and
+    /// normalized.
+    fn is_suppressed(&self, email: &str, hub_id: HubId) -> RepositoryResult<bool>;
+
+    /// Lists the hub's unsubscribed addresses ordered by address, one page
+    /// at a time.
+    fn list_unsubscribes(
+        &self,
+        hub_id: HubId,
+        pagination: Pagination,
+    ) -> RepositoryResult<Vec<UnsubscribeEntry>>;
+}
+
+/// Maintains the global suppression list.
+pub trait SuppressionWriter {
+    /// Suppresses `email` for every hub. Addresses are matched
+    /// case-insensitively; suppressing an address twice is a no-op.
+    fn suppress_globally(&self, email: &str, reason: Option<&str>) -> RepositoryResult<()>;
+
+    /// Removes the hub's unsubscribe of `email`, as given or normalized, so
+    /// the address is mailed again unless it is globally suppressed. Returns
+    /// `false` when there was none.
+    fn delete_unsubscribe(&self, email: &str, hub_id: HubId) -> RepositoryResult<bool>;
 }
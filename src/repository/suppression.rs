@@ -0,0 +1,128 @@
+//! Suppression list repository implementation backed by Diesel.
+//!
+//! Supplies the [`SuppressionReader`] and [`SuppressionWriter`] traits for
+//! [`DieselRepository`].
+
+use std::collections::HashMap;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use pushkind_common::repository::errors::RepositoryResult;
+use pushkind_emailer::domain::types::HubId;
+
+use crate::domain::{Pagination, UnsubscribeEntry, UnsubscribeSource};
+use crate::models::NewGlobalSuppression;
+use crate::repository::{DieselRepository, SuppressionReader, SuppressionWriter};
+
+impl SuppressionReader for DieselRepository {
+    fn is_suppressed(&self, email: &str, hub_id: HubId) -> RepositoryResult<bool> {
+        use crate::schema::{global_suppressions, unsubscribes};
+
+        let forms = self.address_forms(email);
+        let lowercase: Vec<String> = forms.iter().map(|form| form.to_lowercase()).collect();
+        let mut conn = self.conn()?;
+
+        let globally = diesel::select(exists(
+            global_suppressions::table.filter(global_suppressions::email.eq_any(&lowercase)),
+        ))
+        .get_result::<bool>(&mut *conn)?;
+        if globally {
+            return Ok(true);
+        }
+
+        let in_hub = diesel::select(exists(
+            unsubscribes::table
+                .filter(unsubscribes::email.eq_any(&forms))
+                .filter(unsubscribes::hub_id.eq(hub_id.get())),
+        ))
+        .get_result::<bool>(&mut *conn)?;
+        Ok(in_hub)
+    }
+
+    fn list_unsubscribes(
+        &self,
+        hub_id: HubId,
+        pagination: Pagination,
+    ) -> RepositoryResult<Vec<UnsubscribeEntry>> {
+        use crate::schema::{unsubscribe_sources, unsubscribes};
+
+        let mut conn = self.conn()?;
+        let rows = unsubscribes::table
+            .filter(unsubscribes::hub_id.eq(hub_id.get()))
+            .order(unsubscribes::email.asc())
+            .offset(i64::try_from(pagination.offset).unwrap_or(i64::MAX))
+            .limit(i64::try_from(pagination.limit).unwrap_or(i64::MAX))
+            .select((
+                unsubscribes::email,
+                unsubscribes::reason,
+                unsubscribes::created_at,
+            ))
+            .load::<(String, Option<String>, Option<NaiveDateTime>)>(&mut *conn)?;
+
+        let emails: Vec<&str> = rows.iter().map(|(email, _, _)| email.as_str()).collect();
+        let sources: HashMap<String, String> = unsubscribe_sources::table
+            .filter(unsubscribe_sources::hub_id.eq(hub_id.get()))
+            .filter(unsubscribe_sources::email.eq_any(&emails))
+            .select((unsubscribe_sources::email, unsubscribe_sources::source))
+            .load::<(String, String)>(&mut *conn)?
+            .into_iter()
+            .collect();
+
+        Ok(rows
+            .into_iter()
+            .map(|(email, reason, created_at)| UnsubscribeEntry {
+                source: sources
+                    .get(&email)
+                    .and_then(|source| UnsubscribeSource::parse(source)),
+                email,
+                reason,
+                created_at,
+            })
+            .collect())
+    }
+}
+
+impl SuppressionWriter for DieselRepository {
+    fn suppress_globally(&self, email: &str, reason: Option<&str>) -> RepositoryResult<()> {
+        use crate::schema::global_suppressions;
+
+        let mut conn = self.conn()?;
+        let email = email.trim().to_lowercase();
+
+        diesel::insert_into(global_suppressions::table)
+            .values(&NewGlobalSuppression {
+                email: &email,
+                reason,
+                created_at: Utc::now().naive_utc(),
+            })
+            .on_conflict(global_suppressions::email)
+            .do_nothing()
+            .execute(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn delete_unsubscribe(&self, email: &str, hub_id: HubId) -> RepositoryResult<bool> {
+        use crate::schema::{unsubscribe_sources, unsubscribes};
+
+        let forms = self.address_forms(email);
+        let mut conn = self.conn()?;
+        conn.transaction(|conn| {
+            diesel::delete(
+                unsubscribe_sources::table
+                    .filter(unsubscribe_sources::email.eq_any(&forms))
+                    .filter(unsubscribe_sources::hub_id.eq(hub_id.get())),
+            )
+            .execute(conn)?;
+            let deleted = diesel::delete(
+                unsubscribes::table
+                    .filter(unsubscribes::email.eq_any(&forms))
+                    .filter(unsubscribes::hub_id.eq(hub_id.get())),
+            )
+            .execute(conn)?;
+
+            Ok(deleted > 0)
+        })
+    }
+}
@@ -0,0 +1,89 @@
+//! Diesel schema for tables owned by Hedwig.
+//!
+//! The shared tables (hubs, emails, recipients, unsubscribes) come from
+//! `pushkind_emailer::schema`; the DDL for the tables below is documented in
+//! `SPEC.md`. `unsubscribes` is declared here as well, with the `created_at`
+//! column Hedwig adds to the shared table.
+
+diesel::table! {
+    delivery_events (id) {
+        id -> Integer,
+        hub_id -> Integer,
+        kind -> Text,
+        created_at -> Timestamp,
+        imap_uid -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    global_suppressions (email) {
+        email -> Text,
+        reason -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    hub_state (hub_id) {
+        hub_id -> Integer,
+        uid_validity -> Nullable<BigInt>,
+        sending_paused -> Bool,
+    }
+}
+
+diesel::table! {
+    processed_replies (id) {
+        id -> Integer,
+        hub_id -> Integer,
+        message_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    unsubscribe_sources (hub_id, email) {
+        hub_id -> Integer,
+        email -> Text,
+        source -> Text,
+    }
+}
+
+diesel::table! {
+    rejected_recipients (recipient_id) {
+        recipient_id -> Integer,
+        reason -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_deadlines (email_id) {
+        email_id -> Integer,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reply_categories (recipient_id) {
+        recipient_id -> Integer,
+        category -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reply_languages (recipient_id) {
+        recipient_id -> Integer,
+        language -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    unsubscribes (email, hub_id) {
+        email -> Text,
+        hub_id -> Integer,
+        reason -> Nullable<Text>,
+        created_at -> Nullable<Timestamp>,
+    }
+}
@@ -0,0 +1,158 @@
+//! In-memory cache of recently processed ZeroMQ jobs.
+//!
+//! Redelivered payloads arriving while the original is running, or shortly
+//! after it was processed, are dropped before any database or SMTP work is
+//! done.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use pushkind_emailer::models::zmq::ZMQSendEmailMessage;
+
+/// Idempotency key of a send job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKey {
+    /// Retry of a stored email, keyed by `(email_id, hub_id)`.
+    Retry(i32, i32),
+    /// New email, keyed by a hash of the raw payload.
+    New(u64),
+}
+
+impl JobKey {
+    /// Derives the key for a parsed job and its raw payload.
+    pub fn new(msg: &ZMQSendEmailMessage, raw: &[u8]) -> Self {
+        match msg {
+            ZMQSendEmailMessage::RetryEmail((email_id, hub_id)) => {
+                JobKey::Retry(*email_id, *hub_id)
+            }
+            ZMQSendEmailMessage::NewEmail(_) => {
+                let mut hasher = DefaultHasher::new();
+                raw.hash(&mut hasher);
+                JobKey::New(hasher.finish())
+            }
+        }
+    }
+}
+
+/// Bounded cache of job keys processed within a time window.
+///
+/// A job is claimed when it is received and only counts as processed once
+/// it finishes successfully, so a retry of a failed job is not dropped. The
+/// oldest entries are evicted once `capacity` is reached.
+pub struct ProcessedCache {
+    capacity: usize,
+    window: Duration,
+    seen: HashMap<JobKey, Instant>,
+    order: VecDeque<JobKey>,
+    /// Jobs claimed but not finished yet.
+    running: HashSet<JobKey>,
+}
+
+impl ProcessedCache {
+    /// Creates an empty cache.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            running: HashSet::new(),
+        }
+    }
+
+    /// Claims `key` for a job about to run and returns `true`, unless the job
+    /// is still running or was processed within the window.
+    pub fn claim(&mut self, key: JobKey, now: Instant) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+
+        if let Some(&seen_at) = self.seen.get(&key)
+            && now.saturating_duration_since(seen_at) < self.window
+        {
+            return false;
+        }
+        self.running.insert(key)
+    }
+
+    /// Releases the claim on `key` once its job finished. A `processed` job
+    /// is remembered for the window from `now`; a failed one may run again
+    /// right away.
+    pub fn finish(&mut self, key: JobKey, processed: bool, now: Instant) {
+        self.running.remove(&key);
+        if self.capacity == 0 || !processed {
+            return;
+        }
+
+        self.order.retain(|existing| *existing != key);
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.seen.insert(key, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Claims and successfully finishes `key` at `now`.
+    fn process(cache: &mut ProcessedCache, key: JobKey, now: Instant) -> bool {
+        let claimed = cache.claim(key, now);
+        if claimed {
+            cache.finish(key, true, now);
+        }
+        claimed
+    }
+
+    #[test]
+    fn skips_duplicates_within_window() {
+        let mut cache = ProcessedCache::new(8, Duration::from_secs(60));
+        let now = Instant::now();
+        let key = JobKey::Retry(1, 1);
+
+        assert!(process(&mut cache, key, now));
+        assert!(!process(&mut cache, key, now + Duration::from_secs(30)));
+        assert!(process(&mut cache, key, now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn skips_duplicates_while_running() {
+        let mut cache = ProcessedCache::new(8, Duration::from_secs(60));
+        let now = Instant::now();
+        let key = JobKey::Retry(1, 1);
+
+        assert!(cache.claim(key, now));
+        assert!(!cache.claim(key, now));
+        cache.finish(key, true, now + Duration::from_secs(90));
+        assert!(!cache.claim(key, now + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn failed_jobs_may_run_again() {
+        let mut cache = ProcessedCache::new(8, Duration::from_secs(60));
+        let now = Instant::now();
+        let key = JobKey::Retry(1, 1);
+
+        assert!(cache.claim(key, now));
+        cache.finish(key, false, now);
+        assert!(cache.claim(key, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut cache = ProcessedCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(process(&mut cache, JobKey::Retry(1, 1), now));
+        assert!(process(&mut cache, JobKey::Retry(2, 1), now));
+        assert!(process(&mut cache, JobKey::Retry(3, 1), now));
+        assert!(process(&mut cache, JobKey::Retry(1, 1), now));
+        assert!(!process(&mut cache, JobKey::Retry(3, 1), now));
+    }
+}
@@ -1,103 +1,531 @@
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use encoding_rs::Encoding;
 use mail_send::mail_builder::{
     MessageBuilder,
-    headers::{HeaderType, url::URL},
+    encoders::{base64::base64_encode_mime, quoted_printable::quoted_printable_encode},
+    headers::{HeaderType, content_type::ContentType, raw::Raw, text::Text, url::URL},
+    mime::{BodyPart, MimePart},
 };
-use once_cell::sync::Lazy;
 use pushkind_emailer::domain::email::{Email, EmailRecipient};
 use pushkind_emailer::domain::hub::Hub;
-use regex::Regex;
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Replace {key} with values from `vars`; leave unknown {key} intact.
-static PLACEHOLDER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{([\p{L}\p{N}_]+?)\}").unwrap());
+use crate::domain::{
+    FromOverride, RawSend, RecipientAttachment, ReplyThread, message_id, one_click_unsubscribe_url,
+    preview_message_id, template_fields, tracking_opted_out,
+};
+use crate::errors::Error;
+use crate::models::{
+    BodyEncodingConfig, FeedbackIdConfig, HubSettings, ListIdConfig, TransferEncoding,
+};
+use crate::regexes::{LINK, PLACEHOLDER};
+use crate::unsubscribe::UnsubscribeKey;
+
+/// Date formats accepted for `{key:<strftime>}` values.
+const DATE_FORMATS: [&str; 2] = ["%Y-%m-%d", "%d.%m.%Y"];
+/// Date-time formats accepted for `{key:<strftime>}` values.
+const DATETIME_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+
+/// Formats `amount` with two decimals, space-grouped thousands and a decimal
+/// comma, e.g. `1 234,50`.
+fn format_currency(amount: f64) -> String {
+    let fixed = format!("{:.2}", amount.abs());
+    let (int_part, frac_part) = fixed.split_once('.').unwrap_or((&fixed, "00"));
+    let mut grouped = String::new();
+    for (i, digit) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            grouped.push(' ');
+        }
+        grouped.push(digit);
+    }
+    let sign = if amount < 0.0 && fixed.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+        "-"
+    } else {
+        ""
+    };
+    format!("{sign}{grouped},{frac_part}")
+}
 
-fn fill_template(template: &str, vars: &BTreeMap<String, String>) -> String {
-    PLACEHOLDER_RE
+/// Reformats `value` according to a placeholder directive.
+///
+/// `currency` formats a number (see [`format_currency`]); any other directive
+/// is a `strftime` pattern applied to a date or date-time value. Returns
+/// `None` when the value cannot be coerced or the pattern is invalid.
+fn apply_directive(value: &str, directive: &str) -> Option<String> {
+    use std::fmt::Write;
+
+    let value = value.trim();
+    if directive == "currency" {
+        let amount: f64 = value.replace(',', ".").parse().ok()?;
+        return amount.is_finite().then(|| format_currency(amount));
+    }
+
+    let datetime = DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })?;
+    let mut formatted = String::new();
+    write!(formatted, "{}", datetime.format(directive)).ok()?;
+    Some(formatted)
+}
+
+/// Fills `{key}`/`{key:directive}` placeholders from `vars`, leaving unknown
+/// ones intact.
+///
+/// Replacements borrow from `template` and `vars`; only values rewritten by
+/// a directive are newly allocated.
+pub fn fill_template(template: &str, vars: &BTreeMap<String, String>) -> String {
+    PLACEHOLDER
         .replace_all(template, |caps: &regex::Captures| {
-            let key = &caps[1];
-            vars.get(key)
-                .cloned()
-                .unwrap_or_else(|| caps[0].to_string())
+            let Some(value) = vars.get(&caps[1]) else {
+                return Cow::Borrowed(&template[caps.get_match().range()]);
+            };
+            match caps
+                .get(2)
+                .and_then(|directive| apply_directive(value, directive.as_str()))
+            {
+                Some(formatted) => Cow::Owned(formatted),
+                None => Cow::Borrowed(value.as_str()),
+            }
         })
         .into_owned()
 }
 
-/// Builds an email message ready to be sent via SMTP.
+/// Removes the query parameters matching `params` from the links in `body`.
 ///
-/// The message is rendered from the hub template and recipient data,
-/// injecting tracking and unsubscribe links as required.
-#[must_use]
-pub fn build_message<'a>(
-    hub: &'a Hub,
-    email: &'a Email,
-    recipient: &'a EmailRecipient,
-    domain: &'a str,
-) -> MessageBuilder<'a> {
+/// A parameter ending in `*` matches by prefix; names compare
+/// case-insensitively. Queries separated by `&amp;`, as in HTML attributes,
+/// keep that separator. Other parameters and fragments are left intact.
+pub fn strip_link_params<'b>(body: &'b str, params: &[String]) -> Cow<'b, str> {
+    if params.is_empty() {
+        return Cow::Borrowed(body);
+    }
+    LINK.replace_all(body, |caps: &regex::Captures| strip_query(&caps[0], params))
+}
+
+fn strip_query(link: &str, params: &[String]) -> String {
+    let (link, fragment) = link.split_at(link.find('#').unwrap_or(link.len()));
+    let Some((base, query)) = link.split_once('?') else {
+        return format!("{link}{fragment}");
+    };
+    let separator = if query.contains("&amp;") {
+        "&amp;"
+    } else {
+        "&"
+    };
+    let kept: Vec<&str> = query
+        .split(separator)
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            !pair.is_empty() && !is_stripped_param(name, params)
+        })
+        .collect();
+    match kept.is_empty() {
+        true => format!("{base}{fragment}"),
+        false => format!("{base}?{}{fragment}", kept.join(separator)),
+    }
+}
+
+fn is_stripped_param(name: &str, params: &[String]) -> bool {
+    let name = name.to_ascii_lowercase();
+    params.iter().any(|param| {
+        let param = param.trim().to_ascii_lowercase();
+        match param.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == param,
+        }
+    })
+}
+
+/// Renders the message body for a recipient from the hub template.
+///
+/// `template` (e.g. loaded from a file) takes precedence over the hub's
+/// stored template. The result excludes the tracking pixel appended by
+/// [`build_message`].
+pub fn render_body(
+    hub: &Hub,
+    email: &Email,
+    recipient: &EmailRecipient,
+    template: Option<&str>,
+) -> String {
     // 1) Render the inner message with recipient fields
-    let rendered_message = fill_template(email.message.as_str(), &recipient.fields);
+    let rendered_message =
+        fill_template(email.message.as_str(), &template_fields(&recipient.fields));
 
     // 2) Ensure outer template has {message}
-    let template = hub
-        .email_template
-        .as_ref()
-        .map(|template| template.as_str())
+    let template = template
+        .or_else(|| {
+            hub.email_template
+                .as_ref()
+                .map(|template| template.as_str())
+        })
         .unwrap_or("{message}");
     let template = match template.contains("{message}") {
-        true => template.to_string(),
-        false => {
-            let mut template = template.to_string();
-            template.push_str("\n\n{message}");
-            template
-        }
+        true => Cow::Borrowed(template),
+        false => Cow::Owned(format!("{template}\n\n{{message}}")),
     };
 
     // 3) Build fields for the outer template
-    let unsubscribe_url = hub.unsubscribe_url();
     let mut fields: BTreeMap<String, String> = BTreeMap::new();
     fields.insert("name".into(), recipient.name.as_str().to_string());
-    fields.insert("unsubscribe_url".into(), unsubscribe_url.clone());
+    fields.insert("unsubscribe_url".into(), hub.unsubscribe_url());
     fields.insert("message".into(), rendered_message);
 
     // 4) Render outer template (known keys get replaced; unknown stay intact)
-    let mut body = fill_template(&template, &fields);
+    fill_template(&template, &fields)
+}
 
-    body.push_str(&format!(
-        r#"<img height="1" width="1" border="0" src="https://mail.{domain}/track/{}">"#,
-        recipient.id.get()
-    ));
+/// Returns the email subject, or the hub's `default_subject` when the email
+/// has none.
+///
+/// The default is rendered with the recipient fields and `{name}`.
+fn render_subject<'a>(
+    email: &'a Email,
+    recipient: &EmailRecipient,
+    settings: &HubSettings,
+) -> Cow<'a, str> {
+    let subject = email
+        .subject
+        .as_ref()
+        .map(|subject| subject.as_str())
+        .filter(|subject| !subject.trim().is_empty());
+    match (subject, settings.default_subject.as_deref()) {
+        (Some(subject), _) => Cow::Borrowed(subject),
+        (None, Some(default)) => {
+            let mut fields = template_fields(&recipient.fields);
+            fields.insert("name".into(), recipient.name.as_str().to_string());
+            Cow::Owned(fill_template(default, &fields))
+        }
+        (None, None) => Cow::Borrowed(""),
+    }
+}
+
+/// Per-process counter distinguishing sends within the same microsecond.
+static SEND_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies this process in send tokens; see [`set_message_id_node`].
+static MESSAGE_ID_NODE: OnceLock<String> = OnceLock::new();
+
+/// Sets the identifier this process puts in its Message-IDs, keeping only
+/// ASCII letters, digits and `-`.
+///
+/// Only the first call before any send takes effect; returns `false` when
+/// the identifier was already fixed or `node` has no usable characters.
+pub fn set_message_id_node(node: &str) -> bool {
+    let node: String = node
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect();
+    !node.is_empty() && MESSAGE_ID_NODE.set(node).is_ok()
+}
 
-    let message_id = format!("{}@{}", recipient.id.get(), domain);
+/// Returns the process identifier of send tokens, drawing a random one when
+/// none was configured.
+fn message_id_node() -> &'static str {
+    MESSAGE_ID_NODE.get_or_init(|| {
+        let mut bytes = [0u8; 4];
+        let node = match SystemRandom::new().fill(&mut bytes) {
+            Ok(()) => u32::from_le_bytes(bytes),
+            Err(_) => std::process::id(),
+        };
+        format!("{node:08x}")
+    })
+}
 
-    let recipient_address = vec![("", recipient.address.as_str())];
-    let sender_email = hub
+/// Returns a token unique to this send attempt: the current time in
+/// microseconds and a per-process sequence number, both in hex, followed by
+/// the process identifier.
+fn send_token() -> String {
+    let sequence = SEND_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    format!(
+        "{:x}.{sequence:x}.{}",
+        chrono::Utc::now().timestamp_micros(),
+        message_id_node()
+    )
+}
+
+/// Resolves the From display name and address for the hub.
+///
+/// Each part falls back to the other hub field: the display name is
+/// `sender`, then `login`; the address is `login`, then `sender`. A hub with
+/// neither is a configuration error.
+fn from_mailbox(hub: &Hub) -> Result<(&str, &str), Error> {
+    let sender = hub
         .sender
         .as_ref()
         .map(|sender| sender.as_str())
-        .unwrap_or_default();
-    let sender_login = hub
+        .filter(|sender| !sender.trim().is_empty());
+    let login = hub
         .login
         .as_ref()
         .map(|login| login.as_str())
-        .unwrap_or_default();
-    let subject = email
-        .subject
+        .filter(|login| !login.trim().is_empty());
+
+    match (sender.or(login), login.or(sender)) {
+        (Some(name), Some(address)) => Ok((name, address)),
+        _ => Err(Error::Config(format!(
+            "Hub#{} has neither sender nor login for the From header",
+            hub.id
+        ))),
+    }
+}
+
+/// Formats the `List-Id` header value: `"Name" <id>`, or `<id>` without a
+/// name. Non-ASCII names are sent as an RFC 2047 encoded word.
+fn list_id_header(list_id: &ListIdConfig) -> String {
+    let id = list_id.id.trim();
+    match list_id.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() && name.is_ascii() => {
+            let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{escaped}\" <{id}>")
+        }
+        Some(name) if !name.is_empty() => {
+            format!("=?utf-8?B?{}?= <{id}>", STANDARD.encode(name))
+        }
+        _ => format!("<{id}>"),
+    }
+}
+
+/// Formats the Gmail `Feedback-ID` value `{email_id}:{hub_id}:{segment}:{sender_id}`.
+///
+/// The segment is omitted when the recipient has no value for
+/// `segment_field`. Colons inside the segment or sender ID would shift the
+/// fields and are replaced with `-`.
+pub fn feedback_id(
+    config: &FeedbackIdConfig,
+    email: &Email,
+    hub: &Hub,
+    recipient: &EmailRecipient,
+) -> String {
+    let clean = |value: &str| value.trim().replace(':', "-");
+    let mut fields = vec![email.id.get().to_string(), hub.id.get().to_string()];
+    if let Some(segment) = config
+        .segment_field
         .as_ref()
-        .map(|subject| subject.as_str())
-        .unwrap_or_default();
+        .and_then(|field| recipient.fields.get(field))
+        .map(|segment| clean(segment))
+        .filter(|segment| !segment.is_empty())
+    {
+        fields.push(segment);
+    }
+    fields.push(clean(&config.sender_id));
+    fields.join(":")
+}
+
+/// Builds a body part of `content_type` with the forced charset and transfer
+/// encoding.
+///
+/// Without a forced transfer encoding, `mail-builder` still picks one from
+/// the converted bytes. Fails with [`Error::Config`] for an unknown charset.
+fn encoded_body_part(
+    content_type: &str,
+    body: &str,
+    encoding: &BodyEncodingConfig,
+) -> Result<MimePart<'static>, Error> {
+    let label = encoding.charset.as_deref().unwrap_or("utf-8");
+    let charset = Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| Error::Config(format!("Unknown body charset {label:?}")))?;
+    let (bytes, charset, _) = charset.encode(body);
+    let content_type = ContentType::new(content_type.to_string())
+        .attribute("charset", charset.name().to_ascii_lowercase());
+
+    let part = match encoding.transfer_encoding {
+        None => MimePart::new(content_type, BodyPart::Binary(bytes.into_owned().into())),
+        Some(TransferEncoding::Base64) => {
+            let mut encoded = Vec::new();
+            base64_encode_mime(&bytes, &mut encoded, false)?;
+            MimePart::new(content_type, encoded).transfer_encoding("base64")
+        }
+        Some(TransferEncoding::QuotedPrintable) => {
+            let mut encoded = Vec::new();
+            quoted_printable_encode(&bytes, &mut encoded, true)?;
+            MimePart::new(content_type, encoded).transfer_encoding("quoted-printable")
+        }
+    };
+    Ok(part)
+}
+
+/// Per-job options for [`build_message`].
+#[derive(Default)]
+pub struct MessageOptions<'a> {
+    /// Replaces the hub From address; callers must check it against the
+    /// hub allowlist.
+    pub from_override: Option<&'a FromOverride>,
+    /// Replaces the hub's stored template, as in [`render_body`].
+    pub template: Option<&'a str>,
+    /// Signs the recipient's one-click unsubscribe token.
+    pub unsubscribe_key: Option<&'a UnsubscribeKey>,
+    /// Builds a preview for an unsaved recipient: the Message-ID is marked
+    /// as a preview, and the tracking pixel and one-click unsubscribe link
+    /// are left out since they would name a recipient ID.
+    pub preview: bool,
+    /// `Date` header value; `mail-builder` uses the current time when unset.
+    pub date: Option<DateTime<Utc>>,
+    /// Sends `email.message` as the complete body; see [`RawSend`].
+    pub raw: Option<RawSend>,
+}
+
+/// Builds an email message ready to be sent via SMTP.
+///
+/// The message is rendered from the hub template and recipient data,
+/// injecting tracking and unsubscribe links as required. A recipient
+/// attachment takes precedence over the attachment stored on the email.
+/// A `Sender` header is added only when `settings.sender_header` is set.
+/// `options.from_override` replaces the hub From address; its display name
+/// falls back to the hub one. The RFC 8058 one-click URL and
+/// `List-Unsubscribe-Post` are added only with `options.unsubscribe_key`,
+/// whose signature makes the URL's token unforgeable; otherwise
+/// `List-Unsubscribe` carries the hub's unsubscribe address alone.
+/// `settings.body_encoding` forces the body charset and transfer encoding.
+/// A `List-Id` header is added only when `settings.list_id` is set.
+/// `settings.default_subject` is used when the email has no subject.
+/// Query parameters in `settings.strip_link_params` are removed from the
+/// body's links before the tracking pixel is added; recipients whose
+/// `no_tracking` field opts out get no pixel.
+/// `In-Reply-To`/`References` are set from the recipient's [`ReplyThread`]
+/// fields so follow-ups join the conversation. With `options.preview` the
+/// recipient ID appears nowhere in the message.
+/// With `options.raw`, `email.message` is sent unchanged as the body: the
+/// template, placeholders, link stripping and tracking pixel are skipped,
+/// and the `List-Unsubscribe` headers are added only when the raw send
+/// keeps them.
+///
+/// Fails with [`Error::Config`] when the hub cannot provide a From mailbox,
+/// the From domain is not in `settings.allowed_sender_domains`, or the
+/// configured body charset is unknown.
+pub fn build_message<'a>(
+    hub: &'a Hub,
+    email: &'a Email,
+    recipient: &'a EmailRecipient,
+    domain: &'a str,
+    settings: &HubSettings,
+    options: &MessageOptions<'a>,
+) -> Result<MessageBuilder<'a>, Error> {
+    use std::fmt::Write;
+
+    let from = match options.from_override {
+        Some(from) => {
+            let name = from
+                .name
+                .as_deref()
+                .or_else(|| from_mailbox(hub).ok().map(|(name, _)| name))
+                .unwrap_or(from.address.as_str());
+            (name, from.address.as_str())
+        }
+        None => from_mailbox(hub)?,
+    };
+    if !settings.allows_sender_domain(from.1) {
+        return Err(Error::Config(format!(
+            "From address {} of hub#{} is outside its allowed_sender_domains",
+            from.1, hub.id
+        )));
+    }
+    // Unsigned tokens would let anyone unsubscribe arbitrary recipients, so
+    // the one-click link needs a key.
+    let one_click = match options.unsubscribe_key {
+        Some(key) if !options.preview => Some(one_click_unsubscribe_url(
+            &key.sign(recipient.id.get(), hub.id.get()),
+            domain,
+        )),
+        _ => None,
+    };
+    // Raw sends borrow the stored message, so neither MIME part copies it.
+    let mut body: Cow<'a, str> = match options.raw {
+        Some(_) => Cow::Borrowed(email.message.as_str()),
+        None => {
+            let mut body = render_body(hub, email, recipient, options.template);
+            if let Cow::Owned(stripped) = strip_link_params(&body, &settings.strip_link_params) {
+                body = stripped;
+            }
+            Cow::Owned(body)
+        }
+    };
+    let message_id = if options.preview {
+        preview_message_id(&send_token(), domain)
+    } else {
+        if options.raw.is_none() && !tracking_opted_out(&recipient.fields) {
+            // Written in place; formatting into a `String` cannot fail.
+            let _ = write!(
+                body.to_mut(),
+                r#"<img height="1" width="1" border="0" src="https://mail.{domain}/track/{}">"#,
+                recipient.id.get()
+            );
+        }
+        message_id(recipient.id.get(), &send_token(), domain)
+    };
+
+    let recipient_address = (recipient.name.as_str().trim(), recipient.address.as_str());
+    let subject = Text::new(render_subject(email, recipient, settings));
 
     let mut message = MessageBuilder::new()
-        .from((sender_email, sender_login))
+        .from(from)
         .to(recipient_address)
         .subject(subject)
-        .html_body(body.clone())
-        .text_body(body)
-        .message_id(message_id)
-        .header(
+        .message_id(message_id);
+    if options.raw.is_none_or(|raw| raw.list_unsubscribe) {
+        let has_one_click = one_click.is_some();
+        let unsubscribe_urls = std::iter::once(hub.unsubscribe_url()).chain(one_click);
+        message = message.header(
             "List-Unsubscribe",
-            HeaderType::from(URL::new(unsubscribe_url)),
+            HeaderType::from(URL::new_list(unsubscribe_urls)),
         );
+        if has_one_click {
+            message = message.header(
+                "List-Unsubscribe-Post",
+                Raw::new("List-Unsubscribe=One-Click"),
+            );
+        }
+    }
+
+    match settings.body_encoding.as_ref() {
+        Some(encoding) => {
+            message.html_body = Some(encoded_body_part("text/html", &body, encoding)?);
+            message.text_body = Some(encoded_body_part("text/plain", &body, encoding)?);
+        }
+        // Both parts take the `Cow`: a borrowed raw body is shared, a
+        // rendered one is copied once for the HTML part.
+        None => message = message.html_body(body.clone()).text_body(body),
+    }
+
+    if let Some(date) = options.date {
+        message = message.date(date.timestamp());
+    }
+
+    if let Some(thread) = ReplyThread::from_fields(&recipient.fields) {
+        message = message
+            .in_reply_to(thread.in_reply_to)
+            .references(thread.references);
+    }
+
+    if let Some(list_id) = settings.list_id.as_ref() {
+        message = message.header("List-Id", Raw::new(list_id_header(list_id)));
+    }
+
+    if let Some(config) = settings.feedback_id.as_ref() {
+        message = message.header(
+            "Feedback-ID",
+            Raw::new(feedback_id(config, email, hub, recipient)),
+        );
+    }
 
-    if let (Some(mime), Some(name), Some(content)) = (
+    if let Some(sender) = settings.sender_header.as_ref() {
+        message = message.sender((sender.name.clone(), sender.address.clone()));
+    }
+
+    if let Some(attachment) = RecipientAttachment::from_fields(&recipient.fields) {
+        message = message.attachment(attachment.mime, attachment.name, attachment.content);
+    } else if let (Some(mime), Some(name), Some(content)) = (
         email.attachment_mime.as_ref().map(|mime| mime.as_str()),
         email.attachment_name.as_ref().map(|name| name.as_str()),
         email.attachment.as_deref(),
@@ -107,12 +535,73 @@ pub fn build_message<'a>(
         message = message.attachment(mime, name, content);
     }
 
-    message
+    Ok(message)
+}
+
+/// `io::Write` sink that only counts the bytes written to it.
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the serialized size of `message` in bytes, attachments included.
+pub fn message_size(message: MessageBuilder<'_>) -> Result<usize, Error> {
+    let mut counter = ByteCounter::default();
+    message.write_to(&mut counter)?;
+    Ok(counter.0)
+}
+
+/// Returns the `.eml` source of the message [`build_message`] produces for
+/// `recipient`, so operators can download what a recipient received.
+///
+/// Headers and body match what is sent for the same arguments, except for
+/// the per-attempt Message-ID token, MIME boundaries and, unless
+/// `options.date` is set, the `Date` header.
+pub fn export_eml<'a>(
+    hub: &'a Hub,
+    email: &'a Email,
+    recipient: &'a EmailRecipient,
+    domain: &'a str,
+    settings: &HubSettings,
+    options: &MessageOptions<'a>,
+) -> Result<Vec<u8>, Error> {
+    let mut raw = Vec::new();
+    build_message(hub, email, recipient, domain, settings, options)?.write_to(&mut raw)?;
+    Ok(raw)
+}
+
+/// Builds the plain-text email confirming `address`'s unsubscribe request.
+///
+/// It is marked `Auto-Submitted: auto-replied` (RFC 3834) so that replies
+/// and auto-responses to it are never confirmed in turn. Fails with
+/// [`Error::Config`] when the hub cannot provide a From mailbox.
+pub fn unsubscribe_confirmation<'a>(
+    hub: &'a Hub,
+    address: &'a str,
+    subject: &'a str,
+    body: &'a str,
+) -> Result<MessageBuilder<'a>, Error> {
+    Ok(MessageBuilder::new()
+        .from(from_mailbox(hub)?)
+        .to(address)
+        .subject(subject)
+        .header("Auto-Submitted", Raw::new("auto-replied"))
+        .text_body(body))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::MailboxConfig;
     use chrono::Utc;
     use pushkind_emailer::domain::email::{Email, EmailRecipient};
     use pushkind_emailer::domain::hub::Hub;
@@ -176,17 +665,251 @@ mod tests {
         let hub = sample_hub();
         let email = sample_email();
         let recipient = sample_recipient();
-        let builder = build_message(&hub, &email, &recipient, "example.com");
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
 
         let mut out = Vec::new();
         builder.write_to(&mut out).unwrap();
         let msg = String::from_utf8(out).unwrap();
 
         assert!(msg.contains("List-Unsubscribe: <mailto:sender@example.com?subject=unsubscribe>"));
+        assert!(msg.contains("To: \"Alice\" <to@example.com>"));
+        // Without a signing key there is no one-click link.
+        assert!(!msg.contains("/unsubscribe/"));
+        assert!(!msg.contains("List-Unsubscribe-Post:"));
         assert!(msg.contains("track/1"));
-        assert!(msg.contains("Message-ID: <1@example.com>"));
+        assert!(msg.contains("Message-ID: <1."));
         assert!(msg.contains("Hi Alice! Hello blue, I have {favourite fruit}"));
         assert!(msg.contains("unsubscribe"));
+        assert!(!msg.contains("Sender:"));
+        assert!(!msg.contains("List-Id:"));
+        assert!(!msg.contains("In-Reply-To:"));
+    }
+
+    #[test]
+    fn preview_omits_recipient_id() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions {
+                preview: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(msg.contains("Message-ID: <preview."));
+        assert!(msg.contains("List-Unsubscribe: <mailto:sender@example.com?subject=unsubscribe>"));
+        assert!(!msg.contains("/unsubscribe/"));
+        assert!(!msg.contains("List-Unsubscribe-Post:"));
+        assert!(!msg.contains("track/"));
+        assert!(msg.contains("Hi Alice! Hello blue"));
+    }
+
+    #[test]
+    fn uses_configured_date() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let date = DateTime::from_timestamp(1_705_311_000, 0).unwrap();
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions {
+                date: Some(date),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(msg.contains("Date: Mon, 15 Jan 2024 09:30:00 +0000\r\n"));
+    }
+
+    #[test]
+    fn offers_signed_one_click_unsubscribe_when_key_is_set() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let key = UnsubscribeKey::new("secret").unwrap();
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions {
+                unsubscribe_key: Some(&key),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        let url = format!("<https://mail.example.com/unsubscribe/{}>", key.sign(1, 1));
+        assert!(msg.contains(&url));
+        assert!(msg.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(!msg.contains("/unsubscribe/1>"));
+    }
+
+    #[test]
+    fn applies_personalized_default_subject_when_missing() {
+        let hub = sample_hub();
+        let email = Email::try_new(
+            1,
+            "Hello",
+            Utc::now().naive_utc(),
+            false,
+            None,
+            None,
+            None,
+            None,
+            0,
+            0,
+            0,
+            1,
+        )
+        .unwrap();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            default_subject: Some("News for {name} in {favorite_color}".to_string()),
+            ..Default::default()
+        };
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+        assert!(msg.contains("Subject: News for Alice in blue"));
+
+        // An explicit subject wins over the default.
+        assert_eq!(
+            render_subject(&sample_email(), &recipient, &settings),
+            "Subject"
+        );
+    }
+
+    #[test]
+    fn sets_threading_headers_from_recipient_fields() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let mut fields = BTreeMap::new();
+        fields.insert("in_reply_to".into(), "<reply-1@mail.example>".into());
+        fields.insert("references".into(), "<1.abc@example.com>".into());
+        let recipient = EmailRecipient::try_new(
+            1,
+            1,
+            "to@example.com",
+            false,
+            Utc::now().naive_utc(),
+            false,
+            None,
+            "Alice",
+            fields,
+        )
+        .unwrap();
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+        assert!(msg.contains("In-Reply-To: <reply-1@mail.example>"));
+        assert!(msg.contains("References: <1.abc@example.com> <reply-1@mail.example>"));
+    }
+
+    #[test]
+    fn adds_list_id_header_when_configured() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            list_id: Some(ListIdConfig {
+                id: "news.example.com".to_string(),
+                name: Some("Weekly \"News\"".to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(
+            msg.contains("List-Id: \"Weekly \\\"News\\\"\" <news.example.com>"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn encodes_non_ascii_list_id_name() {
+        let list_id = ListIdConfig {
+            id: "news.example.com".to_string(),
+            name: Some("Новости".to_string()),
+        };
+        assert_eq!(
+            list_id_header(&list_id),
+            format!(
+                "=?utf-8?B?{}?= <news.example.com>",
+                STANDARD.encode("Новости")
+            )
+        );
+        let bare = ListIdConfig {
+            id: "news.example.com".to_string(),
+            name: None,
+        };
+        assert_eq!(list_id_header(&bare), "<news.example.com>");
     }
 
     #[test]
@@ -198,7 +921,15 @@ mod tests {
         email.attachment_mime = Some("text/plain".try_into().unwrap());
         let recipient = sample_recipient();
 
-        let builder = build_message(&hub, &email, &recipient, "example.com");
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
 
         let mut out = Vec::new();
         builder.write_to(&mut out).unwrap();
@@ -207,4 +938,699 @@ mod tests {
         assert!(msg.contains("Content-Type: text/plain"));
         assert!(msg.contains("name=\"file.txt\""));
     }
+
+    #[test]
+    fn prefers_recipient_attachment_over_email_attachment() {
+        let hub = sample_hub();
+        let mut email = sample_email();
+        email.attachment = Some(b"shared".to_vec());
+        email.attachment_name = Some("shared.txt".try_into().unwrap());
+        email.attachment_mime = Some("text/plain".try_into().unwrap());
+        let mut recipient = sample_recipient();
+        RecipientAttachment {
+            content: b"invoice".to_vec(),
+            name: "invoice.pdf".into(),
+            mime: "application/pdf".into(),
+        }
+        .write_to_fields(&mut recipient.fields);
+
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(msg.contains("Content-Type: application/pdf"));
+        assert!(msg.contains("name=\"invoice.pdf\""));
+        assert!(!msg.contains("shared.txt"));
+    }
+
+    #[test]
+    fn reserved_fields_are_not_template_variables() {
+        let hub = sample_hub();
+        let email = Email::try_new(
+            1,
+            "Data: {attachment} {attachment_name} {city}",
+            Utc::now().naive_utc(),
+            false,
+            None,
+            None,
+            None,
+            None,
+            0,
+            0,
+            0,
+            1,
+        )
+        .unwrap();
+        let mut recipient = sample_recipient();
+        recipient.fields.insert("city".into(), "Riga".into());
+        RecipientAttachment {
+            content: b"secret payload".to_vec(),
+            name: "invoice.pdf".into(),
+            mime: "application/pdf".into(),
+        }
+        .write_to_fields(&mut recipient.fields);
+
+        let body = render_body(&hub, &email, &recipient, Some("{message}"));
+        assert_eq!(body, "Data: {attachment} {attachment_name} Riga");
+
+        let settings = HubSettings {
+            default_subject: Some("{attachment} for {city}".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_subject(&email, &recipient, &settings),
+            "{attachment} for Riga"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_login_for_display_name() {
+        let mut hub = sample_hub();
+        hub.sender = None;
+        let email = sample_email();
+        let recipient = sample_recipient();
+
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(msg.contains("From: \"sender@example.com\" <sender@example.com>"));
+    }
+
+    #[test]
+    fn rejects_hub_without_sender_or_login() {
+        let mut hub = sample_hub();
+        hub.sender = None;
+        hub.login = None;
+        let email = sample_email();
+        let recipient = sample_recipient();
+
+        let err = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, Error::Config(ref msg) if msg.contains("neither sender nor login")));
+    }
+
+    #[test]
+    fn adds_sender_header_when_configured() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            sender_header: Some(MailboxConfig {
+                address: "agent@example.net".to_string(),
+                name: "Agency".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(msg.contains("Sender: \"Agency\" <agent@example.net>"));
+        assert!(msg.contains("From: \"sender@example.com\" <sender@example.com>"));
+    }
+
+    #[test]
+    fn uses_from_override_when_present() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let from = FromOverride {
+            address: "billing@example.com".into(),
+            name: Some("Billing".into()),
+        };
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions {
+                from_override: Some(&from),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(
+            msg.contains("From: \"Billing\" <billing@example.com>"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn opted_out_recipient_gets_no_tracking_pixel() {
+        use crate::domain::NO_TRACKING_FIELD;
+
+        let hub = sample_hub();
+        let email = sample_email();
+        let tracked = sample_recipient();
+        let mut fields = BTreeMap::new();
+        fields.insert(NO_TRACKING_FIELD.into(), "true".into());
+        let opted_out = EmailRecipient::try_new(
+            2,
+            1,
+            "private@example.com",
+            false,
+            Utc::now().naive_utc(),
+            false,
+            None,
+            "Bob",
+            fields,
+        )
+        .unwrap();
+
+        let render = |recipient: &EmailRecipient| {
+            let mut out = Vec::new();
+            build_message(
+                &hub,
+                &email,
+                recipient,
+                "example.com",
+                &HubSettings::default(),
+                &MessageOptions::default(),
+            )
+            .unwrap()
+            .write_to(&mut out)
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(render(&tracked).contains("track/1"));
+        let msg = render(&opted_out);
+        assert!(!msg.contains("track/"), "{msg}");
+        assert!(msg.contains("List-Unsubscribe"));
+    }
+
+    #[test]
+    fn from_domain_must_be_an_allowed_sender_domain() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let build = |domains: &[&str], from: Option<&FromOverride>| {
+            let settings = HubSettings {
+                allowed_sender_domains: domains.iter().map(|d| d.to_string()).collect(),
+                ..Default::default()
+            };
+            build_message(
+                &hub,
+                &email,
+                &recipient,
+                "example.com",
+                &settings,
+                &MessageOptions {
+                    from_override: from,
+                    ..Default::default()
+                },
+            )
+            .map(|_| ())
+        };
+
+        assert!(build(&[], None).is_ok());
+        assert!(build(&[" Example.COM "], None).is_ok());
+        assert!(matches!(
+            build(&["example.org"], None),
+            Err(Error::Config(_))
+        ));
+
+        let other = FromOverride {
+            address: "billing@billing.example.net".into(),
+            name: None,
+        };
+        assert!(matches!(
+            build(&["example.com"], Some(&other)),
+            Err(Error::Config(_))
+        ));
+        assert!(build(&["example.com", "billing.example.net"], Some(&other)).is_ok());
+    }
+
+    #[test]
+    fn forces_configured_body_encoding() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            body_encoding: Some(BodyEncodingConfig {
+                transfer_encoding: Some(TransferEncoding::Base64),
+                charset: Some("cp1251".into()),
+            }),
+            ..Default::default()
+        };
+
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+
+        assert!(
+            msg.contains("Content-Type: text/plain; charset=\"windows-1251\""),
+            "{msg}"
+        );
+        assert!(msg.contains("Content-Type: text/html; charset=\"windows-1251\""));
+        assert_eq!(msg.matches("Content-Transfer-Encoding: base64").count(), 2);
+        assert!(!msg.contains("Hi Alice!"));
+    }
+
+    #[test]
+    fn rejects_unknown_body_charset() {
+        let settings = HubSettings {
+            body_encoding: Some(BodyEncodingConfig {
+                transfer_encoding: Some(TransferEncoding::QuotedPrintable),
+                charset: Some("klingon".into()),
+            }),
+            ..Default::default()
+        };
+
+        let err = build_message(
+            &sample_hub(),
+            &sample_email(),
+            &sample_recipient(),
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Config(ref msg) if msg.contains("klingon")));
+    }
+
+    #[test]
+    fn fill_template_keeps_unknown_and_repeated_placeholders() {
+        let vars = BTreeMap::from([
+            ("name".to_string(), "Алиса".to_string()),
+            ("цвет".to_string(), "синий".to_string()),
+            ("price".to_string(), "10".to_string()),
+        ]);
+        assert_eq!(
+            fill_template(
+                "{name}, {name}! {цвет} {unknown} {unknown:currency} {price:currency} {} {name",
+                &vars
+            ),
+            "Алиса, Алиса! синий {unknown} {unknown:currency} 10,00 {} {name"
+        );
+        assert_eq!(fill_template("no placeholders", &vars), "no placeholders");
+    }
+
+    #[test]
+    fn formats_currency_directive() {
+        let vars = BTreeMap::from([
+            ("price".to_string(), "1234.5".to_string()),
+            ("small".to_string(), "7".to_string()),
+            ("debt".to_string(), "-1234567,891".to_string()),
+            ("bad".to_string(), "n/a".to_string()),
+        ]);
+        assert_eq!(
+            fill_template(
+                "{price:currency} {small:currency} {debt:currency} {bad:currency}",
+                &vars
+            ),
+            "1 234,50 7,00 -1 234 567,89 n/a"
+        );
+    }
+
+    #[test]
+    fn formats_date_directive() {
+        let vars = BTreeMap::from([
+            ("date".to_string(), "2024-03-05".to_string()),
+            ("at".to_string(), "2024-03-05T14:30:00".to_string()),
+            ("bad".to_string(), "soon".to_string()),
+        ]);
+        assert_eq!(
+            fill_template("{date:%d.%m.%Y} {at:%H:%M} {bad:%d.%m.%Y}", &vars),
+            "05.03.2024 14:30 soon"
+        );
+        // An invalid pattern falls back to the raw value.
+        assert_eq!(fill_template("{date:%Q}", &vars), "2024-03-05");
+        // Unknown keys stay intact, directive included.
+        assert_eq!(
+            fill_template("{missing:currency}", &vars),
+            "{missing:currency}"
+        );
+    }
+
+    #[test]
+    fn message_ids_are_unique_across_retries() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let message_id = || {
+            let builder = build_message(
+                &hub,
+                &email,
+                &recipient,
+                "example.com",
+                &HubSettings::default(),
+                &MessageOptions::default(),
+            )
+            .unwrap();
+            let mut out = Vec::new();
+            builder.write_to(&mut out).unwrap();
+            let msg = String::from_utf8(out).unwrap();
+            let start = msg.find("Message-ID: <").unwrap() + "Message-ID: <".len();
+            let end = start + msg[start..].find('>').unwrap();
+            msg[start..end].to_string()
+        };
+
+        let first = message_id();
+        let second = message_id();
+        assert_ne!(first, second);
+        for id in [first, second] {
+            let (local, domain) = id.split_once('@').unwrap();
+            assert_eq!(domain, "example.com");
+            assert_eq!(
+                crate::domain::recipient_id_from_message_id(local),
+                Some(recipient.id.get())
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_message_ids_are_unique() {
+        use std::collections::HashSet;
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| (0..5_000).map(|_| send_token()).collect::<Vec<_>>()))
+            .collect();
+        let mut ids = HashSet::new();
+        for handle in handles {
+            for token in handle.join().unwrap() {
+                let id = message_id(42, &token, "example.com");
+                let (local, _) = id.split_once('@').unwrap();
+                assert_eq!(crate::domain::recipient_id_from_message_id(local), Some(42));
+                assert!(ids.insert(id), "duplicate Message-ID");
+            }
+        }
+        assert_eq!(ids.len(), 40_000);
+    }
+
+    #[test]
+    fn message_id_node_keeps_safe_characters() {
+        assert!(!set_message_id_node(" .@ "));
+        let node = message_id_node();
+        assert!(!node.is_empty());
+        assert!(node.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        assert!(send_token().ends_with(&format!(".{node}")));
+    }
+
+    #[test]
+    fn message_size_matches_serialized_output() {
+        let message = MessageBuilder::new()
+            .from(("Sender", "sender@example.com"))
+            .to("alice@example.org")
+            .message_id("fixed@example.com")
+            .date(0)
+            .text_body("Hello")
+            .attachment("application/octet-stream", "blob.bin", vec![0u8; 4096]);
+        let mut output = Vec::new();
+        message.clone().write_to(&mut output).unwrap();
+        assert_eq!(message_size(message).unwrap(), output.len());
+        assert!(output.len() > 4096);
+    }
+
+    #[test]
+    fn unsubscribe_confirmation_is_marked_auto_submitted() {
+        use mailparse::MailHeaderMap;
+
+        let hub = sample_hub();
+        let mut raw = Vec::new();
+        unsubscribe_confirmation(&hub, "user@example.org", "Unsubscribed", "Done.")
+            .unwrap()
+            .write_to(&mut raw)
+            .unwrap();
+
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        let header = |name| parsed.headers.get_first_value(name).unwrap();
+        assert_eq!(header("Auto-Submitted"), "auto-replied");
+        assert_eq!(header("To"), "<user@example.org>");
+        assert_eq!(header("Subject"), "Unsubscribed");
+        assert_eq!(parsed.get_body().unwrap().trim(), "Done.");
+    }
+
+    #[test]
+    fn exported_eml_parses_back_to_the_message_headers() {
+        use mailparse::MailHeaderMap;
+
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let date = DateTime::from_timestamp(1_705_311_000, 0).unwrap();
+        let key = UnsubscribeKey::new("secret").unwrap();
+        let raw = export_eml(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions {
+                date: Some(date),
+                unsubscribe_key: Some(&key),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        let header = |name| parsed.headers.get_first_value(name).unwrap();
+        assert_eq!(header("Subject"), "Subject");
+        assert_eq!(
+            header("From"),
+            "\"sender@example.com\" <sender@example.com>"
+        );
+        assert_eq!(header("To"), "\"Alice\" <to@example.com>");
+        assert_eq!(header("Date"), "Mon, 15 Jan 2024 09:30:00 +0000");
+        assert!(header("Message-ID").starts_with("<1."));
+        assert!(header("Message-ID").ends_with("@example.com>"));
+        assert_eq!(
+            header("List-Unsubscribe-Post"),
+            "List-Unsubscribe=One-Click"
+        );
+        let text = parsed
+            .subparts
+            .iter()
+            .find(|part| part.ctype.mimetype == "text/plain")
+            .unwrap();
+        assert!(
+            text.get_body()
+                .unwrap()
+                .contains("Hi Alice! Hello blue, I have {favourite fruit}")
+        );
+    }
+
+    #[test]
+    fn raw_send_uses_the_message_as_the_complete_body() {
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            strip_link_params: vec!["utm_*".into()],
+            ..Default::default()
+        };
+        let key = UnsubscribeKey::new("secret").unwrap();
+        let export = |raw| {
+            let eml = export_eml(
+                &hub,
+                &email,
+                &recipient,
+                "example.com",
+                &settings,
+                &MessageOptions {
+                    raw: Some(raw),
+                    unsubscribe_key: Some(&key),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            String::from_utf8(eml).unwrap()
+        };
+
+        let eml = export(RawSend::default());
+        let parsed = mailparse::parse_mail(eml.as_bytes()).unwrap();
+        for mimetype in ["text/plain", "text/html"] {
+            let part = parsed
+                .subparts
+                .iter()
+                .find(|part| part.ctype.mimetype == mimetype)
+                .unwrap();
+            assert_eq!(
+                part.get_body().unwrap().trim_end(),
+                "Hello {favorite_color}, I have {favourite fruit}"
+            );
+        }
+        assert!(!eml.contains("Hi Alice!"));
+        assert!(!eml.contains("track/"));
+        assert!(!eml.contains("List-Unsubscribe"));
+        assert!(
+            parsed
+                .headers
+                .get_first_value("Message-ID")
+                .unwrap()
+                .starts_with("<1.")
+        );
+
+        let eml = export(RawSend {
+            list_unsubscribe: true,
+        });
+        let url = format!("<https://mail.example.com/unsubscribe/{}>", key.sign(1, 1));
+        assert!(eml.contains(&url));
+        assert!(eml.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(!eml.contains("track/"));
+    }
+
+    #[test]
+    fn strips_configured_link_params_only() {
+        let params = vec!["utm_*".to_string(), "ref".to_string()];
+        let body = concat!(
+            r#"<a href="https://shop.example.com/p?id=7&amp;utm_source=mail&amp;REF=x#top">Buy</a> "#,
+            "https://example.com/?utm_medium=email&utm_campaign=spring ",
+            "https://example.com/a?page=2&referrer=home"
+        );
+        assert_eq!(
+            strip_link_params(body, &params),
+            concat!(
+                r#"<a href="https://shop.example.com/p?id=7#top">Buy</a> "#,
+                "https://example.com/ ",
+                "https://example.com/a?page=2&referrer=home"
+            )
+        );
+        assert!(matches!(strip_link_params(body, &[]), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn feedback_id_header_is_formatted() {
+        use mailparse::MailHeaderMap;
+
+        let hub = sample_hub();
+        let email = sample_email();
+        let recipient = sample_recipient();
+        let header = |segment_field: Option<&str>, sender_id: &str| {
+            let settings = HubSettings {
+                feedback_id: Some(FeedbackIdConfig {
+                    sender_id: sender_id.into(),
+                    segment_field: segment_field.map(Into::into),
+                }),
+                ..Default::default()
+            };
+            let raw = export_eml(
+                &hub,
+                &email,
+                &recipient,
+                "example.com",
+                &settings,
+                &MessageOptions::default(),
+            )
+            .unwrap();
+            let parsed = mailparse::parse_mail(&raw).unwrap();
+            parsed.headers.get_first_value("Feedback-ID")
+        };
+
+        assert_eq!(
+            header(Some("favorite_color"), "acme").as_deref(),
+            Some("1:1:blue:acme")
+        );
+        assert_eq!(header(Some("missing"), "acme").as_deref(), Some("1:1:acme"));
+        assert_eq!(header(None, "acme:mail").as_deref(), Some("1:1:acme-mail"));
+
+        let raw = export_eml(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &HubSettings::default(),
+            &MessageOptions::default(),
+        )
+        .unwrap();
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        assert!(parsed.headers.get_first_value("Feedback-ID").is_none());
+    }
+
+    #[test]
+    fn build_message_strips_link_params() {
+        let hub = sample_hub();
+        let email = Email::try_new(
+            1,
+            "Visit https://example.com/?utm_source=mail&id=1",
+            Utc::now().naive_utc(),
+            false,
+            Some("Subject".to_string()),
+            None,
+            None,
+            None,
+            0,
+            0,
+            0,
+            1,
+        )
+        .unwrap();
+        let recipient = sample_recipient();
+        let settings = HubSettings {
+            strip_link_params: vec!["utm_source".into()],
+            ..Default::default()
+        };
+        let builder = build_message(
+            &hub,
+            &email,
+            &recipient,
+            "example.com",
+            &settings,
+            &MessageOptions::default(),
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        builder.write_to(&mut out).unwrap();
+        let msg = String::from_utf8(out).unwrap();
+        assert!(msg.contains("https://example.com/?id=1"));
+        assert!(!msg.contains("utm_source"));
+        assert!(msg.contains("track/1"));
+    }
 }
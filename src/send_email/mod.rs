@@ -1,87 +1,537 @@
+pub mod dedup;
 pub mod message_builder;
 pub mod service;
+pub mod smtp;
+pub mod spam;
+pub mod template;
 
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use mail_send::SmtpClientBuilder;
+use chrono::Utc;
 use mail_send::mail_builder::MessageBuilder;
-use pushkind_common::db::establish_connection_pool;
+use mail_send::smtp::message::IntoMessage;
+use mail_send::{SmtpClient, SmtpClientBuilder};
 use pushkind_emailer::domain::hub::Hub;
 use pushkind_emailer::models::zmq::ZMQSendEmailMessage;
+use smtp_proto::EhloResponse;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
 
+use crate::credentials::{HubCredentials, SystemSecrets, resolve_credentials};
+use crate::db::{ensure_schema, establish_pool};
+use crate::domain::SendEmailRequest;
 use crate::errors::Error;
+use crate::models::{HubSettings, ServerConfig};
+use crate::net::connect_tcp;
 use crate::repository::DieselRepository;
 
+use dedup::{JobKey, ProcessedCache};
 use service::{Mailer, send_email};
+use smtp::{Envelope, SmtpExtensions, send_transaction};
 
 /// Simple SMTP mailer that leverages [`mail_send`].
-pub struct SmtpMailer;
+pub struct SmtpMailer {
+    config: Arc<ServerConfig>,
+}
+
+impl SmtpMailer {
+    /// Creates a mailer applying per-hub settings from `config`.
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Self { config }
+    }
+}
+
+/// Returns the hub's SMTP server and port.
+fn smtp_address(hub: &Hub) -> Result<(&str, u16), Error> {
+    let smtp_server = hub
+        .smtp_server
+        .as_ref()
+        .map(|host| host.as_str())
+        .ok_or(Error::Config("Missed SMTP server address".to_owned()))?;
+    let smtp_port = hub
+        .smtp_port
+        .ok_or(Error::Config("Missed SMTP port".to_owned()))?
+        .get();
+    Ok((smtp_server, smtp_port))
+}
+
+/// Prepares an SMTP client builder for the hub.
+///
+/// The EHLO/HELO hostname is taken from `settings.helo_host` when set and
+/// otherwise left at the `mail_send` default.
+fn smtp_client_builder<'a>(
+    hub: &'a Hub,
+    credentials: &'a HubCredentials,
+    settings: &HubSettings,
+) -> Result<SmtpClientBuilder<&'a str>, Error> {
+    let (smtp_server, smtp_port) = smtp_address(hub)?;
+    let credentials = (credentials.login.as_str(), credentials.password.as_str());
+
+    let mut builder = SmtpClientBuilder::new(smtp_server, smtp_port)
+        .implicit_tls(true)
+        .credentials(credentials);
+    if let Some(helo_host) = settings.helo_host.as_deref() {
+        builder = builder.helo_host(helo_host);
+    }
+    Ok(builder)
+}
+
+/// Maps a failed SMTP connect to the step that failed: reaching the server
+/// ([`Error::Connection`]), the TLS handshake ([`Error::TlsHandshake`]) or
+/// authentication ([`Error::Auth`]). Other failures stay [`Error::Smtp`].
+fn smtp_connect_error(err: mail_send::Error) -> Error {
+    use mail_send::Error as SmtpError;
+
+    match err {
+        SmtpError::Io(io_err) if io_err.kind() == std::io::ErrorKind::InvalidData => {
+            Error::TlsHandshake(format!("SMTP TLS handshake failed: {io_err}"))
+        }
+        SmtpError::Io(io_err) => {
+            Error::Connection(format!("Can't connect to the SMTP server: {io_err}"))
+        }
+        SmtpError::Timeout => Error::Connection("SMTP server timed out".into()),
+        err @ (SmtpError::Tls(_) | SmtpError::InvalidTLSName | SmtpError::MissingStartTls) => {
+            Error::TlsHandshake(format!("SMTP TLS handshake failed: {err}"))
+        }
+        err @ (SmtpError::Auth(_)
+        | SmtpError::AuthenticationFailed(_)
+        | SmtpError::MissingCredentials
+        | SmtpError::UnsupportedAuthMechanism) => {
+            Error::Auth(format!("SMTP login rejected: {err}"))
+        }
+        other => Error::Smtp(other),
+    }
+}
+
+/// Connects and authenticates to the hub's SMTP server with the credentials
+/// and settings from `config`.
+///
+/// Returns the client together with the server's `EHLO` response, so the
+/// mail transaction can use the advertised extensions.
+async fn connect_smtp(
+    hub: &Hub,
+    config: &ServerConfig,
+) -> Result<(SmtpClient<TlsStream<TcpStream>>, EhloResponse<String>), Error> {
+    let settings = config.hub_settings(hub.id);
+    let key = config.password_key()?;
+    let credentials = resolve_credentials(hub, &settings, &SystemSecrets, key.as_ref()).await?;
+    let builder = smtp_client_builder(hub, &credentials, &settings)?;
+
+    // EHLO and AUTH are run here rather than in `connect` so the EHLO
+    // response is kept.
+    let mut client = match settings.bind_address {
+        Some(local) => {
+            let (smtp_server, smtp_port) = smtp_address(hub)?;
+            connect_smtp_from(&builder, smtp_server, smtp_port, local).await
+        }
+        None => builder.clone().say_ehlo(false).connect().await,
+    }
+    .map_err(smtp_connect_error)?;
+    let ehlo = client
+        .capabilities(&builder.local_host, builder.is_lmtp)
+        .await
+        .map_err(smtp_connect_error)?;
+    if let Some(credentials) = builder.credentials.as_ref() {
+        client
+            .authenticate(credentials, &ehlo)
+            .await
+            .map_err(smtp_connect_error)?;
+    }
+    Ok((client, ehlo))
+}
+
+/// Opens the implicit-TLS connection of `builder` from the local address
+/// `local` and reads the server greeting, as `SmtpClientBuilder::connect`
+/// does without EHLO. Bounded by the builder's timeout.
+async fn connect_smtp_from(
+    builder: &SmtpClientBuilder<&str>,
+    smtp_server: &str,
+    smtp_port: u16,
+    local: IpAddr,
+) -> Result<SmtpClient<TlsStream<TcpStream>>, mail_send::Error> {
+    let connect = async {
+        let stream = connect_tcp(smtp_server, smtp_port, Some(local)).await?;
+        let mut client = SmtpClient {
+            stream,
+            timeout: builder.timeout,
+        }
+        .into_tls(&builder.tls_connector, builder.tls_hostname)
+        .await?;
+        client.read_greeting().await?;
+        Ok::<_, mail_send::Error>(client)
+    };
+    tokio::time::timeout(builder.timeout, connect)
+        .await
+        .map_err(|_| mail_send::Error::Timeout)?
+}
+
+/// Checks a hub's SMTP settings, e.g. before the hub is saved: connects,
+/// authenticates and quits without sending anything.
+///
+/// Fails with [`Error::Connection`] when the server is unreachable,
+/// [`Error::TlsHandshake`] when TLS cannot be established and
+/// [`Error::Auth`] when the credentials are rejected.
+pub async fn test_smtp_connection(hub: &Hub, config: &ServerConfig) -> Result<(), Error> {
+    let (mut client, _) = connect_smtp(hub, config).await?;
+    client.noop().await?;
+    client.quit().await?;
+    Ok(())
+}
 
 #[async_trait]
 impl Mailer for SmtpMailer {
+    /// Uses PIPELINING and CHUNKING when the server advertises them; see
+    /// [`smtp`].
     async fn send(&self, hub: &Hub, message: MessageBuilder<'_>) -> Result<(), Error> {
-        let smtp_server = hub
-            .smtp_server
-            .as_ref()
-            .map(|host| host.as_str())
-            .ok_or(Error::Config("Missed SMTP server address".to_owned()))?;
-        let smtp_port = hub
-            .smtp_port
-            .ok_or(Error::Config("Missed SMTP port".to_owned()))?
-            .get();
-        let credentials = (
-            hub.login
-                .as_ref()
-                .map(|login| login.as_str())
-                .unwrap_or_default(),
-            hub.password
-                .as_ref()
-                .map(|password| password.as_str())
-                .unwrap_or_default(),
+        let message = message.into_message()?;
+        let (mut client, ehlo) = connect_smtp(hub, &self.config).await?;
+        let envelope = Envelope {
+            from: &message.mail_from.email,
+            recipients: message
+                .rcpt_to
+                .iter()
+                .map(|recipient| recipient.email.as_ref())
+                .collect(),
+        };
+        let transaction = send_transaction(
+            &mut client.stream,
+            SmtpExtensions::from_ehlo(&ehlo),
+            &envelope,
+            &message.body,
         );
+        tokio::time::timeout(client.timeout, transaction)
+            .await
+            .map_err(|_| mail_send::Error::Timeout)?
+    }
+}
 
-        SmtpClientBuilder::new(smtp_server, smtp_port)
-            .implicit_tls(true)
-            .credentials(credentials)
-            .connect()
-            .await?
-            .send(message)
-            .await?;
-        Ok(())
+/// Decodes a raw ZeroMQ payload and claims its job, dropping jobs that are
+/// still running or were processed recently.
+fn decode_job(raw: &[u8], cache: &Mutex<ProcessedCache>) -> Option<(SendEmailRequest, JobKey)> {
+    let parsed = match serde_json::from_slice::<SendEmailRequest>(raw) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::error!("Error receiving message: {e}");
+            return None;
+        }
+    };
+
+    let key = JobKey::new(&parsed.message, raw);
+    let claimed = cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .claim(key, Instant::now());
+    if !claimed {
+        log::info!("Skipping duplicate send job {key:?}");
+        return None;
+    }
+
+    Some((parsed, key))
+}
+
+/// Runs a claimed send job and marks it processed in `cache` once
+/// `send_email` returns.
+///
+/// A job that failed, or a `RetryEmail` with failed recipients, is released
+/// so a retry can run it again; a stored `NewEmail` counts as processed, as
+/// running it again would store and mail it twice.
+///
+/// Whenever a warm-up cap deferred recipients, the email is then sent again
+/// as a `RetryEmail` once the cap resets, until none are deferred. These
+/// follow-ups live in this task only, so a restart drops them; the deferred
+/// recipients then wait for the next `RetryEmail`.
+async fn process_job(
+    request: SendEmailRequest,
+    key: JobKey,
+    cache: &Mutex<ProcessedCache>,
+    repo: &DieselRepository,
+    config: &Arc<ServerConfig>,
+) {
+    let mailer = SmtpMailer::new(Arc::clone(config));
+    // Options are not stored with the email, so follow-ups repeat them;
+    // the deadline is, and follow-ups use the stored one.
+    let (from_override, date, raw) = (request.from_override.clone(), request.date, request.raw);
+
+    let result = send_email(request, repo, config, &mailer).await;
+    let processed = match (&result, key) {
+        (Ok(_), JobKey::New(_)) => true,
+        (Ok(summary), JobKey::Retry(..)) => summary.failed == 0,
+        (Err(_), _) => false,
+    };
+    cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .finish(key, processed, Instant::now());
+
+    let mut retry = match result {
+        Ok(summary) => summary.retry,
+        Err(e) => {
+            log::error!("Error sending email message: {e}");
+            return;
+        }
+    };
+    while let Some(next) = retry {
+        log::info!(
+            "Retrying email_id {} for hub#{} at {}",
+            next.email_id,
+            next.hub_id,
+            next.at
+        );
+        let wait = (next.at - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        let request = SendEmailRequest {
+            message: ZMQSendEmailMessage::RetryEmail((next.email_id, next.hub_id)),
+            from_override: from_override.clone(),
+            date,
+            raw,
+            expires_at: None,
+        };
+        retry = match send_email(request, repo, config, &mailer).await {
+            Ok(summary) => summary.retry,
+            Err(e) => {
+                log::error!("Error sending email message: {e}");
+                None
+            }
+        };
     }
 }
 
 /// Entry point for the email sender worker.
-pub async fn run(database_url: &str, domain: &str, zmq_address: &str) -> Result<(), Error> {
-    let db_pool = establish_connection_pool(database_url)?;
-    let repo = DieselRepository::new(db_pool);
+pub async fn run(config: &ServerConfig) -> Result<(), Error> {
+    let db_pool = establish_pool(&config.database_url, &config.db_pool)?;
+    ensure_schema(&db_pool)?;
+    let repo = DieselRepository::new(db_pool).with_normalizer(config.suppression.clone());
 
     let context = zmq::Context::new();
     let responder = context.socket(zmq::SUB)?;
-    responder.connect(zmq_address)?;
+    responder.connect(&config.zmq_emailer_sub)?;
     responder.set_subscribe(b"")?;
 
-    let domain = Arc::new(domain.to_owned());
+    if let Some(node) = config.message_id.node.as_deref()
+        && !message_builder::set_message_id_node(node)
+    {
+        log::warn!("Ignoring message_id.node {node:?}: no letters, digits or '-'");
+    }
+
+    let config = Arc::new(config.clone());
+    let processed = Arc::new(Mutex::new(ProcessedCache::new(
+        config.dedup.capacity,
+        Duration::from_secs(config.dedup.window_secs),
+    )));
+
+    if config.sender_auth.strict {
+        for (hub_id, settings) in &config.hubs {
+            let missing = config.sender_auth.missing_declarations(settings);
+            if !missing.is_empty() {
+                log::warn!(
+                    "Hub#{hub_id} lacks {}; its sends will be refused",
+                    missing.join(", ")
+                );
+            }
+        }
+    }
 
     log::info!("Starting email sending worker");
 
     loop {
         let msg = responder.recv_bytes(0)?;
-        match serde_json::from_slice::<ZMQSendEmailMessage>(&msg) {
-            Ok(parsed) => {
-                let domain = Arc::clone(&domain);
-                let repo = repo.clone();
-                tokio::spawn(async move {
-                    let mailer = SmtpMailer;
-                    if let Err(e) = send_email(parsed, &repo, &domain, &mailer).await {
-                        log::error!("Error sending email message: {e}");
-                    }
-                });
-            }
-            Err(e) => {
-                log::error!("Error receiving message: {e}");
-            }
+        if let Some((parsed, key)) = decode_job(&msg, &processed) {
+            let config = Arc::clone(&config);
+            let processed = Arc::clone(&processed);
+            let repo = repo.clone();
+            tokio::spawn(async move { process_job(parsed, key, &processed, &repo, &config).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> HubCredentials {
+        HubCredentials {
+            login: "sender@example.com".into(),
+            password: "secret".into(),
         }
     }
+
+    fn smtp_hub() -> Hub {
+        hub_at("smtp.example.com", 465)
+    }
+
+    fn hub_at(server: &str, port: i32) -> Hub {
+        // The SMTP builder prepares a TLS connector, which needs a provider.
+        let _ = rustls::crypto::CryptoProvider::install_default(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        );
+
+        Hub::try_new(
+            1,
+            Some("sender@example.com".to_string()),
+            Some("secret".to_string()),
+            Some("sender@example.com".to_string()),
+            Some(server.to_string()),
+            Some(port),
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn smtp_builder_uses_configured_helo_host() {
+        let hub = smtp_hub();
+        let settings = HubSettings {
+            helo_host: Some("mx.example.com".to_string()),
+            ..Default::default()
+        };
+
+        let credentials = credentials();
+        let builder = smtp_client_builder(&hub, &credentials, &settings).unwrap();
+        assert_eq!(builder.local_host, "mx.example.com");
+        assert!(builder.tls_implicit);
+    }
+
+    #[test]
+    fn smtp_builder_keeps_default_helo_host_when_unset() {
+        let hub = smtp_hub();
+        let default_host = SmtpClientBuilder::new("smtp.example.com", 465).local_host;
+
+        let credentials = credentials();
+        let builder = smtp_client_builder(&hub, &credentials, &HubSettings::default()).unwrap();
+        assert_eq!(builder.local_host, default_host);
+    }
+
+    #[tokio::test]
+    async fn unreachable_smtp_server_is_a_connection_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let hub = hub_at("127.0.0.1", i32::from(port));
+        let err = test_smtp_connection(&hub, &ServerConfig::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Connection(_)));
+    }
+
+    #[tokio::test]
+    async fn plaintext_smtp_server_is_a_tls_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket
+                .write_all(b"220 smtp.example.com ESMTP ready\r\n")
+                .await;
+        });
+
+        let hub = hub_at("127.0.0.1", i32::from(port));
+        let err = test_smtp_connection(&hub, &ServerConfig::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TlsHandshake(_)));
+    }
+
+    fn bound_to(local: &str) -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                bind_address: Some(local.parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    #[tokio::test]
+    async fn smtp_connection_leaves_from_the_bound_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let (mut socket, peer) = listener.accept().await.unwrap();
+            let _ = socket
+                .write_all(b"220 smtp.example.com ESMTP ready\r\n")
+                .await;
+            peer
+        });
+
+        let hub = hub_at("127.0.0.1", i32::from(port));
+        let err = test_smtp_connection(&hub, &bound_to("127.0.0.1"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TlsHandshake(_)));
+        assert_eq!(peer.await.unwrap().ip().to_string(), "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn unusable_bind_address_is_a_connection_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let hub = hub_at("127.0.0.1", i32::from(port));
+        for local in ["192.0.2.1", "::1"] {
+            let err = test_smtp_connection(&hub, &bound_to(local))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Connection(_)), "{local}: {err}");
+        }
+    }
+
+    #[test]
+    fn smtp_login_failures_are_auth_errors() {
+        for err in [
+            mail_send::Error::MissingCredentials,
+            mail_send::Error::UnsupportedAuthMechanism,
+        ] {
+            assert!(matches!(smtp_connect_error(err), Error::Auth(_)));
+        }
+        assert!(matches!(
+            smtp_connect_error(mail_send::Error::MissingMailFrom),
+            Error::Smtp(_)
+        ));
+    }
+
+    #[test]
+    fn decode_job_processes_redelivered_message_once() {
+        let cache = Mutex::new(ProcessedCache::new(16, Duration::from_secs(60)));
+        let raw = serde_json::to_vec(&ZMQSendEmailMessage::RetryEmail((5, 1))).unwrap();
+
+        let (_, key) = decode_job(&raw, &cache).unwrap();
+        assert!(decode_job(&raw, &cache).is_none(), "still running");
+        cache.lock().unwrap().finish(key, true, Instant::now());
+        assert!(decode_job(&raw, &cache).is_none(), "processed");
+    }
+
+    #[test]
+    fn decode_job_accepts_a_retry_of_a_failed_job() {
+        let cache = Mutex::new(ProcessedCache::new(16, Duration::from_secs(60)));
+        let raw = serde_json::to_vec(&ZMQSendEmailMessage::RetryEmail((5, 1))).unwrap();
+
+        let (_, key) = decode_job(&raw, &cache).unwrap();
+        cache.lock().unwrap().finish(key, false, Instant::now());
+        assert!(decode_job(&raw, &cache).is_some());
+    }
+
+    #[test]
+    fn decode_job_keeps_distinct_messages() {
+        let cache = Mutex::new(ProcessedCache::new(16, Duration::from_secs(60)));
+        let first = serde_json::to_vec(&ZMQSendEmailMessage::RetryEmail((5, 1))).unwrap();
+        let second = serde_json::to_vec(&ZMQSendEmailMessage::RetryEmail((6, 1))).unwrap();
+
+        assert!(decode_job(&first, &cache).is_some());
+        assert!(decode_job(&second, &cache).is_some());
+    }
 }
@@ -1,14 +1,29 @@
 use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 use mail_send::mail_builder::MessageBuilder;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{StreamExt, stream};
+use pushkind_emailer::domain::email::{Email, EmailRecipient, EmailWithRecipients};
 use pushkind_emailer::domain::hub::Hub;
 use pushkind_emailer::domain::types::{EmailId, HubId};
 use pushkind_emailer::models::zmq::ZMQSendEmailMessage;
 
-use crate::domain::UpdateEmailRecipient;
+use crate::domain::{
+    DeferredRetry, DeliveryEventKind, FromOverride, PreviewRecipient, RawSend, SendEmailRequest,
+    SendSummary, UpdateEmailRecipient,
+};
 use crate::errors::Error;
-use crate::repository::{EmailReader, EmailWriter, HubReader};
+use crate::models::{HubSettings, ServerConfig, SpamCheckConfig};
+use crate::repository::{
+    DeliveryReader, DeliveryWriter, EmailReader, EmailWriter, HubReader, SuppressionReader,
+};
+use crate::unsubscribe::UnsubscribeKey;
 
-use super::message_builder::build_message;
+use super::message_builder::{MessageOptions, build_message, message_size, render_body};
+use super::spam::score_message;
+use super::template::hub_template;
 
 /// Abstraction over message delivery.
 #[async_trait]
@@ -17,19 +32,392 @@ pub trait Mailer: Send + Sync {
     async fn send(&self, hub: &Hub, message: MessageBuilder<'_>) -> Result<(), Error>;
 }
 
+/// Reserves one of the hub's warm-up sends for `today` as a `sent` delivery
+/// event, so concurrent jobs, and other processes, share the day's `cap`.
+///
+/// Returns the reserved event, or `None` when the cap is reached or the
+/// reservation failed; the recipient is then deferred.
+fn reserve_warmup_slot<R>(
+    repo: &R,
+    hub_id: HubId,
+    today: NaiveDate,
+    cap: i64,
+    recipient: &EmailRecipient,
+) -> Option<i32>
+where
+    R: DeliveryWriter + ?Sized,
+{
+    let since = today.and_time(NaiveTime::MIN);
+    match repo.reserve_delivery_event(hub_id, DeliveryEventKind::Sent, since, cap) {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            log::error!(
+                "Cannot reserve a warm-up slot of hub#{hub_id}; deferring {}: {e}",
+                recipient.address
+            );
+            None
+        }
+    }
+}
+
+/// Scores the rendered email, or the message itself for a raw send, and
+/// reports whether it may be sent.
+///
+/// Emails at or above the threshold are logged; in strict mode they are
+/// also blocked.
+fn passes_spam_check(
+    hub: &Hub,
+    email: &EmailWithRecipients,
+    template: Option<&str>,
+    raw: bool,
+    spam_check: &SpamCheckConfig,
+) -> bool {
+    let Some(recipient) = email.recipients.iter().find(|recipient| !recipient.is_sent) else {
+        return true;
+    };
+
+    let subject = email
+        .email
+        .subject
+        .as_ref()
+        .map(|subject| subject.as_str())
+        .unwrap_or_default();
+    let body = match raw {
+        true => email.email.message.as_str().to_string(),
+        false => render_body(hub, &email.email, recipient, template),
+    };
+    let report = score_message(subject, &body);
+    if report.score < spam_check.threshold {
+        return true;
+    }
+
+    log::warn!(
+        "Email_id {} in hub#{} scored {:.1} for spam (threshold {:.1}): {}",
+        email.email.id,
+        hub.id,
+        report.score,
+        spam_check.threshold,
+        report.reasons.join(", ")
+    );
+    if spam_check.strict {
+        log::error!(
+            "Blocking email_id {} in hub#{}: spam score above threshold",
+            email.email.id,
+            hub.id
+        );
+        return false;
+    }
+    true
+}
+
+/// Why a recipient is left out of a send job.
+enum Exclusion {
+    /// Already sent, rejected for good by an earlier job, or its state could
+    /// not be checked.
+    Skipped,
+    /// Unsubscribed from the hub or globally suppressed.
+    Suppressed,
+}
+
+/// Returns why `recipient` does not get the email, or `None` when it still
+/// needs it: it is not sent or rejected yet and its address is not
+/// suppressed for the hub.
+fn exclusion<R>(
+    repo: &R,
+    hub: &Hub,
+    email: &EmailWithRecipients,
+    recipient: &EmailRecipient,
+) -> Option<Exclusion>
+where
+    R: EmailReader + SuppressionReader + ?Sized,
+{
+    if recipient.is_sent {
+        log::info!("Skipping already sent email to {}", recipient.address);
+        return Some(Exclusion::Skipped);
+    }
+
+    match repo.is_recipient_rejected(recipient.id) {
+        Ok(false) => {}
+        Ok(true) => {
+            log::info!(
+                "Skipping rejected recipient {} of email_id {}",
+                recipient.address,
+                email.email.id
+            );
+            return Some(Exclusion::Skipped);
+        }
+        Err(e) => {
+            log::error!(
+                "Cannot check rejection of {}; skipping: {}",
+                recipient.address,
+                e
+            );
+            return Some(Exclusion::Skipped);
+        }
+    }
+
+    match repo.is_suppressed(recipient.address.as_str(), hub.id) {
+        Ok(false) => None,
+        Ok(true) => {
+            log::info!(
+                "Skipping suppressed recipient {} of email_id {}",
+                recipient.address,
+                email.email.id
+            );
+            Some(Exclusion::Suppressed)
+        }
+        Err(e) => {
+            log::error!(
+                "Cannot check suppression for {}; skipping: {}",
+                recipient.address,
+                e
+            );
+            Some(Exclusion::Skipped)
+        }
+    }
+}
+
+/// What happened to a recipient the job attempted.
+enum Outcome {
+    /// Handed to the SMTP server and marked sent.
+    Sent,
+    /// Not sent, for the given reason; the recipient stays unsent.
+    Failed(String),
+    /// Not attempted because the job's deadline had passed.
+    Expired,
+    /// Not attempted because the hub's warm-up cap was reached.
+    Deferred,
+}
+
+/// Awaits `future` and returns its output with the time it took.
+async fn timed<F: Future>(future: F) -> (F::Output, Duration) {
+    let started = Instant::now();
+    let output = future.await;
+    (output, started.elapsed())
+}
+
+/// Job data shared by every recipient send of one email.
+struct SendContext<'a> {
+    hub: &'a Hub,
+    email: &'a Email,
+    domain: &'a str,
+    settings: &'a HubSettings,
+    from_override: Option<&'a FromOverride>,
+    /// Explicit `Date` header requested by the job.
+    date: Option<DateTime<Utc>>,
+    /// Hub template with partials expanded, if any.
+    template: Option<&'a str>,
+    unsubscribe_key: Option<&'a UnsubscribeKey>,
+    /// Sends the stored message without template or tracking.
+    raw: Option<RawSend>,
+    /// Recipients are no longer sent after this time.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Builds the job's message for `recipient`.
+fn message_for<'a>(
+    job: &SendContext<'a>,
+    recipient: &'a EmailRecipient,
+) -> Result<MessageBuilder<'a>, Error> {
+    build_message(
+        job.hub,
+        job.email,
+        recipient,
+        job.domain,
+        job.settings,
+        &MessageOptions {
+            from_override: job.from_override,
+            date: job.date,
+            template: job.template,
+            unsubscribe_key: job.unsubscribe_key,
+            preview: false,
+            raw: job.raw,
+        },
+    )
+}
+
+/// Measures `recipient`'s copy of the job's message and returns why it
+/// cannot be sent when it exceeds the hub's `max_message_bytes`.
+///
+/// Copies differ in personalized text and in per-recipient attachments, so
+/// every recipient is measured. A message that cannot be built is left to
+/// [`send_to_recipient`] to report.
+fn oversize_reason(job: &SendContext<'_>, recipient: &EmailRecipient) -> Option<String> {
+    let limit = job.settings.max_message_bytes?;
+    let message = message_for(job, recipient).ok()?;
+    let size = match message_size(message) {
+        Ok(size) => size,
+        Err(e) => {
+            log::error!(
+                "Cannot measure email_id {} for recipient {}: {}",
+                job.email.id,
+                recipient.id,
+                e
+            );
+            return None;
+        }
+    };
+    log::debug!(
+        "Message of email_id {} for recipient {} is {size} bytes",
+        job.email.id,
+        recipient.id
+    );
+    (size > limit).then(|| format!("message is {size} bytes, over the {limit}-byte limit"))
+}
+
+/// Sends the job's email to one recipient and records the outcome.
+///
+/// `reservation` is the `sent` event reserved under a warm-up cap: it
+/// stands for the delivery on success and is deleted on failure.
+///
+/// Returns `Err(reason)` when the message cannot be built or the SMTP send
+/// fails; the recipient then stays unsent. A failure never affects the
+/// job's other recipients.
+async fn send_to_recipient<R, M>(
+    repo: &R,
+    mailer: &M,
+    job: &SendContext<'_>,
+    recipient: &EmailRecipient,
+    reservation: Option<i32>,
+) -> Result<(), String>
+where
+    R: EmailWriter + DeliveryWriter + ?Sized,
+    M: Mailer,
+{
+    let sent = deliver(repo, mailer, job, recipient, reservation.is_none()).await;
+    if sent.is_err()
+        && let Some(event_id) = reservation
+        && let Err(e) = repo.delete_delivery_event(event_id)
+    {
+        log::error!(
+            "Failed to return the warm-up slot of hub#{}: {}",
+            job.hub.id,
+            e
+        );
+    }
+    sent
+}
+
+/// Builds and sends the message of [`send_to_recipient`], recording a
+/// `sent` delivery event when `record_sent` is set.
+async fn deliver<R, M>(
+    repo: &R,
+    mailer: &M,
+    job: &SendContext<'_>,
+    recipient: &EmailRecipient,
+    record_sent: bool,
+) -> Result<(), String>
+where
+    R: EmailWriter + DeliveryWriter + ?Sized,
+    M: Mailer,
+{
+    let hub = job.hub;
+    let message = match message_for(job, recipient) {
+        Ok(message) => message,
+        Err(e) => {
+            log::error!(
+                "Cannot build email_id {} for {}: {}",
+                job.email.id,
+                recipient.address,
+                e
+            );
+            return Err(e.to_string());
+        }
+    };
+    if job.raw.is_none() && log::log_enabled!(log::Level::Debug) {
+        log::debug!(
+            "Rendered {} body bytes for recipient {} of email_id {}",
+            render_body(hub, job.email, recipient, job.template).len(),
+            recipient.id,
+            job.email.id
+        );
+    }
+
+    let (sent, elapsed) = timed(mailer.send(hub, message)).await;
+    log::debug!(
+        "SMTP send to {} via hub#{} took {:?}",
+        recipient.address,
+        hub.id,
+        elapsed
+    );
+    if let Err(e) = sent {
+        log::error!("Failed to send email to {}: {}", recipient.address, e);
+        return Err(e.to_string());
+    }
+
+    log::info!("Email sent successfully to {}", recipient.address);
+
+    if record_sent && let Err(e) = repo.record_delivery_event(hub.id, DeliveryEventKind::Sent) {
+        log::error!("Failed to record delivery for hub#{}: {}", hub.id, e);
+    }
+
+    if let Err(e) = repo.update_recipient(
+        recipient.id,
+        &UpdateEmailRecipient {
+            sent: Some(true),
+            opened: None,
+            reply: None,
+        },
+    ) {
+        log::error!(
+            "Failed to update sent status for recipient {}: {}",
+            recipient.id,
+            e
+        );
+    }
+    Ok(())
+}
+
 /// Processes a [`ZMQSendEmailMessage`] by fetching data from the repository
 /// and dispatching email messages via the provided [`Mailer`].
+///
+/// Hubs whose recent bounce rate trips the configured circuit breaker are
+/// skipped until the rate falls back under the threshold. Hubs in warm-up
+/// send at most their daily cap; remaining recipients stay unsent until a
+/// later retry. Emails whose rendered content reaches the spam-score
+/// threshold are logged, and skipped entirely in strict mode. Recipients who
+/// unsubscribed from the hub or are globally suppressed are never mailed.
+/// Up to the hub's `send_concurrency` recipients are sent in parallel.
+///
+/// Returns what happened to each recipient as a [`SendSummary`]; SMTP
+/// failures are reported there rather than failing the job. A missing hub,
+/// or a `from_override` not on the hub's `allowed_from` list, fails the job
+/// with [`Error::Config`] before anything is sent; the override is also
+/// checked before anything is stored.
+/// With `sender_auth.strict`, hubs whose configuration does not declare SPF
+/// and a DKIM selector (and DMARC, when required) fail with
+/// [`Error::Config`] before sending; the declarations are not checked
+/// against DNS.
 pub async fn send_email<R, M>(
-    msg: ZMQSendEmailMessage,
+    request: impl Into<SendEmailRequest>,
     repo: &R,
-    domain: &str,
+    config: &ServerConfig,
     mailer: &M,
-) -> Result<(), Error>
+) -> Result<SendSummary, Error>
 where
-    R: EmailReader + EmailWriter + HubReader,
+    R: EmailReader + EmailWriter + HubReader + DeliveryReader + DeliveryWriter + SuppressionReader,
     M: Mailer,
 {
-    let email = match msg {
+    let started = Instant::now();
+    let request = request.into();
+    let from_override = request.from_override.as_ref();
+    if let Some(from) = from_override {
+        let hub_id = request.hub_id();
+        let allowed = HubId::try_from(hub_id)
+            .is_ok_and(|hub_id| config.hub_settings(hub_id).allows_from(&from.address));
+        if !allowed {
+            log::error!(
+                "Rejecting From override {} for hub#{hub_id}: not in allowed_from",
+                from.address
+            );
+            return Err(Error::Config(format!(
+                "From override {} is not allowed for hub#{hub_id}",
+                from.address
+            )));
+        }
+    }
+
+    let email = match request.message {
         ZMQSendEmailMessage::RetryEmail((email_id, hub_id)) => {
             let email_id = EmailId::try_from(email_id)
                 .map_err(|e| Error::Config(format!("Invalid email_id {email_id}: {e}")))?;
@@ -50,13 +438,106 @@ where
         }
     };
 
+    // A job's deadline is stored with the email so that every later job,
+    // including a bare `RetryEmail`, honours it.
+    let expires_at = match request.expires_at {
+        Some(deadline) => {
+            repo.set_email_deadline(email.email.id, deadline)?;
+            Some(deadline)
+        }
+        None => repo.get_email_deadline(email.email.id)?,
+    };
+
     let hub = match repo.get_hub_by_id(email.email.hub_id)? {
         Some(hub) => hub,
         None => {
             log::error!("Hub not found for email_id: {}", email.email.id);
-            return Ok(());
+            return Err(Error::Config("hub not found".into()));
         }
     };
+    // Summary of a job held back as a whole: nobody is attempted.
+    let held = || SendSummary {
+        skipped: email.recipients.len(),
+        ..Default::default()
+    };
+
+    match repo.is_sending_paused(hub.id) {
+        Ok(true) => {
+            log::warn!(
+                "Sending paused for hub#{} by operator; skipping email_id {}",
+                hub.id,
+                email.email.id
+            );
+            return Ok(held());
+        }
+        Ok(false) => {}
+        // The kill-switch must hold when it cannot be read.
+        Err(e) => {
+            log::error!(
+                "Cannot load the pause flag for hub#{}: {e}; holding email_id {}",
+                hub.id,
+                email.email.id
+            );
+            return Ok(held());
+        }
+    }
+
+    let breaker = &config.bounce_breaker;
+    match repo.bounce_stats(hub.id, breaker.window_start(Utc::now().naive_utc())) {
+        Ok(stats) if breaker.is_tripped(&stats) => {
+            log::error!(
+                "Sending paused for hub#{}: bounce rate {:.1}% ({} of {}) exceeds {:.1}%; skipping email_id {}",
+                hub.id,
+                stats.rate() * 100.0,
+                stats.bounced,
+                stats.sent,
+                breaker.threshold * 100.0,
+                email.email.id
+            );
+            return Ok(held());
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Cannot load bounce stats for hub#{}: {e}", hub.id),
+    }
+
+    if config.sender_auth.strict {
+        let missing = config
+            .sender_auth
+            .missing_declarations(&config.hub_settings(hub.id));
+        if !missing.is_empty() {
+            log::error!(
+                "Refusing email_id {} for hub#{}: no declared {}",
+                email.email.id,
+                hub.id,
+                missing.join(", ")
+            );
+            return Err(Error::Config(format!(
+                "hub#{} has no {} configured",
+                hub.id,
+                missing.join(", ")
+            )));
+        }
+    }
+
+    let template = hub_template(config, &hub).await;
+    if !passes_spam_check(
+        &hub,
+        &email,
+        template.as_deref(),
+        request.raw.is_some(),
+        &config.spam_check,
+    ) {
+        return Ok(held());
+    }
+
+    let settings = config.hub_settings(hub.id);
+    let unsubscribe_key = config.unsubscribe_key()?;
+    let today = Utc::now().date_naive();
+    let warmup_cap = settings
+        .warmup
+        .as_ref()
+        .and_then(|warmup| warmup.cap_for(today));
+    let concurrency = settings.send_concurrency.unwrap_or(1).max(1);
 
     log::info!(
         "Sending email for email_id {} via hub {}",
@@ -64,39 +545,270 @@ where
         hub.id
     );
 
-    for recipient in email.recipients {
-        if recipient.is_sent {
-            log::info!("Skipping already sent email to {}", recipient.address);
-            continue;
+    let mut summary = SendSummary::default();
+    let mut pending: Vec<_> = email
+        .recipients
+        .iter()
+        .filter(|recipient| match exclusion(repo, &hub, &email, recipient) {
+            None => true,
+            Some(Exclusion::Skipped) => {
+                summary.skipped += 1;
+                false
+            }
+            Some(Exclusion::Suppressed) => {
+                summary.suppressed += 1;
+                false
+            }
+        })
+        .collect();
+    let job = SendContext {
+        hub: &hub,
+        email: &email.email,
+        domain: settings.domain_or(&config.domain),
+        settings: &settings,
+        from_override,
+        date: request.date,
+        template: template.as_deref(),
+        unsubscribe_key: unsubscribe_key.as_ref(),
+        raw: request.raw,
+        expires_at,
+    };
+    pending.retain(|recipient| {
+        let Some(reason) = oversize_reason(&job, recipient) else {
+            return true;
+        };
+        log::error!(
+            "Not sending email_id {} to recipient {} via hub#{}: {reason}",
+            email.email.id,
+            recipient.id,
+            hub.id
+        );
+        if let Err(e) = repo.reject_recipient(recipient.id, &reason) {
+            log::error!("Failed to reject recipient {}: {}", recipient.id, e);
         }
+        summary.failed += 1;
+        summary.errors.push((recipient.id, reason));
+        false
+    });
+    let outcomes: Vec<_> = stream::iter(pending)
+        .map(|recipient| {
+            let job = &job;
+            async move {
+                if let Some(deadline) = job.expires_at.filter(|deadline| Utc::now() > *deadline) {
+                    // Terminal, so later jobs skip the recipient.
+                    let reason = format!("deadline {deadline} passed");
+                    if let Err(e) = repo.reject_recipient(recipient.id, &reason) {
+                        log::error!("Failed to reject recipient {}: {}", recipient.id, e);
+                    }
+                    return (recipient, Outcome::Expired);
+                }
+                // Reserve a warm-up slot; it is returned if the send fails.
+                let reservation = match warmup_cap {
+                    Some(cap) => match reserve_warmup_slot(repo, job.hub.id, today, cap, recipient)
+                    {
+                        Some(event_id) => Some(event_id),
+                        None => return (recipient, Outcome::Deferred),
+                    },
+                    None => None,
+                };
 
-        let message = build_message(&hub, &email.email, &recipient, domain);
+                match send_to_recipient(repo, mailer, job, recipient, reservation).await {
+                    Ok(()) => (recipient, Outcome::Sent),
+                    Err(reason) => (recipient, Outcome::Failed(reason)),
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
 
-        if let Err(e) = mailer.send(&hub, message).await {
-            log::error!("Failed to send email to {}: {}", recipient.address, e);
-            continue;
+    let mut deferred = 0;
+    for (recipient, outcome) in outcomes {
+        match outcome {
+            Outcome::Sent => summary.sent += 1,
+            Outcome::Failed(reason) => {
+                summary.failed += 1;
+                summary.errors.push((recipient.id, reason));
+            }
+            Outcome::Expired => summary.expired += 1,
+            Outcome::Deferred => deferred += 1,
         }
+    }
+    summary.skipped += deferred;
+    if deferred > 0 {
+        // The cap resets at the next UTC midnight; retrying after the
+        // job's deadline would only expire the recipients.
+        summary.retry = today
+            .succ_opt()
+            .map(|day| day.and_time(NaiveTime::MIN).and_utc())
+            .filter(|at| job.expires_at.is_none_or(|deadline| *at < deadline))
+            .map(|at| DeferredRetry {
+                email_id: email.email.id.get(),
+                hub_id: hub.id.get(),
+                at,
+            });
+        log::warn!(
+            "Warm-up cap reached for hub#{}; deferred {} recipient(s) of email_id {}",
+            hub.id,
+            deferred,
+            email.email.id
+        );
+    }
 
-        log::info!("Email sent successfully to {}", recipient.address);
+    if let Some(deadline) = job.expires_at
+        && summary.expired > 0
+    {
+        log::warn!(
+            "Deadline {deadline} of email_id {} passed; {} recipient(s) expired unsent",
+            email.email.id,
+            summary.expired
+        );
+    }
 
-        if let Err(e) = repo.update_recipient(
-            recipient.id,
-            &UpdateEmailRecipient {
-                sent: Some(true),
-                opened: None,
-                reply: None,
+    log::info!(
+        "Finished processing email_id {}: {} sent, {} failed, {} skipped, {} suppressed, {} expired",
+        email.email.id,
+        summary.sent,
+        summary.failed,
+        summary.skipped,
+        summary.suppressed,
+        summary.expired
+    );
+    log::debug!(
+        "Processing email_id {} took {:?}",
+        email.email.id,
+        started.elapsed()
+    );
+
+    Ok(summary)
+}
+
+/// Placeholder ID of the unsaved preview recipient. Preview messages never
+/// contain it.
+const PREVIEW_RECIPIENT_ID: i32 = i32::MAX;
+
+/// Data a preview message borrows from.
+struct Preview {
+    hub: Hub,
+    email: Email,
+    recipient: EmailRecipient,
+    settings: HubSettings,
+    template: Option<Arc<str>>,
+}
+
+impl Preview {
+    /// Loads the email and hub and wraps `preview` in an unsaved recipient.
+    async fn load<R>(
+        email_id: i32,
+        hub_id: i32,
+        preview: &PreviewRecipient,
+        repo: &R,
+        config: &ServerConfig,
+    ) -> Result<Self, Error>
+    where
+        R: EmailReader + HubReader + ?Sized,
+    {
+        let email_id = EmailId::try_from(email_id)
+            .map_err(|e| Error::Config(format!("Invalid email_id {email_id}: {e}")))?;
+        let hub_id = HubId::try_from(hub_id)
+            .map_err(|e| Error::Config(format!("Invalid hub_id {hub_id}: {e}")))?;
+        let email = repo
+            .get_email_by_id(email_id, hub_id)?
+            .ok_or_else(|| Error::Config(format!("Email {email_id} not found")))?
+            .email;
+        let hub = repo
+            .get_hub_by_id(hub_id)?
+            .ok_or_else(|| Error::Config(format!("Hub#{hub_id} not found")))?;
+        let recipient = EmailRecipient::try_new(
+            PREVIEW_RECIPIENT_ID,
+            email_id.get(),
+            preview.address.as_str(),
+            false,
+            Utc::now().naive_utc(),
+            false,
+            None,
+            preview.name.as_str(),
+            preview.fields.clone(),
+        )
+        .map_err(|e| Error::Config(format!("Invalid preview recipient: {e}")))?;
+
+        Ok(Self {
+            template: hub_template(config, &hub).await,
+            settings: config.hub_settings(hub.id),
+            hub,
+            email,
+            recipient,
+        })
+    }
+
+    fn message<'a>(&'a self, domain: &'a str) -> Result<MessageBuilder<'a>, Error> {
+        build_message(
+            &self.hub,
+            &self.email,
+            &self.recipient,
+            domain,
+            &self.settings,
+            &MessageOptions {
+                template: self.template.as_deref(),
+                preview: true,
+                ..Default::default()
             },
-        ) {
-            log::error!(
-                "Failed to update sent status for recipient {}: {}",
-                recipient.id,
-                e
-            );
-        }
+        )
     }
+}
 
-    log::info!("Finished processing email_id: {}", email.email.id);
+/// Renders email `email_id` for an ad-hoc preview recipient and returns the
+/// raw RFC 5322 message.
+///
+/// Nothing is written to the database.
+pub async fn render_preview<R>(
+    email_id: i32,
+    hub_id: i32,
+    preview: &PreviewRecipient,
+    repo: &R,
+    config: &ServerConfig,
+) -> Result<Vec<u8>, Error>
+where
+    R: EmailReader + HubReader + ?Sized,
+{
+    let preview = Preview::load(email_id, hub_id, preview, repo, config).await?;
+    let mut raw = Vec::new();
+    preview
+        .message(preview.settings.domain_or(&config.domain))?
+        .write_to(&mut raw)?;
+    Ok(raw)
+}
 
+/// Sends email `email_id` to an ad-hoc preview recipient, e.g. for "send test
+/// to myself".
+///
+/// The recipient is not stored and no delivery event or statistic is
+/// recorded. Suppression, pauses and warm-up caps do not apply. The
+/// Message-ID marks the message as a preview, so a reply to it is not
+/// correlated with any recipient.
+pub async fn send_preview<R, M>(
+    email_id: i32,
+    hub_id: i32,
+    preview: &PreviewRecipient,
+    repo: &R,
+    config: &ServerConfig,
+    mailer: &M,
+) -> Result<(), Error>
+where
+    R: EmailReader + HubReader + ?Sized,
+    M: Mailer,
+{
+    let preview = Preview::load(email_id, hub_id, preview, repo, config).await?;
+    mailer
+        .send(
+            &preview.hub,
+            preview.message(preview.settings.domain_or(&config.domain))?,
+        )
+        .await?;
+    log::info!(
+        "Preview of email_id {email_id} sent to {} via hub#{hub_id}",
+        preview.recipient.address
+    );
     Ok(())
 }
 
@@ -112,6 +824,8 @@ mod tests {
         },
     };
 
+    use crate::domain::{RecipientAttachment, UnsubscribeSource};
+    use crate::models::{DeclaredSenderAuthConfig, HubSettings, WarmupConfig};
     use crate::repository::DieselRepository;
     use diesel::{RunQueryDsl, connection::SimpleConnection};
     use pushkind_common::db::establish_connection_pool;
@@ -149,9 +863,11 @@ mod tests {
             conn.batch_execute(
                 "CREATE TABLE hubs (id INTEGER PRIMARY KEY, login TEXT, password TEXT, sender TEXT, smtp_server TEXT, smtp_port INTEGER, created_at TIMESTAMP, updated_at TIMESTAMP, imap_server TEXT, imap_port INTEGER, email_template TEXT, imap_last_uid INTEGER NOT NULL DEFAULT 0);\n\
                  CREATE TABLE emails (id INTEGER PRIMARY KEY, message TEXT NOT NULL, created_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, subject TEXT, attachment BLOB, attachment_name TEXT, attachment_mime TEXT, num_sent INTEGER NOT NULL DEFAULT 0, num_opened INTEGER NOT NULL DEFAULT 0, num_replied INTEGER NOT NULL DEFAULT 0, hub_id INTEGER NOT NULL REFERENCES hubs(id));\n\
-                CREATE TABLE email_recipients (id INTEGER PRIMARY KEY, email_id INTEGER NOT NULL REFERENCES emails(id), address TEXT NOT NULL, opened BOOL NOT NULL, updated_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, reply TEXT, name TEXT, fields TEXT);"
+                CREATE TABLE email_recipients (id INTEGER PRIMARY KEY, email_id INTEGER NOT NULL REFERENCES emails(id), address TEXT NOT NULL, opened BOOL NOT NULL, updated_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, reply TEXT, name TEXT, fields TEXT);\n\
+                CREATE TABLE unsubscribes (email TEXT NOT NULL, hub_id INTEGER NOT NULL, reason TEXT, created_at TIMESTAMP, PRIMARY KEY (email, hub_id));"
             ).unwrap();
         }
+        crate::db::ensure_schema(&pool).unwrap();
         (dir, pool)
     }
 
@@ -176,39 +892,187 @@ mod tests {
             .unwrap();
     }
 
-    fn create_email(repo: &DieselRepository) -> (i32, i32) {
-        let new_email = NewEmail {
-            message: EmailBody::new("Hello").unwrap(),
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            domain: "example.com".into(),
+            ..Default::default()
+        }
+    }
+
+    fn setup_repo() -> (TempDir, pushkind_common::db::DbPool, DieselRepository) {
+        let (dir, pool) = setup_pool();
+        insert_hub(&pool);
+        let repo = DieselRepository::new(pool.clone());
+        (dir, pool, repo)
+    }
+
+    fn mailer() -> MockMailer {
+        MockMailer {
+            calls: Arc::new(AtomicUsize::new(0)),
+            fail: false,
+        }
+    }
+
+    fn failing_mailer() -> MockMailer {
+        MockMailer {
+            fail: true,
+            ..mailer()
+        }
+    }
+
+    fn recipient(address: &str) -> NewEmailRecipient {
+        NewEmailRecipient {
+            address: RecipientEmail::try_from(address).unwrap(),
+            name: RecipientName::new("Alice").unwrap(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Hub 1's email with `message` for `recipients`.
+    fn new_email(message: &str, recipients: Vec<NewEmailRecipient>) -> NewEmail {
+        NewEmail {
+            message: EmailBody::new(message).unwrap(),
             subject: None,
             attachment: None,
             attachment_name: None,
             attachment_mime: None,
             hub_id: HubId::try_from(1).unwrap(),
-            recipients: vec![NewEmailRecipient {
-                address: RecipientEmail::try_from("to@example.com").unwrap(),
-                name: RecipientName::new("Alice").unwrap(),
-                fields: BTreeMap::new(),
-            }],
-        };
-        let stored = repo.create_email(&new_email).unwrap();
+            recipients,
+        }
+    }
+
+    fn create_email(repo: &DieselRepository) -> (i32, i32) {
+        let stored = repo
+            .create_email(&new_email("Hello", vec![recipient("to@example.com")]))
+            .unwrap();
         (stored.email.id.get(), stored.recipients[0].id.get())
     }
 
+    fn create_email_for(repo: &DieselRepository, addresses: &[&str]) -> i32 {
+        let recipients = addresses.iter().map(|address| recipient(address)).collect();
+        repo.create_email(&new_email("Hello", recipients))
+            .unwrap()
+            .email
+            .id
+            .get()
+    }
+
+    fn warmup_config(cap: i64) -> ServerConfig {
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                warmup: Some(WarmupConfig {
+                    start_date: Utc::now().date_naive(),
+                    daily_caps: vec![cap],
+                }),
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    fn sent_recipients(repo: &DieselRepository, email_id: i32) -> usize {
+        repo.get_email_by_id(
+            EmailId::try_from(email_id).unwrap(),
+            HubId::try_from(1).unwrap(),
+        )
+        .unwrap()
+        .unwrap()
+        .recipients
+        .iter()
+        .filter(|recipient| recipient.is_sent)
+        .count()
+    }
+
+    fn preview_recipient() -> PreviewRecipient {
+        PreviewRecipient {
+            address: "me@example.com".into(),
+            name: "Me".into(),
+            fields: BTreeMap::from([("city".to_string(), "Riga".to_string())]),
+        }
+    }
+
+    fn row_counts(pool: &pushkind_common::db::DbPool) -> (i64, i64) {
+        use diesel::QueryDsl;
+        use pushkind_emailer::schema::email_recipients;
+
+        let mut conn = pool.get().unwrap();
+        let recipients = email_recipients::table
+            .count()
+            .get_result(&mut conn)
+            .unwrap();
+        let events = crate::schema::delivery_events::table
+            .count()
+            .get_result(&mut conn)
+            .unwrap();
+        (recipients, events)
+    }
+
+    #[tokio::test]
+    async fn preview_send_writes_nothing() {
+        let (_dir, pool, repo) = setup_repo();
+        let (email_id, recipient_id) = create_email(&repo);
+        let before = row_counts(&pool);
+
+        let mailer = mailer();
+        send_preview(
+            email_id,
+            1,
+            &preview_recipient(),
+            &repo,
+            &test_config(),
+            &mailer,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(row_counts(&pool), before);
+        let stored = repo
+            .get_email_recipient_by_id(
+                EmailRecipientId::try_from(recipient_id).unwrap(),
+                HubId::try_from(1).unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert!(!stored.is_sent);
+    }
+
+    #[tokio::test]
+    async fn preview_is_rendered_for_the_ad_hoc_recipient() {
+        let (_dir, pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let raw = render_preview(email_id, 1, &preview_recipient(), &repo, &test_config())
+            .await
+            .unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+
+        assert!(raw.contains("To: \"Me\" <me@example.com>"));
+        assert!(raw.contains("Hi Me! Hello"));
+        assert!(raw.contains("Message-ID: <preview."));
+        assert!(!raw.contains("track/"));
+        assert_eq!(row_counts(&pool).0, 1);
+    }
+
     #[tokio::test]
     async fn send_email_updates_recipient_on_success() {
-        let (_dir, pool) = setup_pool();
-        insert_hub(&pool);
-        let repo = DieselRepository::new(pool.clone());
+        let (_dir, _pool, repo) = setup_repo();
         let (email_id, recipient_id) = create_email(&repo);
 
-        let mailer = MockMailer {
-            calls: Arc::new(AtomicUsize::new(0)),
-            fail: false,
-        };
+        let mailer = mailer();
         let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
-        send_email(msg, &repo, "example.com", &mailer)
+        let summary = send_email(msg, &repo, &test_config(), &mailer)
             .await
             .unwrap();
+        assert_eq!(
+            summary,
+            SendSummary {
+                sent: 1,
+                ..Default::default()
+            }
+        );
         assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
 
         let updated = repo
@@ -219,23 +1083,30 @@ mod tests {
             .unwrap()
             .unwrap();
         assert!(updated.is_sent);
+
+        let stats = repo
+            .bounce_stats(
+                HubId::try_from(1).unwrap(),
+                chrono::DateTime::UNIX_EPOCH.naive_utc(),
+            )
+            .unwrap();
+        assert_eq!(stats.sent, 1);
     }
 
     #[tokio::test]
     async fn send_email_skips_update_on_failure() {
-        let (_dir, pool) = setup_pool();
-        insert_hub(&pool);
-        let repo = DieselRepository::new(pool.clone());
+        let (_dir, _pool, repo) = setup_repo();
         let (email_id, recipient_id) = create_email(&repo);
 
-        let mailer = MockMailer {
-            calls: Arc::new(AtomicUsize::new(0)),
-            fail: true,
-        };
+        let mailer = failing_mailer();
         let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
-        send_email(msg, &repo, "example.com", &mailer)
+        let summary = send_email(msg, &repo, &test_config(), &mailer)
             .await
             .unwrap();
+        assert_eq!((summary.sent, summary.failed), (0, 1));
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.errors[0].0.get(), recipient_id);
+        assert!(summary.errors[0].1.contains("fail"), "{:?}", summary.errors);
         assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
 
         let updated = repo
@@ -247,4 +1118,588 @@ mod tests {
             .unwrap();
         assert!(!updated.is_sent);
     }
+
+    #[tokio::test]
+    async fn send_email_skips_hub_with_tripped_breaker() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let hub_id = HubId::try_from(1).unwrap();
+        let config = test_config();
+        for _ in 0..config.bounce_breaker.min_sent {
+            repo.record_delivery_event(hub_id, DeliveryEventKind::Sent)
+                .unwrap();
+            repo.record_delivery_event(hub_id, DeliveryEventKind::Bounce)
+                .unwrap();
+        }
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!(
+            summary,
+            SendSummary {
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_without_hub() {
+        let (_dir, pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+        pool.get()
+            .unwrap()
+            .batch_execute("PRAGMA foreign_keys = OFF; DELETE FROM hubs;")
+            .unwrap();
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let result = send_email(msg, &repo, &test_config(), &mailer).await;
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_skips_paused_hub() {
+        use crate::repository::HubWriter;
+
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, recipient_id) = create_email(&repo);
+        let hub_id = HubId::try_from(1).unwrap();
+        repo.set_sending_paused(hub_id, true).unwrap();
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg.clone(), &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.sent, summary.skipped), (0, 1));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        let recipient = repo
+            .get_email_recipient_by_id(EmailRecipientId::try_from(recipient_id).unwrap(), hub_id)
+            .unwrap()
+            .unwrap();
+        assert!(!recipient.is_sent);
+
+        repo.set_sending_paused(hub_id, false).unwrap();
+        let summary = send_email(msg, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.sent, summary.skipped), (1, 0));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_holds_when_the_pause_flag_cannot_be_read() {
+        let (_dir, pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+        pool.get()
+            .unwrap()
+            .batch_execute("DROP TABLE hub_state;")
+            .unwrap();
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.sent, summary.skipped), (0, 1));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(sent_recipients(&repo, email_id), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_skips_suppressed_recipients() {
+        use crate::repository::SuppressionWriter;
+
+        let (_dir, _pool, repo) = setup_repo();
+        let email_id = create_email_for(
+            &repo,
+            &["global@example.com", "hub@example.com", "ok@example.com"],
+        );
+        repo.suppress_globally("Global@Example.com", Some("do not contact"))
+            .unwrap();
+        repo.unsubscribe_recipient(
+            "hub@example.com",
+            HubId::try_from(1).unwrap(),
+            UnsubscribeSource::Request,
+            None,
+        )
+        .unwrap();
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            SendSummary {
+                sent: 1,
+                suppressed: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(sent_recipients(&repo, email_id), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_defers_recipients_beyond_warmup_cap() {
+        let (_dir, _pool, repo) = setup_repo();
+        let email_id = create_email_for(&repo, &["a@example.com", "b@example.com"]);
+
+        let mailer = mailer();
+        let config = warmup_config(1);
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.sent, summary.skipped), (1, 1));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(sent_recipients(&repo, email_id), 1);
+        let retry = summary.retry.unwrap();
+        assert_eq!((retry.email_id, retry.hub_id), (email_id, 1));
+        assert!(retry.at > Utc::now());
+
+        // The cap is persisted, so a retry on the same day sends nothing.
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.sent, summary.skipped), (0, 2));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_warmup_cap_resets_daily() {
+        let (_dir, pool, repo) = setup_repo();
+        let email_id = create_email_for(&repo, &["a@example.com"]);
+
+        {
+            use crate::models::NewDeliveryEvent;
+            use crate::schema::delivery_events;
+
+            let mut conn = pool.get().unwrap();
+            diesel::insert_into(delivery_events::table)
+                .values(&NewDeliveryEvent {
+                    hub_id: 1,
+                    kind: DeliveryEventKind::Sent.as_str(),
+                    created_at: Utc::now().naive_utc() - chrono::Duration::days(1),
+                    imap_uid: None,
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        send_email(msg, &repo, &warmup_config(1), &mailer)
+            .await
+            .unwrap();
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(sent_recipients(&repo, email_id), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_warmup_cap_holds_across_concurrent_jobs() {
+        let (_dir, _pool, repo) = setup_repo();
+        let first = create_email_for(&repo, &["a@example.com"]);
+        let second = create_email_for(&repo, &["b@example.com"]);
+
+        let config = warmup_config(1);
+        let mailer = ConcurrentMailer::default();
+        let (first, second) = tokio::join!(
+            send_email(
+                ZMQSendEmailMessage::RetryEmail((first, 1)),
+                &repo,
+                &config,
+                &mailer
+            ),
+            send_email(
+                ZMQSendEmailMessage::RetryEmail((second, 1)),
+                &repo,
+                &config,
+                &mailer
+            ),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.sent + second.sent, 1);
+        assert_eq!(first.skipped + second.skipped, 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_warmup_slots_of_failed_sends() {
+        let (_dir, _pool, repo) = setup_repo();
+        let email_id = create_email_for(&repo, &["a@example.com"]);
+        let config = warmup_config(1);
+
+        let failing = failing_mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &failing).await.unwrap();
+        assert_eq!((summary.failed, summary.retry), (1, None));
+
+        let mailer = mailer();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!(summary.sent, 1);
+        assert_eq!(
+            repo.count_delivery_events(
+                HubId::try_from(1).unwrap(),
+                DeliveryEventKind::Sent,
+                Utc::now().date_naive().and_time(NaiveTime::MIN),
+            )
+            .unwrap(),
+            1
+        );
+    }
+
+    fn from_override_request(email_id: i32) -> SendEmailRequest {
+        SendEmailRequest {
+            message: ZMQSendEmailMessage::RetryEmail((email_id, 1)),
+            from_override: Some(FromOverride {
+                address: "Billing@Example.com".into(),
+                name: Some("Billing".into()),
+            }),
+            date: None,
+            raw: None,
+            expires_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_skips_recipients_past_the_deadline() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, recipient_id) = create_email(&repo);
+
+        let mailer = mailer();
+        let request = SendEmailRequest {
+            expires_at: Some(Utc::now() - chrono::Duration::minutes(5)),
+            ..ZMQSendEmailMessage::RetryEmail((email_id, 1)).into()
+        };
+        let summary = send_email(request, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            SendSummary {
+                expired: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        let recipient = repo
+            .get_email_recipient_by_id(
+                EmailRecipientId::try_from(recipient_id).unwrap(),
+                HubId::try_from(1).unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert!(!recipient.is_sent);
+        assert!(repo.is_recipient_rejected(recipient.id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn retry_without_a_deadline_honours_the_stored_one() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+        repo.set_email_deadline(
+            EmailId::try_from(email_id).unwrap(),
+            Utc::now() - chrono::Duration::minutes(5),
+        )
+        .unwrap();
+
+        let mailer = mailer();
+        let retry = || ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(retry(), &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.sent, summary.expired), (0, 1));
+
+        // The expired recipient is rejected, so later jobs skip it.
+        let summary = send_email(retry(), &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.skipped, summary.expired), (1, 0));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_before_the_deadline() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let mailer = mailer();
+        let request = SendEmailRequest {
+            expires_at: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..ZMQSendEmailMessage::RetryEmail((email_id, 1)).into()
+        };
+        let summary = send_email(request, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!((summary.sent, summary.expired), (1, 0));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_accepts_allowed_from_override() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                allowed_from: vec!["billing@example.com".into()],
+                ..Default::default()
+            },
+        );
+        let mailer = mailer();
+        send_email(from_override_request(email_id), &repo, &config, &mailer)
+            .await
+            .unwrap();
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_rejects_unauthorized_from_override() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let mailer = mailer();
+        let result = send_email(
+            from_override_request(email_id),
+            &repo,
+            &test_config(),
+            &mailer,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(sent_recipients(&repo, email_id), 0);
+    }
+
+    /// Mailer that records the highest number of sends in flight at once.
+    #[derive(Default)]
+    struct ConcurrentMailer {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Mailer for ConcurrentMailer {
+        async fn send(&self, _hub: &Hub, _message: MessageBuilder<'_>) -> Result<(), Error> {
+            let current = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_recipients_concurrently() {
+        let (_dir, _pool, repo) = setup_repo();
+        let addresses: Vec<String> = (0..6).map(|i| format!("r{i}@example.com")).collect();
+        let addresses: Vec<&str> = addresses.iter().map(String::as_str).collect();
+        let email_id = create_email_for(&repo, &addresses);
+
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                send_concurrency: Some(4),
+                ..Default::default()
+            },
+        );
+        let mailer = ConcurrentMailer::default();
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!(summary.sent, 6);
+
+        let max_in_flight = mailer.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= 4, "{max_in_flight}");
+        assert_eq!(sent_recipients(&repo, email_id), 6);
+        let email = repo
+            .get_email_by_id(
+                EmailId::try_from(email_id).unwrap(),
+                HubId::try_from(1).unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(email.email.num_sent.get(), 6);
+    }
+
+    /// Mailer that takes a fixed time per send.
+    struct SlowMailer(Duration);
+
+    #[async_trait]
+    impl Mailer for SlowMailer {
+        async fn send(&self, _hub: &Hub, _message: MessageBuilder<'_>) -> Result<(), Error> {
+            tokio::time::sleep(self.0).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_wraps_the_send_call() {
+        let hub = Hub::try_new(
+            1, None, None, None, None, None, None, None, None, None, None, 0,
+        )
+        .unwrap();
+        let mailer = SlowMailer(Duration::from_millis(30));
+
+        let (sent, elapsed) = timed(mailer.send(&hub, MessageBuilder::new())).await;
+        assert!(sent.is_ok());
+        assert!(elapsed >= Duration::from_millis(30), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn send_email_rejects_oversized_messages_for_good() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let mailer = mailer();
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                max_message_bytes: Some(256),
+                ..Default::default()
+            },
+        );
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!(summary.failed, 1);
+        assert!(summary.errors[0].1.contains("256-byte limit"));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(sent_recipients(&repo, email_id), 0);
+
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.failed, summary.skipped), (0, 1));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_rejects_only_recipients_over_the_size_limit() {
+        let (_dir, _pool, repo) = setup_repo();
+        let with_attachment = |address: &str, size: usize| {
+            let mut recipient = recipient(address);
+            RecipientAttachment {
+                content: vec![b'x'; size],
+                name: "report.pdf".into(),
+                mime: "application/pdf".into(),
+            }
+            .write_to_fields(&mut recipient.fields);
+            recipient
+        };
+        let stored = repo
+            .create_email(&new_email(
+                "Hello",
+                vec![
+                    with_attachment("small@example.com", 100),
+                    with_attachment("large@example.com", 8000),
+                ],
+            ))
+            .unwrap();
+        let large_id = stored.recipients[1].id;
+
+        let mailer = mailer();
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                max_message_bytes: Some(4096),
+                ..Default::default()
+            },
+        );
+        let msg = ZMQSendEmailMessage::RetryEmail((stored.email.id.get(), 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.sent, summary.failed), (1, 1));
+        assert_eq!(summary.errors[0].0, large_id);
+        assert!(summary.errors[0].1.contains("4096-byte limit"));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_build_errors_per_recipient() {
+        let (_dir, _pool, repo) = setup_repo();
+        let email_id = create_email_for(&repo, &["a@example.com", "b@example.com"]);
+
+        let mailer = mailer();
+        let mut config = test_config();
+        config.hubs.insert(
+            1,
+            HubSettings {
+                allowed_sender_domains: vec!["elsewhere.org".into()],
+                send_concurrency: Some(2),
+                ..Default::default()
+            },
+        );
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.sent, summary.failed), (0, 2));
+        assert_eq!(summary.errors.len(), 2);
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(sent_recipients(&repo, email_id), 0);
+    }
+
+    #[tokio::test]
+    async fn send_email_requires_sender_auth_in_strict_mode() {
+        let (_dir, _pool, repo) = setup_repo();
+        let (email_id, _) = create_email(&repo);
+
+        let mailer = mailer();
+        let mut config = test_config();
+        config.sender_auth.strict = true;
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let result = send_email(msg, &repo, &config, &mailer).await;
+        assert!(matches!(result, Err(Error::Config(_))));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(sent_recipients(&repo, email_id), 0);
+
+        config.hubs.insert(
+            1,
+            HubSettings {
+                sender_auth: Some(DeclaredSenderAuthConfig {
+                    spf: true,
+                    dkim_selector: Some("mail".into()),
+                    dmarc: false,
+                }),
+                ..Default::default()
+            },
+        );
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_blocks_spammy_email_in_strict_mode() {
+        let (_dir, _pool, repo) = setup_repo();
+        let spammy = NewEmail {
+            subject: Some("YOU ARE A WINNER!!!".try_into().unwrap()),
+            ..new_email(
+                "CLICK HERE for a 100% FREE cash bonus, act now",
+                vec![recipient("to@example.com")],
+            )
+        };
+        let email_id = repo.create_email(&spammy).unwrap().email.id.get();
+
+        let mailer = mailer();
+        let mut config = test_config();
+        config.spam_check.strict = true;
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        let summary = send_email(msg, &repo, &config, &mailer).await.unwrap();
+        assert_eq!((summary.sent, summary.skipped), (0, 1));
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 0);
+
+        // Advisory mode only logs the score and still sends.
+        let msg = ZMQSendEmailMessage::RetryEmail((email_id, 1));
+        send_email(msg, &repo, &test_config(), &mailer)
+            .await
+            .unwrap();
+        assert_eq!(mailer.calls.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,476 @@
+//! SMTP mail transaction with PIPELINING (RFC 2920) and CHUNKING (RFC 3030).
+//!
+//! [`SmtpMailer`](super::SmtpMailer) connects and authenticates through
+//! `mail_send`, then runs the `MAIL`/`RCPT`/`DATA` exchange here so the
+//! commands can be batched when the server advertises `PIPELINING`, and the
+//! message sent with `BDAT` when it advertises `CHUNKING`. Without either
+//! extension the commands go out one at a time, as `mail_send` sends them.
+//! `MAIL FROM` carries the `SIZE`, `BODY` and `SMTPUTF8` parameters the
+//! server advertised.
+
+use std::fmt::Write;
+
+use mail_send::Error as SmtpError;
+use smtp_proto::{
+    EXT_8BIT_MIME, EXT_CHUNKING, EXT_PIPELINING, EXT_SIZE, EXT_SMTP_UTF8, EhloResponse, Response,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::errors::Error;
+
+/// ESMTP extensions the mail transaction can use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SmtpExtensions {
+    /// Send `MAIL`, `RCPT` and `DATA` in one batch.
+    pub pipelining: bool,
+    /// Send the message with `BDAT ... LAST` instead of `DATA`.
+    pub chunking: bool,
+    /// Declare the message size with `SIZE=` (RFC 1870).
+    pub size: bool,
+    /// Declare 8-bit content with `BODY=8BITMIME` (RFC 6152).
+    pub eight_bit_mime: bool,
+    /// Declare non-ASCII addresses with `SMTPUTF8` (RFC 6531).
+    pub smtputf8: bool,
+}
+
+impl SmtpExtensions {
+    /// Reads the extensions from the server's `EHLO` response.
+    pub fn from_ehlo(ehlo: &EhloResponse<String>) -> Self {
+        Self {
+            pipelining: ehlo.has_capability(EXT_PIPELINING),
+            chunking: ehlo.has_capability(EXT_CHUNKING),
+            size: ehlo.has_capability(EXT_SIZE),
+            eight_bit_mime: ehlo.has_capability(EXT_8BIT_MIME),
+            smtputf8: ehlo.has_capability(EXT_SMTP_UTF8),
+        }
+    }
+}
+
+/// Sender and recipients of one mail transaction.
+pub struct Envelope<'a> {
+    pub from: &'a str,
+    pub recipients: Vec<&'a str>,
+}
+
+/// Runs one mail transaction for `body` over an authenticated connection.
+///
+/// With pipelining, a rejected sender or recipient is reported after all
+/// batched replies are read, and the message is not sent: `BDAT` carries the
+/// message, so it waits for those replies, and if the server accepted a
+/// pipelined `DATA` regardless, the caller drops the connection, which aborts
+/// the transaction. A rejected command fails with
+/// [`mail_send::Error::UnexpectedReply`].
+pub async fn send_transaction<S>(
+    stream: &mut S,
+    extensions: SmtpExtensions,
+    envelope: &Envelope<'_>,
+    body: &[u8],
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = BufReader::new(stream);
+    let mut commands = vec![format!(
+        "MAIL FROM:<{}>{}\r\n",
+        envelope.from,
+        mail_parameters(extensions, envelope, body)
+    )];
+    commands.extend(
+        envelope
+            .recipients
+            .iter()
+            .map(|recipient| format!("RCPT TO:<{recipient}>\r\n")),
+    );
+
+    if extensions.pipelining {
+        // `DATA` may end the batch (RFC 2920); `BDAT` is followed by the
+        // message itself, so it is sent only once the recipients are known.
+        let mut batch = commands.concat();
+        if !extensions.chunking {
+            batch.push_str(&data_command(extensions, body));
+        }
+        conn.write_all(batch.as_bytes()).await?;
+        conn.flush().await?;
+
+        let mut rejected = None;
+        for _ in &commands {
+            let reply = read_reply(&mut conn).await?;
+            if !is_completed(&reply) && rejected.is_none() {
+                rejected = Some(reply);
+            }
+        }
+        let data_reply = match extensions.chunking {
+            true => None,
+            false => Some(read_reply(&mut conn).await?),
+        };
+        if let Some(reply) = rejected {
+            return Err(SmtpError::UnexpectedReply(reply).into());
+        }
+        if let Some(data_reply) = data_reply {
+            return finish(&mut conn, extensions, data_reply, body).await;
+        }
+    } else {
+        for command in &commands {
+            conn.write_all(command.as_bytes()).await?;
+            conn.flush().await?;
+            let reply = read_reply(&mut conn).await?;
+            if !is_completed(&reply) {
+                return Err(SmtpError::UnexpectedReply(reply).into());
+            }
+        }
+    }
+
+    conn.write_all(data_command(extensions, body).as_bytes())
+        .await?;
+    if extensions.chunking {
+        conn.write_all(body).await?;
+    }
+    conn.flush().await?;
+    let data_reply = read_reply(&mut conn).await?;
+    finish(&mut conn, extensions, data_reply, body).await
+}
+
+/// `MAIL FROM` parameters for the extensions the server advertised: the
+/// message `SIZE`, `BODY=8BITMIME` for 8-bit content and `SMTPUTF8` for
+/// non-ASCII addresses. Each starts with a space.
+fn mail_parameters(extensions: SmtpExtensions, envelope: &Envelope<'_>, body: &[u8]) -> String {
+    let mut parameters = String::new();
+    if extensions.size {
+        // Formatting into a `String` cannot fail.
+        let _ = write!(parameters, " SIZE={}", body.len());
+    }
+    if extensions.eight_bit_mime && !body.is_ascii() {
+        parameters.push_str(" BODY=8BITMIME");
+    }
+    let utf8_address = std::iter::once(envelope.from)
+        .chain(envelope.recipients.iter().copied())
+        .any(|address| !address.is_ascii());
+    if extensions.smtputf8 && utf8_address {
+        parameters.push_str(" SMTPUTF8");
+    }
+    parameters
+}
+
+/// `BDAT {len} LAST` with chunking, else `DATA`.
+fn data_command(extensions: SmtpExtensions, body: &[u8]) -> String {
+    match extensions.chunking {
+        true => format!("BDAT {} LAST\r\n", body.len()),
+        false => "DATA\r\n".to_string(),
+    }
+}
+
+/// Checks the reply to `DATA`/`BDAT`; after `354` the dot-stuffed message is
+/// sent and its reply checked.
+async fn finish<S>(
+    conn: &mut BufReader<S>,
+    extensions: SmtpExtensions,
+    reply: Response<String>,
+    body: &[u8],
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if extensions.chunking {
+        return match is_completed(&reply) {
+            true => Ok(()),
+            false => Err(SmtpError::UnexpectedReply(reply).into()),
+        };
+    }
+    if reply.code != 354 {
+        return Err(SmtpError::UnexpectedReply(reply).into());
+    }
+    conn.write_all(&dot_stuff(body)).await?;
+    conn.flush().await?;
+    let reply = read_reply(conn).await?;
+    match is_completed(&reply) {
+        true => Ok(()),
+        false => Err(SmtpError::UnexpectedReply(reply).into()),
+    }
+}
+
+fn is_completed(reply: &Response<String>) -> bool {
+    (200..300).contains(&reply.code)
+}
+
+/// Reads one, possibly multi-line, SMTP reply.
+async fn read_reply<R>(conn: &mut R) -> Result<Response<String>, Error>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut message = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        if conn.read_until(b'\n', &mut line).await? == 0 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or(SmtpError::UnparseableReply)?;
+        message.push(line.get(4..).unwrap_or_default().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(Response {
+                code,
+                esc: [0, 0, 0],
+                message: message.join("\n"),
+            });
+        }
+    }
+}
+
+/// Escapes lines starting with `.` and appends the `DATA` terminator.
+fn dot_stuff(body: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(body.len() + 5);
+    let mut line_start = true;
+    for &byte in body {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !stuffed.ends_with(b"\r\n") {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed.extend_from_slice(b".\r\n");
+    stuffed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::time::timeout;
+
+    /// Minimal SMTP server recording the exchange as `C:`/`S:` lines.
+    ///
+    /// Replies are held back while more commands arrive within 50 ms, so a
+    /// pipelined batch shows up before its replies in the log.
+    async fn serve(stream: DuplexStream, reject_recipients: bool) -> Vec<String> {
+        let mut conn = BufReader::new(stream);
+        let mut log = Vec::new();
+        let mut replies: Vec<&str> = Vec::new();
+        loop {
+            let mut line = String::new();
+            let read = if replies.is_empty() {
+                conn.read_line(&mut line).await.unwrap()
+            } else {
+                match timeout(Duration::from_millis(50), conn.read_line(&mut line)).await {
+                    Ok(read) => read.unwrap(),
+                    Err(_) => {
+                        flush(&mut conn, &mut replies, &mut log).await;
+                        continue;
+                    }
+                }
+            };
+            if read == 0 {
+                return log;
+            }
+
+            let command = line.trim_end().to_string();
+            log.push(format!("C: {command}"));
+            if command.starts_with("RCPT") && reject_recipients {
+                replies.push("550 5.1.1 No such user");
+            } else if command == "DATA" && reject_recipients {
+                replies.push("554 5.5.1 No valid recipients");
+            } else if command == "DATA" {
+                replies.push("354 Go ahead");
+                flush(&mut conn, &mut replies, &mut log).await;
+                loop {
+                    let mut line = String::new();
+                    conn.read_line(&mut line).await.unwrap();
+                    if line == ".\r\n" {
+                        break;
+                    }
+                }
+                log.push("C: <message>".into());
+                replies.push("250 2.0.0 Queued");
+            } else if let Some(args) = command.strip_prefix("BDAT ") {
+                let len: usize = args.split(' ').next().unwrap().parse().unwrap();
+                let mut chunk = vec![0; len];
+                conn.read_exact(&mut chunk).await.unwrap();
+                log.push(format!("C: <{len} bytes>"));
+                replies.push("250 2.0.0 Queued");
+            } else {
+                replies.push("250 2.1.0 OK");
+            }
+        }
+    }
+
+    async fn flush(
+        conn: &mut BufReader<DuplexStream>,
+        replies: &mut Vec<&str>,
+        log: &mut Vec<String>,
+    ) {
+        for reply in replies.drain(..) {
+            conn.write_all(format!("{reply}\r\n").as_bytes())
+                .await
+                .unwrap();
+            log.push(format!("S: {}", &reply[..3]));
+        }
+        conn.flush().await.unwrap();
+    }
+
+    const BODY: &[u8] = b"Subject: Hi\r\n\r\nHello\r\n";
+
+    async fn exchange(
+        extensions: SmtpExtensions,
+        reject_recipients: bool,
+    ) -> (Result<(), Error>, Vec<String>) {
+        exchange_with(extensions, reject_recipients, "alice@example.org", BODY).await
+    }
+
+    async fn exchange_with(
+        extensions: SmtpExtensions,
+        reject_recipients: bool,
+        recipient: &str,
+        body: &[u8],
+    ) -> (Result<(), Error>, Vec<String>) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(serve(server, reject_recipients));
+        let envelope = Envelope {
+            from: "sender@example.com",
+            recipients: vec![recipient],
+        };
+        let result = send_transaction(&mut client, extensions, &envelope, body).await;
+        drop(client);
+        (result, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn classic_sequence_waits_for_each_reply() {
+        let (result, log) = exchange(SmtpExtensions::default(), false).await;
+        result.unwrap();
+        assert_eq!(
+            log,
+            [
+                "C: MAIL FROM:<sender@example.com>",
+                "S: 250",
+                "C: RCPT TO:<alice@example.org>",
+                "S: 250",
+                "C: DATA",
+                "S: 354",
+                "C: <message>",
+                "S: 250",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelining_batches_commands_up_to_data() {
+        let extensions = SmtpExtensions {
+            pipelining: true,
+            ..Default::default()
+        };
+        let (result, log) = exchange(extensions, false).await;
+        result.unwrap();
+        assert_eq!(
+            log,
+            [
+                "C: MAIL FROM:<sender@example.com>",
+                "C: RCPT TO:<alice@example.org>",
+                "C: DATA",
+                "S: 250",
+                "S: 250",
+                "S: 354",
+                "C: <message>",
+                "S: 250",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn chunking_sends_the_message_with_bdat_after_the_recipients() {
+        let len = BODY.len();
+        let extensions = SmtpExtensions {
+            pipelining: true,
+            chunking: true,
+            ..Default::default()
+        };
+        let (result, log) = exchange(extensions, false).await;
+        result.unwrap();
+        assert_eq!(
+            log,
+            [
+                "C: MAIL FROM:<sender@example.com>".to_string(),
+                "C: RCPT TO:<alice@example.org>".to_string(),
+                "S: 250".to_string(),
+                "S: 250".to_string(),
+                format!("C: BDAT {len} LAST"),
+                format!("C: <{len} bytes>"),
+                "S: 250".to_string(),
+            ]
+        );
+
+        let (result, log) = exchange(
+            SmtpExtensions {
+                chunking: true,
+                ..Default::default()
+            },
+            false,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(log[1], "S: 250");
+        assert_eq!(log[4], format!("C: BDAT {len} LAST"));
+    }
+
+    #[tokio::test]
+    async fn pipelined_rejection_sends_no_message() {
+        for chunking in [false, true] {
+            let extensions = SmtpExtensions {
+                pipelining: true,
+                chunking,
+                ..Default::default()
+            };
+            let (result, log) = exchange(extensions, true).await;
+            assert!(matches!(
+                result,
+                Err(Error::Smtp(SmtpError::UnexpectedReply(ref reply))) if reply.code == 550
+            ));
+            assert!(!log.contains(&"C: <message>".to_string()));
+            assert!(!log.iter().any(|line| line.starts_with("C: BDAT")));
+        }
+    }
+
+    #[tokio::test]
+    async fn mail_from_carries_advertised_parameters() {
+        let advertised = SmtpExtensions {
+            size: true,
+            eight_bit_mime: true,
+            smtputf8: true,
+            ..Default::default()
+        };
+        let (result, log) = exchange(advertised, false).await;
+        result.unwrap();
+        assert_eq!(
+            log[0],
+            format!("C: MAIL FROM:<sender@example.com> SIZE={}", BODY.len())
+        );
+
+        let body = "Subject: Hi\r\n\r\nЗдравствуйте\r\n".as_bytes();
+        let (result, log) = exchange_with(advertised, false, "алиса@example.org", body).await;
+        result.unwrap();
+        assert_eq!(
+            log[0],
+            format!(
+                "C: MAIL FROM:<sender@example.com> SIZE={} BODY=8BITMIME SMTPUTF8",
+                body.len()
+            )
+        );
+
+        let (result, log) =
+            exchange_with(SmtpExtensions::default(), false, "алиса@example.org", body).await;
+        result.unwrap();
+        assert_eq!(log[0], "C: MAIL FROM:<sender@example.com>");
+    }
+
+    #[test]
+    fn dot_stuffing_escapes_leading_dots() {
+        assert_eq!(dot_stuff(b"a\r\n.b\r\n..c"), b"a\r\n..b\r\n...c\r\n.\r\n");
+        assert_eq!(dot_stuff(b".\r\n"), b"..\r\n.\r\n");
+    }
+}
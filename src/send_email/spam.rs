@@ -0,0 +1,125 @@
+//! Heuristic spam scoring for rendered messages.
+//!
+//! The checks are a small subset of what content filters such as
+//! SpamAssassin look at: link density, shouting, and well-known spam phrases.
+//! Scores are additive; higher means more likely to be filtered.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static LINK_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)https?://").unwrap());
+
+/// Phrases commonly associated with unsolicited mail, matched case-insensitively.
+const SPAM_PHRASES: &[&str] = &[
+    "100% free",
+    "act now",
+    "buy now",
+    "cash bonus",
+    "click here",
+    "congratulations",
+    "guaranteed",
+    "limited time",
+    "no credit check",
+    "risk-free",
+    "winner",
+    "бесплатно",
+    "выигрыш",
+    "срочно",
+];
+
+/// Links allowed before each extra link adds to the score.
+const FREE_LINKS: usize = 5;
+
+/// Result of scoring a message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpamReport {
+    pub score: f64,
+    /// Human-readable names of the checks that contributed to the score.
+    pub reasons: Vec<String>,
+}
+
+impl SpamReport {
+    fn add(&mut self, points: f64, reason: impl Into<String>) {
+        self.score += points;
+        self.reasons.push(reason.into());
+    }
+}
+
+fn is_shouting(text: &str, min_letters: usize) -> bool {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.len() < min_letters {
+        return false;
+    }
+    let upper = letters.iter().filter(|c| c.is_uppercase()).count();
+    upper * 10 >= letters.len() * 7
+}
+
+/// Scores a rendered subject and body.
+pub fn score_message(subject: &str, body: &str) -> SpamReport {
+    let mut report = SpamReport::default();
+
+    if subject.trim().is_empty() {
+        report.add(1.0, "empty subject");
+    } else if is_shouting(subject, 5) {
+        report.add(2.0, "all-caps subject");
+    }
+
+    if subject.matches('!').count() > 1 {
+        report.add(1.0, "repeated exclamation marks in subject");
+    }
+
+    if is_shouting(body, 40) {
+        report.add(1.5, "mostly upper-case body");
+    }
+
+    let links = LINK_RE.find_iter(body).count();
+    if links > FREE_LINKS {
+        report.add(
+            0.5 * (links - FREE_LINKS) as f64,
+            format!("{links} links in body"),
+        );
+    }
+
+    let haystack = format!("{subject}\n{body}").to_lowercase();
+    for phrase in SPAM_PHRASES {
+        if haystack.contains(phrase) {
+            report.add(1.0, format!("spam phrase \"{phrase}\""));
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spammy_message_scores_higher_than_clean_one() {
+        let clean = score_message(
+            "Meeting notes for Tuesday",
+            "Hi Alice, attached are the notes from our call. See https://example.com/notes.",
+        );
+        let links = "https://a.example/ ".repeat(8);
+        let spammy = score_message(
+            "YOU ARE A WINNER!!!",
+            &format!("CLICK HERE to claim your 100% FREE cash bonus. Act now! {links}"),
+        );
+
+        assert_eq!(clean.score, 0.0);
+        assert!(spammy.score > clean.score);
+        assert!(spammy.score >= 5.0, "{spammy:?}");
+        assert!(
+            spammy
+                .reasons
+                .iter()
+                .any(|reason| reason == "all-caps subject")
+        );
+    }
+
+    #[test]
+    fn penalises_empty_subject() {
+        let report = score_message("", "Hello");
+        assert_eq!(report.score, 1.0);
+    }
+}
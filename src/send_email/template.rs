@@ -0,0 +1,464 @@
+//! File-based hub templates and template partials.
+//!
+//! A hub template can live on disk instead of in the database
+//! `email_template` column. Loaded files are kept in a small LRU cache
+//! keyed by hub, path and file version (modification time and size), so a
+//! send job only stats the file; edits and `template_path` changes take
+//! effect with the next job without a restart or a database write.
+//!
+//! Templates may include shared blocks such as a header or footer with
+//! `{>name}`; the blocks are kept once in `template_partials` and expanded
+//! by [`hub_template`] before placeholders are filled.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use pushkind_emailer::domain::hub::Hub;
+use pushkind_emailer::domain::types::HubId;
+
+use crate::errors::Error;
+use crate::models::ServerConfig;
+use crate::regexes::PARTIAL;
+
+/// Number of hub templates kept by the shared cache.
+const TEMPLATE_CACHE_CAPACITY: usize = 64;
+
+/// Deepest chain of nested `{>name}` includes that is expanded.
+pub const MAX_PARTIAL_DEPTH: usize = 8;
+
+static TEMPLATES: Lazy<TemplateCache> = Lazy::new(|| TemplateCache::new(TEMPLATE_CACHE_CAPACITY));
+
+/// Returns the template file for the hub: its `template_path` setting, else
+/// `{template_dir}/{hub_id}.html` when a template directory is configured.
+pub fn template_path(config: &ServerConfig, hub_id: HubId) -> Option<PathBuf> {
+    config.hub_settings(hub_id).template_path.or_else(|| {
+        config
+            .template_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.html", hub_id.get())))
+    })
+}
+
+/// Reads the hub's template file through the shared [`TemplateCache`].
+///
+/// Returns `None` when no file is configured or it cannot be read, in which
+/// case the database template is used. A file missing from `template_dir`
+/// is expected; any other read failure is logged.
+pub async fn load_template(config: &ServerConfig, hub_id: HubId) -> Option<Arc<str>> {
+    TEMPLATES.load(config, hub_id).await
+}
+
+/// Returns the template the hub's mail is rendered with: its template file
+/// (see [`load_template`]), else the stored `email_template`, with `{>name}`
+/// includes expanded from `template_partials`.
+///
+/// Returns `None` when the hub has neither, so only the message is sent.
+pub async fn hub_template(config: &ServerConfig, hub: &Hub) -> Option<Arc<str>> {
+    let template = match load_template(config, hub.id).await {
+        Some(template) => template,
+        None => hub.email_template.as_ref()?.as_str().into(),
+    };
+    match expand_partials(&template, &config.template_partials) {
+        Cow::Borrowed(_) => Some(template),
+        Cow::Owned(expanded) => Some(expanded.into()),
+    }
+}
+
+/// Replaces `{>name}` includes in `template` with the named partials, which
+/// may include further partials.
+///
+/// Unknown partials are left intact, as are includes nested deeper than
+/// [`MAX_PARTIAL_DEPTH`], e.g. in a partial that includes itself; the latter
+/// are logged.
+pub fn expand_partials<'t>(template: &'t str, partials: &HashMap<String, String>) -> Cow<'t, str> {
+    if partials.is_empty() {
+        return Cow::Borrowed(template);
+    }
+    let mut too_deep = None;
+    let expanded = expand(template, partials, 0, &mut too_deep);
+    if let Some(name) = too_deep {
+        log::warn!(
+            "Template partial {{>{name}}} is nested deeper than {MAX_PARTIAL_DEPTH} levels; left unexpanded"
+        );
+    }
+    expanded
+}
+
+/// Checks that every partial expands within [`MAX_PARTIAL_DEPTH`] levels,
+/// so a partial including itself is reported at startup.
+pub fn check_partials(partials: &HashMap<String, String>) -> Result<(), Error> {
+    for name in partials.keys() {
+        let mut too_deep = None;
+        expand(&format!("{{>{name}}}"), partials, 0, &mut too_deep);
+        if too_deep.is_some() {
+            return Err(Error::Config(format!(
+                "template partial {name} includes itself or is nested deeper than {MAX_PARTIAL_DEPTH} levels"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Expands the includes of `template`, which sits `depth` includes deep,
+/// recording the first partial left unexpanded at the depth limit.
+fn expand<'t>(
+    template: &'t str,
+    partials: &HashMap<String, String>,
+    depth: usize,
+    too_deep: &mut Option<String>,
+) -> Cow<'t, str> {
+    PARTIAL.replace_all(template, |caps: &regex::Captures| {
+        let Some(partial) = partials.get(&caps[1]) else {
+            return caps[0].to_string();
+        };
+        if depth == MAX_PARTIAL_DEPTH {
+            too_deep.get_or_insert_with(|| caps[1].to_string());
+            return caps[0].to_string();
+        }
+        expand(partial, partials, depth + 1, too_deep).into_owned()
+    })
+}
+
+/// Contents version of a template file, compared without reading it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TemplateVersion {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl TemplateVersion {
+    fn of(metadata: &Metadata) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        }
+    }
+}
+
+struct CachedTemplate {
+    hub_id: i32,
+    path: PathBuf,
+    version: TemplateVersion,
+    template: Arc<str>,
+}
+
+/// Least-recently-used cache of hub template files.
+pub struct TemplateCache {
+    capacity: usize,
+    /// Most recently used entry last.
+    entries: Mutex<VecDeque<CachedTemplate>>,
+}
+
+impl TemplateCache {
+    /// Creates an empty cache holding at most `capacity` templates; `0`
+    /// reads the file every time.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the hub's template file, reading it only when it is not
+    /// cached or changed since it was cached. See [`load_template`].
+    pub async fn load(&self, config: &ServerConfig, hub_id: HubId) -> Option<Arc<str>> {
+        let path = template_path(config, hub_id)?;
+        let version = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => TemplateVersion::of(&metadata),
+            Err(e) => return self.unreadable(config, hub_id, &path, e),
+        };
+        if let Some(template) = self.get(hub_id, &path, version) {
+            return Some(template);
+        }
+
+        match tokio::fs::read_to_string(&path).await {
+            Ok(template) => {
+                let template: Arc<str> = template.into();
+                self.insert(CachedTemplate {
+                    hub_id: hub_id.get(),
+                    path,
+                    version,
+                    template: Arc::clone(&template),
+                });
+                Some(template)
+            }
+            Err(e) => self.unreadable(config, hub_id, &path, e),
+        }
+    }
+
+    fn get(&self, hub_id: HubId, path: &Path, version: TemplateVersion) -> Option<Arc<str>> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let pos = entries
+            .iter()
+            .position(|entry| entry.hub_id == hub_id.get())?;
+        let entry = entries.remove(pos)?;
+        if entry.path != path || entry.version != version {
+            return None;
+        }
+        let template = Arc::clone(&entry.template);
+        entries.push_back(entry);
+        Some(template)
+    }
+
+    fn insert(&self, entry: CachedTemplate) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.retain(|cached| cached.hub_id != entry.hub_id);
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Drops the hub's cached template and logs why the file is not used.
+    fn unreadable(
+        &self,
+        config: &ServerConfig,
+        hub_id: HubId,
+        path: &Path,
+        e: std::io::Error,
+    ) -> Option<Arc<str>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|cached| cached.hub_id != hub_id.get());
+        if e.kind() == ErrorKind::NotFound && config.hub_settings(hub_id).template_path.is_none() {
+            log::debug!("No template file {} for hub#{hub_id}", path.display());
+        } else {
+            log::warn!(
+                "Cannot read template file {} for hub#{hub_id}, using the stored template: {e}",
+                path.display()
+            );
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::HubSettings;
+
+    fn hub_id() -> HubId {
+        HubId::try_from(3).unwrap()
+    }
+
+    #[test]
+    fn hub_path_takes_precedence_over_directory() {
+        let mut config = ServerConfig {
+            template_dir: Some(PathBuf::from("/etc/hedwig/templates")),
+            ..Default::default()
+        };
+        assert_eq!(
+            template_path(&config, hub_id()),
+            Some(PathBuf::from("/etc/hedwig/templates/3.html"))
+        );
+
+        config.hubs.insert(
+            3,
+            HubSettings {
+                template_path: Some(PathBuf::from("/srv/promo.html")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            template_path(&config, hub_id()),
+            Some(PathBuf::from("/srv/promo.html"))
+        );
+        assert_eq!(template_path(&ServerConfig::default(), hub_id()), None);
+    }
+
+    #[tokio::test]
+    async fn loads_template_file_and_falls_back_when_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            template_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(load_template(&config, hub_id()).await, None);
+
+        std::fs::write(dir.path().join("3.html"), "<p>{message}</p>").unwrap();
+        assert_eq!(
+            load_template(&config, hub_id()).await.as_deref(),
+            Some("<p>{message}</p>")
+        );
+    }
+
+    /// Overwrites `path` with `contents` and sets its modification time.
+    fn write(path: &Path, contents: &str, modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn dir_config(dir: &Path) -> ServerConfig {
+        ServerConfig {
+            template_dir: Some(dir.to_path_buf()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_serves_unchanged_files_and_rereads_changed_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir_config(dir.path());
+        let path = dir.path().join("3.html");
+        let cache = TemplateCache::new(4);
+        let then = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+        write(&path, "<p>one {message}</p>", then);
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("<p>one {message}</p>")
+        );
+
+        // Same size and modification time: served from the cache.
+        write(&path, "<p>two {message}</p>", then);
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("<p>one {message}</p>")
+        );
+
+        // A newer modification time invalidates the entry.
+        write(
+            &path,
+            "<p>two {message}</p>",
+            then + std::time::Duration::from_secs(1),
+        );
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("<p>two {message}</p>")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cache.load(&config, hub_id()).await, None);
+    }
+
+    #[tokio::test]
+    async fn cache_evicts_the_least_recently_used_hub() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir_config(dir.path());
+        let then = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let cache = TemplateCache::new(1);
+        let other = HubId::try_from(4).unwrap();
+
+        write(&dir.path().join("3.html"), "three", then);
+        write(&dir.path().join("4.html"), "four", then);
+        cache.load(&config, hub_id()).await;
+        cache.load(&config, other).await;
+
+        // Hub 4 is still cached, so its edit with an unchanged version is
+        // not seen; hub 3 was evicted and is read again.
+        write(&dir.path().join("3.html"), "THREE", then);
+        write(&dir.path().join("4.html"), "FOUR", then);
+        assert_eq!(cache.load(&config, other).await.as_deref(), Some("four"));
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("THREE")
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_template_path_misses_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let then = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        write(&dir.path().join("3.html"), "from dir", then);
+        write(&dir.path().join("promo.html"), "promo", then);
+        let cache = TemplateCache::new(4);
+
+        let mut config = dir_config(dir.path());
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("from dir")
+        );
+
+        config.hubs.insert(
+            3,
+            HubSettings {
+                template_path: Some(dir.path().join("promo.html")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            cache.load(&config, hub_id()).await.as_deref(),
+            Some("promo")
+        );
+    }
+
+    fn partials(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, body)| (name.to_string(), body.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn includes_expand_partials_and_nested_partials() {
+        let partials = partials(&[
+            ("header", "<h1>{>logo} Hello {name}</h1>"),
+            ("logo", "<img src=\"logo.png\">"),
+            ("footer", "<p>Unsubscribe: {unsubscribe_url}</p>"),
+        ]);
+        assert_eq!(
+            expand_partials("{>header}{message}{>footer}{>missing}", &partials),
+            "<h1><img src=\"logo.png\"> Hello {name}</h1>{message}\
+             <p>Unsubscribe: {unsubscribe_url}</p>{>missing}"
+        );
+        assert!(matches!(
+            expand_partials("{message}", &partials),
+            Cow::Borrowed("{message}")
+        ));
+    }
+
+    #[test]
+    fn recursive_includes_stop_at_the_depth_limit() {
+        let looping = partials(&[("a", "a{>b}"), ("b", "b{>a}")]);
+        assert_eq!(expand_partials("{>a}", &looping), "abababab{>a}");
+        assert!(check_partials(&looping).is_err());
+
+        let itself = partials(&[("me", "{>me}")]);
+        assert_eq!(expand_partials("x{>me}", &itself), "x{>me}");
+        assert!(check_partials(&itself).is_err());
+
+        assert!(check_partials(&partials(&[("a", "{>b}"), ("b", "b")])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn hub_template_expands_the_stored_template() {
+        let hub = Hub::try_new(
+            3,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("{>header}{message}".to_string()),
+            0,
+        )
+        .unwrap();
+        let config = ServerConfig {
+            template_partials: partials(&[("header", "Hi {name}! ")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            hub_template(&config, &hub).await.as_deref(),
+            Some("Hi {name}! {message}")
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! One-click unsubscribe processing.
+//!
+//! Outbound mail advertises an RFC 8058 one-click link built by
+//! [`crate::domain::one_click_unsubscribe_url`]. The web endpoint serving
+//! that link hands the token from the URL to
+//! [`process_one_click_unsubscribe`], which records the unsubscribe.
+//!
+//! The token is `{recipient_id}.{signature}`, an HMAC-SHA256 over the
+//! recipient and hub IDs keyed with `unsubscribe_secret`, so links cannot
+//! be forged for other recipients. Without the secret no link is advertised
+//! and every token is rejected.
+
+use aws_lc_rs::hmac;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use pushkind_emailer::domain::types::{EmailRecipientId, HubId};
+
+use crate::address::AddressNormalizer;
+use crate::domain::UnsubscribeSource;
+use crate::errors::Error;
+use crate::models::ServerConfig;
+use crate::repository::{EmailReader, EmailWriter};
+
+/// Reason stored with unsubscribes received through the one-click link.
+pub const ONE_CLICK_REASON: &str = "one-click unsubscribe";
+
+/// HMAC key signing one-click unsubscribe tokens.
+pub struct UnsubscribeKey(hmac::Key);
+
+impl UnsubscribeKey {
+    /// Creates a key from the configured secret, which must not be empty.
+    pub fn new(secret: &str) -> Result<Self, Error> {
+        if secret.is_empty() {
+            return Err(Error::Config("unsubscribe_secret must not be empty".into()));
+        }
+        Ok(Self(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())))
+    }
+
+    /// Returns the signed token `{recipient_id}.{signature}`.
+    pub fn sign(&self, recipient_id: i32, hub_id: i32) -> String {
+        let tag = hmac::sign(&self.0, &signed_payload(recipient_id, hub_id));
+        format!("{recipient_id}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    /// Returns the recipient ID of a token signed for `hub_id`, or `None`
+    /// when the token is malformed or its signature does not match.
+    pub fn verify(&self, token: &str, hub_id: i32) -> Option<i32> {
+        let (id, signature) = token.trim().split_once('.')?;
+        let recipient_id: i32 = id.parse().ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.0, &signed_payload(recipient_id, hub_id), &signature).ok()?;
+        Some(recipient_id)
+    }
+}
+
+/// Bytes covered by the token signature.
+fn signed_payload(recipient_id: i32, hub_id: i32) -> Vec<u8> {
+    format!("{recipient_id}:{hub_id}").into_bytes()
+}
+
+/// Resolves the recipient a one-click token signed for the hub refers to.
+fn recipient_from_token(
+    key: &UnsubscribeKey,
+    token: &str,
+    hub_id: HubId,
+) -> Option<EmailRecipientId> {
+    let id = key.verify(token, hub_id.get())?;
+    EmailRecipientId::try_from(id).ok()
+}
+
+/// Records an unsubscribe for the recipient identified by a one-click token.
+///
+/// Returns the unsubscribed address, or `None` when no `unsubscribe_secret`
+/// is configured, the token is malformed or carries an invalid signature,
+/// or it no longer refers to a recipient of the hub (e.g. its email was
+/// purged). Repeated requests for the same recipient are idempotent.
+pub fn process_one_click_unsubscribe(
+    repo: &(impl EmailReader + EmailWriter + ?Sized),
+    token: &str,
+    hub_id: HubId,
+    config: &ServerConfig,
+) -> Result<Option<String>, Error> {
+    let Some(key) = config.unsubscribe_key()? else {
+        log::warn!(
+            "Rejected one-click unsubscribe for hub#{hub_id}: no unsubscribe_secret is configured"
+        );
+        return Ok(None);
+    };
+    let Some(recipient_id) = recipient_from_token(&key, token, hub_id) else {
+        log::warn!("Rejected invalid one-click unsubscribe token for hub#{hub_id}");
+        return Ok(None);
+    };
+    let Some(recipient) = repo.get_email_recipient_by_id(recipient_id, hub_id)? else {
+        log::warn!(
+            "Rejected one-click unsubscribe for unknown recipient#{} in hub#{hub_id}",
+            recipient_id.get()
+        );
+        return Ok(None);
+    };
+
+    let email = config.suppression.normalize(recipient.address.as_str());
+    repo.unsubscribe_recipient(
+        &email,
+        hub_id,
+        UnsubscribeSource::OneClick,
+        Some(ONE_CLICK_REASON),
+    )?;
+    log::info!("Persisted one-click unsubscribe for {email} in hub#{hub_id}");
+    Ok(Some(email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> UnsubscribeKey {
+        UnsubscribeKey::new("test-secret").unwrap()
+    }
+
+    #[test]
+    fn signed_token_round_trips() {
+        let token = key().sign(42, 1);
+        assert!(token.starts_with("42."));
+        assert_eq!(key().verify(&token, 1), Some(42));
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let token = key().sign(42, 1);
+        let (_, signature) = token.split_once('.').unwrap();
+
+        assert_eq!(key().verify(&format!("43.{signature}"), 1), None);
+        assert_eq!(key().verify(&token, 2), None);
+        assert_eq!(key().verify(&format!("{token}A"), 1), None);
+        assert_eq!(key().verify("42", 1), None);
+        assert_eq!(key().verify("42.not-base64!", 1), None);
+
+        let other = UnsubscribeKey::new("other-secret").unwrap();
+        assert_eq!(other.verify(&token, 1), None);
+    }
+
+    #[test]
+    fn rejects_empty_secret() {
+        assert!(matches!(UnsubscribeKey::new(""), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn rejects_bare_recipient_ids() {
+        let hub_id = HubId::try_from(1).unwrap();
+        assert!(recipient_from_token(&key(), "42", hub_id).is_none());
+        let token = key().sign(42, 1);
+        assert_eq!(
+            recipient_from_token(&key(), &token, hub_id).map(|id| id.get()),
+            Some(42)
+        );
+    }
+}
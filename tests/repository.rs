@@ -2,16 +2,28 @@ mod common;
 
 use std::collections::BTreeMap;
 
-use diesel::{RunQueryDsl, connection::SimpleConnection};
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, connection::SimpleConnection};
 use pushkind_common::db::DbPool;
+use pushkind_common::repository::errors::RepositoryError;
 use pushkind_emailer::domain::email::{NewEmail, NewEmailRecipient};
 use pushkind_emailer::domain::types::{
-    EmailBody, EmailId, EmailRecipientId, EmailRecipientReply, HubId, RecipientEmail, RecipientName,
+    EmailBody, EmailId, EmailRecipientId, EmailRecipientReply, HubId, ImapUid, RecipientEmail,
+    RecipientName,
 };
 use pushkind_emailer::models::hub::NewHub as DbNewHub;
-use pushkind_emailer::schema::hubs;
-use pushkind_hedwig::domain::UpdateEmailRecipient;
-use pushkind_hedwig::repository::{DieselRepository, EmailReader, EmailWriter, HubReader};
+use pushkind_emailer::schema::{hubs, unsubscribes};
+use pushkind_hedwig::domain::{
+    DeliveryEventKind, Pagination, UnsubscribeSource, UpdateEmailRecipient, normalize_address,
+};
+use pushkind_hedwig::models::{ServerConfig, SuppressionConfig};
+use pushkind_hedwig::repository::{
+    DeliveryReader, DeliveryWriter, DieselRepository, EmailReader, EmailWriter, HubReader,
+    HubWriter, SuppressionReader, SuppressionWriter,
+};
+use pushkind_hedwig::unsubscribe::{
+    ONE_CLICK_REASON, UnsubscribeKey, process_one_click_unsubscribe,
+};
 use tempfile::TempDir;
 
 fn create_schema(pool: &DbPool) {
@@ -19,9 +31,11 @@ fn create_schema(pool: &DbPool) {
     conn.batch_execute(
         "CREATE TABLE hubs (id INTEGER PRIMARY KEY, login TEXT, password TEXT, sender TEXT, smtp_server TEXT, smtp_port INTEGER, created_at TIMESTAMP, updated_at TIMESTAMP, imap_server TEXT, imap_port INTEGER, email_template TEXT, imap_last_uid INTEGER NOT NULL DEFAULT 0);\n\
          CREATE TABLE emails (id INTEGER PRIMARY KEY, message TEXT NOT NULL, created_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, subject TEXT, attachment BLOB, attachment_name TEXT, attachment_mime TEXT, num_sent INTEGER NOT NULL DEFAULT 0, num_opened INTEGER NOT NULL DEFAULT 0, num_replied INTEGER NOT NULL DEFAULT 0, hub_id INTEGER NOT NULL REFERENCES hubs(id));\n\
-         CREATE TABLE email_recipients (id INTEGER PRIMARY KEY, email_id INTEGER NOT NULL REFERENCES emails(id), address TEXT NOT NULL, opened BOOL NOT NULL, updated_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, reply TEXT, name TEXT, fields TEXT);"
+         CREATE TABLE email_recipients (id INTEGER PRIMARY KEY, email_id INTEGER NOT NULL REFERENCES emails(id), address TEXT NOT NULL, opened BOOL NOT NULL, updated_at TIMESTAMP NOT NULL, is_sent BOOL NOT NULL, reply TEXT, name TEXT, fields TEXT);\n\
+         CREATE TABLE unsubscribes (email TEXT NOT NULL, hub_id INTEGER NOT NULL, reason TEXT, created_at TIMESTAMP, PRIMARY KEY (email, hub_id));"
     )
     .unwrap();
+    pushkind_hedwig::db::ensure_schema(pool).unwrap();
 }
 
 fn setup_test_db(db_name: &str) -> (TempDir, common::TestDb, DbPool) {
@@ -90,6 +104,67 @@ fn create_and_get_email() {
     assert_eq!(fetched.recipients[0].id.get(), recipient_id);
 }
 
+#[test]
+fn create_email_skips_duplicate_addresses() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("create_email_duplicates.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let recipient = |name: &str| NewEmailRecipient {
+        address: RecipientEmail::try_from("to@example.com").unwrap(),
+        name: RecipientName::new(name).unwrap(),
+        fields: BTreeMap::new(),
+    };
+    let new_email = NewEmail {
+        message: EmailBody::new("Hello").unwrap(),
+        subject: None,
+        attachment: None,
+        attachment_name: None,
+        attachment_mime: None,
+        hub_id: HubId::try_from(1).unwrap(),
+        recipients: vec![recipient("Alice"), recipient("Alice again")],
+    };
+
+    let stored = repo.create_email(&new_email).unwrap();
+    assert_eq!(stored.recipients.len(), 1);
+    assert_eq!(stored.recipients[0].name.as_str(), "Alice");
+}
+
+#[test]
+fn create_email_dedupes_by_normalized_address() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("create_email_normalized.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone()).with_normalizer(SuppressionConfig {
+        gmail_canonical: true,
+        ..Default::default()
+    });
+    let recipient = |address: &str| NewEmailRecipient {
+        address: RecipientEmail::try_from(address).unwrap(),
+        name: RecipientName::new("Alice").unwrap(),
+        fields: BTreeMap::new(),
+    };
+    let new_email = NewEmail {
+        message: EmailBody::new("Hello").unwrap(),
+        subject: None,
+        attachment: None,
+        attachment_name: None,
+        attachment_mime: None,
+        hub_id: HubId::try_from(1).unwrap(),
+        recipients: vec![
+            recipient("John.Doe@gmail.com"),
+            recipient("johndoe+news@gmail.com"),
+            recipient("other@example.com"),
+        ],
+    };
+
+    let stored = repo.create_email(&new_email).unwrap();
+    let addresses: Vec<&str> = stored
+        .recipients
+        .iter()
+        .map(|r| r.address.as_str())
+        .collect();
+    assert_eq!(addresses, ["John.Doe@gmail.com", "other@example.com"]);
+}
+
 #[test]
 fn list_and_get_recipient() {
     let (_temp_dir, _test_db, pool) = setup_test_db("list_and_get_recipient.db");
@@ -146,6 +221,132 @@ fn update_recipient_updates_stats() {
     assert_eq!(updated.email.num_replied.get(), 1);
 }
 
+#[test]
+fn update_recipient_rolls_back_when_stats_recalc_fails() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("update_recipient_rolls_back.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let (email_id, recipient_id) = create_email(&repo);
+
+    // Make the counter recalculation fail after the recipient row is updated.
+    pool.get()
+        .unwrap()
+        .batch_execute(
+            "CREATE TRIGGER fail_recalc BEFORE UPDATE OF num_sent ON emails \
+             BEGIN SELECT RAISE(ABORT, 'recalc failed'); END;",
+        )
+        .unwrap();
+
+    let result = repo.update_recipient(
+        EmailRecipientId::try_from(recipient_id).unwrap(),
+        &UpdateEmailRecipient {
+            sent: Some(true),
+            opened: None,
+            reply: None,
+        },
+    );
+    assert!(result.is_err());
+
+    let email = repo
+        .get_email_by_id(
+            EmailId::try_from(email_id).unwrap(),
+            HubId::try_from(1).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+    assert!(!email.recipients[0].is_sent);
+    assert_eq!(email.email.num_sent.get(), 0);
+}
+
+#[test]
+fn rejected_recipients_stay_rejected() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("rejected_recipients.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let (_, recipient_id) = create_email(&repo);
+    let recipient_id = EmailRecipientId::try_from(recipient_id).unwrap();
+
+    assert!(!repo.is_recipient_rejected(recipient_id).unwrap());
+    repo.reject_recipient(recipient_id, "message too large")
+        .unwrap();
+    repo.reject_recipient(recipient_id, "again").unwrap();
+    assert!(repo.is_recipient_rejected(recipient_id).unwrap());
+}
+
+#[test]
+fn email_deadline_is_stored_and_replaced() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("email_deadlines.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let (email_id, _) = create_email(&repo);
+    let email_id = EmailId::try_from(email_id).unwrap();
+    let first = NaiveDate::from_ymd_opt(2026, 5, 1)
+        .unwrap()
+        .and_hms_opt(9, 0, 0)
+        .unwrap()
+        .and_utc();
+    let later = first + Duration::days(1);
+
+    assert_eq!(repo.get_email_deadline(email_id).unwrap(), None);
+    repo.set_email_deadline(email_id, first).unwrap();
+    assert_eq!(repo.get_email_deadline(email_id).unwrap(), Some(first));
+    repo.set_email_deadline(email_id, later).unwrap();
+    assert_eq!(repo.get_email_deadline(email_id).unwrap(), Some(later));
+}
+
+#[test]
+fn purge_emails_older_than_keeps_recent_emails() {
+    use pushkind_emailer::schema::{email_recipients, emails};
+
+    let (_temp_dir, _test_db, pool) = setup_test_db("purge_emails_older_than.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let (old_id, old_recipient) = create_email(&repo);
+    let (recent_id, recent_recipient) = create_email(&repo);
+    let old_recipient = EmailRecipientId::try_from(old_recipient).unwrap();
+    repo.set_reply_language(old_recipient, "ru").unwrap();
+    assert_eq!(
+        repo.get_reply_language(old_recipient).unwrap().as_deref(),
+        Some("ru")
+    );
+    repo.reject_recipient(old_recipient, "too large").unwrap();
+    let old_email = EmailId::try_from(old_id).unwrap();
+    repo.set_email_deadline(old_email, Utc::now()).unwrap();
+    {
+        let mut conn = pool.get().unwrap();
+        diesel::update(emails::table.filter(emails::id.eq(old_id)))
+            .set(emails::created_at.eq(Utc::now().naive_utc() - Duration::days(90)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    let cutoff = Utc::now().naive_utc() - Duration::days(30);
+    assert_eq!(
+        repo.purge_emails_older_than(cutoff, HubId::try_from(2).unwrap())
+            .unwrap(),
+        0
+    );
+    assert_eq!(repo.purge_emails_older_than(cutoff, hub_id).unwrap(), 1);
+
+    assert!(repo.get_email_by_id(old_email, hub_id).unwrap().is_none());
+    let recent = repo
+        .get_email_by_id(EmailId::try_from(recent_id).unwrap(), hub_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(recent.recipients[0].id.get(), recent_recipient);
+    assert_eq!(repo.get_reply_language(old_recipient).unwrap(), None);
+    assert!(!repo.is_recipient_rejected(old_recipient).unwrap());
+    assert_eq!(repo.get_email_deadline(old_email).unwrap(), None);
+
+    let mut conn = pool.get().unwrap();
+    let remaining: i64 = email_recipients::table
+        .count()
+        .get_result(&mut conn)
+        .unwrap();
+    assert_eq!(remaining, 1);
+}
+
 #[test]
 fn hub_queries() {
     let (_temp_dir, _test_db, pool) = setup_test_db("hub_queries.db");
@@ -160,3 +361,568 @@ fn hub_queries() {
     let hubs = repo.list_hubs().unwrap();
     assert_eq!(hubs.len(), 1);
 }
+
+#[test]
+fn bounce_stats_counts_events_in_window() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("bounce_stats_counts_events_in_window.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let other_hub = HubId::try_from(2).unwrap();
+
+    repo.record_delivery_event(hub_id, DeliveryEventKind::Sent)
+        .unwrap();
+    repo.record_delivery_event(hub_id, DeliveryEventKind::Sent)
+        .unwrap();
+    repo.record_delivery_event(hub_id, DeliveryEventKind::Bounce)
+        .unwrap();
+    repo.record_delivery_event(other_hub, DeliveryEventKind::Bounce)
+        .unwrap();
+
+    let since = Utc::now().naive_utc() - Duration::hours(1);
+    let stats = repo.bounce_stats(hub_id, since).unwrap();
+    assert_eq!(stats.sent, 2);
+    assert_eq!(stats.bounced, 1);
+    assert_eq!(stats.rate(), 0.5);
+
+    let future = Utc::now().naive_utc() + Duration::hours(1);
+    let stats = repo.bounce_stats(hub_id, future).unwrap();
+    assert_eq!(stats.sent, 0);
+    assert_eq!(stats.bounced, 0);
+}
+
+#[test]
+fn hub_daily_stats_buckets_events_by_utc_day() {
+    use pushkind_hedwig::models::NewDeliveryEvent;
+    use pushkind_hedwig::schema::delivery_events;
+
+    let (_temp_dir, _test_db, pool) = setup_test_db("hub_daily_stats.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+    let at = |d, h| day(d).and_hms_opt(h, 0, 0).unwrap();
+
+    let events = [
+        (1, at(1, 23), DeliveryEventKind::Sent),
+        (1, at(2, 0), DeliveryEventKind::Sent),
+        (1, at(2, 9), DeliveryEventKind::Sent),
+        (1, at(2, 10), DeliveryEventKind::Opened),
+        (1, at(2, 11), DeliveryEventKind::Replied),
+        (1, at(4, 23), DeliveryEventKind::Bounce),
+        (1, at(5, 0), DeliveryEventKind::Sent),
+        (2, at(2, 12), DeliveryEventKind::Sent),
+    ];
+    {
+        let mut conn = pool.get().unwrap();
+        for (hub, created_at, kind) in events {
+            diesel::insert_into(delivery_events::table)
+                .values(&NewDeliveryEvent {
+                    hub_id: hub,
+                    kind: kind.as_str(),
+                    created_at,
+                    imap_uid: None,
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+    }
+
+    let stats = repo.hub_daily_stats(hub_id, day(2), day(4)).unwrap();
+    let summary: Vec<_> = stats
+        .iter()
+        .map(|s| (s.date, s.sent, s.opened, s.replied, s.bounced))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (day(2), 2, 1, 1, 0),
+            (day(3), 0, 0, 0, 0),
+            (day(4), 0, 0, 0, 1),
+        ]
+    );
+
+    assert!(
+        repo.hub_daily_stats(hub_id, day(4), day(2))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn bounce_for_same_message_is_recorded_once() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("bounce_recorded_once.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    assert!(repo.record_bounce_once(hub_id, 7).unwrap());
+    assert!(!repo.record_bounce_once(hub_id, 7).unwrap());
+    assert!(repo.record_bounce_once(hub_id, 8).unwrap());
+
+    let since = Utc::now().naive_utc() - Duration::hours(1);
+    assert_eq!(repo.bounce_stats(hub_id, since).unwrap().bounced, 2);
+}
+
+#[test]
+fn forgotten_bounce_uids_can_be_recorded_again() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("forget_bounce_uids.db");
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let other_hub = HubId::try_from(2).unwrap();
+
+    assert!(repo.record_bounce_once(hub_id, 7).unwrap());
+    assert!(repo.record_bounce_once(other_hub, 7).unwrap());
+    repo.record_delivery_event(hub_id, DeliveryEventKind::Sent)
+        .unwrap();
+
+    assert_eq!(repo.forget_bounce_uids(hub_id).unwrap(), 1);
+    assert!(repo.record_bounce_once(hub_id, 7).unwrap());
+    assert!(!repo.record_bounce_once(other_hub, 7).unwrap());
+
+    let since = Utc::now().naive_utc() - Duration::hours(1);
+    let stats = repo.bounce_stats(hub_id, since).unwrap();
+    assert_eq!((stats.sent, stats.bounced), (1, 2));
+}
+
+#[test]
+fn reply_message_ids_are_recorded_once_per_hub() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("reply_message_ids.db");
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let other_hub = HubId::try_from(2).unwrap();
+
+    assert!(
+        !repo
+            .is_reply_recorded(hub_id, "a@mail.example.org")
+            .unwrap()
+    );
+    assert!(
+        repo.record_reply_once(hub_id, "a@mail.example.org", 10)
+            .unwrap()
+    );
+    assert!(
+        repo.is_reply_recorded(hub_id, "a@mail.example.org")
+            .unwrap()
+    );
+    assert!(
+        !repo
+            .is_reply_recorded(other_hub, "a@mail.example.org")
+            .unwrap()
+    );
+    assert!(
+        !repo
+            .record_reply_once(hub_id, "a@mail.example.org", 10)
+            .unwrap()
+    );
+    assert!(
+        repo.record_reply_once(other_hub, "a@mail.example.org", 10)
+            .unwrap()
+    );
+}
+
+#[test]
+fn reply_message_ids_keep_only_the_newest() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("reply_message_ids_bounded.db");
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    for id in ["a", "b", "c"] {
+        assert!(repo.record_reply_once(hub_id, id, 2).unwrap());
+    }
+    // "a" was evicted and counts as new again, which in turn evicts "b".
+    assert!(!repo.record_reply_once(hub_id, "c", 2).unwrap());
+    assert!(repo.record_reply_once(hub_id, "a", 2).unwrap());
+    assert!(repo.record_reply_once(hub_id, "b", 2).unwrap());
+}
+
+#[test]
+fn transaction_rolls_back_all_operations_on_error() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("transaction_rolls_back.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let (_, recipient_id) = create_email(&repo);
+
+    let result: Result<(), RepositoryError> = repo.transaction(|tx| {
+        tx.set_imap_last_uid(hub_id, ImapUid::try_from(42).unwrap())?;
+        tx.update_recipient(
+            EmailRecipientId::try_from(recipient_id).unwrap(),
+            &UpdateEmailRecipient {
+                sent: Some(true),
+                opened: None,
+                reply: None,
+            },
+        )?;
+        Err(RepositoryError::NotFound)
+    });
+    assert!(result.is_err());
+
+    let hub = repo.get_hub_by_id(hub_id).unwrap().unwrap();
+    assert_eq!(hub.imap_last_uid.get(), 0);
+    let recipient = repo
+        .get_email_recipient_by_id(EmailRecipientId::try_from(recipient_id).unwrap(), hub_id)
+        .unwrap()
+        .unwrap();
+    assert!(!recipient.is_sent);
+}
+
+#[test]
+fn transaction_commits_all_operations_on_success() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("transaction_commits.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    repo.transaction(|tx| {
+        tx.set_imap_last_uid(hub_id, ImapUid::try_from(42).unwrap())?;
+        tx.record_delivery_event(hub_id, DeliveryEventKind::Sent)
+    })
+    .unwrap();
+
+    let hub = repo.get_hub_by_id(hub_id).unwrap().unwrap();
+    assert_eq!(hub.imap_last_uid.get(), 42);
+    let since = Utc::now().naive_utc() - Duration::hours(1);
+    assert_eq!(repo.bounce_stats(hub_id, since).unwrap().sent, 1);
+}
+
+#[test]
+fn uid_validity_round_trip() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("uid_validity_round_trip.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    assert_eq!(repo.get_uid_validity(hub_id).unwrap(), None);
+
+    repo.set_uid_validity(hub_id, 100).unwrap();
+    assert_eq!(repo.get_uid_validity(hub_id).unwrap(), Some(100));
+
+    repo.set_uid_validity(hub_id, u32::MAX).unwrap();
+    assert_eq!(repo.get_uid_validity(hub_id).unwrap(), Some(u32::MAX));
+}
+
+#[test]
+fn sending_pause_round_trip_keeps_uid_validity() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("sending_pause_round_trip.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    assert!(!repo.is_sending_paused(hub_id).unwrap());
+
+    repo.set_uid_validity(hub_id, 7).unwrap();
+    repo.set_sending_paused(hub_id, true).unwrap();
+    assert!(repo.is_sending_paused(hub_id).unwrap());
+    assert_eq!(repo.get_uid_validity(hub_id).unwrap(), Some(7));
+
+    repo.set_sending_paused(hub_id, false).unwrap();
+    assert!(!repo.is_sending_paused(hub_id).unwrap());
+}
+
+#[test]
+fn tagged_and_untagged_addresses_share_suppression_entry() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("subaddress_suppression.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+
+    for address in ["user+promo@example.com", "user@example.com"] {
+        repo.unsubscribe_recipient(
+            &normalize_address(address, true),
+            hub_id,
+            UnsubscribeSource::Request,
+            None,
+        )
+        .unwrap();
+    }
+
+    let mut conn = pool.get().unwrap();
+    let stored: Vec<String> = unsubscribes::table
+        .select(unsubscribes::email)
+        .load(&mut conn)
+        .unwrap();
+    assert_eq!(stored, vec!["user@example.com".to_string()]);
+}
+
+#[test]
+fn unsubscribes_are_listed_and_deleted() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("list_unsubscribes.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_one = HubId::try_from(1).unwrap();
+    let hub_two = HubId::try_from(2).unwrap();
+
+    repo.unsubscribe_recipient(
+        "c@example.com",
+        hub_one,
+        UnsubscribeSource::Complaint,
+        Some("complaint: abuse"),
+    )
+    .unwrap();
+    repo.unsubscribe_recipient("a@example.com", hub_one, UnsubscribeSource::Bounce, None)
+        .unwrap();
+    repo.unsubscribe_recipient(
+        "b@example.com",
+        hub_one,
+        UnsubscribeSource::Request,
+        Some("Too many emails"),
+    )
+    .unwrap();
+    repo.unsubscribe_recipient(
+        "other@example.com",
+        hub_two,
+        UnsubscribeSource::Request,
+        None,
+    )
+    .unwrap();
+
+    let page = |offset, limit| {
+        repo.list_unsubscribes(hub_one, Pagination { offset, limit })
+            .unwrap()
+    };
+    let first = page(0, 2);
+    assert_eq!(
+        first
+            .iter()
+            .map(|entry| (entry.email.as_str(), entry.source, entry.reason.as_deref()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("a@example.com", Some(UnsubscribeSource::Bounce), None),
+            (
+                "b@example.com",
+                Some(UnsubscribeSource::Request),
+                Some("Too many emails")
+            ),
+        ]
+    );
+    assert!(first.iter().all(|entry| entry.created_at.is_some()));
+    let second = page(2, 2);
+    assert_eq!(second.len(), 1);
+    assert_eq!(second[0].email, "c@example.com");
+    assert_eq!(second[0].reason.as_deref(), Some("complaint: abuse"));
+    assert_eq!(second[0].source, Some(UnsubscribeSource::Complaint));
+
+    assert!(repo.delete_unsubscribe("b@example.com", hub_one).unwrap());
+    assert!(!repo.delete_unsubscribe("b@example.com", hub_one).unwrap());
+    assert!(
+        !repo
+            .delete_unsubscribe("other@example.com", hub_one)
+            .unwrap()
+    );
+    assert!(!repo.is_suppressed("b@example.com", hub_one).unwrap());
+    assert_eq!(
+        page(0, 10)
+            .into_iter()
+            .map(|entry| entry.email)
+            .collect::<Vec<_>>(),
+        vec!["a@example.com", "c@example.com"]
+    );
+    assert!(repo.is_suppressed("other@example.com", hub_two).unwrap());
+
+    // Deleting an unsubscribe drops its source along with it.
+    repo.unsubscribe_recipient("b@example.com", hub_one, UnsubscribeSource::OneClick, None)
+        .unwrap();
+    let entries = page(0, 10);
+    assert_eq!(entries[1].email, "b@example.com");
+    assert_eq!(entries[1].source, Some(UnsubscribeSource::OneClick));
+}
+
+#[test]
+fn suppression_matches_rows_stored_before_normalization() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("suppression_normalized.db");
+    let hub_id = HubId::try_from(1).unwrap();
+    DieselRepository::new(pool.clone())
+        .unsubscribe_recipient(
+            "John.Doe@gmail.com",
+            hub_id,
+            UnsubscribeSource::Request,
+            None,
+        )
+        .unwrap();
+
+    // Turning the Gmail rules on must not resubscribe the stored address.
+    let repo = DieselRepository::new(pool.clone()).with_normalizer(SuppressionConfig {
+        lowercase: true,
+        gmail_canonical: true,
+        ..Default::default()
+    });
+    assert!(repo.is_suppressed(" John.Doe@gmail.com ", hub_id).unwrap());
+
+    // New unsubscribes are stored normalized and match every spelling.
+    repo.unsubscribe_recipient(
+        "J.Smith+news@GoogleMail.com",
+        hub_id,
+        UnsubscribeSource::Request,
+        None,
+    )
+    .unwrap();
+    assert!(repo.is_suppressed("jsmith@gmail.com", hub_id).unwrap());
+    assert!(repo.is_suppressed("J.Smith@gmail.com", hub_id).unwrap());
+
+    assert!(
+        repo.delete_unsubscribe("John.Doe@gmail.com", hub_id)
+            .unwrap()
+    );
+    assert!(!repo.is_suppressed("John.Doe@gmail.com", hub_id).unwrap());
+}
+
+#[test]
+fn unsubscribe_keeps_its_first_timestamp() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("unsubscribe_timestamp.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let all = Pagination {
+        offset: 0,
+        limit: 10,
+    };
+
+    let before = Utc::now().naive_utc();
+    repo.unsubscribe_recipient(
+        "user@example.com",
+        hub_id,
+        UnsubscribeSource::Request,
+        Some("first"),
+    )
+    .unwrap();
+    let created_at = repo.list_unsubscribes(hub_id, all).unwrap()[0]
+        .created_at
+        .expect("timestamp set on insert");
+    assert!(created_at >= before - Duration::seconds(1));
+    assert!(created_at <= Utc::now().naive_utc());
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    repo.unsubscribe_recipient(
+        "user@example.com",
+        hub_id,
+        UnsubscribeSource::Complaint,
+        Some("second"),
+    )
+    .unwrap();
+    let entries = repo.list_unsubscribes(hub_id, all).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].reason.as_deref(), Some("first"));
+    assert_eq!(entries[0].source, Some(UnsubscribeSource::Request));
+    assert_eq!(entries[0].created_at, Some(created_at));
+}
+
+#[test]
+fn global_suppression_applies_to_every_hub() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("global_suppression.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_one = HubId::try_from(1).unwrap();
+    let hub_two = HubId::try_from(2).unwrap();
+
+    repo.unsubscribe_recipient(
+        "local@example.com",
+        hub_one,
+        UnsubscribeSource::Request,
+        None,
+    )
+    .unwrap();
+    repo.suppress_globally(" DNC@Example.com ", Some("legal request"))
+        .unwrap();
+    repo.suppress_globally("dnc@example.com", None).unwrap();
+
+    assert!(repo.is_suppressed("local@example.com", hub_one).unwrap());
+    assert!(!repo.is_suppressed("local@example.com", hub_two).unwrap());
+    assert!(repo.is_suppressed("dnc@example.com", hub_one).unwrap());
+    assert!(repo.is_suppressed("DNC@example.com", hub_two).unwrap());
+    assert!(!repo.is_suppressed("other@example.com", hub_two).unwrap());
+}
+
+const SECRET: &str = "test-secret";
+
+fn one_click_config() -> ServerConfig {
+    ServerConfig {
+        unsubscribe_secret: Some(SECRET.into()),
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn one_click_unsubscribe_records_recipient() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("one_click_unsubscribe.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let (_, recipient_id) = create_email(&repo);
+    let config = one_click_config();
+
+    let token = UnsubscribeKey::new(SECRET).unwrap().sign(recipient_id, 1);
+    let unsubscribed = process_one_click_unsubscribe(&repo, &token, hub_id, &config).unwrap();
+    assert_eq!(unsubscribed.as_deref(), Some("to@example.com"));
+    assert!(repo.is_suppressed("to@example.com", hub_id).unwrap());
+    let entries = repo
+        .list_unsubscribes(
+            hub_id,
+            Pagination {
+                offset: 0,
+                limit: 10,
+            },
+        )
+        .unwrap();
+    assert_eq!(entries[0].source, Some(UnsubscribeSource::OneClick));
+
+    let mut conn = pool.get().unwrap();
+    let reason: Option<String> = unsubscribes::table
+        .select(unsubscribes::reason)
+        .first(&mut conn)
+        .unwrap();
+    assert_eq!(reason.as_deref(), Some(ONE_CLICK_REASON));
+}
+
+#[test]
+fn one_click_unsubscribe_rejects_invalid_and_expired_tokens() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("one_click_invalid.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let (_, recipient_id) = create_email(&repo);
+    let config = one_click_config();
+    let token = UnsubscribeKey::new(SECRET).unwrap().sign(recipient_id, 1);
+
+    let bare = recipient_id.to_string();
+    for invalid in ["", "not-a-token", "-1", bare.as_str()] {
+        assert_eq!(
+            process_one_click_unsubscribe(&repo, invalid, hub_id, &config).unwrap(),
+            None
+        );
+    }
+    // The recipient belongs to hub 1 only.
+    let other_hub = HubId::try_from(2).unwrap();
+    assert_eq!(
+        process_one_click_unsubscribe(&repo, &token, other_hub, &config).unwrap(),
+        None
+    );
+
+    // Once the email is purged, its links no longer unsubscribe anyone.
+    let cutoff = Utc::now().naive_utc() + Duration::days(1);
+    repo.purge_emails_older_than(cutoff, hub_id).unwrap();
+    assert_eq!(
+        process_one_click_unsubscribe(&repo, &token, hub_id, &config).unwrap(),
+        None
+    );
+    assert!(!repo.is_suppressed("to@example.com", hub_id).unwrap());
+}
+
+#[test]
+fn one_click_unsubscribe_requires_a_secret() {
+    let (_temp_dir, _test_db, pool) = setup_test_db("one_click_no_secret.db");
+    insert_hub(&pool);
+    let repo = DieselRepository::new(pool.clone());
+    let hub_id = HubId::try_from(1).unwrap();
+    let (_, recipient_id) = create_email(&repo);
+
+    let token = UnsubscribeKey::new(SECRET).unwrap().sign(recipient_id, 1);
+    let config = ServerConfig::default();
+    for token in [token, recipient_id.to_string()] {
+        assert_eq!(
+            process_one_click_unsubscribe(&repo, &token, hub_id, &config).unwrap(),
+            None
+        );
+    }
+    assert!(!repo.is_suppressed("to@example.com", hub_id).unwrap());
+}